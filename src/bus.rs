@@ -1,7 +1,15 @@
-use anyhow::{Context, Result};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
 
 use crate::{
+    cpu::ExitStatus,
+    device_tree::NodeContext,
+    events::{EventQueue, Timer},
     memory::{dram::Sizes, virtual_memory::MemorySize},
+    trace::{AccessKind, MmioAccess, MmioObserver, WatchFilter, WatchId},
     trap::Exception,
 };
 
@@ -9,27 +17,181 @@ pub trait Device {
     fn as_any(&self) -> &dyn std::any::Any;
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
 
+    /// A short name of the device for diagnostics, its type name by default.
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
     fn load(&self, addr: u64, size: Sizes) -> Result<MemorySize>;
     fn store(&mut self, addr: u64, size: Sizes, value: MemorySize) -> Result<()>;
 
-    fn increment(&mut self) {}
+    /// Put the device back in its power-on state when the machine resets.
+    fn reset(&mut self) {}
+
+    /// Whether instructions may be fetched from the device.
+    fn executable(&self) -> bool {
+        true
+    }
+
+    /// The host memory behind the device, for devices that are plain memory like RAM and ROM.
+    /// The CPU then accesses it directly instead of through [`Device::load`], so reads of such a
+    /// device must not have side effects. Bytes and halfwords are sign-extended.
+    fn host_memory(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The host memory behind the device for stores, for devices that are plain writable memory
+    /// like RAM. Stores to it then bypass [`Device::store`] and [`Device::take_request`].
+    fn host_memory_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Write `data` at `addr` from the host, like a loader or a flash programmer fills the device
+    /// before the guest runs. This bypasses what the guest sees, like read-only memory or the
+    /// command set of a flash. Devices with host memory support it by default.
+    fn preload(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let name = self.name();
+        let Some(memory) = self.host_memory_mut() else {
+            bail!("{name} cannot be preloaded");
+        };
+        match memory
+            .get_mut(addr as usize..)
+            .and_then(|memory| memory.get_mut(..data.len()))
+        {
+            Some(memory) => memory.copy_from_slice(data),
+            None => bail!("{name} has no memory at {addr:#x}"),
+        }
+        Ok(())
+    }
+
+    /// Something the device wants the machine to do, checked after every store to the device
+    /// and every event.
+    fn take_request(&mut self) -> Option<DeviceRequest> {
+        None
+    }
+
+    /// Hand the device its timer when it is added to a bus. Devices that need to act at some
+    /// point in emulated time keep it and schedule their deadlines on it.
+    fn connect_timer(&mut self, _timer: Timer) {}
+
+    /// The deadline the device scheduled on its timer came up. The device sees the rest of the
+    /// bus so it can issue memory transactions of its own, like DMA.
+    fn event(&mut self, _memory: &mut BusView) {}
+
+    /// Hand the device its interrupt output `index` when it is added to a bus. Devices without
+    /// interrupts ignore it.
+    fn connect_irq(&mut self, _index: usize, _line: IrqLine) {}
+
+    /// The device tree node describing the device, which goes under the `soc` node. `node` has
+    /// where the device is mapped and renders its `reg` and interrupt properties. Devices the
+    /// guest finds without the device tree have none.
+    fn dts_node(&self, _node: &NodeContext) -> Option<String> {
+        None
+    }
+}
+
+/// An interrupt output of a device. The device raises and lowers it, the bus keeps a clone and
+/// routes the level to where the machine wired the line.
+#[derive(Debug, Clone, Default)]
+pub struct IrqLine {
+    level: Rc<Cell<bool>>,
+    /// Set whenever the level changes.
+    changed: Rc<Cell<bool>>,
+}
+
+impl IrqLine {
+    /// A line that is not wired anywhere yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A line that sets `changed` whenever its level changes.
+    pub(crate) fn with_flag(changed: Rc<Cell<bool>>) -> Self {
+        Self {
+            level: Rc::default(),
+            changed,
+        }
+    }
+
+    pub fn set(&self, level: bool) {
+        if self.level.replace(level) != level {
+            self.changed.set(true);
+        }
+    }
+
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.level.get()
+    }
+}
+
+/// Where an interrupt line is wired to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqTarget {
+    /// An interrupt source of the PLIC.
+    Plic(u32),
+    /// Bits of the `mip` register of the hart, like [`crate::csr::MTIP_BIT`]. Several lines
+    /// wired to the same bit are or-ed.
+    Mip(u32),
 }
 
+/// A request from a device to the machine it is part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceRequest {
+    /// Stop the machine, [`crate::cpu::Riscv32Cpu::run`] returns the status.
+    Exit(ExitStatus),
+    /// Reset the hart and every device.
+    Reset,
+}
+
+/// Identifies a device on a [`Bus`] from [`Bus::add_device`] until it is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceHandle(usize);
+
 pub struct VirtualDevice {
     inner_device: Box<dyn Device>,
     base: u64,
     size: u64,
+    /// Where the interrupt outputs of the device go, by index.
+    irqs: Vec<IrqTarget>,
+    /// The handle of the device once it is on a bus, which is also the id of its timer.
+    handle: Option<DeviceHandle>,
+    /// Whether the device exposes its host memory, so other devices skip looking for it.
+    direct: bool,
 }
 
 impl VirtualDevice {
     pub fn new(inner_device: Box<dyn Device>, base: u64, size: u64) -> Self {
+        let direct = inner_device.host_memory().is_some();
         Self {
             inner_device,
             base,
             size,
+            irqs: Vec::new(),
+            handle: None,
+            direct,
         }
     }
 
+    /// Wire the interrupt outputs of the device, replacing the wiring it came with. Output `n`
+    /// goes to the `n`th target, outputs without a target stay unconnected.
+    pub fn with_irqs(mut self, irqs: impl IntoIterator<Item = IrqTarget>) -> Self {
+        self.irqs = irqs.into_iter().collect();
+        self
+    }
+
+    pub fn irqs(&self) -> &[IrqTarget] {
+        &self.irqs
+    }
+
     pub fn base(&self) -> u64 {
         self.base
     }
@@ -38,6 +200,30 @@ impl VirtualDevice {
         self.size
     }
 
+    pub fn name(&self) -> &'static str {
+        self.inner_device.name()
+    }
+
+    /// The handle of the device while it is on a bus.
+    pub fn handle(&self) -> Option<DeviceHandle> {
+        self.handle
+    }
+
+    /// The device itself, if it is a `T`.
+    pub fn downcast_ref<T: Device + 'static>(&self) -> Option<&T> {
+        self.inner_device.as_any().downcast_ref::<T>()
+    }
+
+    /// The device itself, if it is a `T`.
+    pub fn downcast_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.inner_device.as_any_mut().downcast_mut::<T>()
+    }
+
+    /// Whether `address` falls into the range the device is mapped at.
+    pub fn contains(&self, address: u64) -> bool {
+        address.wrapping_sub(self.base) < self.size
+    }
+
     pub fn load(&self, addr: u64, size: Sizes) -> Result<MemorySize> {
         self.inner_device.load(addr, size)
     }
@@ -46,13 +232,113 @@ impl VirtualDevice {
         self.inner_device.store(addr, size, value)
     }
 
-    pub fn increment(&mut self) {
-        self.inner_device.increment();
+    pub fn reset(&mut self) {
+        self.inner_device.reset();
+    }
+
+    pub fn take_request(&mut self) -> Option<DeviceRequest> {
+        self.inner_device.take_request()
+    }
+
+    pub fn executable(&self) -> bool {
+        self.inner_device.executable()
+    }
+
+    /// The host memory of the device from `address` on, if the device is plain memory.
+    fn host_memory(&self, address: u64) -> Option<&[u8]> {
+        if !self.direct {
+            return None;
+        }
+        let offset = (address - self.base) as usize;
+        self.inner_device.host_memory()?.get(offset..)
     }
+
+    /// The writable host memory of the device from `address` on, if the device is plain memory.
+    fn host_memory_mut(&mut self, address: u64) -> Option<&mut [u8]> {
+        if !self.direct {
+            return None;
+        }
+        let offset = (address - self.base) as usize;
+        self.inner_device.host_memory_mut()?.get_mut(offset..)
+    }
+
+    pub fn event(&mut self, memory: &mut BusView) {
+        self.inner_device.event(memory);
+    }
+
+    pub fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        self.inner_device.dts_node(node)
+    }
+}
+
+/// A device mapped on the bus, as listed by [`Bus::memory_map`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: u64,
+    pub size: u64,
+    pub name: &'static str,
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#010x}-{:#010x} {}",
+            self.base,
+            self.base + (self.size - 1),
+            self.name
+        )
+    }
+}
+
+/// The index of the device mapping `address` in `devices`, which are sorted by their base.
+fn find_index(devices: &[VirtualDevice], address: u64) -> Option<usize> {
+    let index = devices
+        .partition_point(|device| device.base() <= address)
+        .checked_sub(1)?;
+    devices[index].contains(address).then_some(index)
+}
+
+/// A watch on the accesses to the bus.
+struct Watch {
+    id: WatchId,
+    filter: WatchFilter,
+    observer: Box<dyn MmioObserver>,
+}
+
+/// An interrupt line of a device on the bus.
+struct IrqConnection {
+    device: DeviceHandle,
+    target: IrqTarget,
+    line: IrqLine,
+    /// Lines of disabled devices are disconnected and read as low.
+    connected: bool,
 }
 
 pub struct Bus {
+    /// The devices, sorted by their base address. Their ranges never overlap.
     devices: Vec<VirtualDevice>,
+    /// Devices that are on the bus but not mapped.
+    disabled: Vec<VirtualDevice>,
+    /// The index of the device the last access went to, tried before searching.
+    last_hit: Cell<usize>,
+    /// The interrupt lines of the devices and where they are wired to.
+    irqs: Vec<IrqConnection>,
+    /// Where the lines of removed devices were wired to, until the CPU drove them low once so
+    /// the sources they drove see the line go low.
+    released: Vec<IrqTarget>,
+    /// The timers of the devices.
+    events: EventQueue,
+    /// A request a device made on a store or an event, before it is taken.
+    request: Option<DeviceRequest>,
+    /// Set when an interrupt line changed, a timer was scheduled or a device made a request, so
+    /// the CPU stops its batch of instructions and lets the machine catch up.
+    attention: Rc<Cell<bool>>,
+    /// Where the accesses to the bus are reported to.
+    watches: RefCell<Vec<Watch>>,
+    next_watch: usize,
+    /// The hart and the address of the instruction the accesses come from, for the watches.
+    origin: Cell<(u32, u32)>,
 }
 
 impl Default for Bus {
@@ -63,64 +349,564 @@ impl Default for Bus {
 
 impl Bus {
     pub fn new() -> Self {
+        let attention = Rc::new(Cell::new(false));
         Self {
             devices: Vec::new(),
+            disabled: Vec::new(),
+            last_hit: Cell::new(0),
+            irqs: Vec::new(),
+            released: Vec::new(),
+            events: EventQueue::with_flag(attention.clone()),
+            request: None,
+            attention,
+            watches: RefCell::default(),
+            next_watch: 0,
+            origin: Cell::new((0, 0)),
         }
     }
 
+    /// The first mapped device of type `T`. Use [`Bus::device`] when there can be several.
     pub fn get_device<T>(&self) -> Option<&T>
     where
         T: Device + 'static,
     {
-        for device in &self.devices {
-            if let Some(device) = device.inner_device.as_any().downcast_ref::<T>() {
-                return Some(device);
-            }
-        }
-        None
+        self.devices.iter().find_map(VirtualDevice::downcast_ref)
     }
 
+    /// The first mapped device of type `T`. Use [`Bus::device_mut`] when there can be several.
     pub fn get_device_mut<T>(&mut self) -> Option<&mut T>
     where
         T: Device + 'static,
     {
-        for device in &mut self.devices {
-            if let Some(device) = device.inner_device.as_any_mut().downcast_mut::<T>() {
-                return Some(device);
-            }
-        }
-        None
+        self.devices
+            .iter_mut()
+            .find_map(VirtualDevice::downcast_mut)
     }
 
-    pub fn get_devices_mut(&mut self) -> &mut Vec<VirtualDevice> {
+    /// The device `handle` refers to, if it is still on the bus and a `T`.
+    pub fn device<T>(&self, handle: DeviceHandle) -> Option<&T>
+    where
+        T: Device + 'static,
+    {
+        self.devices
+            .iter()
+            .chain(&self.disabled)
+            .find(|device| device.handle == Some(handle))?
+            .downcast_ref()
+    }
+
+    /// The device `handle` refers to, if it is still on the bus and a `T`.
+    pub fn device_mut<T>(&mut self, handle: DeviceHandle) -> Option<&mut T>
+    where
+        T: Device + 'static,
+    {
+        self.devices
+            .iter_mut()
+            .chain(&mut self.disabled)
+            .find(|device| device.handle == Some(handle))?
+            .downcast_mut()
+    }
+
+    /// The devices, sorted by their base address.
+    pub fn get_devices_mut(&mut self) -> &mut [VirtualDevice] {
         &mut self.devices
     }
 
-    pub fn get_devices(&self) -> &Vec<VirtualDevice> {
+    /// The devices, sorted by their base address.
+    pub fn get_devices(&self) -> &[VirtualDevice] {
         &self.devices
     }
 
-    pub fn add_device(&mut self, device: VirtualDevice) {
-        self.devices.push(device);
+    /// Every device on the bus with the range it is mapped at, by address.
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        self.devices
+            .iter()
+            .map(|device| MemoryRegion {
+                base: device.base(),
+                size: device.size(),
+                name: device.name(),
+            })
+            .collect()
+    }
+
+    /// Where a device named `name` would go in `devices` if it was mapped at `base`. Fails when
+    /// the range is empty or overlaps a mapped device.
+    fn free_slot(&self, name: &str, base: u64, size: u64) -> Result<usize> {
+        if size == 0 {
+            bail!("{name} at {base:#x} has a size of 0");
+        }
+        let Some(end) = base.checked_add(size) else {
+            bail!("{name} at {base:#x} with a size of {size:#x} extends past the address space");
+        };
+        let index = self.devices.partition_point(|other| other.base() < base);
+        let neighbours = index.checked_sub(1).into_iter().chain([index]);
+        for other in neighbours.filter_map(|index| self.devices.get(index)) {
+            if other.base() < end && base < other.base() + other.size() {
+                bail!(
+                    "{name} at {base:#x}-{:#x} overlaps {} at {:#x}-{:#x}",
+                    end - 1,
+                    other.name(),
+                    other.base(),
+                    other.base() + (other.size() - 1)
+                );
+            }
+        }
+        Ok(index)
+    }
+
+    /// Add a device, connect its interrupt outputs as its wiring declares and hand it a timer.
+    /// Fails when the device has no size or overlaps a device already on the bus. The handle
+    /// refers to the device until it is removed.
+    pub fn add_device(&mut self, mut device: VirtualDevice) -> Result<DeviceHandle> {
+        let index = self.free_slot(device.name(), device.base(), device.size())?;
+
+        let timer = self.events.timer();
+        let handle = DeviceHandle(timer.id());
+        for (index, target) in device.irqs.iter().enumerate() {
+            let line = IrqLine::with_flag(self.attention.clone());
+            device.inner_device.connect_irq(index, line.clone());
+            self.irqs.push(IrqConnection {
+                device: handle,
+                target: *target,
+                line,
+                connected: true,
+            });
+        }
+        device.handle = Some(handle);
+        device.inner_device.connect_timer(timer);
+        self.devices.insert(index, device);
+        Ok(handle)
+    }
+
+    /// Take the device `handle` refers to off the list it is on.
+    fn take(&mut self, handle: DeviceHandle) -> Result<VirtualDevice> {
+        if let Some(index) = self.position(handle) {
+            return Ok(self.devices.remove(index));
+        }
+        match self
+            .disabled
+            .iter()
+            .position(|device| device.handle == Some(handle))
+        {
+            Some(index) => Ok(self.disabled.remove(index)),
+            None => bail!("{handle:?} is not on the bus"),
+        }
+    }
+
+    /// The index of the mapped device `handle` refers to.
+    fn position(&self, handle: DeviceHandle) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|device| device.handle == Some(handle))
+    }
+
+    fn connect_irqs(&mut self, handle: DeviceHandle, connected: bool) {
+        for irq in self.irqs.iter_mut().filter(|irq| irq.device == handle) {
+            irq.connected = connected;
+        }
+        self.attention.set(true);
+    }
+
+    /// Move the device `handle` refers to to `base`. Fails when it would overlap another
+    /// device, in which case it stays where it is.
+    pub fn remap(&mut self, handle: DeviceHandle, base: u64) -> Result<()> {
+        let Some(index) = self.position(handle) else {
+            let device = self
+                .disabled
+                .iter_mut()
+                .find(|device| device.handle == Some(handle))
+                .with_context(|| format!("{handle:?} is not on the bus"))?;
+            device.base = base;
+            return Ok(());
+        };
+        let device = self.devices.remove(index);
+        match self.free_slot(device.name(), base, device.size()) {
+            Ok(slot) => {
+                let mut device = device;
+                device.base = base;
+                self.devices.insert(slot, device);
+                Ok(())
+            }
+            Err(e) => {
+                self.devices.insert(index, device);
+                Err(e)
+            }
+        }
+    }
+
+    /// Unmap the device `handle` refers to and disconnect its interrupt lines. The device keeps
+    /// its state and its timer, and its range is free for other devices until it is enabled.
+    pub fn disable(&mut self, handle: DeviceHandle) -> Result<()> {
+        let Some(index) = self.position(handle) else {
+            return self.take(handle).map(|device| self.disabled.push(device));
+        };
+        self.disabled.push(self.devices.remove(index));
+        self.connect_irqs(handle, false);
+        Ok(())
+    }
+
+    /// Map the device `handle` refers to again after [`Bus::disable`]. Fails when another device
+    /// took its range in the meantime.
+    pub fn enable(&mut self, handle: DeviceHandle) -> Result<()> {
+        if self.position(handle).is_some() {
+            return Ok(());
+        }
+        let device = self.take(handle)?;
+        match self.free_slot(device.name(), device.base(), device.size()) {
+            Ok(slot) => {
+                self.devices.insert(slot, device);
+                self.connect_irqs(handle, true);
+                Ok(())
+            }
+            Err(e) => {
+                self.disabled.push(device);
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether the device `handle` refers to is on the bus and mapped.
+    pub fn is_enabled(&self, handle: DeviceHandle) -> bool {
+        self.position(handle).is_some()
+    }
+
+    /// Take the device `handle` refers to off the bus. Its interrupt lines are disconnected and
+    /// go low, and its events are dropped. It can be added again, with a new handle.
+    pub fn remove(&mut self, handle: DeviceHandle) -> Result<VirtualDevice> {
+        let mut device = self.take(handle)?;
+        device.handle = None;
+        let (removed, kept) = std::mem::take(&mut self.irqs)
+            .into_iter()
+            .partition::<Vec<_>, _>(|irq| irq.device == handle);
+        self.irqs = kept;
+        for irq in removed {
+            if !self.released.contains(&irq.target) {
+                self.released.push(irq.target);
+            }
+        }
+        self.attention.set(true);
+        Ok(device)
+    }
+
+    /// The index of the device mapping `address`.
+    fn find(&self, address: u64) -> Option<usize> {
+        let last = self.last_hit.get();
+        if self
+            .devices
+            .get(last)
+            .is_some_and(|device| device.contains(address))
+        {
+            return Some(last);
+        }
+        let index = find_index(&self.devices, address)?;
+        self.last_hit.set(index);
+        Some(index)
+    }
+
+    /// The host memory from `address` to the end of the device there, if that device is plain
+    /// memory. See [`Device::host_memory`].
+    pub fn host_memory(&self, address: u64) -> Option<&[u8]> {
+        if self.is_watched(address) {
+            return None;
+        }
+        self.devices[self.find(address)?].host_memory(address)
+    }
+
+    /// The writable host memory from `address` to the end of the device there, if that device
+    /// is plain writable memory. See [`Device::host_memory_mut`].
+    pub fn host_memory_mut(&mut self, address: u64) -> Option<&mut [u8]> {
+        if self.is_watched(address) {
+            return None;
+        }
+        let index = self.find(address)?;
+        self.devices[index].host_memory_mut(address)
+    }
+
+    /// Copy `data` into memory starting at `address`, like a loader does.
+    pub fn write_bytes(&mut self, address: u64, data: &[u8]) -> Result<()> {
+        if let Some(memory) = self
+            .host_memory_mut(address)
+            .and_then(|memory| memory.get_mut(..data.len()))
+        {
+            memory.copy_from_slice(data);
+            return Ok(());
+        }
+        for (offset, byte) in data.iter().enumerate() {
+            self.write(address + offset as u64, *byte as MemorySize, Sizes::Byte)?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `address` from the host, see [`Device::preload`]. The data may span
+    /// devices mapped next to each other.
+    pub fn preload(&mut self, mut address: u64, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let index = self
+                .find(address)
+                .with_context(|| format!("no device at {address:#x}"))?;
+            let device = &mut self.devices[index];
+            let offset = address - device.base;
+            let len = (device.size - offset).min(data.len() as u64) as usize;
+            device.inner_device.preload(offset, &data[..len])?;
+            address += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Report the accesses the filter lets through to `observer`, until [`Bus::unwatch`].
+    /// Watched RAM is accessed through its device instead of directly, so the watch sees it.
+    pub fn watch(&mut self, filter: WatchFilter, observer: impl MmioObserver + 'static) -> WatchId {
+        let id = WatchId(self.next_watch);
+        self.next_watch += 1;
+        self.watches.get_mut().push(Watch {
+            id,
+            filter,
+            observer: Box::new(observer),
+        });
+        id
+    }
+
+    /// Stop a watch. Returns whether it was there.
+    pub fn unwatch(&mut self, id: WatchId) -> bool {
+        let watches = self.watches.get_mut();
+        let len = watches.len();
+        watches.retain(|watch| watch.id != id);
+        watches.len() != len
+    }
+
+    /// Whether there are any watches on the bus.
+    pub fn has_watches(&self) -> bool {
+        !self.watches.borrow().is_empty()
+    }
+
+    /// Whether a watch may see accesses to `address`.
+    fn is_watched(&self, address: u64) -> bool {
+        self.watches
+            .borrow()
+            .iter()
+            .any(|watch| watch.filter.covers(address))
+    }
+
+    /// Attribute the following accesses to instruction `pc` of hart `hart`.
+    pub fn set_origin(&self, hart: u32, pc: u32) {
+        self.origin.set((hart, pc));
+    }
+
+    /// Tell the watches about an access to the device at `index`.
+    fn report(&self, index: usize, kind: AccessKind, address: u64, size: Sizes, value: MemorySize) {
+        let mut watches = self.watches.borrow_mut();
+        if watches.is_empty() {
+            return;
+        }
+        let device = &self.devices[index];
+        let (hart, pc) = self.origin.get();
+        let access = MmioAccess {
+            kind,
+            address,
+            size,
+            value,
+            hart,
+            pc,
+            device: device.handle.expect("devices on a bus have a handle"),
+            device_name: device.name(),
+            offset: address - device.base,
+        };
+        for watch in watches.iter_mut() {
+            if watch.filter.matches(&access) {
+                watch.observer.access(&access);
+            }
+        }
+    }
+
+    /// The current emulated time in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.events.now()
+    }
+
+    /// The earliest deadline a device scheduled.
+    pub fn next_event(&self) -> Option<u64> {
+        self.events.next_event()
+    }
+
+    /// Run the events of the devices that are due by now. Each device sees the rest of the bus
+    /// through a [`BusView`] so it can read and write guest memory.
+    pub fn run_events(&mut self) {
+        while let Some(timer) = self.events.pop_due() {
+            let handle = Some(DeviceHandle(timer));
+            let (device, mut memory) = match self.position(DeviceHandle(timer)) {
+                Some(index) => {
+                    let (before, rest) = self.devices.split_at_mut(index);
+                    let (device, after) = rest.split_first_mut().unwrap();
+                    (device, BusView { before, after })
+                }
+                None => {
+                    let Some(device) = self
+                        .disabled
+                        .iter_mut()
+                        .find(|device| device.handle == handle)
+                    else {
+                        // the device was removed
+                        continue;
+                    };
+                    let memory = BusView {
+                        before: &mut self.devices,
+                        after: &mut [],
+                    };
+                    (device, memory)
+                }
+            };
+            device.event(&mut memory);
+            if let Some(request) = device.take_request() {
+                self.request.get_or_insert(request);
+                self.attention.set(true);
+            }
+        }
+    }
+
+    /// Move emulated time forward to `time_ns`, running every event on the way at its deadline.
+    pub fn run_until(&mut self, time_ns: u64) {
+        while let Some(deadline) = self.next_event().filter(|&deadline| deadline <= time_ns) {
+            self.events.advance_to(deadline);
+            self.run_events();
+        }
+        self.events.advance_to(time_ns);
+    }
+
+    /// Whether anything happened that the CPU has to look at since the last call.
+    pub(crate) fn take_attention(&self) -> bool {
+        self.attention.replace(false)
+    }
+
+    /// Make the CPU look at the machine before its next instruction.
+    pub(crate) fn request_attention(&self) {
+        self.attention.set(true);
+    }
+
+    /// The current level of every interrupt line. Lines of disabled devices are low.
+    pub fn irq_levels(&self) -> impl Iterator<Item = (IrqTarget, bool)> + '_ {
+        self.irqs
+            .iter()
+            .map(|irq| (irq.target, irq.connected && irq.line.is_raised()))
+    }
+
+    /// Take where the lines of the devices removed since the last call were wired to.
+    pub(crate) fn take_released(&mut self) -> Vec<IrqTarget> {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Take the first pending request of the devices on the bus.
+    pub fn take_request(&mut self) -> Option<DeviceRequest> {
+        self.request.take().or_else(|| {
+            self.devices
+                .iter_mut()
+                .find_map(|device| device.take_request())
+        })
+    }
+
+    /// Whether instructions may be fetched from `address`. Unmapped addresses are left to fault
+    /// on the read itself.
+    pub fn is_executable(&self, address: u64) -> bool {
+        self.find(address)
+            .is_none_or(|index| self.devices[index].executable())
     }
 
     pub fn read(&self, address: u64, size: Sizes) -> Result<MemorySize> {
-        for device in &self.devices {
-            if device.base() <= address && address < device.base() + device.size() {
-                return device.load(address - device.base(), size);
+        match self.find(address) {
+            Some(index) => {
+                let device = &self.devices[index];
+                let value = device.load(address - device.base(), size)?;
+                self.report(index, AccessKind::Read, address, size, value);
+                Ok(value)
             }
+            None => Err(Exception::LoadAccessFault)
+                .context(format!("address: {address:#08X}, size: {size:?}")),
+        }
+    }
+
+    pub fn write(&mut self, address: u64, value: MemorySize, size: Sizes) -> Result<()> {
+        let Some(index) = self.find(address) else {
+            return Err(Exception::StoreAccessFault)
+                .context(format!("address: {address}, size: {size:?}"));
+        };
+        let device = &mut self.devices[index];
+        device.store(address - device.base(), size, value)?;
+        self.report(index, AccessKind::Write, address, size, value);
+        let device = &mut self.devices[index];
+        if let Some(request) = device.take_request() {
+            self.request.get_or_insert(request);
+            self.attention.set(true);
         }
+        Ok(())
+    }
+}
 
-        Err(Exception::LoadAccessFault).context(format!("address: {address:#08X}, size: {size:?}"))
+/// Physical memory as seen from one device: every device on the bus except the device itself.
+pub struct BusView<'a> {
+    before: &'a mut [VirtualDevice],
+    after: &'a mut [VirtualDevice],
+}
+
+impl BusView<'_> {
+    fn find(&self, address: u64) -> Option<&VirtualDevice> {
+        find_index(self.before, address)
+            .map(|index| &self.before[index])
+            .or_else(|| find_index(self.after, address).map(|index| &self.after[index]))
+    }
+
+    fn find_mut(&mut self, address: u64) -> Option<&mut VirtualDevice> {
+        match find_index(self.before, address) {
+            Some(index) => Some(&mut self.before[index]),
+            None => find_index(self.after, address).map(|index| &mut self.after[index]),
+        }
+    }
+
+    pub fn read(&self, address: u64, size: Sizes) -> Result<MemorySize> {
+        match self.find(address) {
+            Some(device) => device.load(address - device.base(), size),
+            None => Err(Exception::LoadAccessFault)
+                .context(format!("address: {address:#08X}, size: {size:?}")),
+        }
     }
 
     pub fn write(&mut self, address: u64, value: MemorySize, size: Sizes) -> Result<()> {
-        for device in &mut self.devices {
-            if device.base() <= address && address < device.base() + device.size() {
-                return device.store(address - device.base(), size, value);
+        match self.find_mut(address) {
+            Some(device) => {
+                let base = device.base();
+                device.store(address - base, size, value)
             }
+            None => Err(Exception::StoreAccessFault)
+                .context(format!("address: {address:#08X}, size: {size:?}")),
         }
+    }
 
-        Err(Exception::StoreAccessFault).context(format!("address: {address}, size: {size:?}"))
+    /// Fill `buffer` with the bytes starting at `address`.
+    pub fn read_bytes(&self, address: u64, buffer: &mut [u8]) -> Result<()> {
+        if let Some(memory) = self
+            .find(address)
+            .and_then(|device| device.host_memory(address)?.get(..buffer.len()))
+        {
+            buffer.copy_from_slice(memory);
+            return Ok(());
+        }
+        for (offset, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read(address + offset as u64, Sizes::Byte)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` into memory starting at `address`.
+    pub fn write_bytes(&mut self, address: u64, data: &[u8]) -> Result<()> {
+        if let Some(memory) = self
+            .find_mut(address)
+            .and_then(|device| device.host_memory_mut(address)?.get_mut(..data.len()))
+        {
+            memory.copy_from_slice(data);
+            return Ok(());
+        }
+        for (offset, byte) in data.iter().enumerate() {
+            self.write(address + offset as u64, *byte as MemorySize, Sizes::Byte)?;
+        }
+        Ok(())
     }
 }
@@ -1,14 +1,27 @@
 use std::collections::HashMap;
 
 use crate::{
-    bus::{Bus, Device, VirtualDevice},
-    csr::{CpuCsr, Csr, CsrAddress, MEPC, MSTATUS, SATP, SEPC, SSTATUS},
+    bus::{Bus, Device, DeviceHandle, DeviceRequest, IrqTarget, MemoryRegion, VirtualDevice},
+    csr::{
+        CpuCsr, Csr, CsrAddress, MEIP_BIT, MEPC, MHARTID, MIDELEG, MIE, MIP, MISA, MSIP_BIT,
+        MSTATUS, MSTATUS_MIE, MTIP_BIT, MTVEC, SATP, SEIP_BIT, SEPC, SSIP_BIT, SSTATUS, STIP_BIT,
+        XSTATUS_SIE,
+    },
+    device_tree::{self, Chosen, Fdt, Hart},
+    devices::{plic::Plic, NS_PER_STEP},
+    htif::Htif,
+    interrupt::Interrupt,
+    loader::{
+        elf::{Class, Elf},
+        Image,
+    },
     memory::{
         dram::{Sizes, DRAM_BASE, DRAM_SIZE},
-        virtual_memory::MemorySize,
+        virtual_memory::{load_host, store_host, MemorySize},
     },
     registers::{FRegisters, XRegisterSize, XRegisters},
-    rom::POINTER_TO_DTB,
+    rom::{Rom, MROM_BASE},
+    trace::{MmioObserver, WatchFilter, WatchId},
     trap::{Exception, Trap},
 };
 use anyhow::{bail, Result};
@@ -22,6 +35,9 @@ use riscv_decoder::{
 const PAGE_SIZE: u32 = 4096;
 /// The size of a page table entry.
 const PTE_SIZE: u32 = 4;
+/// The most emulated time [`Riscv32Cpu::run`] lets pass without looking at the machine, so the
+/// HTIF gets serviced even when no device has an event scheduled.
+const MAX_BATCH_NS: u64 = 1_000_000;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AccessType {
@@ -39,9 +55,28 @@ pub enum Privilege {
     // Reserved,
 }
 
+/// Why [`Riscv32Cpu::run`] stopped the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The guest powered the machine off with an exit code, 0 meaning success.
+    Exit { code: u32 },
+    /// The watchdog ran out twice without being kicked, so the guest is presumed hung.
+    WatchdogReset,
+}
+
+impl ExitStatus {
+    /// Whether the guest reported success.
+    pub fn success(&self) -> bool {
+        matches!(self, ExitStatus::Exit { code: 0 })
+    }
+}
+
 pub struct Mem {
     /// program counter
     pc: XRegisterSize,
+    /// The address of the instruction being executed, which is where the exceptions it raises
+    /// are taken. The pc already points past it.
+    instruction_pc: XRegisterSize,
 
     /// little endian memory
     bus: Bus,
@@ -56,6 +91,9 @@ pub struct Mem {
     enable_paging: bool,
     /// Physical page number (PPN)
     ppn: u64,
+
+    /// The host-target interface, watched on every store.
+    htif: Option<Htif>,
 }
 
 impl Default for Mem {
@@ -67,12 +105,14 @@ impl Default for Mem {
 impl Mem {
     pub fn new() -> Self {
         Self {
-            pc: DRAM_BASE as u32,
+            pc: MROM_BASE as u32,
+            instruction_pc: MROM_BASE as u32,
             bus: Bus::new(),
             csr: CpuCsr::new(),
             enable_paging: false,
             ppn: 0,
             privilege: Privilege::Machine,
+            htif: None,
         }
     }
 
@@ -80,6 +120,28 @@ impl Mem {
         self.csr.dump();
     }
 
+    /// Put the hart state back to how it is at power-on, about to run the reset vector in the
+    /// ROM. The bus is left alone.
+    fn reset(&mut self) {
+        self.pc = MROM_BASE as u32;
+        self.instruction_pc = MROM_BASE as u32;
+        self.csr = CpuCsr::new();
+        self.enable_paging = false;
+        self.ppn = 0;
+        self.privilege = Privilege::Machine;
+        if let Some(htif) = &mut self.htif {
+            htif.reset();
+        }
+    }
+
+    /// The first pending request of the HTIF or of a device on the bus.
+    fn take_request(&mut self) -> Option<DeviceRequest> {
+        self.htif
+            .as_mut()
+            .and_then(Htif::take_request)
+            .or_else(|| self.bus.take_request())
+    }
+
     fn translate_vaddr(&mut self, vaddr: u32, access: AccessType) -> Result<u64> {
         info!("Translating address: {:#X}", vaddr);
         info!("Privilege: {:?}", self.privilege);
@@ -395,11 +457,40 @@ impl Mem {
     }
 
     pub fn read_raw(&mut self, addr: u64, size: Sizes) -> Result<XRegisterSize> {
-        self.bus.read(addr as u64, size)
+        self.load(addr, size)
     }
 
     pub fn write_raw(&mut self, addr: u64, value: XRegisterSize, size: Sizes) -> Result<()> {
-        self.bus.write(addr as u64, value, size)
+        self.store(addr, value, size)
+    }
+
+    /// Read physical memory, straight from the host memory of RAM and ROM and through the
+    /// device otherwise.
+    #[inline]
+    fn load(&self, paddr: u64, size: Sizes) -> Result<MemorySize> {
+        if let Some(value) = self
+            .bus
+            .host_memory(paddr)
+            .and_then(|memory| load_host(memory, size))
+        {
+            return Ok(value);
+        }
+        self.bus.read(paddr, size)
+    }
+
+    /// Write physical memory, straight to the host memory of RAM and through the device
+    /// otherwise.
+    #[inline]
+    fn store(&mut self, paddr: u64, value: MemorySize, size: Sizes) -> Result<()> {
+        if self
+            .bus
+            .host_memory_mut(paddr)
+            .and_then(|memory| store_host(memory, size, value))
+            .is_some()
+        {
+            return Ok(());
+        }
+        self.bus.write(paddr, value, size)
     }
 }
 
@@ -410,6 +501,9 @@ impl Cpu for Mem {
     fn set_pc(&mut self, value: XRegisterSize) {
         self.pc = value;
     }
+    fn get_instruction_pc(&self) -> XRegisterSize {
+        self.instruction_pc
+    }
 
     fn read(
         &mut self,
@@ -417,8 +511,12 @@ impl Cpu for Mem {
         size: Sizes,
         access: AccessType,
     ) -> Result<XRegisterSize> {
+        let fetch = access == AccessType::Executable;
         let paddr = self.translate_address(addr, access)?;
-        self.bus.read(paddr, size)
+        if fetch && !self.bus.is_executable(paddr) {
+            bail!(Exception::InstructionAccessFault);
+        }
+        self.load(paddr, size)
     }
     fn write(
         &mut self,
@@ -428,7 +526,14 @@ impl Cpu for Mem {
         access: AccessType,
     ) -> Result<()> {
         let paddr = self.translate_address(addr, access)?;
-        self.bus.write(paddr, value, size)
+        self.store(paddr, value, size)?;
+        if let Some(htif) = &mut self.htif {
+            htif.written(&mut self.bus, paddr, size);
+            if htif.has_request() {
+                self.bus.request_attention();
+            }
+        }
+        Ok(())
     }
 
     fn state(&mut self) -> &impl Csr {
@@ -485,6 +590,9 @@ impl Cpu for Riscv32Cpu {
     fn get_pc(&self) -> XRegisterSize {
         self.mem.get_pc()
     }
+    fn get_instruction_pc(&self) -> XRegisterSize {
+        self.mem.get_instruction_pc()
+    }
     fn get_privilege(&self) -> Privilege {
         self.mem.get_privilege()
     }
@@ -524,16 +632,30 @@ impl Cpu for Riscv32Cpu {
 
 impl Riscv32Cpu {
     pub fn new() -> Self {
-        let mut exec = Executor::new();
-        exec.xregs[2] = (DRAM_BASE + DRAM_SIZE) as u32; // stack pointer
-        exec.xregs[10] = 0;
-        exec.xregs[11] = POINTER_TO_DTB; // pointer to device tree blob
-
         Self {
             mem: Mem::default(),
             syscall_table: HashMap::new(),
 
-            exec,
+            exec: Self::boot_executor(),
+        }
+    }
+
+    /// The registers as they are when the hart comes out of reset. The reset vector in the ROM
+    /// sets up the arguments of the program it boots.
+    fn boot_executor() -> Executor {
+        let mut exec = Executor::new();
+        exec.xregs[2] = (DRAM_BASE + DRAM_SIZE) as u32; // stack pointer
+        exec
+    }
+
+    /// Reset the machine: the hart and every device go back to their power-on state, while
+    /// memory keeps its contents.
+    pub fn reset(&mut self) {
+        info!("Resetting the machine");
+        self.exec = Self::boot_executor();
+        self.mem.reset();
+        for device in self.get_devices_mut().iter_mut() {
+            device.reset();
         }
     }
 
@@ -590,16 +712,139 @@ impl Riscv32Cpu {
         self.mem.bus.get_device_mut()
     }
 
-    pub fn get_devices_mut(&mut self) -> &mut Vec<VirtualDevice> {
+    pub fn get_devices_mut(&mut self) -> &mut [VirtualDevice] {
         self.mem.bus.get_devices_mut()
     }
 
-    pub fn get_devices(&self) -> &Vec<VirtualDevice> {
+    pub fn get_devices(&self) -> &[VirtualDevice] {
         self.mem.bus.get_devices()
     }
 
-    pub fn add_device(&mut self, device: VirtualDevice) {
-        self.mem.bus.add_device(device);
+    /// Fails when the device has no size or overlaps a device already on the bus.
+    pub fn add_device(&mut self, device: VirtualDevice) -> Result<DeviceHandle> {
+        self.mem.bus.add_device(device)
+    }
+
+    pub fn device<T>(&self, handle: DeviceHandle) -> Option<&T>
+    where
+        T: Device + 'static,
+    {
+        self.mem.bus.device(handle)
+    }
+
+    pub fn device_mut<T>(&mut self, handle: DeviceHandle) -> Option<&mut T>
+    where
+        T: Device + 'static,
+    {
+        self.mem.bus.device_mut(handle)
+    }
+
+    /// Move a device to `base`. Fails when it would overlap another device.
+    pub fn remap_device(&mut self, handle: DeviceHandle, base: u64) -> Result<()> {
+        self.mem.bus.remap(handle, base)
+    }
+
+    /// Unmap a device and disconnect its interrupt lines until it is enabled again.
+    pub fn disable_device(&mut self, handle: DeviceHandle) -> Result<()> {
+        self.mem.bus.disable(handle)
+    }
+
+    /// Fails when another device took the range of the device while it was disabled.
+    pub fn enable_device(&mut self, handle: DeviceHandle) -> Result<()> {
+        self.mem.bus.enable(handle)
+    }
+
+    pub fn remove_device(&mut self, handle: DeviceHandle) -> Result<VirtualDevice> {
+        self.mem.bus.remove(handle)
+    }
+
+    /// Report the accesses of the hart the filter lets through to `observer`.
+    pub fn watch(&mut self, filter: WatchFilter, observer: impl MmioObserver + 'static) -> WatchId {
+        self.mem.bus.watch(filter, observer)
+    }
+
+    pub fn unwatch(&mut self, id: WatchId) -> bool {
+        self.mem.bus.unwatch(id)
+    }
+
+    /// Every device on the bus with the range it is mapped at, by address.
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        self.mem.bus.memory_map()
+    }
+
+    /// The device tree source describing the machine as it is now: the hart and every device
+    /// mapped on the bus.
+    pub fn device_tree(&mut self) -> String {
+        let hart = Hart {
+            id: self.mem.read_csr(MHARTID),
+            misa: self.mem.read_csr(MISA),
+        };
+        device_tree::generate(&self.mem.bus, &[hart])
+    }
+
+    /// Map the ROM with the device tree of the machine and `chosen` in its `/chosen` node, so
+    /// add it after the other devices.
+    pub fn add_rom(&mut self, chosen: &Chosen) -> Result<DeviceHandle> {
+        let fdt = Fdt::from_dts(&self.device_tree())?;
+        self.add_rom_with_device_tree(fdt, chosen)
+    }
+
+    /// Map the ROM with a device tree from the user, like a blob read with [`Fdt::parse`] or
+    /// source compiled with [`Fdt::from_dts`], and `chosen` in its `/chosen` node.
+    pub fn add_rom_with_device_tree(
+        &mut self,
+        mut fdt: Fdt,
+        chosen: &Chosen,
+    ) -> Result<DeviceHandle> {
+        fdt.patch_chosen(chosen);
+        self.add_device(Rom::new_device_with_dtb(fdt.to_dtb())?)
+    }
+
+    /// Load an ELF program for the hart: every segment goes to its physical address and the
+    /// hart starts at the entry point, see [`Riscv32Cpu::start_at`]. Returns the symbol table.
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<HashMap<String, u64>> {
+        let elf = Elf::parse(data)?;
+        elf.check_isa(Class::Elf32, self.mem.read_csr(MISA))?;
+        elf.load(&mut self.mem.bus)?;
+        self.start_at(elf.entry()? as u32);
+        elf.symbols()
+    }
+
+    /// Load a memory image, like an Intel HEX or S-record file, into whatever memory is mapped at
+    /// its addresses. The hart starts at the start address of the image, if it has one, see
+    /// [`Riscv32Cpu::start_at`].
+    pub fn load_image(&mut self, image: &Image) -> Result<()> {
+        image.load(&mut self.mem.bus)?;
+        if let Some(start) = image.start {
+            self.start_at(start as u32);
+        }
+        Ok(())
+    }
+
+    /// Run a program at `entry`. With a ROM the hart goes through its reset vector, which jumps
+    /// to `entry` with the hart id and the device tree in a0 and a1 like firmware expects them.
+    /// Without one the hart starts at `entry` directly.
+    pub fn start_at(&mut self, entry: u32) {
+        match self.get_device_mut::<Rom>() {
+            Some(rom) => {
+                rom.set_entry(entry);
+                self.set_pc(MROM_BASE as u32);
+            }
+            None => self.set_pc(entry),
+        }
+    }
+
+    /// Talk to the guest through the HTIF `tohost` and `fromhost` doublewords.
+    pub fn set_htif(&mut self, htif: Htif) {
+        self.mem.htif = Some(htif);
+    }
+
+    pub fn htif(&self) -> Option<&Htif> {
+        self.mem.htif.as_ref()
+    }
+
+    pub fn htif_mut(&mut self) -> Option<&mut Htif> {
+        self.mem.htif.as_mut()
     }
 
     pub fn get_register(&self, register: XRegisterSize) -> Result<&XRegisterSize, String> {
@@ -636,32 +881,160 @@ impl Riscv32Cpu {
         debug!("{:-^80}", "");
     }
 
-    fn devices_increment(&mut self) {
-        for device in self.get_devices_mut().iter_mut() {
-            device.increment();
+    /// Catch up with what the devices did since the last look: deliver HTIF answers and follow
+    /// the interrupt lines.
+    fn sync_devices(&mut self) {
+        if let Some(htif) = &mut self.mem.htif {
+            htif.service(&mut self.mem.bus);
+        }
+        self.update_interrupts();
+        self.mem.bus.take_attention();
+    }
+
+    /// Follow the interrupt lines of the devices: lines wired to the PLIC set the level of their
+    /// source, then lines wired to the hart, like the outputs of the PLIC, set their `mip` bits.
+    /// A source or bit is raised while any of its lines is. Where the lines of removed devices
+    /// went is driven low once.
+    fn update_interrupts(&mut self) {
+        let bus = &mut self.mem.bus;
+        let released = bus.take_released();
+        let lowered = released.iter().map(|target| (*target, false));
+
+        let mut sources: Vec<(u32, bool)> = Vec::new();
+        for (target, level) in lowered.clone().chain(bus.irq_levels()) {
+            let IrqTarget::Plic(source) = target else {
+                continue;
+            };
+            match sources.iter_mut().find(|(other, _)| *other == source) {
+                Some((_, raised)) => *raised |= level,
+                None => sources.push((source, level)),
+            }
+        }
+        if let Some(plic) = bus.get_device_mut::<Plic>() {
+            for (source, level) in sources {
+                plic.set_level(source, level);
+            }
+        }
+
+        // the outputs of the PLIC follow the levels it was just given
+        let (mut wired, mut raised) = (0, 0);
+        for (target, level) in lowered.chain(self.mem.bus.irq_levels()) {
+            if let IrqTarget::Mip(bits) = target {
+                wired |= bits;
+                if level {
+                    raised |= bits;
+                }
+            }
+        }
+        if wired != 0 {
+            let mip = self.mem.csr.read(MIP) & !wired | raised;
+            self.mem.csr.write(MIP, mip);
+        }
+    }
+
+    /// The interrupt the hart takes before the next instruction, if any.
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let csr = &self.mem.csr;
+        let pending = csr.read(MIP) & csr.read(MIE);
+        if pending == 0 {
+            return None;
+        }
+        let mideleg = csr.read(MIDELEG);
+        let privilege = self.mem.privilege;
+        let mut enabled = 0;
+        if privilege < Privilege::Machine || csr.read_mstatus(MSTATUS_MIE) == 1 {
+            enabled |= pending & !mideleg;
+        }
+        if privilege < Privilege::Supervisor
+            || (privilege == Privilege::Supervisor && csr.read_sstatus(XSTATUS_SIE) == 1)
+        {
+            enabled |= pending & mideleg;
+        }
+
+        // 3.1.9 "Multiple simultaneous interrupts destined for M-mode are handled in the
+        // following decreasing priority order: MEI, MSI, MTI, SEI, SSI, STI."
+        [
+            (MEIP_BIT, Interrupt::MachineExternalInterrupt),
+            (MSIP_BIT, Interrupt::MachineSoftwareInterrupt),
+            (MTIP_BIT, Interrupt::MachineTimerInterrupt),
+            (SEIP_BIT, Interrupt::SupervisorExternalInterrupt),
+            (SSIP_BIT, Interrupt::SupervisorSoftwareInterrupt),
+            (STIP_BIT, Interrupt::SupervisorTimerInterrupt),
+        ]
+        .into_iter()
+        .find(|(bit, _)| enabled & bit != 0)
+        .map(|(_, interrupt)| interrupt)
+    }
+
+    /// Let emulated time pass for one step, running the device events that come due, then take
+    /// a pending interrupt or execute an instruction. Requests of the devices are handled like
+    /// [`Riscv32Cpu::run`] does: a reset restarts the hart and an exit is returned instead of
+    /// executing anything.
+    pub fn step(&mut self) -> Result<Option<ExitStatus>> {
+        let now = self.mem.bus.now() + NS_PER_STEP;
+        self.mem.bus.run_until(now);
+        self.sync_devices();
+        if let Some(status) = self.handle_request() {
+            return Ok(Some(status));
         }
+        self.execute_step()?;
+        Ok(None)
     }
 
-    pub fn step(&mut self) -> Result<()> {
-        self.devices_increment();
+    /// Handle the first pending device request, returning the status of the machine if it has
+    /// to stop.
+    fn handle_request(&mut self) -> Option<ExitStatus> {
+        match self.mem.take_request()? {
+            DeviceRequest::Exit(status) => {
+                info!("Machine stopped: {status:?}");
+                Some(status)
+            }
+            DeviceRequest::Reset => {
+                self.reset();
+                None
+            }
+        }
+    }
 
-        let inst = self.fetch()?;
+    fn execute_step(&mut self) -> Result<()> {
+        if let Some(interrupt) = self.pending_interrupt() {
+            interrupt.take_trap(&mut self.mem);
+            return Ok(());
+        }
+
+        let inst = match self.fetch() {
+            Ok(inst) => inst,
+            Err(error) => {
+                let exception = error.downcast::<Exception>()?;
+                // without a trap handler the hart would keep faulting at address 0
+                if self.mem.read_csr(MTVEC) == 0 {
+                    bail!("{exception:?} fetching at {:#x}", self.get_pc());
+                }
+                exception.take_trap(self.get_interface());
+                return Ok(());
+            }
+        };
 
         // Execute an instruction.
 		let exec_trap = self.exec.execute(&mut self.mem, inst);
         let trap = match exec_trap.map_err(|e| e.downcast::<Exception>().expect("Failed to downcast exception")) {
             Ok(_) => Trap::Requested, // Return a placeholder trap
-            Err(Exception::EnvironmentCallFromMMode)
-            | Err(Exception::EnvironmentCallFromUMode)
-            | Err(Exception::EnvironmentCallFromSMode) => {
-                let syscall = self.get_register(17 /* register ( a7 ) */).unwrap();
+            Err(
+                exception @ (Exception::EnvironmentCallFromMMode
+                | Exception::EnvironmentCallFromUMode
+                | Exception::EnvironmentCallFromSMode),
+            ) => {
+                let syscall = *self.get_register(17 /* register ( a7 ) */).unwrap();
                 println!("Syscall: {0:}[{0:#X}]", syscall);
-                match self.syscall_table.get(syscall) {
+                match self.syscall_table.get(&syscall) {
                     Some(syscall) => syscall(self),
-                    None => {
+                    // without a trap handler nobody can service the call
+                    None if self.mem.read_csr(MTVEC) == 0 => {
                         warn!("Unknown syscall: {:#X}", syscall);
                         Trap::Fatal
                     }
+                    // let the guest's trap handler deal with it, like riscv-tests and pk do
+                    None => exception.take_trap(self.get_interface()),
                 }
             }
             Err(exception) => {
@@ -678,9 +1051,28 @@ impl Riscv32Cpu {
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), Exception> {
+    /// Run until a device stops the machine, resetting it whenever a device asks for it.
+    ///
+    /// Instructions run in batches up to the next device event. A batch ends early when a
+    /// device changes an interrupt line, schedules an event or makes a request.
+    pub fn run(&mut self) -> Result<ExitStatus> {
+        let mut batch_end = 0;
         loop {
-            self.step().expect("Failed during execution of the CPU in step");
+            let now = self.mem.bus.now() + NS_PER_STEP;
+            self.mem.bus.run_until(now);
+            if now >= batch_end || self.mem.bus.take_attention() {
+                self.sync_devices();
+                if let Some(status) = self.handle_request() {
+                    return Ok(status);
+                }
+                batch_end = self
+                    .mem
+                    .bus
+                    .next_event()
+                    .map_or(u64::MAX, |deadline| deadline.max(now + NS_PER_STEP))
+                    .min(now + MAX_BATCH_NS);
+            }
+            self.execute_step()?;
         }
     }
 
@@ -700,20 +1092,27 @@ impl Riscv32Cpu {
         // The result of the read method can be `Exception::LoadAccessFault`. In fetch(), an error
         // should be `Exception::InstructionAccessFault`.
         // and we also need to check if we are reading from a virtual address or a physical address
-        let paddr = self
-            .translate(self.get_pc().wrapping_sub(4), AccessType::Executable)
-            .expect("Failed to translate address");
+        let pc = self.get_pc();
+        self.mem.instruction_pc = pc;
+        let paddr = self.translate(pc, AccessType::Executable)?;
+        if !self.mem.bus.is_executable(paddr) {
+            bail!(Exception::InstructionAccessFault);
+        }
+        if self.mem.bus.has_watches() {
+            let hart = self.mem.read_csr(MHARTID);
+            self.mem.bus.set_origin(hart, pc);
+        }
         let inst = self
             .mem
             .read_raw(paddr, Sizes::Word)
-            .expect("Failed to read instruction");
+            .map_err(|_| Exception::InstructionAccessFault)?;
 
         self.mem.pc += if is_compressed(inst) { 2 } else { 4 };
 
         // decode the instruction (automatically detects if compressed)
         let inst = try_decode(inst)?;
 
-        debug!(target: "execution", "{:#08X}: {inst}", self.get_pc() - 4);
+        debug!(target: "execution", "{pc:#08X}: {inst}");
 
         Ok(inst)
     }
@@ -722,6 +1121,8 @@ impl Riscv32Cpu {
 pub trait Cpu {
     fn set_pc(&mut self, value: XRegisterSize);
     fn get_pc(&self) -> XRegisterSize;
+    /// The address of the instruction last fetched, 2 or 4 bytes before the pc unless it jumped.
+    fn get_instruction_pc(&self) -> XRegisterSize;
 
     fn write(
         &mut self,
@@ -175,7 +175,7 @@ const MARCHID: CsrAddress = 0xf12;
 /// Implementation ID.
 const MIMPID: CsrAddress = 0xf13;
 /// Hardware thread ID.
-const MHARTID: CsrAddress = 0xf14;
+pub const MHARTID: CsrAddress = 0xf14;
 
 // Machine trap setup.
 /// Machine status register.
@@ -184,7 +184,7 @@ pub const MSTATUS: CsrAddress = 0x300;
 /// This register is used to store the upper 32 bits of the MSTATUS register.
 pub const MSTATUSH: CsrAddress = 0x310;
 /// ISA and extensions.
-const MISA: CsrAddress = 0x301;
+pub const MISA: CsrAddress = 0x301;
 /// Machine exception delefation register.
 pub const MEDELEG: CsrAddress = 0x302;
 /// Machine interrupt delefation register.
@@ -0,0 +1,639 @@
+//! Generation of the device tree that describes the machine to the guest. Instead of a fixed
+//! source, the tree is built from what the machine actually has: a node for every hart with the
+//! ISA its `misa` advertises, a memory node for every RAM bank and a node for every device on the
+//! bus that describes itself through [`crate::bus::Device::dts_node`], with its interrupts wired
+//! the way the bus routes them. A tree from the user, as source or as a blob, is used as it is
+//! except for the `/chosen` node, which the host fills in through [`Fdt::patch_chosen`].
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+use devicetree_tool::DeviceTree;
+
+use crate::bus::{Bus, IrqTarget};
+use crate::devices::{plic::Plic, NS_PER_STEP};
+use crate::memory::dram::Dram;
+
+/// The frequency the `time` CSR counts at, one tick per step of the machine.
+pub const TIMEBASE_FREQUENCY: u64 = 1_000_000_000 / NS_PER_STEP;
+
+/// The supervisor mode bit of `misa`.
+const MISA_S: u32 = 1 << (b'S' - b'A');
+
+/// The single letter extensions in the order the ISA string lists them.
+const EXTENSIONS: &str = "iemafdqlcbjtpvh";
+
+/// A hart as the device tree describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hart {
+    /// The `mhartid` of the hart.
+    pub id: u32,
+    pub misa: u32,
+}
+
+/// Where a device is mapped and where its interrupts go, for the node describing the device.
+pub struct NodeContext<'a> {
+    base: u64,
+    size: u64,
+    irqs: &'a [IrqTarget],
+    phandle: u32,
+    /// The phandle of the PLIC, if the machine has one.
+    plic: Option<u32>,
+    /// The phandles of the interrupt controllers of the harts.
+    intcs: &'a [u32],
+}
+
+impl NodeContext<'_> {
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// A phandle reserved for the device, for a node other nodes refer to.
+    pub fn phandle(&self) -> u32 {
+        self.phandle
+    }
+
+    /// The `reg` property and the interrupt properties of the device, a line each, indented for
+    /// the body of its node.
+    pub fn properties(&self) -> String {
+        let mut properties = format!(
+            "\t\treg = <{:#x} {:#x} {:#x} {:#x}>;\n",
+            self.base >> 32,
+            self.base & 0xffff_ffff,
+            self.size >> 32,
+            self.size & 0xffff_ffff,
+        );
+        properties.push_str(&self.interrupts());
+        properties
+    }
+
+    /// `interrupts` with the PLIC as the parent when every output goes to the PLIC, otherwise
+    /// `interrupts-extended` naming the controller of every output. Outputs to a PLIC the
+    /// machine does not have are left out.
+    fn interrupts(&self) -> String {
+        if self.irqs.is_empty() {
+            return String::new();
+        }
+        let plic_only = self
+            .irqs
+            .iter()
+            .all(|target| matches!(target, IrqTarget::Plic(_)));
+        match self.plic {
+            Some(plic) if plic_only => {
+                let sources = self.irqs.iter().map(|target| match target {
+                    IrqTarget::Plic(source) => *source,
+                    IrqTarget::Mip(_) => unreachable!(),
+                });
+                format!(
+                    "\t\tinterrupts = <{}>;\n\t\tinterrupt-parent = <{plic:#x}>;\n",
+                    cells(sources)
+                )
+            }
+            _ => {
+                let mut specifiers = Vec::new();
+                for target in self.irqs {
+                    match *target {
+                        IrqTarget::Plic(source) => {
+                            if let Some(plic) = self.plic {
+                                specifiers.extend([plic, source]);
+                            }
+                        }
+                        IrqTarget::Mip(bits) => {
+                            for intc in self.intcs {
+                                let causes = (0..32).filter(|bit| bits & 1 << bit != 0);
+                                specifiers.extend(causes.flat_map(|cause| [*intc, cause]));
+                            }
+                        }
+                    }
+                }
+                if specifiers.is_empty() {
+                    return String::new();
+                }
+                format!("\t\tinterrupts-extended = <{}>;\n", cells(specifiers))
+            }
+        }
+    }
+}
+
+/// Cells of a property, like `0x2 0xb`.
+fn cells(values: impl IntoIterator<Item = u32>) -> String {
+    values
+        .into_iter()
+        .map(|value| format!("{value:#x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The ISA string of a hart, like `rv32ima`. The S and U bits are privilege modes, not
+/// extensions, so they are left out.
+pub fn isa_string(misa: u32) -> String {
+    let xlen = match misa >> 30 {
+        2 => 64,
+        3 => 128,
+        _ => 32,
+    };
+    let extensions: String = EXTENSIONS
+        .bytes()
+        .filter(|letter| misa & 1 << (letter - b'a') != 0)
+        .map(char::from)
+        .collect();
+    format!("rv{xlen}{extensions}")
+}
+
+/// The device tree source describing `harts` and every device mapped on `bus`.
+pub fn generate(bus: &Bus, harts: &[Hart]) -> String {
+    // every hart has a phandle for its node and one for its interrupt controller, the devices
+    // get theirs after that
+    let intcs: Vec<u32> = (0..harts.len() as u32).map(|index| 2 * index + 2).collect();
+    let first = 2 * harts.len() as u32 + 1;
+    let devices = bus.get_devices();
+    let plic = (first..)
+        .zip(devices)
+        .find_map(|(phandle, device)| device.downcast_ref::<Plic>().map(|_| phandle));
+
+    let mut cpus = String::new();
+    for (hart, intc) in harts.iter().zip(&intcs) {
+        let mmu = match (hart.misa & MISA_S != 0, hart.misa >> 30) {
+            (false, _) => String::new(),
+            (true, 2) => "\t\t\tmmu-type = \"riscv,sv39\";\n".to_string(),
+            (true, _) => "\t\t\tmmu-type = \"riscv,sv32\";\n".to_string(),
+        };
+        cpus.push_str(&format!(
+            r#"
+		cpu@{id:x} {{
+			phandle = <{:#x}>;
+			device_type = "cpu";
+			reg = <{id:#x}>;
+			status = "okay";
+			compatible = "riscv";
+			riscv,isa = "{}";
+{mmu}
+			interrupt-controller {{
+				#interrupt-cells = <0x1>;
+				interrupt-controller;
+				compatible = "riscv,cpu-intc";
+				phandle = <{intc:#x}>;
+			}};
+		}};
+"#,
+            intc - 1,
+            isa_string(hart.misa),
+            id = hart.id,
+        ));
+    }
+
+    let mut memory = String::new();
+    let mut soc = String::new();
+    for (phandle, device) in (first..).zip(devices) {
+        if device.downcast_ref::<Dram>().is_some() {
+            let (base, size) = (device.base(), device.size());
+            memory.push_str(&format!(
+                r#"
+	memory@{base:x} {{
+		device_type = "memory";
+		reg = <{:#x} {:#x} {:#x} {:#x}>;
+	}};
+"#,
+                base >> 32,
+                base & 0xffff_ffff,
+                size >> 32,
+                size & 0xffff_ffff,
+            ));
+            continue;
+        }
+        let node = NodeContext {
+            base: device.base(),
+            size: device.size(),
+            irqs: device.irqs(),
+            phandle,
+            plic,
+            intcs: &intcs,
+        };
+        if let Some(node) = device.dts_node(&node) {
+            soc.push_str(&node.replace("\n\t", "\n\t\t"));
+        }
+    }
+
+    format!(
+        r#"/dts-v1/;
+
+/ {{
+	#address-cells = <0x2>;
+	#size-cells = <0x2>;
+	compatible = "riscv-virtio";
+	model = "riscv-virtio,qemu";
+
+	chosen {{
+	}};
+
+	cpus {{
+		#address-cells = <0x1>;
+		#size-cells = <0x0>;
+		timebase-frequency = <{TIMEBASE_FREQUENCY:#x}>;
+{cpus}	}};
+{memory}
+	soc {{
+		#address-cells = <0x2>;
+		#size-cells = <0x2>;
+		compatible = "simple-bus";
+		ranges;
+{soc}	}};
+}};
+"#
+    )
+}
+
+/// Compile device tree source to a device tree blob (DTB). The compiler panics on source it can't
+/// parse, that is returned as an error instead.
+pub fn compile(dts: &str) -> Result<Vec<u8>> {
+    std::panic::catch_unwind(|| DeviceTree::from_dts_bytes(dts.as_bytes()).generate_dtb()).map_err(
+        |panic| {
+            let message = panic
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| panic.downcast_ref::<&str>().copied())
+                .unwrap_or("unknown error");
+            anyhow!("the device tree source did not compile: {message}")
+        },
+    )
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+/// The version of the blobs written, and the oldest version they are compatible with.
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+/// What the host tells the guest through the `/chosen` node. Only what is set is written, the
+/// rest of the node stays as the tree has it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chosen {
+    bootargs: Option<String>,
+    stdout_path: Option<String>,
+    initrd: Option<Range<u64>>,
+    rng_seed: Option<Vec<u8>>,
+}
+
+impl Chosen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The kernel command line.
+    pub fn with_bootargs(mut self, bootargs: impl Into<String>) -> Self {
+        self.bootargs = Some(bootargs.into());
+        self
+    }
+
+    /// The path of the console node, optionally followed by its options, like
+    /// `/soc/serial@10000000:115200`.
+    pub fn with_stdout_path(mut self, path: impl Into<String>) -> Self {
+        self.stdout_path = Some(path.into());
+        self
+    }
+
+    /// Where the initial ramdisk was loaded in physical memory.
+    pub fn with_initrd(mut self, initrd: Range<u64>) -> Self {
+        self.initrd = Some(initrd);
+        self
+    }
+
+    /// Entropy for the kernel to seed its random number generator with before it has any other.
+    pub fn with_rng_seed(mut self, seed: impl Into<Vec<u8>>) -> Self {
+        self.rng_seed = Some(seed.into());
+        self
+    }
+}
+
+/// A node of a flattened device tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Node {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    /// Whether `component` of a path names the node. The unit address may be left out.
+    fn is(&self, component: &str) -> bool {
+        self.name == component || self.name.split('@').next() == Some(component)
+    }
+}
+
+/// A flattened device tree, read from a device tree blob so its properties can be changed before
+/// it is written out again. This is how a tree the user supplies gets the `/chosen` node of the
+/// machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fdt {
+    root: Node,
+    /// The memory reservation block, as address and size pairs.
+    reservations: Vec<(u64, u64)>,
+    boot_cpuid: u32,
+}
+
+impl Fdt {
+    /// Read a device tree blob, as `dtc -O dtb` writes them.
+    pub fn parse(dtb: &[u8]) -> Result<Self> {
+        let mut reader = Reader { dtb, offset: 0 };
+        if reader.u32()? != FDT_MAGIC {
+            bail!("not a device tree blob");
+        }
+        let total = reader.u32()? as usize;
+        if total > dtb.len() {
+            bail!(
+                "the device tree blob says it has {total} bytes but has {}",
+                dtb.len()
+            );
+        }
+        let structure = reader.u32()? as usize;
+        let strings = reader.u32()? as usize;
+        let reservations = reader.u32()? as usize;
+        let _version = reader.u32()?;
+        let compatible = reader.u32()?;
+        if compatible > FDT_VERSION {
+            bail!("device tree blobs of version {compatible} are not supported");
+        }
+        let boot_cpuid = reader.u32()?;
+        let dtb = &dtb[..total];
+
+        let mut reader = Reader {
+            dtb,
+            offset: reservations,
+        };
+        let mut reserved = Vec::new();
+        loop {
+            let entry = (reader.u64()?, reader.u64()?);
+            if entry == (0, 0) {
+                break;
+            }
+            reserved.push(entry);
+        }
+
+        let strings = dtb
+            .get(strings..)
+            .context("the device tree blob is truncated")?;
+        let mut reader = Reader {
+            dtb,
+            offset: structure,
+        };
+        if reader.token()? != FDT_BEGIN_NODE {
+            bail!("the structure block does not start with the root node");
+        }
+        let root = reader.node(strings)?;
+        if reader.token()? != FDT_END {
+            bail!("the structure block does not end after the root node");
+        }
+        Ok(Self {
+            root,
+            reservations: reserved,
+            boot_cpuid,
+        })
+    }
+
+    /// Compile device tree source and read the blob.
+    pub fn from_dts(dts: &str) -> Result<Self> {
+        Self::parse(&compile(dts)?).context("the device tree source did not compile")
+    }
+
+    /// The node at `path`, like `/soc/rtc@101000`.
+    fn node(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .try_fold(&self.root, |node, component| {
+                node.children.iter().find(|child| child.is(component))
+            })
+    }
+
+    /// The node at `path`, added with its parents if the tree does not have it.
+    fn node_mut(&mut self, path: &str) -> &mut Node {
+        let mut node = &mut self.root;
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            let index = match node.children.iter().position(|child| child.is(component)) {
+                Some(index) => index,
+                None => {
+                    node.children.push(Node {
+                        name: component.to_string(),
+                        ..Node::default()
+                    });
+                    node.children.len() - 1
+                }
+            };
+            node = &mut node.children[index];
+        }
+        node
+    }
+
+    /// The value of the property `name` of the node at `path`.
+    pub fn property(&self, path: &str, name: &str) -> Option<&[u8]> {
+        let node = self.node(path)?;
+        let (_, value) = node
+            .properties
+            .iter()
+            .find(|(property, _)| property == name)?;
+        Some(value)
+    }
+
+    /// Set the property `name` of the node at `path`, adding the node if needed.
+    pub fn set_property(&mut self, path: &str, name: &str, value: impl Into<Vec<u8>>) {
+        let value = value.into();
+        let node = self.node_mut(path);
+        match node
+            .properties
+            .iter_mut()
+            .find(|(property, _)| property == name)
+        {
+            Some((_, old)) => *old = value,
+            None => node.properties.push((name.to_string(), value)),
+        }
+    }
+
+    /// Set a property holding a string.
+    pub fn set_string(&mut self, path: &str, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.set_property(path, name, bytes);
+    }
+
+    /// Write what is set in `chosen` into the `/chosen` node.
+    pub fn patch_chosen(&mut self, chosen: &Chosen) {
+        if let Some(bootargs) = &chosen.bootargs {
+            self.set_string("/chosen", "bootargs", bootargs);
+        }
+        if let Some(path) = &chosen.stdout_path {
+            self.set_string("/chosen", "stdout-path", path);
+        }
+        if let Some(initrd) = &chosen.initrd {
+            let start = initrd.start.to_be_bytes();
+            let end = initrd.end.to_be_bytes();
+            self.set_property("/chosen", "linux,initrd-start", start);
+            self.set_property("/chosen", "linux,initrd-end", end);
+        }
+        if let Some(seed) = &chosen.rng_seed {
+            self.set_property("/chosen", "rng-seed", seed.clone());
+        }
+    }
+
+    /// Write the tree as a device tree blob: the header, the memory reservation block, the
+    /// structure block and the strings block, in that order.
+    pub fn to_dtb(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        write_node(&self.root, &mut structure, &mut strings);
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let mut reservations = Vec::new();
+        for (address, size) in self.reservations.iter().chain([&(0, 0)]) {
+            reservations.extend_from_slice(&address.to_be_bytes());
+            reservations.extend_from_slice(&size.to_be_bytes());
+        }
+
+        let reservations_offset = FDT_HEADER_SIZE;
+        let structure_offset = reservations_offset + reservations.len();
+        let strings_offset = structure_offset + structure.len();
+        let total = strings_offset + strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            reservations_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structure.len() as u32,
+        ];
+        let mut dtb = Vec::with_capacity(total);
+        for word in header {
+            dtb.extend_from_slice(&word.to_be_bytes());
+        }
+        dtb.append(&mut reservations);
+        dtb.append(&mut structure);
+        dtb.append(&mut strings);
+        dtb
+    }
+}
+
+/// Append `node` and its children to the structure block, and the names of their properties
+/// the strings block does not have yet to it.
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    align(structure);
+    for (name, value) in &node.properties {
+        let offset = find_string(strings, name).unwrap_or_else(|| {
+            let offset = strings.len();
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        });
+        structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        structure.extend_from_slice(&(offset as u32).to_be_bytes());
+        structure.extend_from_slice(value);
+        align(structure);
+    }
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+}
+
+/// The offset of `name` in the strings block.
+fn find_string(strings: &[u8], name: &str) -> Option<usize> {
+    let mut offset = 0;
+    for string in strings.split(|&byte| byte == 0) {
+        if string == name.as_bytes() {
+            return Some(offset);
+        }
+        offset += string.len() + 1;
+    }
+    None
+}
+
+/// Pad the structure block to the next token.
+fn align(structure: &mut Vec<u8>) {
+    structure.resize(structure.len().next_multiple_of(4), 0);
+}
+
+/// Reads the big endian fields of a device tree blob.
+struct Reader<'a> {
+    dtb: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.dtb.get(self.offset..end))
+            .context("the device tree blob is truncated")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// The next token that is not a no-op.
+    fn token(&mut self) -> Result<u32> {
+        loop {
+            let token = self.u32()?;
+            if token != FDT_NOP {
+                return Ok(token);
+            }
+        }
+    }
+
+    /// The node whose begin token was just read, with the names of its properties looked up in
+    /// `strings`.
+    fn node(&mut self, strings: &[u8]) -> Result<Node> {
+        let rest = self.dtb.get(self.offset..).unwrap_or_default();
+        let name = string(rest).context("a node name is not terminated")?;
+        self.bytes((name.len() + 1).next_multiple_of(4))?;
+        let mut node = Node {
+            name: name.to_string(),
+            ..Node::default()
+        };
+        loop {
+            let offset = self.offset;
+            match self.token()? {
+                FDT_PROP => {
+                    let len = self.u32()? as usize;
+                    let name_offset = self.u32()? as usize;
+                    let value = self.bytes(len)?.to_vec();
+                    self.bytes(len.next_multiple_of(4) - len)?;
+                    let name = strings
+                        .get(name_offset..)
+                        .and_then(string)
+                        .with_context(|| format!("no property name at {name_offset:#x}"))?;
+                    node.properties.push((name.to_string(), value));
+                }
+                FDT_BEGIN_NODE => node.children.push(self.node(strings)?),
+                FDT_END_NODE => return Ok(node),
+                token => bail!("unexpected token {token:#x} at {offset:#x}"),
+            }
+        }
+    }
+}
+
+/// The string `bytes` start with, up to its terminating nul.
+fn string(bytes: &[u8]) -> Option<&str> {
+    let end = bytes.iter().position(|&byte| byte == 0)?;
+    std::str::from_utf8(&bytes[..end]).ok()
+}
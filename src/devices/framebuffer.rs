@@ -0,0 +1,361 @@
+//! A linear framebuffer described to the guest by a `simple-framebuffer` device tree node. The
+//! host can snapshot it to PPM or PNG images at any time, or have it dumped periodically.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+
+use crate::bus::{BusView, Device, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::events::Timer;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+use crate::trap::Exception;
+
+/// The default address of the framebuffer.
+pub const FRAMEBUFFER_BASE: u64 = 0x2800_0000;
+
+/// The pixel formats Linux' simplefb driver understands. The name gives the components from the
+/// most to the least significant bits of a little endian pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+    A8R8G8B8,
+    X8B8G8R8,
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::R5G6B5 => 2,
+            PixelFormat::R8G8B8 => 3,
+            _ => 4,
+        }
+    }
+
+    /// The name used by the `format` property of the device tree node.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::R5G6B5 => "r5g6b5",
+            PixelFormat::R8G8B8 => "r8g8b8",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+            PixelFormat::A8R8G8B8 => "a8r8g8b8",
+            PixelFormat::X8B8G8R8 => "x8b8g8r8",
+            PixelFormat::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    /// Convert the bytes of a pixel to red, green and blue.
+    fn rgb(&self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let r = (value >> 11) as u8 & 0x1f;
+                let g = (value >> 5) as u8 & 0x3f;
+                let b = value as u8 & 0x1f;
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            }
+            PixelFormat::R8G8B8 | PixelFormat::X8R8G8B8 | PixelFormat::A8R8G8B8 => {
+                [pixel[2], pixel[1], pixel[0]]
+            }
+            PixelFormat::X8B8G8R8 | PixelFormat::A8B8G8R8 => [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+/// The file format of a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Binary portable pixmap (P6).
+    Ppm,
+    /// PNG with uncompressed image data.
+    Png,
+}
+
+impl ImageFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// Where and how often frames are dumped.
+struct FrameDump {
+    directory: PathBuf,
+    format: ImageFormat,
+    interval_ns: u64,
+    frame: u64,
+}
+
+/// A simple framebuffer.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    data: Vec<u8>,
+    /// Fires when the next frame is due to be dumped.
+    timer: Timer,
+    dump: Option<FrameDump>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        let size = width as usize * height as usize * format.bytes_per_pixel();
+        Self {
+            width,
+            height,
+            format,
+            data: vec![0; size],
+            timer: Timer::new(),
+            dump: None,
+        }
+    }
+
+    /// Map a framebuffer at `base`.
+    pub fn new_device(base: u64, width: u32, height: u32, format: PixelFormat) -> VirtualDevice {
+        let framebuffer = Self::new(width, height, format);
+        let size = framebuffer.size();
+        VirtualDevice::new(Box::new(framebuffer), base, size)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// The number of bytes of a line.
+    pub fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel() as u32
+    }
+
+    /// The size of the framebuffer in bytes.
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// The raw contents of the framebuffer.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The picture as packed red, green and blue bytes, line by line.
+    pub fn rgb(&self) -> Vec<u8> {
+        self.data
+            .chunks_exact(self.format.bytes_per_pixel())
+            .flat_map(|pixel| self.format.rgb(pixel))
+            .collect()
+    }
+
+    /// Write the current picture to `writer`.
+    pub fn write_image(&self, writer: &mut impl Write, format: ImageFormat) -> Result<()> {
+        match format {
+            ImageFormat::Ppm => {
+                write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+                writer.write_all(&self.rgb())?;
+            }
+            ImageFormat::Png => writer.write_all(&png(self.width, self.height, &self.rgb()))?,
+        }
+        Ok(())
+    }
+
+    /// Save the current picture to `path`, in the format given by its extension.
+    pub fn snapshot(&self, path: &Path) -> Result<()> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("ppm") => ImageFormat::Ppm,
+            Some("png") => ImageFormat::Png,
+            _ => bail!("{} is neither a .ppm nor a .png file", path.display()),
+        };
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_image(&mut writer, format)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Save a frame to `directory` every `interval_ms` milliseconds of emulated time, starting
+    /// now. Frames are numbered from 0.
+    pub fn dump_frames(&mut self, directory: &Path, interval_ms: u64, format: ImageFormat) {
+        self.dump = Some(FrameDump {
+            directory: directory.to_path_buf(),
+            format,
+            interval_ns: interval_ms.max(1) * 1_000_000,
+            frame: 0,
+        });
+        self.timer.schedule_in(0);
+    }
+
+    /// Stop dumping frames.
+    pub fn stop_dumping(&mut self) {
+        self.dump = None;
+        self.timer.cancel();
+    }
+
+    fn offset(&self, addr: u64, len: usize, fault: Exception) -> Result<usize> {
+        let offset = addr as usize;
+        if offset + len > self.data.len() {
+            return Err(fault).context(format!("framebuffer offset: {addr:#x}"));
+        }
+        Ok(offset)
+    }
+}
+
+fn access_len(size: Sizes) -> usize {
+    match size {
+        Sizes::Byte => 1,
+        Sizes::HalfWord => 2,
+        Sizes::Word => 4,
+    }
+}
+
+impl Device for Framebuffer {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, size: Sizes) -> Result<MemorySize> {
+        let len = access_len(size);
+        let offset = self.offset(addr, len, Exception::LoadAccessFault)?;
+        let mut bytes = [0; 4];
+        bytes[..len].copy_from_slice(&self.data[offset..offset + len]);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn store(&mut self, addr: u64, size: Sizes, value: MemorySize) -> Result<()> {
+        let len = access_len(size);
+        let offset = self.offset(addr, len, Exception::StoreAccessFault)?;
+        self.data[offset..offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        if self.dump.is_some() {
+            timer.schedule_in(0);
+        }
+        self.timer = timer;
+    }
+
+    fn event(&mut self, _memory: &mut BusView) {
+        let Some(dump) = &self.dump else {
+            return;
+        };
+
+        let path = dump.directory.join(format!(
+            "frame-{:05}.{}",
+            dump.frame,
+            dump.format.extension()
+        ));
+        info!("Dumping the framebuffer to {}", path.display());
+        if let Err(e) = self.snapshot(&path) {
+            error!("Failed to dump a frame: {e:#}");
+        }
+        let dump = self.dump.as_mut().unwrap();
+        dump.frame += 1;
+        self.timer.schedule_in(dump.interval_ns);
+    }
+
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	framebuffer@{:x} {{
+		compatible = "simple-framebuffer";
+{}		width = <{:#x}>;
+		height = <{:#x}>;
+		stride = <{:#x}>;
+		format = "{}";
+	}};
+"#,
+            node.base(),
+            node.properties(),
+            self.width,
+            self.height,
+            self.stride(),
+            self.format.name(),
+        ))
+    }
+}
+
+/// Encode an RGB picture as a PNG file. The image data is stored without compression, which
+/// keeps the encoder small and the files readable by every decoder.
+fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    // every line starts with the filter type, 0 meaning none
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for line in rgb.chunks(width as usize * 3) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    // a zlib stream of stored deflate blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let len = block.len() as u16;
+        zlib.push(last);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per component, truecolor, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
@@ -0,0 +1,325 @@
+//! The SiFive GPIO controller (`sifive,gpio0`). Every pin has its own PLIC interrupt source. The
+//! host drives the inputs, reads the outputs and can look at every change of the pins in emulated
+//! time.
+use anyhow::Result;
+use log::warn;
+
+use crate::bus::{Device, IrqLine, IrqTarget, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::events::Timer;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+/// The address of the GPIO controller, where the FU540 has it.
+pub const GPIO_BASE: u64 = 0x1006_0000;
+/// The size of the GPIO registers.
+pub const GPIO_SIZE: u64 = 0x1000;
+/// The PLIC source of pin 0. Pin `n` uses source `GPIO_IRQ_BASE + n`.
+pub const GPIO_IRQ_BASE: u32 = 16;
+/// The number of pins.
+pub const GPIO_PINS: u32 = 16;
+
+// Register offsets.
+const INPUT_VAL: u64 = 0x00;
+const INPUT_EN: u64 = 0x04;
+const OUTPUT_EN: u64 = 0x08;
+const OUTPUT_VAL: u64 = 0x0c;
+const PUE: u64 = 0x10;
+const DS: u64 = 0x14;
+const RISE_IE: u64 = 0x18;
+const RISE_IP: u64 = 0x1c;
+const FALL_IE: u64 = 0x20;
+const FALL_IP: u64 = 0x24;
+const HIGH_IE: u64 = 0x28;
+const HIGH_IP: u64 = 0x2c;
+const LOW_IE: u64 = 0x30;
+const LOW_IP: u64 = 0x34;
+const IOF_EN: u64 = 0x38;
+const IOF_SEL: u64 = 0x3c;
+const OUT_XOR: u64 = 0x40;
+
+/// Who changed a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinDriver {
+    /// The guest through the output registers.
+    Guest,
+    /// The host through [`Gpio::set_input`].
+    Host,
+}
+
+/// A change of the level of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinChange {
+    /// Nanoseconds of emulated time since the machine was created.
+    pub time_ns: u64,
+    pub pin: u32,
+    pub level: bool,
+    pub driver: PinDriver,
+}
+
+/// The SiFive GPIO controller.
+pub struct Gpio {
+    pins: u32,
+    /// The levels the host drives onto the pins.
+    external: u32,
+    /// Pins the host drives at all. Undriven pins float, or are pulled up.
+    external_en: u32,
+    input_en: u32,
+    output_en: u32,
+    output_val: u32,
+    pue: u32,
+    ds: u32,
+    rise_ie: u32,
+    rise_ip: u32,
+    fall_ie: u32,
+    fall_ip: u32,
+    high_ie: u32,
+    high_ip: u32,
+    low_ie: u32,
+    low_ip: u32,
+    iof_en: u32,
+    iof_sel: u32,
+    out_xor: u32,
+    /// Tells the time of pin changes.
+    timer: Timer,
+    changes: Vec<PinChange>,
+    /// The interrupt output of every pin.
+    irqs: Vec<IrqLine>,
+}
+
+impl Default for Gpio {
+    fn default() -> Self {
+        Self::new(GPIO_PINS)
+    }
+}
+
+impl Gpio {
+    /// Create a controller with `pins` pins, at most 32.
+    pub fn new(pins: u32) -> Self {
+        assert!(pins <= 32, "a GPIO controller has at most 32 pins");
+        Self {
+            pins,
+            external: 0,
+            external_en: 0,
+            input_en: 0,
+            output_en: 0,
+            output_val: 0,
+            pue: 0,
+            ds: 0,
+            rise_ie: 0,
+            rise_ip: 0,
+            fall_ie: 0,
+            fall_ip: 0,
+            high_ie: 0,
+            high_ip: 0,
+            low_ie: 0,
+            low_ip: 0,
+            iof_en: 0,
+            iof_sel: 0,
+            out_xor: 0,
+            timer: Timer::new(),
+            changes: Vec::new(),
+            irqs: (0..pins).map(|_| IrqLine::new()).collect(),
+        }
+    }
+
+    /// A controller with `pins` pins whose interrupts go to the PLIC sources from
+    /// [`GPIO_IRQ_BASE`] on.
+    pub fn new_device(pins: u32) -> VirtualDevice {
+        VirtualDevice::new(Box::new(Self::new(pins)), GPIO_BASE, GPIO_SIZE)
+            .with_irqs((0..pins).map(|pin| IrqTarget::Plic(GPIO_IRQ_BASE + pin)))
+    }
+
+    pub fn pins(&self) -> u32 {
+        self.pins
+    }
+
+    fn mask(&self) -> u32 {
+        if self.pins == 32 {
+            u32::MAX
+        } else {
+            (1 << self.pins) - 1
+        }
+    }
+
+    /// The levels of all pins. Outputs win over the host, undriven pins read as their pull-up.
+    pub fn levels(&self) -> u32 {
+        let driven = (self.output_val ^ self.out_xor) & self.output_en;
+        let external = self.external & self.external_en & !self.output_en;
+        let pulled = self.pue & !self.external_en & !self.output_en;
+        (driven | external | pulled) & self.mask()
+    }
+
+    /// The level of `pin`.
+    pub fn level(&self, pin: u32) -> bool {
+        self.levels() & (1 << pin) != 0
+    }
+
+    /// The level the guest drives onto `pin`, if it is an output.
+    pub fn output(&self, pin: u32) -> Option<bool> {
+        (self.output_en & (1 << pin) != 0).then(|| self.level(pin))
+    }
+
+    /// Drive `pin` from the host. A pin the guest drives as an output ignores the host.
+    pub fn set_input(&mut self, pin: u32, level: bool) {
+        assert!(pin < self.pins, "GPIO pin {pin} does not exist");
+        let before = self.levels();
+        self.external_en |= 1 << pin;
+        if level {
+            self.external |= 1 << pin;
+        } else {
+            self.external &= !(1 << pin);
+        }
+        self.update(before, PinDriver::Host);
+    }
+
+    /// Stop driving `pin` from the host, leaving it floating or pulled up.
+    pub fn release_input(&mut self, pin: u32) {
+        let before = self.levels();
+        self.external_en &= !(1 << pin);
+        self.update(before, PinDriver::Host);
+    }
+
+    /// Every change of the pins since the last call, in order.
+    pub fn take_changes(&mut self) -> Vec<PinChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// The pins asserting their interrupt, a bit per pin.
+    pub fn interrupts(&self) -> u32 {
+        (self.rise_ip & self.rise_ie)
+            | (self.fall_ip & self.fall_ie)
+            | (self.high_ip & self.high_ie)
+            | (self.low_ip & self.low_ie)
+    }
+
+    /// Latch edges and levels of the input pins and record the changes since `before`.
+    fn update(&mut self, before: u32, driver: PinDriver) {
+        let after = self.levels();
+        let sampled = self.input_en;
+        self.rise_ip |= after & !before & sampled;
+        self.fall_ip |= !after & before & sampled;
+        self.high_ip |= after & sampled;
+        self.low_ip |= !after & sampled & self.mask();
+
+        let interrupts = self.interrupts();
+        for (pin, irq) in self.irqs.iter().enumerate() {
+            irq.set(interrupts & (1 << pin) != 0);
+        }
+
+        let changed = before ^ after;
+        for pin in (0..self.pins).filter(|pin| changed & (1 << pin) != 0) {
+            self.changes.push(PinChange {
+                time_ns: self.timer.now(),
+                pin,
+                level: after & (1 << pin) != 0,
+                driver,
+            });
+        }
+    }
+}
+
+impl Device for Gpio {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, _size: Sizes) -> Result<MemorySize> {
+        Ok(match addr {
+            INPUT_VAL => self.levels() & self.input_en,
+            INPUT_EN => self.input_en,
+            OUTPUT_EN => self.output_en,
+            OUTPUT_VAL => self.output_val,
+            PUE => self.pue,
+            DS => self.ds,
+            RISE_IE => self.rise_ie,
+            RISE_IP => self.rise_ip,
+            FALL_IE => self.fall_ie,
+            FALL_IP => self.fall_ip,
+            HIGH_IE => self.high_ie,
+            HIGH_IP => self.high_ip,
+            LOW_IE => self.low_ie,
+            LOW_IP => self.low_ip,
+            IOF_EN => self.iof_en,
+            IOF_SEL => self.iof_sel,
+            OUT_XOR => self.out_xor,
+            _ => {
+                warn!("Read from unknown GPIO register {addr:#x}");
+                0
+            }
+        })
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        let before = self.levels();
+        let value = value & self.mask();
+        match addr {
+            INPUT_EN => self.input_en = value,
+            OUTPUT_EN => self.output_en = value,
+            OUTPUT_VAL => self.output_val = value,
+            PUE => self.pue = value,
+            DS => self.ds = value,
+            RISE_IE => self.rise_ie = value,
+            FALL_IE => self.fall_ie = value,
+            HIGH_IE => self.high_ie = value,
+            LOW_IE => self.low_ie = value,
+            // pending bits are cleared by writing ones
+            RISE_IP => self.rise_ip &= !value,
+            FALL_IP => self.fall_ip &= !value,
+            HIGH_IP => self.high_ip &= !value,
+            LOW_IP => self.low_ip &= !value,
+            IOF_EN => self.iof_en = value,
+            IOF_SEL => self.iof_sel = value,
+            OUT_XOR => self.out_xor = value,
+            INPUT_VAL => {}
+            _ => warn!("Write to unknown GPIO register {addr:#x}: {value:#x}"),
+        }
+        self.update(before, PinDriver::Guest);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        let before = self.levels();
+        let (external, external_en) = (self.external, self.external_en);
+        let (timer, changes) = (self.timer.clone(), std::mem::take(&mut self.changes));
+        let irqs = std::mem::take(&mut self.irqs);
+        *self = Self::new(self.pins);
+        self.external = external;
+        self.external_en = external_en;
+        self.timer = timer;
+        self.changes = changes;
+        self.irqs = irqs;
+        self.update(before, PinDriver::Guest);
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.timer = timer;
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if let Some(irq) = self.irqs.get_mut(index) {
+            *irq = line;
+        }
+    }
+
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	gpio@{:x} {{
+		#interrupt-cells = <0x2>;
+		interrupt-controller;
+		#gpio-cells = <0x2>;
+		gpio-controller;
+		ngpios = <{:#x}>;
+{}		compatible = "sifive,gpio0";
+	}};
+"#,
+            node.base(),
+            self.pins,
+            node.properties(),
+        ))
+    }
+}
@@ -0,0 +1,15 @@
+//! The devices module contains the memory mapped peripherals of the board that are not memory
+//! and not behind a virtio transport.
+pub mod framebuffer;
+pub mod gpio;
+pub mod pdma;
+pub mod pflash;
+pub mod plic;
+pub mod rtc;
+pub mod spi;
+pub mod test_finisher;
+pub mod watchdog;
+
+/// Emulated time advances by this many nanoseconds with every step, which matches the 10 MHz
+/// timebase the device tree advertises.
+pub const NS_PER_STEP: u64 = 100;
@@ -0,0 +1,311 @@
+//! The SiFive platform DMA engine (`sifive,fu540-c000-pdma`), a memory-to-memory copier with
+//! independent channels. The driver fills in the Next registers of a channel, which describe the
+//! transfer, and sets `run`; the channel copies them to its Exec registers and moves a bounded
+//! number of bytes every step until it is done or hits an address nothing answers on.
+use anyhow::Result;
+use log::{info, warn};
+
+use super::NS_PER_STEP;
+use crate::bus::{BusView, Device, IrqLine, IrqTarget, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::events::Timer;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+/// The address of the PDMA, where the FU540 has it.
+pub const PDMA_BASE: u64 = 0x300_0000;
+/// The size of the PDMA registers.
+pub const PDMA_SIZE: u64 = 0x8000;
+/// The number of channels.
+pub const PDMA_CHANNELS: usize = 4;
+/// The PLIC source of the done interrupt of channel 0. Channel `n` signals completion on
+/// `PDMA_IRQ_BASE + 2 * n` and errors on the source after it.
+pub const PDMA_IRQ_BASE: u32 = 42;
+
+const CHANNEL_STRIDE: u64 = 0x1000;
+
+// Register offsets within a channel.
+const CONTROL: u64 = 0x000;
+const NEXT_CONFIG: u64 = 0x004;
+const NEXT_BYTES: u64 = 0x008;
+const NEXT_DESTINATION: u64 = 0x010;
+const NEXT_SOURCE: u64 = 0x018;
+const NEXT_END: u64 = 0x020;
+const EXEC_CONFIG: u64 = 0x104;
+const EXEC_BYTES: u64 = 0x108;
+const EXEC_DESTINATION: u64 = 0x110;
+const EXEC_SOURCE: u64 = 0x118;
+const EXEC_END: u64 = 0x120;
+
+// Control bits.
+const CONTROL_CLAIM: u32 = 1 << 0;
+const CONTROL_RUN: u32 = 1 << 1;
+const CONTROL_DONE_IE: u32 = 1 << 14;
+const CONTROL_ERROR_IE: u32 = 1 << 15;
+const CONTROL_DONE: u32 = 1 << 30;
+const CONTROL_ERROR: u32 = 1 << 31;
+const CONTROL_WRITABLE: u32 = CONTROL_CLAIM | CONTROL_RUN | CONTROL_DONE_IE | CONTROL_ERROR_IE;
+
+// Config bits.
+const CONFIG_REPEAT: u32 = 1 << 2;
+
+/// The most bytes a channel moves in a step.
+const BYTES_PER_STEP: u64 = 64;
+
+/// A transfer: the Next or Exec registers of a channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    pub config: u32,
+    pub bytes: u64,
+    pub destination: u64,
+    pub source: u64,
+}
+
+/// A single DMA channel.
+#[derive(Default)]
+struct Channel {
+    control: u32,
+    next: Descriptor,
+    exec: Descriptor,
+    /// Why the last transfer failed.
+    error: Option<String>,
+}
+
+impl Channel {
+    fn running(&self) -> bool {
+        self.control & CONTROL_RUN != 0
+    }
+
+    fn write_control(&mut self, value: u32) {
+        let claimed = self.control & CONTROL_CLAIM != 0;
+        // the status bits can only be cleared
+        let status = self.control & value & (CONTROL_DONE | CONTROL_ERROR);
+        let mut control = value & CONTROL_WRITABLE | status;
+        if value & CONTROL_CLAIM != 0 && !claimed {
+            self.next = Descriptor::default();
+        }
+        if value & CONTROL_CLAIM == 0 && self.running() {
+            warn!("PDMA channel released while it is running");
+            control |= CONTROL_CLAIM;
+        }
+        if value & CONTROL_RUN != 0 && !self.running() {
+            self.exec = self.next;
+            self.error = None;
+            control &= !(CONTROL_DONE | CONTROL_ERROR);
+        }
+        self.control = control;
+    }
+
+    /// Move the next chunk of the transfer.
+    fn step(&mut self, memory: &mut BusView) {
+        let len = self.exec.bytes.min(BYTES_PER_STEP);
+        let mut buffer = vec![0; len as usize];
+        let result = memory
+            .read_bytes(self.exec.source, &mut buffer)
+            .and_then(|()| memory.write_bytes(self.exec.destination, &buffer));
+        if let Err(e) = result {
+            warn!("PDMA transfer failed: {e:#}");
+            self.error = Some(format!("{e:#}"));
+            self.control = self.control & !CONTROL_RUN | CONTROL_ERROR;
+            return;
+        }
+
+        self.exec.bytes -= len;
+        self.exec.source += len;
+        self.exec.destination += len;
+        if self.exec.bytes == 0 {
+            self.control |= CONTROL_DONE;
+            if self.exec.config & CONFIG_REPEAT != 0 {
+                self.exec = self.next;
+            } else {
+                self.control &= !CONTROL_RUN;
+            }
+        }
+    }
+}
+
+/// The SiFive PDMA.
+pub struct Pdma {
+    channels: [Channel; PDMA_CHANNELS],
+    /// The done and error outputs of every channel, interleaved.
+    irqs: [IrqLine; 2 * PDMA_CHANNELS],
+    /// Fires every step while a channel is running.
+    timer: Timer,
+}
+
+impl Default for Pdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pdma {
+    pub fn new() -> Self {
+        Self {
+            channels: Default::default(),
+            irqs: Default::default(),
+            timer: Timer::new(),
+        }
+    }
+
+    pub fn new_device() -> VirtualDevice {
+        VirtualDevice::new(Box::new(Self::new()), PDMA_BASE, PDMA_SIZE).with_irqs(
+            (0..2 * PDMA_CHANNELS as u32).map(|index| IrqTarget::Plic(PDMA_IRQ_BASE + index)),
+        )
+    }
+
+    fn update_irqs(&self) {
+        for channel in 0..PDMA_CHANNELS {
+            self.irqs[2 * channel].set(self.done_pending(channel));
+            self.irqs[2 * channel + 1].set(self.error_pending(channel));
+        }
+    }
+
+    /// Whether `channel` is asserting its done interrupt.
+    pub fn done_pending(&self, channel: usize) -> bool {
+        let control = self.channels[channel].control;
+        control & CONTROL_DONE != 0 && control & CONTROL_DONE_IE != 0
+    }
+
+    /// Whether `channel` is asserting its error interrupt.
+    pub fn error_pending(&self, channel: usize) -> bool {
+        let control = self.channels[channel].control;
+        control & CONTROL_ERROR != 0 && control & CONTROL_ERROR_IE != 0
+    }
+
+    /// Why the last transfer of `channel` failed, if it did.
+    pub fn error(&self, channel: usize) -> Option<&str> {
+        self.channels[channel].error.as_deref()
+    }
+
+    /// The transfer `channel` is executing, advanced past the bytes already moved.
+    pub fn exec(&self, channel: usize) -> Descriptor {
+        self.channels[channel].exec
+    }
+
+    /// The channel and register of an address.
+    fn channel_register(addr: u64) -> Option<(usize, u64)> {
+        let channel = (addr / CHANNEL_STRIDE) as usize;
+        (channel < PDMA_CHANNELS).then_some((channel, addr % CHANNEL_STRIDE))
+    }
+}
+
+/// Replace the half of `value` that `offset` selects.
+fn write_half(value: &mut u64, offset: u64, half: u32) {
+    if offset & 4 == 0 {
+        *value = *value & !0xffff_ffff | half as u64;
+    } else {
+        *value = *value & 0xffff_ffff | (half as u64) << 32;
+    }
+}
+
+/// The half of `value` that `offset` selects.
+fn read_half(value: u64, offset: u64) -> u32 {
+    (value >> ((offset & 4) * 8)) as u32
+}
+
+impl Device for Pdma {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, _size: Sizes) -> Result<MemorySize> {
+        let Some((channel, offset)) = Self::channel_register(addr) else {
+            warn!("Read from unknown PDMA register {addr:#x}");
+            return Ok(0);
+        };
+        let channel = &self.channels[channel];
+        Ok(match offset {
+            CONTROL => channel.control,
+            NEXT_CONFIG => channel.next.config,
+            NEXT_BYTES..NEXT_DESTINATION => read_half(channel.next.bytes, offset),
+            NEXT_DESTINATION..NEXT_SOURCE => read_half(channel.next.destination, offset),
+            NEXT_SOURCE..NEXT_END => read_half(channel.next.source, offset),
+            EXEC_CONFIG => channel.exec.config,
+            EXEC_BYTES..EXEC_DESTINATION => read_half(channel.exec.bytes, offset),
+            EXEC_DESTINATION..EXEC_SOURCE => read_half(channel.exec.destination, offset),
+            EXEC_SOURCE..EXEC_END => read_half(channel.exec.source, offset),
+            _ => {
+                warn!("Read from unknown PDMA register {addr:#x}");
+                0
+            }
+        })
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        let Some((index, offset)) = Self::channel_register(addr) else {
+            warn!("Write to unknown PDMA register {addr:#x}: {value:#x}");
+            return Ok(());
+        };
+        let channel = &mut self.channels[index];
+        if offset == CONTROL {
+            let running = channel.running();
+            channel.write_control(value);
+            if channel.running() && !running {
+                info!("PDMA channel {index} started: {:x?}", channel.exec);
+                self.timer.schedule_in(0);
+            }
+            self.update_irqs();
+            return Ok(());
+        }
+        if channel.control & CONTROL_CLAIM == 0 {
+            warn!("Write to PDMA channel {index}, which is not claimed");
+            return Ok(());
+        }
+        match offset {
+            NEXT_CONFIG => channel.next.config = value,
+            NEXT_BYTES..NEXT_DESTINATION => write_half(&mut channel.next.bytes, offset, value),
+            NEXT_DESTINATION..NEXT_SOURCE => {
+                write_half(&mut channel.next.destination, offset, value)
+            }
+            NEXT_SOURCE..NEXT_END => write_half(&mut channel.next.source, offset, value),
+            // the Exec registers are read-only
+            EXEC_CONFIG..EXEC_END => {}
+            _ => warn!("Write to unknown PDMA register {addr:#x}: {value:#x}"),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.channels = Default::default();
+        self.timer.cancel();
+        self.update_irqs();
+    }
+
+    fn event(&mut self, memory: &mut BusView) {
+        for channel in self.channels.iter_mut().filter(|channel| channel.running()) {
+            channel.step(memory);
+        }
+        if self.channels.iter().any(Channel::running) {
+            self.timer.schedule_in(NS_PER_STEP);
+        }
+        self.update_irqs();
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.timer = timer;
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if let Some(irq) = self.irqs.get_mut(index) {
+            *irq = line;
+        }
+    }
+
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	dma-controller@{:x} {{
+		compatible = "sifive,fu540-c000-pdma", "sifive,pdma0";
+{}		dma-channels = <{PDMA_CHANNELS:#x}>;
+		#dma-cells = <0x1>;
+	}};
+"#,
+            node.base(),
+            node.properties(),
+        ))
+    }
+}
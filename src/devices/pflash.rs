@@ -0,0 +1,502 @@
+//! A CFI parallel NOR flash (`cfi-flash`) with the Intel (0x0001) or AMD (0x0002) command set,
+//! optionally backed by a host file so programmed data survives the machine. Program and erase
+//! operations complete immediately, so status polling always finds the flash ready.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+
+use crate::bus::{Device, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+use crate::trap::Exception;
+
+/// The address of the first flash on the virt board.
+pub const PFLASH_BASE: u64 = 0x2000_0000;
+
+/// The value of erased flash.
+const ERASED: u8 = 0xff;
+
+// Intel status register bits.
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+// AMD unlock addresses, in bank width units.
+const AMD_UNLOCK0: u64 = 0x555;
+const AMD_UNLOCK1: u64 = 0x2aa;
+const AMD_UNLOCK_MASK: u64 = 0x7ff;
+
+/// The command set a flash understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandSet {
+    /// The Intel/Sharp extended command set, like `pflash_cfi01` in QEMU.
+    Intel,
+    /// The AMD/Fujitsu standard command set, like `pflash_cfi02` in QEMU.
+    Amd,
+}
+
+/// The geometry and behavior of a flash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PFlashConfig {
+    pub command_set: CommandSet,
+    /// The size of the flash in bytes, a power of two.
+    pub size: u64,
+    /// The size of an erase sector in bytes, a power of two dividing `size`.
+    pub sector_size: u64,
+    /// The width of the data bus in bytes: 1, 2 or 4. Commands are taken from the low byte and
+    /// command addresses are counted in units of the width.
+    pub bank_width: u8,
+    /// Whether instructions may be fetched from the flash.
+    pub execute_in_place: bool,
+}
+
+impl Default for PFlashConfig {
+    /// A 32 MiB Intel flash with 256 KiB sectors on a 32-bit bus, like the flashes of QEMU's
+    /// virt board.
+    fn default() -> Self {
+        Self {
+            command_set: CommandSet::Intel,
+            size: 32 << 20,
+            sector_size: 256 << 10,
+            bank_width: 4,
+            execute_in_place: true,
+        }
+    }
+}
+
+/// What reads return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadMode {
+    Array,
+    Status,
+    Query,
+    Identifier,
+}
+
+/// The progress through a multi-cycle command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sequence {
+    Idle,
+    /// Intel: the next write programs.
+    Program,
+    /// Intel: waiting for the erase confirmation.
+    Erase,
+    /// Intel: waiting for the lock bit command.
+    Lock,
+    /// AMD: the first unlock cycle was seen. `erase` tells whether this is the second pair of
+    /// unlock cycles of an erase.
+    Unlocked1 {
+        erase: bool,
+    },
+    /// AMD: both unlock cycles were seen.
+    Unlocked2 {
+        erase: bool,
+    },
+    /// AMD: waiting for the unlock cycles of an erase.
+    EraseSetup,
+}
+
+/// A CFI parallel NOR flash.
+pub struct PFlash {
+    config: PFlashConfig,
+    data: Vec<u8>,
+    file: Option<File>,
+    read_mode: ReadMode,
+    sequence: Sequence,
+    /// The Intel status register. AMD flashes have none, their status is polled through the
+    /// data, which is final right away.
+    status: u8,
+}
+
+impl PFlash {
+    /// Create an erased flash that is not backed by a file.
+    pub fn new(config: PFlashConfig) -> Result<Self> {
+        Self::check(&config)?;
+        Ok(Self::with_data(
+            config,
+            vec![ERASED; config.size as usize],
+            None,
+        ))
+    }
+
+    /// Open a flash backed by the file at `path`, which is created if it does not exist. A file
+    /// shorter than the flash is padded with erased sectors, a longer one is rejected.
+    pub fn open(path: &Path, config: PFlashConfig) -> Result<Self> {
+        Self::check(&config)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if data.len() as u64 > config.size {
+            bail!(
+                "{} is {} bytes, more than the {} bytes of the flash",
+                path.display(),
+                data.len(),
+                config.size
+            );
+        }
+        if (data.len() as u64) < config.size {
+            let len = data.len();
+            data.resize(config.size as usize, ERASED);
+            file.write_all(&data[len..])
+                .with_context(|| format!("failed to extend {}", path.display()))?;
+        }
+        info!("Opened flash image {}", path.display());
+        Ok(Self::with_data(config, data, Some(file)))
+    }
+
+    /// Map a flash that is not backed by a file at `base`.
+    pub fn new_device(config: PFlashConfig, base: u64) -> Result<VirtualDevice> {
+        Ok(VirtualDevice::new(
+            Box::new(Self::new(config)?),
+            base,
+            config.size,
+        ))
+    }
+
+    /// Map a flash backed by the file at `path` at `base`.
+    pub fn open_device(path: &Path, config: PFlashConfig, base: u64) -> Result<VirtualDevice> {
+        Ok(VirtualDevice::new(
+            Box::new(Self::open(path, config)?),
+            base,
+            config.size,
+        ))
+    }
+
+    fn check(config: &PFlashConfig) -> Result<()> {
+        if !config.size.is_power_of_two()
+            || !config.sector_size.is_power_of_two()
+            || config.sector_size > config.size
+        {
+            bail!(
+                "invalid flash geometry: {:#x} bytes in sectors of {:#x} bytes",
+                config.size,
+                config.sector_size
+            );
+        }
+        if !matches!(config.bank_width, 1 | 2 | 4) {
+            bail!("invalid flash bank width: {}", config.bank_width);
+        }
+        Ok(())
+    }
+
+    fn with_data(config: PFlashConfig, data: Vec<u8>, file: Option<File>) -> Self {
+        Self {
+            config,
+            data,
+            file,
+            read_mode: ReadMode::Array,
+            sequence: Sequence::Idle,
+            status: STATUS_READY,
+        }
+    }
+
+    pub fn config(&self) -> PFlashConfig {
+        self.config
+    }
+
+    /// The contents of the flash.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The byte at `index` of the CFI query table, counted in bank width units.
+    fn query(&self, index: u64) -> u8 {
+        let sectors = self.config.size / self.config.sector_size - 1;
+        let sector_size = self.config.sector_size / 256;
+        let (command_set, extended) = match self.config.command_set {
+            CommandSet::Intel => (0x0001u16, 0x31u16),
+            CommandSet::Amd => (0x0002, 0x40),
+        };
+        match (index, self.config.command_set) {
+            (0x10, _) => b'Q',
+            (0x11, _) => b'R',
+            (0x12, _) => b'Y',
+            (0x13, _) => command_set as u8,
+            (0x14, _) => (command_set >> 8) as u8,
+            (0x15, _) => extended as u8,
+            (0x16, _) => (extended >> 8) as u8,
+            // Vcc and Vpp in 100 mV
+            (0x1b, _) => 0x45,
+            (0x1c, _) => 0x55,
+            // typical timeouts: 2^n us for a word, 2^n ms for a sector and the chip
+            (0x1f, _) => 0x07,
+            (0x21, _) => 0x0a,
+            (0x22, CommandSet::Amd) => 0x0f,
+            // maximum timeouts as multiples of the typical ones
+            (0x23, _) => 0x04,
+            (0x25, _) => 0x04,
+            (0x26, CommandSet::Amd) => 0x04,
+            (0x27, _) => self.config.size.trailing_zeros() as u8,
+            // x8/x16 asynchronous interface, no write buffer
+            (0x28, _) => 0x02,
+            // one region of uniform sectors
+            (0x2c, _) => 1,
+            (0x2d, _) => sectors as u8,
+            (0x2e, _) => (sectors >> 8) as u8,
+            (0x2f, _) => sector_size as u8,
+            (0x30, _) => (sector_size >> 8) as u8,
+            // the primary vendor-specific extended query tables, version 1.0
+            (0x31, CommandSet::Intel) | (0x40, CommandSet::Amd) => b'P',
+            (0x32, CommandSet::Intel) | (0x41, CommandSet::Amd) => b'R',
+            (0x33, CommandSet::Intel) | (0x42, CommandSet::Amd) => b'I',
+            (0x34, CommandSet::Intel) | (0x43, CommandSet::Amd) => b'1',
+            (0x35, CommandSet::Intel) | (0x44, CommandSet::Amd) => b'0',
+            _ => 0,
+        }
+    }
+
+    /// The manufacturer and device identifiers.
+    fn identifier(&self, index: u64) -> u8 {
+        match (index, self.config.command_set) {
+            (0, CommandSet::Intel) => 0x89,
+            (1, CommandSet::Intel) => 0x18,
+            (0, CommandSet::Amd) => 0x01,
+            (1, CommandSet::Amd) => 0x7e,
+            // sectors are never locked
+            _ => 0,
+        }
+    }
+
+    fn index(&self, addr: u64) -> u64 {
+        addr / self.config.bank_width as u64
+    }
+
+    /// Write `len` bytes of the data from `start` on to the backing file.
+    fn persist(&mut self, start: usize, len: usize) -> bool {
+        let Some(file) = &mut self.file else {
+            return true;
+        };
+        let result = file
+            .seek(SeekFrom::Start(start as u64))
+            .and_then(|_| file.write_all(&self.data[start..start + len]));
+        if let Err(e) = result {
+            error!("Failed to write the flash image: {e}");
+            return false;
+        }
+        true
+    }
+
+    fn program(&mut self, addr: u64, size: Sizes, value: MemorySize) -> bool {
+        let len = match size {
+            Sizes::Byte => 1,
+            Sizes::HalfWord => 2,
+            Sizes::Word => 4,
+        };
+        let start = addr as usize;
+        if start + len > self.data.len() {
+            return false;
+        }
+        // programming can only clear bits, setting them takes an erase
+        for (i, byte) in value.to_le_bytes()[..len].iter().enumerate() {
+            self.data[start + i] &= byte;
+        }
+        self.persist(start, len)
+    }
+
+    fn erase(&mut self, start: u64, len: u64) -> bool {
+        let start = (start as usize).min(self.data.len());
+        let len = (len as usize).min(self.data.len() - start);
+        self.data[start..start + len].fill(ERASED);
+        self.persist(start, len)
+    }
+
+    fn erase_sector(&mut self, addr: u64) -> bool {
+        let start = addr & !(self.config.sector_size - 1);
+        self.erase(start, self.config.sector_size)
+    }
+
+    fn store_intel(&mut self, addr: u64, size: Sizes, value: MemorySize) {
+        let command = value as u8;
+        match self.sequence {
+            Sequence::Program => {
+                if !self.program(addr, size, value) {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+                self.sequence = Sequence::Idle;
+                self.read_mode = ReadMode::Status;
+                return;
+            }
+            Sequence::Erase => {
+                if command != 0xd0 || !self.erase_sector(addr) {
+                    // an improper command sequence sets both error bits
+                    self.status |= STATUS_ERASE_ERROR;
+                    if command != 0xd0 {
+                        self.status |= STATUS_PROGRAM_ERROR;
+                    }
+                }
+                self.sequence = Sequence::Idle;
+                self.read_mode = ReadMode::Status;
+                return;
+            }
+            Sequence::Lock => {
+                // lock bits are accepted, but sectors are never locked
+                self.sequence = Sequence::Idle;
+                self.read_mode = ReadMode::Status;
+                return;
+            }
+            _ => {}
+        }
+
+        match command {
+            0xff => self.read_mode = ReadMode::Array,
+            0x90 => self.read_mode = ReadMode::Identifier,
+            0x98 => self.read_mode = ReadMode::Query,
+            0x70 => self.read_mode = ReadMode::Status,
+            0x50 => self.status = STATUS_READY,
+            0x10 | 0x40 => self.sequence = Sequence::Program,
+            0x20 => self.sequence = Sequence::Erase,
+            0x60 => self.sequence = Sequence::Lock,
+            // operations never run long enough to be suspended
+            0xb0 | 0xd0 => self.read_mode = ReadMode::Status,
+            _ => {
+                warn!("Unknown flash command {command:#x} at {addr:#x}");
+                self.read_mode = ReadMode::Array;
+            }
+        }
+    }
+
+    fn store_amd(&mut self, addr: u64, size: Sizes, value: MemorySize) {
+        let command = value as u8;
+        let unlock = self.index(addr) & AMD_UNLOCK_MASK;
+        // the data of a program cycle and the confirmation of a sector erase can look like a
+        // reset, they are handled below
+        let confirming = matches!(
+            self.sequence,
+            Sequence::Program | Sequence::Unlocked2 { erase: true }
+        );
+        if command == 0xf0 && !confirming {
+            self.read_mode = ReadMode::Array;
+            self.sequence = Sequence::Idle;
+            return;
+        }
+
+        self.sequence = match (self.sequence, unlock, command) {
+            (Sequence::Program, _, _) => {
+                if !self.program(addr, size, value) {
+                    warn!("Failed to program the flash at {addr:#x}");
+                }
+                Sequence::Idle
+            }
+            (Sequence::Idle, 0x55, 0x98) => {
+                self.read_mode = ReadMode::Query;
+                Sequence::Idle
+            }
+            (Sequence::Idle, AMD_UNLOCK0, 0xaa) => Sequence::Unlocked1 { erase: false },
+            (Sequence::EraseSetup, AMD_UNLOCK0, 0xaa) => Sequence::Unlocked1 { erase: true },
+            (Sequence::Unlocked1 { erase }, AMD_UNLOCK1, 0x55) => Sequence::Unlocked2 { erase },
+            (Sequence::Unlocked2 { erase: false }, AMD_UNLOCK0, 0xa0) => Sequence::Program,
+            (Sequence::Unlocked2 { erase: false }, AMD_UNLOCK0, 0x80) => Sequence::EraseSetup,
+            (Sequence::Unlocked2 { erase: false }, AMD_UNLOCK0, 0x90) => {
+                self.read_mode = ReadMode::Identifier;
+                Sequence::Idle
+            }
+            (Sequence::Unlocked2 { erase: true }, AMD_UNLOCK0, 0x10) => {
+                if !self.erase(0, self.config.size) {
+                    warn!("Failed to erase the flash");
+                }
+                Sequence::Idle
+            }
+            (Sequence::Unlocked2 { erase: true }, _, 0x30) => {
+                if !self.erase_sector(addr) {
+                    warn!("Failed to erase the flash sector at {addr:#x}");
+                }
+                Sequence::Idle
+            }
+            // erase suspend and resume have nothing to do
+            (_, _, 0xb0 | 0x30) => self.sequence,
+            _ => {
+                warn!("Unexpected flash command {command:#x} at {addr:#x}");
+                Sequence::Idle
+            }
+        };
+    }
+}
+
+impl Device for PFlash {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, size: Sizes) -> Result<MemorySize> {
+        let value = match self.read_mode {
+            ReadMode::Array => {
+                let len = match size {
+                    Sizes::Byte => 1,
+                    Sizes::HalfWord => 2,
+                    Sizes::Word => 4,
+                };
+                let start = addr as usize;
+                let Some(bytes) = self.data.get(start..start + len) else {
+                    bail!(Exception::LoadAccessFault);
+                };
+                let mut value = [0; 4];
+                value[..len].copy_from_slice(bytes);
+                return Ok(u32::from_le_bytes(value));
+            }
+            ReadMode::Status => self.status,
+            ReadMode::Query => self.query(self.index(addr)),
+            ReadMode::Identifier => self.identifier(self.index(addr)),
+        };
+        Ok(value as MemorySize)
+    }
+
+    fn store(&mut self, addr: u64, size: Sizes, value: MemorySize) -> Result<()> {
+        match self.config.command_set {
+            CommandSet::Intel => self.store_intel(addr, size, value),
+            CommandSet::Amd => self.store_amd(addr, size, value),
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.read_mode = ReadMode::Array;
+        self.sequence = Sequence::Idle;
+        self.status = STATUS_READY;
+    }
+
+    fn executable(&self) -> bool {
+        self.config.execute_in_place
+    }
+
+    /// Programs the flash array without the command set, like an external programmer. The
+    /// backing file is updated too.
+    fn preload(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let start = addr as usize;
+        let Some(array) = self.data.get_mut(start..start + data.len()) else {
+            bail!("the flash has no memory at {addr:#x}");
+        };
+        array.copy_from_slice(data);
+        if !self.persist(start, data.len()) {
+            bail!("failed to write the flash image");
+        }
+        Ok(())
+    }
+
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	flash@{:x} {{
+		bank-width = <{:#x}>;
+{}		compatible = "cfi-flash";
+	}};
+"#,
+            node.base(),
+            self.config.bank_width,
+            node.properties(),
+        ))
+    }
+}
@@ -0,0 +1,264 @@
+//! The platform-level interrupt controller (`riscv,plic0`, section 7 of the FU540 manual). It
+//! gathers the interrupts of the devices and presents them to the external interrupt inputs of
+//! the hart: context 0 is machine mode and context 1 is supervisor mode. Each context drives an
+//! interrupt output, wired to the external interrupt pending bit of its mode by default.
+use std::cell::Cell;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::bus::{Device, IrqLine, IrqTarget, VirtualDevice};
+use crate::csr::{MEIP_BIT, SEIP_BIT};
+use crate::device_tree::NodeContext;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+/// The address of the PLIC on the virt board.
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+/// The size of the PLIC registers.
+pub const PLIC_SIZE: u64 = 0x0400_0000;
+
+/// The number of interrupt sources, which the device tree advertises as `riscv,ndev`. Source 0
+/// means "no interrupt".
+pub const PLIC_SOURCES: u32 = 53;
+/// The machine mode context of hart 0.
+pub const CONTEXT_MACHINE: usize = 0;
+/// The supervisor mode context of hart 0.
+pub const CONTEXT_SUPERVISOR: usize = 1;
+const CONTEXTS: usize = 2;
+
+// Register offsets.
+const PRIORITY: u64 = 0x00_0000;
+const PENDING: u64 = 0x00_1000;
+const ENABLE: u64 = 0x00_2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const THRESHOLD: u64 = 0x0;
+const CLAIM: u64 = 0x4;
+
+/// Priorities are 3 bits wide, like on the FU540.
+const PRIORITY_MASK: u32 = 0x7;
+
+/// The PLIC, with level-triggered gateways for every source.
+pub struct Plic {
+    priority: [u32; PLIC_SOURCES as usize + 1],
+    /// A bit per source, bit 0 is never set.
+    enable: [u64; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+    /// The current levels of the interrupt lines.
+    level: u64,
+    /// Interrupts waiting to be claimed. Claims happen on reads, hence the cell.
+    pending: Cell<u64>,
+    /// Interrupts claimed but not completed yet, which the gateway holds back.
+    in_flight: Cell<u64>,
+    /// The interrupt output of every context.
+    outputs: [IrqLine; CONTEXTS],
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: [0; PLIC_SOURCES as usize + 1],
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+            level: 0,
+            pending: Cell::new(0),
+            in_flight: Cell::new(0),
+            outputs: Default::default(),
+        }
+    }
+
+    pub fn new_device() -> VirtualDevice {
+        VirtualDevice::new(Box::new(Self::new()), PLIC_BASE, PLIC_SIZE)
+            .with_irqs([IrqTarget::Mip(MEIP_BIT), IrqTarget::Mip(SEIP_BIT)])
+    }
+
+    /// Set the level of the interrupt line of `source`.
+    pub fn set_level(&mut self, source: u32, level: bool) {
+        if source == 0 || source > PLIC_SOURCES {
+            warn!("Interrupt from unknown PLIC source {source}");
+            return;
+        }
+        let bit = 1 << source;
+        if level {
+            self.level |= bit;
+        } else {
+            self.level &= !bit;
+        }
+        self.update_gateways();
+    }
+
+    /// Whether `source` waits to be claimed.
+    pub fn is_pending(&self, source: u32) -> bool {
+        self.pending.get() & (1 << source) != 0
+    }
+
+    /// Whether the PLIC interrupts `context`, which drives the external interrupt pending bit of
+    /// the hart.
+    pub fn context_pending(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    /// Raised lines become pending unless their previous interrupt is still being handled.
+    fn update_gateways(&self) {
+        let ready = self.level & !self.in_flight.get();
+        self.pending.set(self.pending.get() | ready);
+        self.update_outputs();
+    }
+
+    fn update_outputs(&self) {
+        for (context, output) in self.outputs.iter().enumerate() {
+            output.set(self.context_pending(context));
+        }
+    }
+
+    /// The pending and enabled source with the highest priority above the threshold. Ties go to
+    /// the lowest source.
+    fn best(&self, context: usize) -> Option<u32> {
+        let candidates = self.pending.get() & self.enable[context];
+        (1..=PLIC_SOURCES)
+            .filter(|source| candidates & (1 << source) != 0)
+            .filter(|source| self.priority[*source as usize] > self.threshold[context])
+            .min_by_key(|source| std::cmp::Reverse(self.priority[*source as usize]))
+    }
+
+    fn claim(&self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else {
+            return 0;
+        };
+        self.pending.set(self.pending.get() & !(1 << source));
+        self.in_flight.set(self.in_flight.get() | 1 << source);
+        self.update_outputs();
+        source
+    }
+
+    fn complete(&mut self, source: u32) {
+        if source == 0 || source > PLIC_SOURCES {
+            return;
+        }
+        self.in_flight.set(self.in_flight.get() & !(1 << source));
+        self.update_gateways();
+    }
+
+    /// The context and register of an address in the context area.
+    fn context_register(addr: u64) -> Option<(usize, u64)> {
+        let context = ((addr - CONTEXT) / CONTEXT_STRIDE) as usize;
+        (context < CONTEXTS).then_some((context, (addr - CONTEXT) % CONTEXT_STRIDE))
+    }
+}
+
+impl Device for Plic {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, _size: Sizes) -> Result<MemorySize> {
+        Ok(match addr {
+            PRIORITY..PENDING => {
+                let source = ((addr - PRIORITY) / 4) as usize;
+                self.priority.get(source).copied().unwrap_or(0)
+            }
+            PENDING..ENABLE => match (addr - PENDING) / 4 {
+                0 => self.pending.get() as u32,
+                1 => (self.pending.get() >> 32) as u32,
+                _ => 0,
+            },
+            ENABLE..CONTEXT => {
+                let context = ((addr - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = (addr - ENABLE) % ENABLE_STRIDE / 4;
+                match (self.enable.get(context), word) {
+                    (Some(enable), 0) => *enable as u32,
+                    (Some(enable), 1) => (*enable >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            _ => match Self::context_register(addr) {
+                Some((context, THRESHOLD)) => self.threshold[context],
+                Some((context, CLAIM)) => self.claim(context),
+                _ => {
+                    warn!("Read from unknown PLIC register {addr:#x}");
+                    0
+                }
+            },
+        })
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        match addr {
+            PRIORITY..PENDING => {
+                let source = ((addr - PRIORITY) / 4) as usize;
+                if (1..=PLIC_SOURCES as usize).contains(&source) {
+                    self.priority[source] = value & PRIORITY_MASK;
+                }
+            }
+            // pending bits are read-only
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let context = ((addr - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = (addr - ENABLE) % ENABLE_STRIDE / 4;
+                let valid = (1u64 << (PLIC_SOURCES + 1)) - 2;
+                if let Some(enable) = self.enable.get_mut(context) {
+                    match word {
+                        0 => *enable = (*enable & !0xffff_ffff) | value as u64,
+                        1 => *enable = (*enable & 0xffff_ffff) | (value as u64) << 32,
+                        _ => {}
+                    }
+                    *enable &= valid;
+                }
+            }
+            _ => match Self::context_register(addr) {
+                Some((context, THRESHOLD)) => self.threshold[context] = value & PRIORITY_MASK,
+                Some((_, CLAIM)) => self.complete(value),
+                _ => warn!("Write to unknown PLIC register {addr:#x}: {value:#x}"),
+            },
+        }
+        self.update_outputs();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        let level = self.level;
+        let outputs = std::mem::take(&mut self.outputs);
+        *self = Self::new();
+        self.level = level;
+        self.outputs = outputs;
+        self.update_gateways();
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if let Some(output) = self.outputs.get_mut(index) {
+            *output = line;
+            self.update_outputs();
+        }
+    }
+
+    /// The PLIC with the outputs of its contexts going to the interrupt controllers of the hart.
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	interrupt-controller@{:x} {{
+		phandle = <{:#x}>;
+		riscv,ndev = <{:#x}>;
+{}		interrupt-controller;
+		compatible = "riscv,plic0";
+		#interrupt-cells = <0x1>;
+		#address-cells = <0x0>;
+	}};
+"#,
+            node.base(),
+            node.phandle(),
+            PLIC_SOURCES,
+            node.properties(),
+        ))
+    }
+}
@@ -0,0 +1,218 @@
+//! The Goldfish real-time clock (`google,goldfish-rtc`). It counts nanoseconds since the Unix
+//! epoch and raises an interrupt when an alarm expires.
+use std::cell::Cell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::bus::{BusView, Device, IrqLine, IrqTarget, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::events::Timer;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+/// The address of the RTC on the virt board.
+pub const RTC_BASE: u64 = 0x10_1000;
+/// The size of the RTC registers.
+pub const RTC_SIZE: u64 = 0x1000;
+/// The PLIC source of the RTC interrupt.
+pub const RTC_IRQ: u32 = 11;
+
+// Register offsets.
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0c;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1c;
+
+/// Where the RTC takes the time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    /// The wall clock of the host.
+    Host,
+    /// A clock stopped at the given number of seconds since the Unix epoch.
+    Fixed(u64),
+    /// A clock starting at the given number of seconds since the Unix epoch and advancing with
+    /// emulated time, so the same run always sees the same time.
+    Emulated(u64),
+}
+
+/// The Goldfish RTC.
+pub struct GoldfishRtc {
+    source: TimeSource,
+    /// The difference between the time the guest set and the time source.
+    offset: i64,
+    /// The upper half of the time, latched when the lower half is read.
+    time_high: Cell<u32>,
+    /// The upper half of the next time or alarm written by the guest.
+    high_written: u32,
+    alarm: u64,
+    alarm_running: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    irq: IrqLine,
+    /// Fires when the alarm may have expired.
+    timer: Timer,
+}
+
+impl GoldfishRtc {
+    pub fn new(source: TimeSource) -> Self {
+        Self {
+            source,
+            offset: 0,
+            time_high: Cell::new(0),
+            high_written: 0,
+            alarm: 0,
+            alarm_running: false,
+            irq_enabled: false,
+            irq_pending: false,
+            irq: IrqLine::new(),
+            timer: Timer::new(),
+        }
+    }
+
+    pub fn new_device(source: TimeSource) -> VirtualDevice {
+        VirtualDevice::new(Box::new(Self::new(source)), RTC_BASE, RTC_SIZE)
+            .with_irqs([IrqTarget::Plic(RTC_IRQ)])
+    }
+
+    pub fn source(&self) -> TimeSource {
+        self.source
+    }
+
+    /// Nanoseconds since the Unix epoch according to the time source alone.
+    fn source_time(&self) -> u64 {
+        match self.source {
+            TimeSource::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64),
+            TimeSource::Fixed(seconds) => seconds * 1_000_000_000,
+            TimeSource::Emulated(seconds) => seconds * 1_000_000_000 + self.timer.now(),
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch as seen by the guest.
+    pub fn time(&self) -> u64 {
+        self.source_time().wrapping_add_signed(self.offset)
+    }
+
+    /// Whether the RTC is asserting its interrupt.
+    pub fn interrupt_pending(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm_running && self.time() >= self.alarm {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+        self.irq.set(self.interrupt_pending());
+        self.schedule_alarm();
+    }
+
+    /// Set the timer to when the alarm expires. The wall clock of the host does not follow
+    /// emulated time, so for it the alarm is checked again when it fires.
+    fn schedule_alarm(&mut self) {
+        match self.source {
+            _ if !self.alarm_running => self.timer.cancel(),
+            TimeSource::Fixed(_) => self.timer.cancel(),
+            TimeSource::Host | TimeSource::Emulated(_) => {
+                let remaining = self.alarm.saturating_sub(self.time());
+                self.timer.schedule_in(remaining);
+            }
+        }
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, _size: Sizes) -> Result<MemorySize> {
+        Ok(match addr {
+            TIME_LOW => {
+                let time = self.time();
+                self.time_high.set((time >> 32) as u32);
+                time as u32
+            }
+            TIME_HIGH => self.time_high.get(),
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm_running as u32,
+            _ => {
+                warn!("Read from unknown RTC register {addr:#x}");
+                0
+            }
+        })
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        match addr {
+            TIME_HIGH | ALARM_HIGH => self.high_written = value,
+            TIME_LOW => {
+                let time = (self.high_written as u64) << 32 | value as u64;
+                self.offset = time.wrapping_sub(self.source_time()) as i64;
+                self.schedule_alarm();
+            }
+            ALARM_LOW => {
+                self.alarm = (self.high_written as u64) << 32 | value as u64;
+                self.alarm_running = true;
+                self.check_alarm();
+            }
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => {
+                self.alarm_running = false;
+                self.timer.cancel();
+            }
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => warn!("Write to unknown RTC register {addr:#x}: {value:#x}"),
+        }
+        self.irq.set(self.interrupt_pending());
+        Ok(())
+    }
+
+    fn event(&mut self, _memory: &mut BusView) {
+        self.check_alarm();
+    }
+
+    fn reset(&mut self) {
+        // the clock keeps running through a reset, only the alarm is lost
+        self.alarm_running = false;
+        self.timer.cancel();
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.irq.lower();
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.timer = timer;
+        self.schedule_alarm();
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if index == 0 {
+            self.irq = line;
+        }
+    }
+
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	rtc@{:x} {{
+{}		compatible = "google,goldfish-rtc";
+	}};
+"#,
+            node.base(),
+            node.properties(),
+        ))
+    }
+}
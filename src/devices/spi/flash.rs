@@ -0,0 +1,374 @@
+//! A JEDEC SPI NOR flash (`jedec,spi-nor`) with 3-byte addresses, optionally backed by a host
+//! file. Program and erase operations complete when the chip select is released, so the busy bit
+//! is never seen set.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+
+use super::SpiSlave;
+
+/// The value of erased flash.
+const ERASED: u8 = 0xff;
+/// The manufacturer ID, Winbond, whose parts nearly every driver knows.
+const MANUFACTURER: u8 = 0xef;
+/// The memory type of the Winbond W25Q series.
+const MEMORY_TYPE: u8 = 0x40;
+
+const PAGE_SIZE: usize = 256;
+const SECTOR_SIZE: usize = 4 << 10;
+const BLOCK_SIZE: usize = 64 << 10;
+
+// Commands.
+const WRITE_STATUS: u8 = 0x01;
+const PAGE_PROGRAM: u8 = 0x02;
+const READ: u8 = 0x03;
+const WRITE_DISABLE: u8 = 0x04;
+const READ_STATUS: u8 = 0x05;
+const WRITE_ENABLE: u8 = 0x06;
+const FAST_READ: u8 = 0x0b;
+const SECTOR_ERASE: u8 = 0x20;
+const CHIP_ERASE_ALT: u8 = 0x60;
+const RESET_ENABLE: u8 = 0x66;
+const MANUFACTURER_DEVICE_ID: u8 = 0x90;
+const RESET: u8 = 0x99;
+const READ_ID: u8 = 0x9f;
+const RELEASE_POWER_DOWN: u8 = 0xab;
+const POWER_DOWN: u8 = 0xb9;
+const CHIP_ERASE: u8 = 0xc7;
+const BLOCK_ERASE: u8 = 0xd8;
+
+// Status register bits.
+const STATUS_WEL: u8 = 1 << 1;
+
+/// The phase of the command on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Waiting for the command byte.
+    Command,
+    /// Collecting the address bytes of the command.
+    Address { command: u8, addr: u32, left: u8 },
+    /// Transferring data, the address advancing with every byte.
+    Data { command: u8, addr: u32, dummy: u8 },
+    /// Shifting out a fixed response, repeated for as long as the chip is selected.
+    Response { command: u8, index: usize },
+    /// Ignoring the rest of the command.
+    Done,
+}
+
+/// A JEDEC SPI NOR flash.
+pub struct SpiFlash {
+    data: Vec<u8>,
+    file: Option<File>,
+    phase: Phase,
+    status: u8,
+    powered_down: bool,
+    reset_enabled: bool,
+    /// The range of `data` changed by the current command, written to the file on deselect.
+    dirty: Option<(usize, usize)>,
+    /// The erase executed on deselect: the start and length.
+    erase: Option<(usize, usize)>,
+}
+
+impl SpiFlash {
+    /// Create an erased flash of `size` bytes that is not backed by a file.
+    pub fn new(size: usize) -> Result<Self> {
+        Self::check(size)?;
+        Ok(Self::with_data(vec![ERASED; size], None))
+    }
+
+    /// Open a flash of `size` bytes backed by the file at `path`, which is created if it does not
+    /// exist. A shorter file is padded with erased bytes, a longer file is an error.
+    pub fn open(path: &Path, size: usize) -> Result<Self> {
+        Self::check(size)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if data.len() > size {
+            bail!(
+                "{} is {} bytes, more than the {size} bytes of the flash",
+                path.display(),
+                data.len()
+            );
+        }
+        if data.len() < size {
+            let len = data.len();
+            data.resize(size, ERASED);
+            file.write_all(&data[len..])
+                .with_context(|| format!("failed to extend {}", path.display()))?;
+        }
+        info!("Opened SPI flash image {}", path.display());
+        Ok(Self::with_data(data, Some(file)))
+    }
+
+    fn check(size: usize) -> Result<()> {
+        if !size.is_power_of_two() || !(BLOCK_SIZE..=16 << 20).contains(&size) {
+            bail!("SPI flash size {size:#x} must be a power of two from 64 KiB to 16 MiB");
+        }
+        Ok(())
+    }
+
+    fn with_data(data: Vec<u8>, file: Option<File>) -> Self {
+        Self {
+            data,
+            file,
+            phase: Phase::Command,
+            status: 0,
+            powered_down: false,
+            reset_enabled: false,
+            dirty: None,
+            erase: None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The JEDEC ID: manufacturer, memory type and log2 of the capacity.
+    pub fn jedec_id(&self) -> [u8; 3] {
+        [
+            MANUFACTURER,
+            MEMORY_TYPE,
+            self.data.len().trailing_zeros() as u8,
+        ]
+    }
+
+    /// The legacy device ID, which is one less than log2 of the capacity on Winbond parts.
+    fn device_id(&self) -> u8 {
+        self.jedec_id()[2] - 1
+    }
+
+    fn wrap(&self, addr: u32) -> usize {
+        addr as usize & (self.data.len() - 1)
+    }
+
+    fn begin(&mut self, command: u8) -> Phase {
+        if self.powered_down && command != RELEASE_POWER_DOWN {
+            return Phase::Done;
+        }
+        let reset_enabled = std::mem::take(&mut self.reset_enabled);
+        let address = |command| Phase::Address {
+            command,
+            addr: 0,
+            left: 3,
+        };
+        match command {
+            READ | FAST_READ | MANUFACTURER_DEVICE_ID => address(command),
+            PAGE_PROGRAM | SECTOR_ERASE | BLOCK_ERASE if self.status & STATUS_WEL != 0 => {
+                address(command)
+            }
+            READ_ID | READ_STATUS | RELEASE_POWER_DOWN => Phase::Response { command, index: 0 },
+            WRITE_ENABLE => {
+                self.status |= STATUS_WEL;
+                Phase::Done
+            }
+            WRITE_DISABLE => {
+                self.status &= !STATUS_WEL;
+                Phase::Done
+            }
+            CHIP_ERASE | CHIP_ERASE_ALT => {
+                if self.status & STATUS_WEL != 0 {
+                    self.erase = Some((0, self.data.len()));
+                }
+                Phase::Done
+            }
+            POWER_DOWN => {
+                self.powered_down = true;
+                Phase::Done
+            }
+            RESET_ENABLE => {
+                self.reset_enabled = true;
+                Phase::Done
+            }
+            RESET => {
+                if reset_enabled {
+                    self.status = 0;
+                }
+                Phase::Done
+            }
+            // the status register has no protection bits to write
+            WRITE_STATUS => Phase::Done,
+            PAGE_PROGRAM | SECTOR_ERASE | BLOCK_ERASE => Phase::Done,
+            _ => {
+                warn!("Unknown SPI flash command {command:#04x}");
+                Phase::Done
+            }
+        }
+    }
+
+    fn response(&self, command: u8, index: usize) -> u8 {
+        match command {
+            READ_ID => self.jedec_id().get(index).copied().unwrap_or(0),
+            READ_STATUS => self.status,
+            RELEASE_POWER_DOWN => self.device_id(),
+            MANUFACTURER_DEVICE_ID => [MANUFACTURER, self.device_id()][index % 2],
+            _ => ERASED,
+        }
+    }
+
+    /// Program a byte, which can only clear bits.
+    fn program(&mut self, addr: u32, value: u8) {
+        let index = self.wrap(addr);
+        self.data[index] &= value;
+        self.dirty = Some((index & !(PAGE_SIZE - 1), PAGE_SIZE));
+    }
+
+    /// Write `len` bytes of the data from `start` on to the backing file.
+    fn persist(&mut self, start: usize, len: usize) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let result = file
+            .seek(SeekFrom::Start(start as u64))
+            .and_then(|_| file.write_all(&self.data[start..start + len]));
+        if let Err(e) = result {
+            error!("Failed to write the SPI flash image: {e}");
+        }
+    }
+}
+
+impl SpiSlave for SpiFlash {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn dts_node(&self, cs: u32) -> Option<String> {
+        Some(format!(
+            r#"
+	flash@{cs:x} {{
+		compatible = "jedec,spi-nor";
+		reg = <{cs:#x}>;
+		spi-max-frequency = <0x2faf080>;
+	}};
+"#
+        ))
+    }
+
+    fn select(&mut self) {
+        self.phase = Phase::Command;
+    }
+
+    fn deselect(&mut self) {
+        if let Some((start, len)) = self.erase.take() {
+            self.data[start..start + len].fill(ERASED);
+            self.dirty = Some((start, len));
+        }
+        if let Some((start, len)) = self.dirty.take() {
+            self.persist(start, len);
+            self.status &= !STATUS_WEL;
+        }
+        if matches!(
+            self.phase,
+            Phase::Response {
+                command: RELEASE_POWER_DOWN,
+                ..
+            }
+        ) {
+            self.powered_down = false;
+        }
+        self.phase = Phase::Command;
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        let (phase, miso) = match self.phase {
+            Phase::Command => (self.begin(mosi), ERASED),
+            Phase::Address {
+                command,
+                addr,
+                left,
+            } => {
+                let addr = addr << 8 | mosi as u32;
+                let phase = match (left, command) {
+                    (1, SECTOR_ERASE | BLOCK_ERASE) => {
+                        let len = if command == SECTOR_ERASE {
+                            SECTOR_SIZE
+                        } else {
+                            BLOCK_SIZE
+                        };
+                        self.erase = Some((self.wrap(addr) & !(len - 1), len));
+                        Phase::Done
+                    }
+                    (1, MANUFACTURER_DEVICE_ID) => Phase::Response {
+                        command,
+                        index: addr as usize & 1,
+                    },
+                    (1, FAST_READ) => Phase::Data {
+                        command,
+                        addr,
+                        dummy: 1,
+                    },
+                    (1, _) => Phase::Data {
+                        command,
+                        addr,
+                        dummy: 0,
+                    },
+                    _ => Phase::Address {
+                        command,
+                        addr,
+                        left: left - 1,
+                    },
+                };
+                (phase, ERASED)
+            }
+            Phase::Data {
+                command,
+                addr,
+                dummy: 0,
+            } => {
+                let (next, miso) = if command == PAGE_PROGRAM {
+                    self.program(addr, mosi);
+                    // programming wraps within the page
+                    let page = addr & !(PAGE_SIZE as u32 - 1);
+                    (page | (addr + 1) & (PAGE_SIZE as u32 - 1), ERASED)
+                } else {
+                    (addr.wrapping_add(1), self.data[self.wrap(addr)])
+                };
+                let phase = Phase::Data {
+                    command,
+                    addr: next,
+                    dummy: 0,
+                };
+                (phase, miso)
+            }
+            Phase::Data {
+                command,
+                addr,
+                dummy,
+            } => (
+                Phase::Data {
+                    command,
+                    addr,
+                    dummy: dummy - 1,
+                },
+                ERASED,
+            ),
+            Phase::Response { command, index } => (
+                Phase::Response {
+                    command,
+                    index: index + 1,
+                },
+                self.response(command, index),
+            ),
+            Phase::Done => (Phase::Done, ERASED),
+        };
+        self.phase = phase;
+        miso
+    }
+}
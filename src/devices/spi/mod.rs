@@ -0,0 +1,344 @@
+//! The SiFive SPI controller (`sifive,spi0`) and the devices that can be attached to its chip
+//! selects. Frames are exchanged as soon as they are written, so the transmit FIFO never fills.
+pub mod flash;
+pub mod sd;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::bus::{Device, IrqLine, IrqTarget, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+pub use flash::SpiFlash;
+pub use sd::SdCard;
+
+/// The address of the SPI controller the SD card slot of the FU540 is wired to.
+pub const SPI_BASE: u64 = 0x1005_0000;
+/// The size of the SPI registers.
+pub const SPI_SIZE: u64 = 0x1000;
+/// The PLIC source of the controller, like QSPI2 on the FU540.
+pub const SPI_IRQ: u32 = 41;
+
+// Register offsets.
+const SCKDIV: u64 = 0x00;
+const SCKMODE: u64 = 0x04;
+const CSID: u64 = 0x10;
+const CSDEF: u64 = 0x14;
+const CSMODE: u64 = 0x18;
+const DELAY0: u64 = 0x28;
+const DELAY1: u64 = 0x2c;
+const FMT: u64 = 0x40;
+const TXDATA: u64 = 0x48;
+const RXDATA: u64 = 0x4c;
+const TXMARK: u64 = 0x50;
+const RXMARK: u64 = 0x54;
+const FCTRL: u64 = 0x60;
+const FFMT: u64 = 0x64;
+const IE: u64 = 0x70;
+const IP: u64 = 0x74;
+
+// Chip select modes.
+const CSMODE_AUTO: u32 = 0;
+const CSMODE_HOLD: u32 = 2;
+const CSMODE_OFF: u32 = 3;
+
+// Frame format fields.
+const FMT_ENDIAN_LSB: u32 = 1 << 2;
+const FMT_DIR_TX: u32 = 1 << 3;
+const FMT_LEN_SHIFT: u32 = 16;
+
+// Interrupt bits.
+const IP_TXWM: u32 = 1 << 0;
+const IP_RXWM: u32 = 1 << 1;
+
+/// The depth of the FIFOs.
+const FIFO_DEPTH: usize = 8;
+/// Set in txdata when the transmit FIFO is full and in rxdata when the receive FIFO is empty.
+const FIFO_FLAG: u32 = 1 << 31;
+
+/// A device on the SPI bus.
+pub trait SpiSlave {
+    fn as_any(&self) -> &dyn std::any::Any;
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
+
+    /// The chip select of the device was asserted.
+    fn select(&mut self) {}
+    /// The chip select of the device was released, which ends the current command.
+    fn deselect(&mut self) {}
+    /// Exchange a byte: the device receives `mosi` and returns the byte it shifts out.
+    fn transfer(&mut self, mosi: u8) -> u8;
+
+    /// The device tree node describing the device at chip select `cs`, a child of the
+    /// controller.
+    fn dts_node(&self, _cs: u32) -> Option<String> {
+        None
+    }
+}
+
+/// The SiFive SPI controller.
+pub struct SifiveSpi {
+    /// The device on every chip select, if any.
+    slaves: Vec<Option<Box<dyn SpiSlave>>>,
+    /// The chip select currently asserted.
+    selected: Option<usize>,
+
+    sckdiv: u32,
+    sckmode: u32,
+    csid: u32,
+    csdef: u32,
+    csmode: u32,
+    delay0: u32,
+    delay1: u32,
+    fmt: u32,
+    txmark: u32,
+    rxmark: u32,
+    fctrl: u32,
+    ffmt: u32,
+    ie: u32,
+    /// Reads of rxdata pop the FIFO, hence the cell.
+    rx: RefCell<VecDeque<u8>>,
+    irq: IrqLine,
+}
+
+impl SifiveSpi {
+    /// Create a controller with the given devices on chip selects 0, 1, ...
+    pub fn new(slaves: Vec<Option<Box<dyn SpiSlave>>>) -> Self {
+        Self {
+            slaves,
+            selected: None,
+            sckdiv: 3,
+            sckmode: 0,
+            csid: 0,
+            csdef: u32::MAX,
+            csmode: CSMODE_AUTO,
+            delay0: 0x0001_0001,
+            delay1: 0x0000_0001,
+            fmt: 8 << FMT_LEN_SHIFT,
+            txmark: 0,
+            rxmark: 0,
+            fctrl: 0,
+            ffmt: 0,
+            ie: 0,
+            rx: RefCell::new(VecDeque::new()),
+            irq: IrqLine::new(),
+        }
+    }
+
+    pub fn new_device(slaves: Vec<Option<Box<dyn SpiSlave>>>, base: u64) -> VirtualDevice {
+        VirtualDevice::new(Box::new(Self::new(slaves)), base, SPI_SIZE)
+            .with_irqs([IrqTarget::Plic(SPI_IRQ)])
+    }
+
+    /// The device on chip select `cs`.
+    pub fn slave<T: SpiSlave + 'static>(&self, cs: usize) -> Option<&T> {
+        self.slaves.get(cs)?.as_ref()?.as_any().downcast_ref::<T>()
+    }
+
+    /// The device on chip select `cs`.
+    pub fn slave_mut<T: SpiSlave + 'static>(&mut self, cs: usize) -> Option<&mut T> {
+        self.slaves
+            .get_mut(cs)?
+            .as_mut()?
+            .as_any_mut()
+            .downcast_mut::<T>()
+    }
+
+    /// Whether the controller is asserting its interrupt.
+    pub fn interrupt_pending(&self) -> bool {
+        self.ip() & self.ie != 0
+    }
+
+    fn update_irq(&self) {
+        self.irq.set(self.interrupt_pending());
+    }
+
+    fn ip(&self) -> u32 {
+        // frames leave right away, so the transmit FIFO is always empty
+        let mut ip = 0;
+        if self.txmark > 0 {
+            ip |= IP_TXWM;
+        }
+        if self.rx.borrow().len() > self.rxmark as usize {
+            ip |= IP_RXWM;
+        }
+        ip
+    }
+
+    fn select(&mut self) {
+        let cs = self.csid as usize;
+        if self.selected == Some(cs) {
+            return;
+        }
+        self.deselect();
+        if let Some(Some(slave)) = self.slaves.get_mut(cs) {
+            slave.select();
+        }
+        self.selected = Some(cs);
+    }
+
+    fn deselect(&mut self) {
+        if let Some(cs) = self.selected.take() {
+            if let Some(Some(slave)) = self.slaves.get_mut(cs) {
+                slave.deselect();
+            }
+        }
+    }
+
+    fn transmit(&mut self, value: u8) {
+        let len = self.fmt >> FMT_LEN_SHIFT & 0xf;
+        if len != 8 {
+            warn!("SPI frames of {len} bits are not supported");
+        }
+        let lsb_first = self.fmt & FMT_ENDIAN_LSB != 0;
+        let mosi = if lsb_first {
+            value.reverse_bits()
+        } else {
+            value
+        };
+
+        let miso = match self.csmode {
+            CSMODE_OFF => 0xff,
+            mode => {
+                self.select();
+                let cs = self.csid as usize;
+                let miso = match self.slaves.get_mut(cs) {
+                    Some(Some(slave)) => slave.transfer(mosi),
+                    // nothing drives MISO, it floats high
+                    _ => 0xff,
+                };
+                if mode != CSMODE_HOLD {
+                    self.deselect();
+                }
+                miso
+            }
+        };
+
+        if self.fmt & FMT_DIR_TX == 0 {
+            let mut rx = self.rx.borrow_mut();
+            if rx.len() < FIFO_DEPTH {
+                rx.push_back(if lsb_first { miso.reverse_bits() } else { miso });
+            } else {
+                warn!("SPI receive FIFO overflow");
+            }
+        }
+    }
+}
+
+impl Device for SifiveSpi {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, _size: Sizes) -> Result<MemorySize> {
+        let value = match addr {
+            SCKDIV => self.sckdiv,
+            SCKMODE => self.sckmode,
+            CSID => self.csid,
+            CSDEF => self.csdef,
+            CSMODE => self.csmode,
+            DELAY0 => self.delay0,
+            DELAY1 => self.delay1,
+            FMT => self.fmt,
+            TXDATA => 0,
+            RXDATA => match self.rx.borrow_mut().pop_front() {
+                Some(byte) => byte as u32,
+                None => FIFO_FLAG,
+            },
+            TXMARK => self.txmark,
+            RXMARK => self.rxmark,
+            FCTRL => self.fctrl,
+            FFMT => self.ffmt,
+            IE => self.ie,
+            IP => self.ip(),
+            _ => {
+                warn!("Read from unknown SPI register {addr:#x}");
+                0
+            }
+        };
+        self.update_irq();
+        Ok(value)
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        match addr {
+            SCKDIV => self.sckdiv = value & 0xfff,
+            SCKMODE => self.sckmode = value & 0x3,
+            CSID => {
+                if value != self.csid {
+                    self.deselect();
+                }
+                self.csid = value;
+            }
+            CSDEF => self.csdef = value,
+            CSMODE => {
+                self.csmode = value & 0x3;
+                if self.csmode != CSMODE_HOLD {
+                    self.deselect();
+                }
+            }
+            DELAY0 => self.delay0 = value,
+            DELAY1 => self.delay1 = value,
+            FMT => self.fmt = value,
+            TXDATA => self.transmit(value as u8),
+            RXDATA | IP => {}
+            TXMARK => self.txmark = value & 0x7,
+            RXMARK => self.rxmark = value & 0x7,
+            FCTRL => {
+                if value & 1 != 0 {
+                    warn!("The memory-mapped SPI flash interface is not supported");
+                }
+                self.fctrl = value & 1;
+            }
+            FFMT => self.ffmt = value,
+            IE => self.ie = value & (IP_TXWM | IP_RXWM),
+            _ => warn!("Write to unknown SPI register {addr:#x}: {value:#x}"),
+        }
+        self.update_irq();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.deselect();
+        let slaves = std::mem::take(&mut self.slaves);
+        let irq = std::mem::take(&mut self.irq);
+        *self = Self::new(slaves);
+        self.irq = irq;
+        self.update_irq();
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if index == 0 {
+            self.irq = line;
+        }
+    }
+
+    /// The controller with the devices on its chip selects as children.
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        let children: String = self
+            .slaves
+            .iter()
+            .enumerate()
+            .filter_map(|(cs, slave)| slave.as_ref()?.dts_node(cs as u32))
+            .map(|child| child.replace("\n\t", "\n\t\t"))
+            .collect();
+        Some(format!(
+            r#"
+	spi@{:x} {{
+		compatible = "sifive,fu540-c000-spi", "sifive,spi0";
+{}		#address-cells = <0x1>;
+		#size-cells = <0x0>;
+{children}	}};
+"#,
+            node.base(),
+            node.properties(),
+        ))
+    }
+}
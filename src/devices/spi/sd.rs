@@ -0,0 +1,433 @@
+//! An SDHC card in SPI mode, backed by an image file or by memory. CRCs are accepted without
+//! being checked, like a card that never had `CMD59` turn checking on.
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+
+use super::SpiSlave;
+
+/// The size of a block, the only one an SDHC card supports.
+pub const BLOCK_SIZE: usize = 512;
+
+// Commands.
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SEND_CID: u8 = 10;
+const STOP_TRANSMISSION: u8 = 12;
+const SEND_STATUS: u8 = 13;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const READ_MULTIPLE_BLOCK: u8 = 18;
+const WRITE_BLOCK: u8 = 24;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const CRC_ON_OFF: u8 = 59;
+// Application commands, following APP_CMD.
+const SD_SEND_OP_COND: u8 = 41;
+
+// R1 response bits.
+const R1_IDLE: u8 = 1 << 0;
+const R1_ILLEGAL_COMMAND: u8 = 1 << 2;
+const R1_ADDRESS_ERROR: u8 = 1 << 5;
+const R1_PARAMETER_ERROR: u8 = 1 << 6;
+
+/// The token starting a data block, in both directions.
+const START_BLOCK: u8 = 0xfe;
+/// The error token sent instead of a block that is out of range.
+const ERROR_OUT_OF_RANGE: u8 = 0x08;
+/// The data response accepting a written block.
+const DATA_ACCEPTED: u8 = 0x05;
+/// The data response rejecting a written block.
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+/// The OCR: powered up, high capacity, 2.7-3.6 V.
+const OCR: u32 = 0xc0ff_8000;
+
+/// What the card does with the bytes it receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a command.
+    Idle,
+    /// Waiting for the start token of the block written at `block`.
+    WriteToken { block: u64 },
+    /// Receiving the data and the CRC of the block written at `block`.
+    WriteData { block: u64 },
+}
+
+/// Where the blocks live.
+enum Backing {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+/// An SD card in SPI mode.
+pub struct SdCard {
+    backing: Backing,
+    blocks: u64,
+    state: State,
+    /// The command being received, starting with its index.
+    command: Vec<u8>,
+    /// The bytes waiting to be shifted out.
+    output: VecDeque<u8>,
+    /// The block data being written.
+    buffer: Vec<u8>,
+    /// The next block of a multiple block read, if one is in progress.
+    next_read: Option<u64>,
+    /// Whether the card still has to be initialized with `ACMD41`.
+    idle: bool,
+    /// Whether the previous command was `APP_CMD`.
+    app_command: bool,
+}
+
+impl SdCard {
+    /// Create a card holding `data`, which is padded to whole blocks.
+    pub fn new(mut data: Vec<u8>) -> Self {
+        data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+        let blocks = (data.len() / BLOCK_SIZE) as u64;
+        Self::with_backing(Backing::Memory(data), blocks)
+    }
+
+    /// Open a card backed by the image at `path`. Written blocks go straight to the file.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let len = file
+            .metadata()
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .len();
+        if len == 0 || len % BLOCK_SIZE as u64 != 0 {
+            bail!(
+                "{} is {len} bytes, not a whole number of {BLOCK_SIZE} byte blocks",
+                path.display()
+            );
+        }
+        info!("Opened SD card image {}", path.display());
+        Ok(Self::with_backing(
+            Backing::File(file),
+            len / BLOCK_SIZE as u64,
+        ))
+    }
+
+    fn with_backing(backing: Backing, blocks: u64) -> Self {
+        Self {
+            backing,
+            blocks,
+            state: State::Idle,
+            command: Vec::new(),
+            output: VecDeque::new(),
+            buffer: Vec::new(),
+            next_read: None,
+            idle: true,
+            app_command: false,
+        }
+    }
+
+    /// The number of blocks of the card.
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Read block `block` of the card.
+    pub fn read_block(&mut self, block: u64) -> Result<[u8; BLOCK_SIZE]> {
+        if block >= self.blocks {
+            bail!(
+                "block {block} is beyond the {} blocks of the card",
+                self.blocks
+            );
+        }
+        let mut data = [0; BLOCK_SIZE];
+        let start = block * BLOCK_SIZE as u64;
+        match &mut self.backing {
+            Backing::Memory(memory) => {
+                let start = start as usize;
+                data.copy_from_slice(&memory[start..start + BLOCK_SIZE]);
+            }
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(start))?;
+                file.read_exact(&mut data)?;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Write block `block` of the card.
+    pub fn write_block(&mut self, block: u64, data: &[u8; BLOCK_SIZE]) -> Result<()> {
+        if block >= self.blocks {
+            bail!(
+                "block {block} is beyond the {} blocks of the card",
+                self.blocks
+            );
+        }
+        let start = block * BLOCK_SIZE as u64;
+        match &mut self.backing {
+            Backing::Memory(memory) => {
+                let start = start as usize;
+                memory[start..start + BLOCK_SIZE].copy_from_slice(data);
+            }
+            Backing::File(file) => {
+                file.seek(SeekFrom::Start(start))?;
+                file.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The R1 response with the error bits in `errors`.
+    fn r1(&self, errors: u8) -> u8 {
+        errors | if self.idle { R1_IDLE } else { 0 }
+    }
+
+    /// Queue a response after the single byte of delay every command gets.
+    fn respond(&mut self, bytes: &[u8]) {
+        self.output.push_back(0xff);
+        self.output.extend(bytes);
+    }
+
+    /// Queue a data block: the start token, the data and its CRC.
+    fn send_block(&mut self, data: &[u8]) {
+        self.output.extend([0xff, START_BLOCK]);
+        self.output.extend(data);
+        self.output.extend(crc16(data).to_be_bytes());
+    }
+
+    /// Queue block `block` of a read, or the error token if it does not exist.
+    fn send_read_block(&mut self, block: u64) -> bool {
+        match self.read_block(block) {
+            Ok(data) => {
+                self.send_block(&data);
+                true
+            }
+            Err(e) => {
+                warn!("SD card read failed: {e}");
+                self.output.extend([0xff, ERROR_OUT_OF_RANGE]);
+                false
+            }
+        }
+    }
+
+    fn execute(&mut self, index: u8, arg: u32) {
+        let app_command = std::mem::take(&mut self.app_command);
+        // an SDHC card addresses blocks, not bytes
+        let block = arg as u64;
+        match (index, app_command) {
+            (GO_IDLE_STATE, _) => {
+                self.idle = true;
+                self.next_read = None;
+                self.respond(&[R1_IDLE]);
+            }
+            (SEND_IF_COND, _) => {
+                // voltage accepted, check pattern echoed
+                let r1 = self.r1(0);
+                self.respond(&[r1, 0, 0, (arg >> 8) as u8 & 0xf, arg as u8]);
+            }
+            (APP_CMD, _) => {
+                self.app_command = true;
+                let r1 = self.r1(0);
+                self.respond(&[r1]);
+            }
+            (SD_SEND_OP_COND, true) => {
+                // initialization finishes right away
+                self.idle = false;
+                self.respond(&[0]);
+            }
+            (READ_OCR, _) => {
+                let r1 = self.r1(0);
+                self.respond(&[r1]);
+                self.output.extend(OCR.to_be_bytes());
+            }
+            (CRC_ON_OFF, _) => {
+                let r1 = self.r1(0);
+                self.respond(&[r1]);
+            }
+            _ if self.idle => {
+                let r1 = self.r1(R1_ILLEGAL_COMMAND);
+                self.respond(&[r1]);
+            }
+            (SEND_CSD, _) => {
+                self.respond(&[0]);
+                let csd = self.csd();
+                self.send_block(&csd);
+            }
+            (SEND_CID, _) => {
+                self.respond(&[0]);
+                self.send_block(&cid());
+            }
+            (STOP_TRANSMISSION, _) => {
+                self.next_read = None;
+                self.output.clear();
+                // a stuff byte, the response and a byte of busy
+                self.output.extend([0xff, 0xff, 0, 0]);
+            }
+            (SEND_STATUS, _) => self.respond(&[0, 0]),
+            (SET_BLOCKLEN, _) => {
+                let errors = if arg as usize == BLOCK_SIZE {
+                    0
+                } else {
+                    R1_PARAMETER_ERROR
+                };
+                self.respond(&[errors]);
+            }
+            (READ_SINGLE_BLOCK | READ_MULTIPLE_BLOCK | WRITE_BLOCK, _) if block >= self.blocks => {
+                self.respond(&[R1_ADDRESS_ERROR]);
+            }
+            (READ_SINGLE_BLOCK, _) => {
+                self.respond(&[0]);
+                self.send_read_block(block);
+            }
+            (READ_MULTIPLE_BLOCK, _) => {
+                self.respond(&[0]);
+                if self.send_read_block(block) {
+                    self.next_read = Some(block + 1);
+                }
+            }
+            (WRITE_BLOCK, _) => {
+                self.respond(&[0]);
+                self.state = State::WriteToken { block };
+            }
+            _ => {
+                warn!("Unsupported SD card command {index}, arg {arg:#x}");
+                self.respond(&[R1_ILLEGAL_COMMAND]);
+            }
+        }
+    }
+
+    /// The CSD, version 2.0.
+    fn csd(&self) -> [u8; 16] {
+        let c_size = (self.blocks / 1024).max(1) - 1;
+        let mut csd = [
+            0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00, 0x00, 0x00, 0x00, 0x7f, 0x80, 0x0a, 0x40,
+            0x00, 0x00,
+        ];
+        csd[7] = (c_size >> 16) as u8 & 0x3f;
+        csd[8] = (c_size >> 8) as u8;
+        csd[9] = c_size as u8;
+        csd[15] = crc7(&csd[..15]) << 1 | 1;
+        csd
+    }
+
+    fn receive_command(&mut self, mosi: u8) {
+        if self.command.is_empty() && mosi & 0xc0 != 0x40 {
+            return;
+        }
+        self.command.push(mosi);
+        if self.command.len() == 6 {
+            let command = std::mem::take(&mut self.command);
+            let index = command[0] & 0x3f;
+            let arg = u32::from_be_bytes([command[1], command[2], command[3], command[4]]);
+            self.execute(index, arg);
+        }
+    }
+}
+
+impl SpiSlave for SdCard {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn dts_node(&self, cs: u32) -> Option<String> {
+        Some(format!(
+            r#"
+	mmc@{cs:x} {{
+		compatible = "mmc-spi-slot";
+		reg = <{cs:#x}>;
+		spi-max-frequency = <0x1312d00>;
+		voltage-ranges = <0xce4 0xce4>;
+		disable-wp;
+	}};
+"#
+        ))
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        let miso = match self.output.pop_front() {
+            Some(byte) => byte,
+            None => {
+                if let Some(block) = self.next_read {
+                    self.next_read = self.send_read_block(block).then_some(block + 1);
+                }
+                0xff
+            }
+        };
+
+        match self.state {
+            State::Idle => self.receive_command(mosi),
+            State::WriteToken { block } => {
+                if mosi == START_BLOCK {
+                    self.buffer.clear();
+                    self.state = State::WriteData { block };
+                }
+            }
+            State::WriteData { block } => {
+                self.buffer.push(mosi);
+                // the data is followed by two bytes of CRC
+                if self.buffer.len() == BLOCK_SIZE + 2 {
+                    let mut data = [0; BLOCK_SIZE];
+                    data.copy_from_slice(&self.buffer[..BLOCK_SIZE]);
+                    let response = match self.write_block(block, &data) {
+                        Ok(()) => DATA_ACCEPTED,
+                        Err(e) => {
+                            error!("SD card write failed: {e}");
+                            DATA_WRITE_ERROR
+                        }
+                    };
+                    // the response and a byte of busy
+                    self.output.extend([response, 0]);
+                    self.state = State::Idle;
+                }
+            }
+        }
+        miso
+    }
+}
+
+/// The CID: manufacturer, application, product name and revision, serial number and date.
+fn cid() -> [u8; 16] {
+    let mut cid = [
+        0x00, b'R', b'V', b'R', b'V', b'S', b'D', b'C', 0x10, 0x00, 0x00, 0x00, 0x01, 0x01, 0x8a,
+        0x00,
+    ];
+    cid[15] = crc7(&cid[..15]) << 1 | 1;
+    cid
+}
+
+/// The CRC7 of the command and register formats.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let feedback = (crc >> 6 ^ byte >> bit) & 1;
+            crc = crc << 1 & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// The CRC16-CCITT of data blocks.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
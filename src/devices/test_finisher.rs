@@ -0,0 +1,119 @@
+//! The SiFive test finisher (`sifive,test0`). Guests power off or reboot the machine by writing a
+//! magic value to it, either directly or through the `syscon-poweroff` and `syscon-reboot` nodes
+//! of the device tree.
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::bus::{Device, DeviceRequest, VirtualDevice};
+use crate::cpu::ExitStatus;
+use crate::device_tree::NodeContext;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+/// The address of the test finisher on the virt board.
+pub const TEST_FINISHER_BASE: u64 = 0x10_0000;
+/// The size of the test finisher registers.
+pub const TEST_FINISHER_SIZE: u64 = 0x1000;
+
+/// Stop with the exit code in the upper 16 bits.
+const FINISHER_FAIL: u32 = 0x3333;
+/// Stop successfully.
+const FINISHER_PASS: u32 = 0x5555;
+/// Reset the machine.
+const FINISHER_RESET: u32 = 0x7777;
+
+/// The SiFive test finisher.
+#[derive(Default)]
+pub struct TestFinisher {
+    request: Option<DeviceRequest>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_device() -> VirtualDevice {
+        VirtualDevice::new(
+            Box::new(Self::new()),
+            TEST_FINISHER_BASE,
+            TEST_FINISHER_SIZE,
+        )
+    }
+}
+
+impl Device for TestFinisher {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, _addr: u64, _size: Sizes) -> Result<MemorySize> {
+        Ok(0)
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        if addr != 0 {
+            return Ok(());
+        }
+        self.request = match value & 0xffff {
+            FINISHER_PASS => {
+                info!("Guest finished successfully");
+                Some(DeviceRequest::Exit(ExitStatus::Exit { code: 0 }))
+            }
+            FINISHER_FAIL => {
+                let code = value >> 16;
+                info!("Guest finished with exit code {code}");
+                Some(DeviceRequest::Exit(ExitStatus::Exit { code }))
+            }
+            FINISHER_RESET => {
+                info!("Guest requested a reset");
+                Some(DeviceRequest::Reset)
+            }
+            _ => {
+                warn!("Unknown test finisher command {value:#x}");
+                None
+            }
+        };
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.request = None;
+    }
+
+    fn take_request(&mut self) -> Option<DeviceRequest> {
+        self.request.take()
+    }
+
+    /// The test finisher as a syscon, with the nodes that power off and reboot through it.
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        let phandle = node.phandle();
+        Some(format!(
+            r#"
+	test@{:x} {{
+		phandle = <{phandle:#x}>;
+{}		compatible = "sifive,test1", "sifive,test0", "syscon";
+	}};
+
+	poweroff {{
+		value = <{FINISHER_PASS:#x}>;
+		offset = <0x0>;
+		regmap = <{phandle:#x}>;
+		compatible = "syscon-poweroff";
+	}};
+
+	reboot {{
+		value = <{FINISHER_RESET:#x}>;
+		offset = <0x0>;
+		regmap = <{phandle:#x}>;
+		compatible = "syscon-reboot";
+	}};
+"#,
+            node.base(),
+            node.properties(),
+        ))
+    }
+}
@@ -0,0 +1,295 @@
+//! The ARM SP805 watchdog (`arm,sp805`). When its counter runs out the first time it raises an
+//! interrupt and starts over; if the interrupt is still pending when the counter runs out again,
+//! it resets the machine and [`crate::cpu::Riscv32Cpu::run`] returns
+//! [`ExitStatus::WatchdogReset`].
+use anyhow::Result;
+use log::{info, warn};
+
+use super::NS_PER_STEP;
+use crate::bus::{BusView, Device, DeviceRequest, IrqLine, IrqTarget, VirtualDevice};
+use crate::cpu::ExitStatus;
+use crate::device_tree::NodeContext;
+use crate::events::Timer;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+
+/// The address of the watchdog, next to the RTC.
+pub const WATCHDOG_BASE: u64 = 0x10_2000;
+/// The size of the watchdog registers.
+pub const WATCHDOG_SIZE: u64 = 0x1000;
+/// The PLIC source of the watchdog interrupt.
+pub const WATCHDOG_IRQ: u32 = 12;
+/// The counter counts down once per step, at the timebase frequency.
+pub const WATCHDOG_CLOCK_HZ: u64 = 1_000_000_000 / NS_PER_STEP;
+
+// Register offsets.
+const LOAD: u64 = 0x000;
+const VALUE: u64 = 0x004;
+const CONTROL: u64 = 0x008;
+const INT_CLR: u64 = 0x00c;
+const RIS: u64 = 0x010;
+const MIS: u64 = 0x014;
+const LOCK: u64 = 0xc00;
+const PERIPH_ID: u64 = 0xfe0;
+
+// Control bits.
+const CONTROL_INTEN: u32 = 1 << 0;
+const CONTROL_RESEN: u32 = 1 << 1;
+
+/// Writing this to the lock register allows writes to the other registers.
+const UNLOCK_KEY: u32 = 0x1acc_e551;
+
+/// The peripheral and PrimeCell identification registers, a byte each.
+const ID: [u32; 8] = [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// The SP805 watchdog.
+pub struct Watchdog {
+    /// The timeout the watchdog is armed with when the machine starts, in nanoseconds.
+    armed: Option<u64>,
+    load: u32,
+    /// The counter while it is stopped. While it runs, it is derived from the deadline of the
+    /// timer.
+    value: u32,
+    control: u32,
+    /// The raw interrupt status.
+    interrupt: bool,
+    locked: bool,
+    request: Option<DeviceRequest>,
+    irq: IrqLine,
+    timer: Timer,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Watchdog {
+    /// Create a watchdog the guest has to start.
+    pub fn new() -> Self {
+        Self {
+            armed: None,
+            load: u32::MAX,
+            value: u32::MAX,
+            control: 0,
+            interrupt: false,
+            locked: false,
+            request: None,
+            irq: IrqLine::new(),
+            timer: Timer::new(),
+        }
+    }
+
+    /// Create a watchdog that is already running with interrupt and reset enabled, for firmware
+    /// that is expected to kick it from the start. The interrupt comes after `timeout_ns` and the
+    /// reset after twice that.
+    pub fn armed(timeout_ns: u64) -> Self {
+        let mut watchdog = Self::new();
+        watchdog.armed = Some(timeout_ns);
+        watchdog.arm();
+        watchdog
+    }
+
+    pub fn new_device() -> VirtualDevice {
+        VirtualDevice::new(Box::new(Self::new()), WATCHDOG_BASE, WATCHDOG_SIZE)
+            .with_irqs([IrqTarget::Plic(WATCHDOG_IRQ)])
+    }
+
+    pub fn armed_device(timeout_ns: u64) -> VirtualDevice {
+        VirtualDevice::new(
+            Box::new(Self::armed(timeout_ns)),
+            WATCHDOG_BASE,
+            WATCHDOG_SIZE,
+        )
+        .with_irqs([IrqTarget::Plic(WATCHDOG_IRQ)])
+    }
+
+    fn arm(&mut self) {
+        if let Some(timeout_ns) = self.armed {
+            let ticks = timeout_ns / NS_PER_STEP;
+            self.load = ticks.clamp(1, u32::MAX as u64) as u32;
+            self.value = self.load;
+            self.control = CONTROL_INTEN | CONTROL_RESEN;
+            self.start();
+        }
+    }
+
+    /// Schedule the expiry of the counter from `value`.
+    fn start(&mut self) {
+        self.timer.schedule_in(self.value.max(1) as u64 * NS_PER_STEP);
+    }
+
+    /// The counter, counting down once per step while the watchdog runs.
+    fn value(&self) -> u32 {
+        match self.timer.deadline() {
+            Some(deadline) if self.running() => {
+                (deadline.saturating_sub(self.timer.now()) / NS_PER_STEP) as u32
+            }
+            _ => self.value,
+        }
+    }
+
+    /// Whether the counter is running.
+    pub fn running(&self) -> bool {
+        self.control & CONTROL_INTEN != 0
+    }
+
+    /// Nanoseconds until the counter runs out next.
+    pub fn remaining_ns(&self) -> u64 {
+        self.value() as u64 * NS_PER_STEP
+    }
+
+    /// Whether the watchdog is asserting its interrupt.
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt && self.running()
+    }
+
+    /// Restart the count and clear the interrupt.
+    fn kick(&mut self) {
+        self.interrupt = false;
+        self.value = self.load;
+        if self.running() {
+            self.start();
+        }
+    }
+
+    fn expire(&mut self) {
+        if !self.interrupt {
+            info!("Watchdog timed out, raising its interrupt");
+            self.interrupt = true;
+        } else if self.control & CONTROL_RESEN != 0 {
+            info!("Watchdog timed out again, resetting the machine");
+            self.request = Some(DeviceRequest::Exit(ExitStatus::WatchdogReset));
+            // stop counting until the machine is reset
+            self.control = 0;
+            self.value = 0;
+            return;
+        }
+        self.value = self.load;
+        self.start();
+    }
+}
+
+impl Device for Watchdog {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, _size: Sizes) -> Result<MemorySize> {
+        Ok(match addr {
+            LOAD => self.load,
+            VALUE => self.value(),
+            CONTROL => self.control,
+            RIS => self.interrupt as u32,
+            MIS => self.interrupt_pending() as u32,
+            LOCK => self.locked as u32,
+            PERIPH_ID..=0xffc => ID[((addr - PERIPH_ID) / 4) as usize],
+            _ => {
+                warn!("Read from unknown watchdog register {addr:#x}");
+                0
+            }
+        })
+    }
+
+    fn store(&mut self, addr: u64, _size: Sizes, value: MemorySize) -> Result<()> {
+        if addr == LOCK {
+            self.locked = value != UNLOCK_KEY;
+            return Ok(());
+        }
+        if self.locked {
+            warn!("Write to locked watchdog register {addr:#x}: {value:#x}");
+            return Ok(());
+        }
+        match addr {
+            LOAD => {
+                // a load of 0 interrupts right away
+                self.load = value;
+                self.value = value;
+                if self.running() {
+                    self.start();
+                }
+            }
+            CONTROL => {
+                let start = value & CONTROL_INTEN != 0 && !self.running();
+                if value & CONTROL_INTEN == 0 && self.running() {
+                    self.value = self.value();
+                    self.timer.cancel();
+                }
+                self.control = value & (CONTROL_INTEN | CONTROL_RESEN);
+                if start {
+                    self.value = self.load;
+                    self.start();
+                }
+            }
+            INT_CLR => self.kick(),
+            VALUE | RIS | MIS => {}
+            _ => warn!("Write to unknown watchdog register {addr:#x}: {value:#x}"),
+        }
+        self.irq.set(self.interrupt_pending());
+        Ok(())
+    }
+
+    fn event(&mut self, _memory: &mut BusView) {
+        if self.running() {
+            self.expire();
+        }
+        self.irq.set(self.interrupt_pending());
+    }
+
+    fn reset(&mut self) {
+        let armed = self.armed;
+        let irq = std::mem::take(&mut self.irq);
+        let timer = std::mem::take(&mut self.timer);
+        *self = Self::new();
+        self.armed = armed;
+        self.irq = irq;
+        self.irq.lower();
+        self.timer = timer;
+        self.timer.cancel();
+        self.arm();
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.timer = timer;
+        if self.running() {
+            self.start();
+        }
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if index == 0 {
+            self.irq = line;
+        }
+    }
+
+    fn take_request(&mut self) -> Option<DeviceRequest> {
+        self.request.take()
+    }
+
+    /// The watchdog with the fixed clock it counts, which is also its bus clock.
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        let clock = node.phandle();
+        Some(format!(
+            r#"
+	watchdog@{:x} {{
+		compatible = "arm,sp805", "arm,primecell";
+{}		clocks = <{clock:#x} {clock:#x}>;
+		clock-names = "wdog_clk", "apb_pclk";
+
+		clock {{
+			compatible = "fixed-clock";
+			#clock-cells = <0x0>;
+			clock-frequency = <{WATCHDOG_CLOCK_HZ:#x}>;
+			phandle = <{clock:#x}>;
+		}};
+	}};
+"#,
+            node.base(),
+            node.properties(),
+        ))
+    }
+}
@@ -0,0 +1,167 @@
+//! Emulated time and the events devices schedule on it. Every device on a [`crate::bus::Bus`]
+//! gets a [`Timer`] it sets to its next deadline, like a timer compare or the end of a transfer,
+//! and the CPU runs instructions uninterrupted until the earliest deadline comes up.
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+/// The state shared by an [`EventQueue`] and its timers.
+#[derive(Default)]
+struct Timeline {
+    /// Nanoseconds of emulated time since the machine was created.
+    now: Cell<u64>,
+    /// The deadline of every timer, by id.
+    deadlines: RefCell<Vec<Option<u64>>>,
+    /// Deadlines with the id of their timer, earliest first. An entry stays in here when its
+    /// timer is rescheduled or cancelled and is dropped once it comes up.
+    queue: RefCell<BinaryHeap<Reverse<(u64, usize)>>>,
+    /// Set whenever a timer is scheduled, so the CPU can cut its batch short.
+    scheduled: Rc<Cell<bool>>,
+}
+
+/// A deadline of a device in emulated time. When it comes up, the bus calls
+/// [`crate::bus::Device::event`] on the device that was handed the timer. Timers only fire once,
+/// the device schedules the next deadline from the event.
+#[derive(Clone)]
+pub struct Timer {
+    timeline: Rc<Timeline>,
+    id: usize,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Timer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Timer")
+            .field("id", &self.id)
+            .field("deadline", &self.deadline())
+            .finish()
+    }
+}
+
+impl Timer {
+    /// A timer that is not on any bus, so it never fires and its time stands still.
+    pub fn new() -> Self {
+        EventQueue::new().timer()
+    }
+
+    /// The id the queue knows the timer by.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The current emulated time in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.timeline.now.get()
+    }
+
+    /// The deadline the timer is set to, if any.
+    pub fn deadline(&self) -> Option<u64> {
+        self.timeline.deadlines.borrow()[self.id]
+    }
+
+    /// Fire at `time_ns`, replacing the previous deadline. A deadline in the past fires as soon
+    /// as the bus runs its events.
+    pub fn schedule_at(&self, time_ns: u64) {
+        let time_ns = time_ns.max(self.now());
+        self.timeline.deadlines.borrow_mut()[self.id] = Some(time_ns);
+        self.timeline
+            .queue
+            .borrow_mut()
+            .push(Reverse((time_ns, self.id)));
+        self.timeline.scheduled.set(true);
+    }
+
+    /// Fire at `time_ns` unless the timer is set to fire earlier already. Parts of a device that
+    /// share a timer use this so they do not push back each other's deadlines.
+    pub fn wake_at(&self, time_ns: u64) {
+        if self.deadline().is_none_or(|deadline| deadline > time_ns) {
+            self.schedule_at(time_ns);
+        }
+    }
+
+    /// Fire `delay_ns` from now, replacing the previous deadline.
+    pub fn schedule_in(&self, delay_ns: u64) {
+        self.schedule_at(self.now().saturating_add(delay_ns));
+    }
+
+    /// Forget the deadline.
+    pub fn cancel(&self) {
+        self.timeline.deadlines.borrow_mut()[self.id] = None;
+    }
+}
+
+/// The timers of a bus, ordered by their deadlines.
+pub struct EventQueue {
+    timeline: Rc<Timeline>,
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self {
+            timeline: Rc::default(),
+        }
+    }
+
+    /// A queue that sets `scheduled` whenever one of its timers is scheduled.
+    pub(crate) fn with_flag(scheduled: Rc<Cell<bool>>) -> Self {
+        Self {
+            timeline: Rc::new(Timeline {
+                scheduled,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Create a timer on the queue, identified by [`Timer::id`].
+    pub fn timer(&mut self) -> Timer {
+        let mut deadlines = self.timeline.deadlines.borrow_mut();
+        deadlines.push(None);
+        Timer {
+            timeline: self.timeline.clone(),
+            id: deadlines.len() - 1,
+        }
+    }
+
+    /// The current emulated time in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.timeline.now.get()
+    }
+
+    /// Move emulated time forward to `time_ns`. Time never goes backwards.
+    pub fn advance_to(&mut self, time_ns: u64) {
+        self.timeline.now.set(time_ns.max(self.now()));
+    }
+
+    /// The earliest deadline of the timers.
+    pub fn next_event(&self) -> Option<u64> {
+        let deadlines = self.timeline.deadlines.borrow();
+        let mut queue = self.timeline.queue.borrow_mut();
+        while let Some(&Reverse((time, id))) = queue.peek() {
+            if deadlines[id] == Some(time) {
+                return Some(time);
+            }
+            queue.pop();
+        }
+        None
+    }
+
+    /// Take a timer whose deadline has come up, clearing its deadline. Returns its id.
+    pub fn pop_due(&mut self) -> Option<usize> {
+        self.next_event().filter(|&time| time <= self.now())?;
+        let Reverse((_, id)) = self.timeline.queue.borrow_mut().pop()?;
+        self.timeline.deadlines.borrow_mut()[id] = None;
+        Some(id)
+    }
+}
@@ -0,0 +1,384 @@
+//! The Berkeley host-target interface (HTIF) used by riscv-tests, Spike and the proxy kernel.
+//! The guest writes a command to the `tohost` doubleword in memory and the host answers through
+//! `fromhost`. A command holds the device in bits 63..56, the command in bits 55..48 and a
+//! payload in the remaining bits.
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::bus::{Bus, DeviceRequest};
+use crate::cpu::ExitStatus;
+use crate::loader::elf::Elf;
+use crate::memory::dram::Sizes;
+
+/// The syscall proxy, which also carries the exit command.
+const DEVICE_SYSCALL: u8 = 0;
+/// The console ("bcd") device.
+const DEVICE_CONSOLE: u8 = 1;
+
+const CONSOLE_GETCHAR: u8 = 0;
+const CONSOLE_PUTCHAR: u8 = 1;
+
+// The syscalls forwarded by the proxy kernel, with the numbers of the RISC-V Linux ABI.
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_PREAD: u64 = 67;
+const SYS_PWRITE: u64 = 68;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+/// Copies the program arguments to the guest.
+const SYS_GETMAINVARS: u64 = 2011;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+const ENOMEM: i64 = 12;
+
+// Linux open flags of openat.
+const O_ACCMODE: u64 = 3;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+
+/// The host side of the HTIF.
+pub struct Htif {
+    tohost: u64,
+    fromhost: u64,
+    /// Arguments handed to the guest by `getmainvars`.
+    args: Vec<String>,
+    /// Write console output to the host standard output as well.
+    echo: bool,
+    output: Vec<u8>,
+    input: VecDeque<u8>,
+    /// Console reads waiting for input.
+    pending_reads: usize,
+    /// Answers waiting for the guest to clear `fromhost`.
+    responses: VecDeque<u64>,
+    files: HashMap<u64, File>,
+    next_fd: u64,
+    request: Option<DeviceRequest>,
+}
+
+impl Htif {
+    /// Use the `tohost` and `fromhost` doublewords at the given physical addresses.
+    pub fn new(tohost: u64, fromhost: u64) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            args: Vec::new(),
+            echo: true,
+            output: Vec::new(),
+            input: VecDeque::new(),
+            pending_reads: 0,
+            responses: VecDeque::new(),
+            files: HashMap::new(),
+            next_fd: 3,
+            request: None,
+        }
+    }
+
+    /// Find `tohost` and `fromhost` in the symbol table of an ELF file.
+    pub fn from_elf(data: &[u8]) -> Result<Self> {
+        let symbols = Elf::parse(data)?.symbols()?;
+        let (Some(&tohost), Some(&fromhost)) = (symbols.get("tohost"), symbols.get("fromhost"))
+        else {
+            bail!("the ELF file does not define the tohost and fromhost symbols");
+        };
+        info!("HTIF tohost at {tohost:#x}, fromhost at {fromhost:#x}");
+        Ok(Self::new(tohost, fromhost))
+    }
+
+    pub fn tohost(&self) -> u64 {
+        self.tohost
+    }
+
+    pub fn fromhost(&self) -> u64 {
+        self.fromhost
+    }
+
+    /// Set the arguments the proxy kernel passes to the program, starting with its name.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// Whether console output is also written to the host standard output (the default).
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Everything written to the console since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Queue input for the console and for reads of the standard input.
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Forget the commands in flight when the machine resets.
+    pub(crate) fn reset(&mut self) {
+        self.pending_reads = 0;
+        self.responses.clear();
+        self.request = None;
+    }
+
+    pub(crate) fn has_request(&self) -> bool {
+        self.request.is_some()
+    }
+
+    pub(crate) fn take_request(&mut self) -> Option<DeviceRequest> {
+        self.request.take()
+    }
+
+    /// Called after the guest stored `size` at `address`. Commands are picked up once the
+    /// upper half of `tohost` is written, which is the last store of a 64 bit write on RV32.
+    pub(crate) fn written(&mut self, bus: &mut Bus, address: u64, size: Sizes) {
+        let len = match size {
+            Sizes::Byte => 1,
+            Sizes::HalfWord => 2,
+            Sizes::Word => 4,
+        };
+        if address + len <= self.tohost + 4 || address >= self.tohost + 8 {
+            return;
+        }
+        if let Err(e) = self.handle_tohost(bus) {
+            warn!("HTIF command failed: {e:#}");
+        }
+    }
+
+    /// Deliver answers the guest is waiting for.
+    pub(crate) fn service(&mut self, bus: &mut Bus) {
+        while self.pending_reads > 0 {
+            let Some(ch) = self.input.pop_front() else {
+                break;
+            };
+            self.pending_reads -= 1;
+            self.responses
+                .push_back(command(DEVICE_CONSOLE, CONSOLE_GETCHAR, 0x100 | ch as u64));
+        }
+        if self.responses.is_empty() {
+            return;
+        }
+        match read64(bus, self.fromhost) {
+            Ok(0) => {
+                let response = self.responses.pop_front().unwrap();
+                if let Err(e) = write64(bus, self.fromhost, response) {
+                    warn!("Failed to write fromhost: {e:#}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to read fromhost: {e:#}"),
+        }
+    }
+
+    fn handle_tohost(&mut self, bus: &mut Bus) -> Result<()> {
+        let value = read64(bus, self.tohost)?;
+        if value == 0 {
+            return Ok(());
+        }
+        write64(bus, self.tohost, 0)?;
+
+        let device = (value >> 56) as u8;
+        let cmd = (value >> 48) as u8;
+        let payload = value & 0xffff_ffff_ffff;
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                let code = (payload >> 1) as u32;
+                info!("Guest exited through HTIF with code {code}");
+                self.request = Some(DeviceRequest::Exit(ExitStatus::Exit { code }));
+            }
+            (DEVICE_SYSCALL, 0) => {
+                self.syscall(bus, payload)?;
+                self.responses.push_back(command(DEVICE_SYSCALL, 0, 1));
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                self.console_write(&[payload as u8]);
+                self.responses
+                    .push_back(command(DEVICE_CONSOLE, CONSOLE_PUTCHAR, 0));
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => self.pending_reads += 1,
+            _ => warn!("Unknown HTIF command {value:#x}"),
+        }
+        self.service(bus);
+        Ok(())
+    }
+
+    fn console_write(&mut self, data: &[u8]) {
+        self.output.extend_from_slice(data);
+        if self.echo {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(data);
+            let _ = stdout.flush();
+        }
+    }
+
+    /// Run the syscall described by the eight doublewords at `magic_mem` and store its return
+    /// value in the first one.
+    fn syscall(&mut self, bus: &mut Bus, magic_mem: u64) -> Result<()> {
+        let mut args = [0; 8];
+        for (i, arg) in args.iter_mut().enumerate() {
+            *arg = read64(bus, magic_mem + i as u64 * 8)?;
+        }
+
+        let result = match self.dispatch(bus, args) {
+            Ok(value) => value,
+            Err(errno) => -errno,
+        };
+        write64(bus, magic_mem, result as u64)
+    }
+
+    fn dispatch(&mut self, bus: &mut Bus, args: [u64; 8]) -> Result<i64, i64> {
+        let [number, a0, a1, a2, a3, a4, ..] = args;
+        match number {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                let code = a0 as u32;
+                info!("Guest exited through the HTIF syscall proxy with code {code}");
+                self.request = Some(DeviceRequest::Exit(ExitStatus::Exit { code }));
+                Ok(0)
+            }
+            SYS_WRITE | SYS_PWRITE => {
+                let data = read_bytes(bus, a1, a2)?;
+                match (number, a0) {
+                    (SYS_WRITE, 1 | 2) => {
+                        self.console_write(&data);
+                        Ok(data.len() as i64)
+                    }
+                    (SYS_WRITE, fd) => Ok(self.file(fd)?.write(&data).map_err(errno)? as i64),
+                    (_, fd) => Ok(self.file(fd)?.write_at(&data, a3).map_err(errno)? as i64),
+                }
+            }
+            SYS_READ | SYS_PREAD => {
+                let mut data = vec![0; a2.min(1 << 20) as usize];
+                let len = match (number, a0) {
+                    (SYS_READ, 0) => {
+                        let len = data.len().min(self.input.len());
+                        for (byte, input) in data.iter_mut().zip(self.input.drain(..len)) {
+                            *byte = input;
+                        }
+                        len
+                    }
+                    (SYS_READ, fd) => self.file(fd)?.read(&mut data).map_err(errno)?,
+                    (_, fd) => self.file(fd)?.read_at(&mut data, a3).map_err(errno)?,
+                };
+                write_bytes(bus, a1, &data[..len])?;
+                Ok(len as i64)
+            }
+            SYS_OPENAT => {
+                // (dirfd, path, path length including the NUL, flags, mode)
+                let path = read_bytes(bus, a1, a2)?;
+                let path = std::str::from_utf8(path.strip_suffix(&[0]).unwrap_or(&path))
+                    .map_err(|_| EINVAL)?
+                    .to_string();
+                let mut options = OpenOptions::new();
+                match a3 & O_ACCMODE {
+                    O_WRONLY => options.write(true),
+                    O_RDWR => options.read(true).write(true),
+                    _ => options.read(true),
+                };
+                let file = options
+                    .custom_flags((a3 & !O_ACCMODE) as i32)
+                    .mode(a4 as u32)
+                    .open(&path)
+                    .map_err(errno)?;
+                let fd = self.next_fd;
+                self.next_fd += 1;
+                self.files.insert(fd, file);
+                Ok(fd as i64)
+            }
+            SYS_CLOSE => {
+                // the standard streams belong to the host
+                if a0 > 2 {
+                    self.files.remove(&a0).ok_or(EBADF)?;
+                }
+                Ok(0)
+            }
+            SYS_LSEEK => {
+                let position = match a2 {
+                    0 => SeekFrom::Start(a1),
+                    1 => SeekFrom::Current(a1 as i64),
+                    2 => SeekFrom::End(a1 as i64),
+                    _ => return Err(EINVAL),
+                };
+                Ok(self.file(a0)?.seek(position).map_err(errno)? as i64)
+            }
+            SYS_GETMAINVARS => self.getmainvars(bus, a0, a1),
+            _ => {
+                warn!("Unsupported HTIF syscall {number}");
+                Err(ENOSYS)
+            }
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut File, i64> {
+        self.files.get_mut(&fd).ok_or(EBADF)
+    }
+
+    /// Write argc, argv, an empty environment and the argument strings to `buffer`.
+    fn getmainvars(&mut self, bus: &mut Bus, buffer: u64, limit: u64) -> Result<i64, i64> {
+        let words = self.args.len() as u64 + 3;
+        let mut data = Vec::new();
+        data.extend_from_slice(&(self.args.len() as u64).to_le_bytes());
+        let mut string = buffer + words * 8;
+        for arg in &self.args {
+            data.extend_from_slice(&string.to_le_bytes());
+            string += arg.len() as u64 + 1;
+        }
+        // argv[argc] and envp[0]
+        data.extend_from_slice(&[0; 16]);
+        for arg in &self.args {
+            data.extend_from_slice(arg.as_bytes());
+            data.push(0);
+        }
+
+        if data.len() as u64 > limit {
+            return Err(ENOMEM);
+        }
+        write_bytes(bus, buffer, &data)?;
+        Ok(0)
+    }
+}
+
+fn command(device: u8, cmd: u8, payload: u64) -> u64 {
+    (device as u64) << 56 | (cmd as u64) << 48 | payload & 0xffff_ffff_ffff
+}
+
+fn errno(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(EINVAL, |code| code as i64)
+}
+
+fn read64(bus: &Bus, address: u64) -> Result<u64> {
+    let low = bus.read(address, Sizes::Word)? as u64;
+    let high = bus.read(address + 4, Sizes::Word)? as u64;
+    Ok(high << 32 | low)
+}
+
+fn write64(bus: &mut Bus, address: u64, value: u64) -> Result<()> {
+    bus.write(address, value as u32, Sizes::Word)?;
+    bus.write(address + 4, (value >> 32) as u32, Sizes::Word)
+}
+
+fn read_bytes(bus: &Bus, address: u64, len: u64) -> Result<Vec<u8>, i64> {
+    (0..len.min(1 << 20))
+        .map(|i| {
+            bus.read(address + i, Sizes::Byte)
+                .map(|byte| byte as u8)
+                .map_err(|_| EFAULT)
+        })
+        .collect()
+}
+
+fn write_bytes(bus: &mut Bus, address: u64, data: &[u8]) -> Result<(), i64> {
+    for (i, byte) in data.iter().enumerate() {
+        bus.write(address + i as u64, *byte as u32, Sizes::Byte)
+            .map_err(|_| EFAULT)?;
+    }
+    Ok(())
+}
@@ -1,11 +1,18 @@
 pub mod bus;
 pub mod cpu;
 pub mod csr;
+pub mod device_tree;
+pub mod devices;
+pub mod events;
+pub mod htif;
 pub mod interrupt;
+pub mod loader;
 pub mod memory;
 pub mod registers;
 pub mod rom;
+pub mod trace;
 pub mod trap;
+pub mod virtio;
 
 use std::io::IsTerminal;
 
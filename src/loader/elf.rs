@@ -0,0 +1,317 @@
+//! Reading of little endian ELF32 and ELF64 files, and loading them onto a bus.
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+
+use crate::bus::Bus;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+/// An executable file, as opposed to relocatable or shared object files.
+const ET_EXEC: u16 = 2;
+/// The RISC-V machine.
+const EM_RISCV: u16 = 243;
+/// A section holding a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// A segment to be loaded into memory.
+const PT_LOAD: u32 = 1;
+
+// RISC-V ELF header flags.
+/// The program contains compressed instructions.
+const EF_RISCV_RVC: u32 = 0x1;
+/// The floating point calling convention.
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+const EF_RISCV_FLOAT_ABI_SINGLE: u32 = 0x2;
+const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+const EF_RISCV_FLOAT_ABI_QUAD: u32 = 0x6;
+
+/// The `misa` bits of the extensions the flags of a file can call for.
+const MISA_C: u32 = 1 << 2;
+const MISA_D: u32 = 1 << 3;
+const MISA_F: u32 = 1 << 5;
+const MISA_Q: u32 = 1 << 16;
+
+/// Whether the file uses 32 or 64 bit addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Elf32,
+    Elf64,
+}
+
+/// An entry of the section header table.
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    kind: u32,
+    offset: u64,
+    size: u64,
+    link: u32,
+    entry_size: u64,
+}
+
+/// A part of the program to be placed in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// The physical address the segment goes to.
+    pub address: u64,
+    /// The contents of the segment in the file. The rest of the segment, like `.bss`, is zero.
+    pub data: &'a [u8],
+    /// The size of the segment in memory.
+    pub size: u64,
+}
+
+/// A parsed ELF file borrowing its contents.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    class: Class,
+    sections: Vec<Section>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < 16 || &data[..4] != ELF_MAGIC {
+            bail!("not an ELF file");
+        }
+        let class = match data[4] {
+            ELFCLASS32 => Class::Elf32,
+            ELFCLASS64 => Class::Elf64,
+            class => bail!("unknown ELF class {class}"),
+        };
+        if data[5] != ELFDATA2LSB {
+            bail!("big endian ELF files are not supported");
+        }
+
+        let mut elf = Self {
+            data,
+            class,
+            sections: Vec::new(),
+        };
+        elf.sections = elf
+            .read_sections()
+            .context("malformed section header table")?;
+        Ok(elf)
+    }
+
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    /// The address execution starts at.
+    pub fn entry(&self) -> Result<u64> {
+        self.word(0x18)
+    }
+
+    pub fn machine(&self) -> Result<u16> {
+        self.u16(0x12)
+    }
+
+    /// The processor specific flags, like the floating point ABI.
+    pub fn flags(&self) -> Result<u32> {
+        match self.class {
+            Class::Elf32 => self.u32(0x24),
+            Class::Elf64 => self.u32(0x30),
+        }
+    }
+
+    /// Check that a hart with `class` addresses and the extensions in `misa` can run the
+    /// program.
+    pub fn check_isa(&self, class: Class, misa: u32) -> Result<()> {
+        let machine = self.machine()?;
+        if machine != EM_RISCV {
+            bail!("the ELF file is for machine {machine}, not RISC-V");
+        }
+        if self.u16(0x10)? != ET_EXEC {
+            bail!("the ELF file is not an executable");
+        }
+        if self.class != class {
+            bail!(
+                "the ELF file is {:?} but the hart needs {class:?}",
+                self.class
+            );
+        }
+
+        let flags = self.flags()?;
+        if flags & EF_RISCV_RVC != 0 && misa & MISA_C == 0 {
+            bail!("the program uses compressed instructions but the hart has no C extension");
+        }
+        let (abi, extension, name) = match flags & EF_RISCV_FLOAT_ABI {
+            EF_RISCV_FLOAT_ABI_SINGLE => ("single", MISA_F, 'F'),
+            EF_RISCV_FLOAT_ABI_DOUBLE => ("double", MISA_D, 'D'),
+            EF_RISCV_FLOAT_ABI_QUAD => ("quad", MISA_Q, 'Q'),
+            _ => return Ok(()),
+        };
+        if misa & extension == 0 {
+            bail!("the program uses the {abi} float ABI but the hart has no {name} extension");
+        }
+        Ok(())
+    }
+
+    /// The segments to be loaded into memory.
+    pub fn segments(&self) -> Result<Vec<Segment<'a>>> {
+        let (table, entry_size, count) = match self.class {
+            Class::Elf32 => (self.word(0x1c)?, self.u16(0x2a)?, self.u16(0x2c)?),
+            Class::Elf64 => (self.word(0x20)?, self.u16(0x36)?, self.u16(0x38)?),
+        };
+
+        let mut segments = Vec::new();
+        for index in 0..count as u64 {
+            let header = table + index * entry_size as u64;
+            if self.u32(header)? != PT_LOAD {
+                continue;
+            }
+            let (offset, address, file_size, size) = match self.class {
+                Class::Elf32 => (
+                    self.word(header + 4)?,
+                    self.word(header + 12)?,
+                    self.word(header + 16)?,
+                    self.word(header + 20)?,
+                ),
+                Class::Elf64 => (
+                    self.word(header + 8)?,
+                    self.word(header + 24)?,
+                    self.word(header + 32)?,
+                    self.word(header + 40)?,
+                ),
+            };
+            if file_size > size {
+                bail!("segment {index} is larger in the file than in memory");
+            }
+            if address.checked_add(size).is_none() {
+                bail!("segment {index} runs past the end of the address space");
+            }
+            segments.push(Segment {
+                address,
+                data: self.bytes(offset, file_size)?,
+                size,
+            });
+        }
+        Ok(segments)
+    }
+
+    /// Place every segment at its physical address and zero the rest of it.
+    pub fn load(&self, bus: &mut Bus) -> Result<()> {
+        const ZEROS: [u8; 4096] = [0; 4096];
+        for segment in self.segments().context("malformed program header table")? {
+            let place = |bus: &mut Bus| {
+                bus.write_bytes(segment.address, segment.data)?;
+                let mut address = segment.address + segment.data.len() as u64;
+                let end = segment.address + segment.size;
+                while address < end {
+                    let len = (end - address).min(ZEROS.len() as u64);
+                    bus.write_bytes(address, &ZEROS[..len as usize])?;
+                    address += len;
+                }
+                anyhow::Ok(())
+            };
+            place(bus).with_context(|| {
+                format!(
+                    "no memory for the segment at {:#x}-{:#x}",
+                    segment.address,
+                    segment.address + segment.size
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn bytes(&self, offset: u64, len: u64) -> Result<&'a [u8]> {
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len() as u64);
+        match end {
+            Some(end) => Ok(&self.data[offset as usize..end as usize]),
+            None => bail!("{len} bytes at offset {offset:#x} are past the end of the file"),
+        }
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into()?))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into()?))
+    }
+
+    /// A field that is 4 bytes long in ELF32 files and 8 bytes long in ELF64 files.
+    fn word(&self, offset: u64) -> Result<u64> {
+        match self.class {
+            Class::Elf32 => Ok(self.u32(offset)? as u64),
+            Class::Elf64 => self.u64(offset),
+        }
+    }
+
+    fn read_sections(&self) -> Result<Vec<Section>> {
+        let (table, entry_size, count) = match self.class {
+            Class::Elf32 => (self.word(0x20)?, self.u16(0x2e)?, self.u16(0x30)?),
+            Class::Elf64 => (self.word(0x28)?, self.u16(0x3a)?, self.u16(0x3c)?),
+        };
+
+        (0..count as u64)
+            .map(|index| {
+                let header = table + index * entry_size as u64;
+                Ok(match self.class {
+                    Class::Elf32 => Section {
+                        kind: self.u32(header + 4)?,
+                        offset: self.word(header + 16)?,
+                        size: self.word(header + 20)?,
+                        link: self.u32(header + 24)?,
+                        entry_size: self.word(header + 36)?,
+                    },
+                    Class::Elf64 => Section {
+                        kind: self.u32(header + 4)?,
+                        offset: self.word(header + 24)?,
+                        size: self.word(header + 32)?,
+                        link: self.u32(header + 40)?,
+                        entry_size: self.word(header + 56)?,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// The NUL terminated string at `offset` of the string table in section `index`.
+    fn string(&self, index: u32, offset: u64) -> Result<String> {
+        let Some(table) = self.sections.get(index as usize) else {
+            bail!("string table {index} does not exist");
+        };
+        if offset >= table.size {
+            bail!("string at {offset:#x} is past the end of its table");
+        }
+        let bytes = self.bytes(table.offset + offset, table.size - offset)?;
+        let len = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    /// The value of every named symbol of the symbol table. Files without one have no symbols.
+    pub fn symbols(&self) -> Result<HashMap<String, u64>> {
+        let mut symbols = HashMap::new();
+        for table in self.sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+            if table.entry_size == 0 {
+                bail!("symbol table with entries of size 0");
+            }
+            for index in 0..table.size / table.entry_size {
+                let symbol = table.offset + index * table.entry_size;
+                let (name, value) = match self.class {
+                    Class::Elf32 => (self.u32(symbol)?, self.word(symbol + 4)?),
+                    Class::Elf64 => (self.u32(symbol)?, self.word(symbol + 8)?),
+                };
+                if name == 0 {
+                    continue;
+                }
+                let name = self
+                    .string(table.link, name as u64)
+                    .context("malformed symbol table")?;
+                symbols.insert(name, value);
+            }
+        }
+        Ok(symbols)
+    }
+}
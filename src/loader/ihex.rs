@@ -0,0 +1,96 @@
+//! Reading of Intel HEX files, as `objcopy -O ihex` writes them.
+use anyhow::{bail, Context, Result};
+
+use super::{big_endian, decode_hex, Image};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+/// The next data records are relative to the segment base, the value times 16.
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+/// The CS:IP to start execution at.
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+/// The next data records are relative to the value shifted up by 16 bits.
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+/// The 32 bit address to start execution at.
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Parse the records of an Intel HEX file. Errors name the line they are on.
+pub fn parse(text: &str) -> Result<Image> {
+    let mut image = Image::default();
+    let mut base = 0;
+    let mut ended = false;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        if ended {
+            bail!("line {number}: record after the end of file record");
+        }
+        let record = record(line).with_context(|| format!("line {number}"))?;
+        let data = &record.data;
+        match record.kind {
+            DATA => image.push(base + record.offset, data),
+            END_OF_FILE => ended = true,
+            EXTENDED_SEGMENT_ADDRESS => base = big_endian(data) << 4,
+            EXTENDED_LINEAR_ADDRESS => base = big_endian(data) << 16,
+            START_SEGMENT_ADDRESS => {
+                image.start = Some((big_endian(&data[..2]) << 4) + big_endian(&data[2..]))
+            }
+            START_LINEAR_ADDRESS => image.start = Some(big_endian(data)),
+            _ => unreachable!(),
+        }
+    }
+    if !ended {
+        bail!("no end of file record");
+    }
+    Ok(image)
+}
+
+struct Record {
+    kind: u8,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Decode and check a line like `:0300300002337A1E`.
+fn record(line: &str) -> Result<Record> {
+    let Some(digits) = line.strip_prefix(':') else {
+        bail!("records start with ':'");
+    };
+    let bytes = decode_hex(digits, 2)?;
+    if bytes.len() < 5 {
+        bail!("record of {} bytes is too short", bytes.len());
+    }
+    let len = bytes[0] as usize;
+    if bytes.len() != len + 5 {
+        bail!(
+            "record says it has {len} data bytes but has {}",
+            bytes.len() - 5
+        );
+    }
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    if sum != 0 {
+        let checksum = bytes[len + 4];
+        let expected = checksum.wrapping_sub(sum);
+        bail!("checksum is {checksum:#04x}, expected {expected:#04x}");
+    }
+
+    let kind = bytes[3];
+    let expected_len = match kind {
+        DATA => None,
+        END_OF_FILE => Some(0),
+        EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => Some(2),
+        START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => Some(4),
+        _ => bail!("unknown record type {kind:#04x}"),
+    };
+    if let Some(expected) = expected_len.filter(|&expected| expected != len) {
+        bail!("record of type {kind:#04x} has {len} data bytes instead of {expected}");
+    }
+    Ok(Record {
+        kind,
+        offset: big_endian(&bytes[1..3]),
+        data: bytes[4..len + 4].to_vec(),
+    })
+}
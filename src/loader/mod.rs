@@ -0,0 +1,61 @@
+//! The loader module reads guest programs from the file formats toolchains produce.
+pub mod elf;
+pub mod ihex;
+pub mod srec;
+
+use anyhow::{bail, Result};
+
+use crate::bus::Bus;
+
+/// The contents of a memory image file like Intel HEX or Motorola S-records.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    /// Runs of bytes with the address of their first byte, in the order of the file. Runs the
+    /// file has right after each other are merged.
+    pub chunks: Vec<(u64, Vec<u8>)>,
+    /// Where execution starts, if the file says.
+    pub start: Option<u64>,
+}
+
+impl Image {
+    /// Add `data` at `address`, merging it into the last run when it follows it.
+    fn push(&mut self, address: u64, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some((start, run)) if *start + run.len() as u64 == address => {
+                run.extend_from_slice(data)
+            }
+            _ => self.chunks.push((address, data.to_vec())),
+        }
+    }
+
+    /// Write every run through the bus into whatever memory is mapped there, including ROM and
+    /// flash, see [`Bus::preload`].
+    pub fn load(&self, bus: &mut Bus) -> Result<()> {
+        for (address, data) in &self.chunks {
+            bus.preload(*address, data)?;
+        }
+        Ok(())
+    }
+}
+
+/// The bytes written as pairs of hex digits in `digits`, which start at `column` of their line.
+fn decode_hex(digits: &str, column: usize) -> Result<Vec<u8>> {
+    if let Some(index) = digits.find(|c: char| !c.is_ascii_hexdigit()) {
+        let c = digits[index..].chars().next().unwrap();
+        bail!("invalid hex digit {c:?} at column {}", column + index);
+    }
+    if !digits.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+/// The big endian number in `bytes`.
+fn big_endian(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| value << 8 | byte as u64)
+}
@@ -0,0 +1,91 @@
+//! Reading of Motorola S-record files, as `objcopy -O srec` writes them.
+use anyhow::{bail, Context, Result};
+
+use super::{big_endian, decode_hex, Image};
+
+/// Parse the records of an S-record file. The file ends with a termination record, whose
+/// address is where execution starts. Errors name the line they are on.
+pub fn parse(text: &str) -> Result<Image> {
+    let mut image = Image::default();
+    let mut data_records = 0u64;
+    let mut ended = false;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        if ended {
+            bail!("line {number}: record after the termination record");
+        }
+        let record = record(line).with_context(|| format!("line {number}"))?;
+        match record.kind {
+            // the header, usually the name of the file
+            b'0' => {}
+            b'1' | b'2' | b'3' => {
+                image.push(record.address, &record.data);
+                data_records += 1;
+            }
+            b'5' | b'6' => {
+                if record.address != data_records {
+                    let count = record.address;
+                    bail!(
+                        "line {number}: {count} data records counted, the file has {data_records}"
+                    );
+                }
+            }
+            _ => {
+                image.start = Some(record.address);
+                ended = true;
+            }
+        }
+    }
+    if !ended {
+        bail!("no termination record");
+    }
+    Ok(image)
+}
+
+struct Record {
+    kind: u8,
+    address: u64,
+    data: Vec<u8>,
+}
+
+/// Decode and check a line like `S1130000285F245F2212226A000424290008237C2A`.
+fn record(line: &str) -> Result<Record> {
+    let Some(digits) = line.strip_prefix('S') else {
+        bail!("records start with 'S'");
+    };
+    let kind = digits.bytes().next().context("record without a type")?;
+    let address_len = match kind {
+        b'0' | b'1' | b'5' | b'9' => 2,
+        b'2' | b'6' | b'8' => 3,
+        b'3' | b'7' => 4,
+        _ => bail!("unknown record type S{}", kind as char),
+    };
+    let bytes = decode_hex(&digits[1..], 3)?;
+    let Some((&count, rest)) = bytes.split_first() else {
+        bail!("record without a byte count");
+    };
+    if rest.len() != count as usize {
+        bail!("record says it has {count} bytes but has {}", rest.len());
+    }
+    if rest.len() < address_len + 1 {
+        bail!("record of {count} bytes is too short for its address");
+    }
+    let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    if sum != 0xff {
+        let checksum = bytes[bytes.len() - 1];
+        let expected = !sum.wrapping_sub(checksum);
+        bail!("checksum is {checksum:#04x}, expected {expected:#04x}");
+    }
+    if matches!(kind, b'5' | b'6' | b'7' | b'8' | b'9') && rest.len() != address_len + 1 {
+        bail!("record of type S{} has data", kind as char);
+    }
+    Ok(Record {
+        kind,
+        address: big_endian(&rest[..address_len]),
+        data: rest[address_len..rest.len() - 1].to_vec(),
+    })
+}
@@ -6,7 +6,7 @@ use super::virtual_memory::HeapMemory;
 pub const DRAM_SIZE: u64 = 128 * 1024 * 1024; // 1 GiB
 pub const DRAM_BASE: u64 = 0x8000_0000;
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Sizes {
     Byte,
     HalfWord,
@@ -68,6 +68,14 @@ impl Device for Dram {
     fn store(&mut self, addr: u64, size: Sizes, value: u32) -> Result<()> {
         self.write(addr, value, size)
     }
+
+    fn host_memory(&self) -> Option<&[u8]> {
+        Some(self.memory.memory())
+    }
+
+    fn host_memory_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.memory.memory_mut())
+    }
 }
 
 impl Default for Dram {
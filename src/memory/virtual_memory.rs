@@ -2,6 +2,7 @@ use std::cmp::Ordering;
 
 use anyhow::{Context, Result};
 
+use crate::memory::dram::Sizes;
 use crate::trap::Exception;
 
 /// (type) size of each memory cell (index)
@@ -134,7 +135,7 @@ impl<const L: usize> HeapMemory<L> {
     }
 
     pub fn read32(&self, index: u64) -> Result<MemorySize> {
-        if index + 3 > L as u64 {
+        if index + 4 > L as u64 {
             return Err(Exception::LoadAccessFault).context(format!("index: {index}, length: {L}"));
         }
         let index = index as usize;
@@ -148,7 +149,7 @@ impl<const L: usize> HeapMemory<L> {
     }
 
     pub fn read16(&self, index: u64) -> Result<u32> {
-        if index + 1 > L as u64 {
+        if index + 2 > L as u64 {
             return Err(Exception::LoadAccessFault).context(format!("index: {index}, length: {L}"));
         }
         let index = index as usize;
@@ -157,7 +158,7 @@ impl<const L: usize> HeapMemory<L> {
     }
 
     pub fn read8(&self, index: u64) -> Result<u32> {
-        if index > L as u64 {
+        if index >= L as u64 {
             return Err(Exception::LoadAccessFault).context(format!("index: {index}, length: {L}"));
         }
 
@@ -165,7 +166,7 @@ impl<const L: usize> HeapMemory<L> {
     }
 
     pub fn write32(&mut self, index: u64, value: MemorySize) -> Result<()> {
-        if index + 3 > L as u64 {
+        if index + 4 > L as u64 {
             return Err(Exception::StoreAccessFault)
                 .context(format!("index: {index}, length: {L}"));
         }
@@ -181,7 +182,7 @@ impl<const L: usize> HeapMemory<L> {
     }
 
     pub fn write16(&mut self, index: u64, value: MemorySize) -> Result<()> {
-        if index + 1 > L as u64 {
+        if index + 2 > L as u64 {
             return Err(Exception::StoreAccessFault)
                 .context(format!("index: {index}, length: {L}"));
         }
@@ -195,7 +196,7 @@ impl<const L: usize> HeapMemory<L> {
     }
 
     pub fn write8(&mut self, index: u64, value: MemorySize) -> Result<()> {
-        if index > L as u64 {
+        if index >= L as u64 {
             return Err(Exception::StoreAccessFault)
                 .context(format!("index: {index}, length: {L}"));
         }
@@ -211,6 +212,31 @@ impl<const L: usize> HeapMemory<L> {
     }
 }
 
+/// Read `size` little-endian bytes from the start of `memory`, sign-extending bytes and
+/// halfwords like [`HeapMemory`] does. `None` if `memory` is too short.
+#[inline]
+pub fn load_host(memory: &[u8], size: Sizes) -> Option<MemorySize> {
+    Some(match size {
+        Sizes::Byte => *memory.first()? as i8 as MemorySize,
+        Sizes::HalfWord => i16::from_le_bytes(*memory.first_chunk()?) as MemorySize,
+        Sizes::Word => MemorySize::from_le_bytes(*memory.first_chunk()?),
+    })
+}
+
+/// Write the low `size` bytes of `value` to the start of `memory`, little-endian. `None` if
+/// `memory` is too short.
+#[inline]
+pub fn store_host(memory: &mut [u8], size: Sizes, value: MemorySize) -> Option<()> {
+    let bytes = value.to_le_bytes();
+    let length = match size {
+        Sizes::Byte => 1,
+        Sizes::HalfWord => 2,
+        Sizes::Word => 4,
+    };
+    memory.get_mut(..length)?.copy_from_slice(&bytes[..length]);
+    Some(())
+}
+
 impl<const L: usize> Default for HeapMemory<L> {
     fn default() -> Self {
         Self::new()
@@ -1,9 +1,9 @@
-//! The rom module contains the read-only memory structure and implementation to read the memory. ROM includes a device tree blob (DTB) compiled from a device tree source (DTS).
+//! The rom module contains the read-only memory structure and implementation to read the memory. ROM includes a device tree blob (DTB) generated from the machine, see [`crate::device_tree`].
 use anyhow::{bail, Result};
 use log::info;
 
 use crate::bus::{Device, VirtualDevice};
-use crate::memory::dram::Sizes;
+use crate::memory::dram::{Sizes, DRAM_BASE};
 use crate::memory::virtual_memory::MemorySize;
 use crate::trap::Exception;
 
@@ -14,103 +14,23 @@ pub const MROM_BASE: u64 = 0x1000;
 /// the size of the mask ROM
 pub const MROM_SIZE: u64 = 0xf000;
 
-const DTS: &str = r#"
-/dts-v1/;
-
-/ {
-	#address-cells = <0x2>;
-	#size-cells = <0x2>;
-	compatible = "riscv-virtio";
-	model = "riscv-virtio,qemu";
-
-	chosen {
-		bootargs = "root=/dev/vda rw ttyS0";
-		stdout-path = "/uart@10000000";
-	};
-
-	uart@10000000 {
-		interrupts = <0xa>;
-		interrupt-parent = <0x3>;
-		clock-frequency = <0x384000>;
-		reg = <0x0 0x10000000 0x0 0x100>;
-		compatible = "ns16550a";
-	};
-
-	virtio_mmio@10001000 {
-		interrupts = <0x1>;
-		interrupt-parent = <0x3>;
-		reg = <0x0 0x10001000 0x0 0x1000>;
-		compatible = "virtio,mmio";
-	};
-
-	cpus {
-		#address-cells = <0x1>;
-		#size-cells = <0x0>;
-		timebase-frequency = <0x989680>;
-
-		cpu-map {
-			cluster0 {
-				core0 {
-					cpu = <0x1>;
-				};
-			};
-		};
-
-		cpu@0 {
-			phandle = <0x1>;
-			device_type = "cpu";
-			reg = <0x0>;
-			status = "okay";
-			compatible = "riscv";
-			riscv,isa = "rv64imafdcsu";
-			mmu-type = "riscv,sv39";
-
-			interrupt-controller {
-				#interrupt-cells = <0x1>;
-				interrupt-controller;
-				compatible = "riscv,cpu-intc";
-				phandle = <0x2>;
-			};
-		};
-	};
-
-	memory@80000000 {
-		device_type = "memory";
-		reg = <0x0 0x80000000 0x0 0x8000000>;
-	};
-
-	soc {
-		#address-cells = <0x2>;
-		#size-cells = <0x2>;
-		compatible = "simple-bus";
-		ranges;
-
-		interrupt-controller@c000000 {
-			phandle = <0x3>;
-			riscv,ndev = <0x35>;
-			reg = <0x0 0xc000000 0x0 0x4000000>;
-			interrupts-extended = <0x2 0xb 0x2 0x9>;
-			interrupt-controller;
-			compatible = "riscv,plic0";
-			#interrupt-cells = <0x1>;
-			#address-cells = <0x0>;
-		};
-
-		clint@2000000 {
-			interrupts-extended = <0x2 0x3 0x2 0x7>;
-			reg = <0x0 0x2000000 0x0 0x10000>;
-			compatible = "riscv,clint0";
-		};
-	};
-};"#;
-
-/// Read a dtb file. First, create a dts file. Second, compile it to a dtb file. Finally, read the dtb file and return the binary content.
-fn dtb() -> Vec<u8> {
-    // instead we should use a library so we dont have to rely on the user having dtc installed
-    use devicetree_tool::DeviceTree;
-    // turn our dtb string into bytes
-    let dt = DeviceTree::from_dts_bytes(DTS.as_bytes());
-    dt.generate_dtb()
+/// The offset of the address the reset vector jumps to.
+const ENTRY_OFFSET: usize = 0x18;
+
+/// The reset vector, like the one of the QEMU virt board. It passes the hart id in a0 and the
+/// address of the DTB in a1, the boot protocol of OpenSBI and Linux, and jumps to `entry`, which
+/// is kept right after the code.
+fn reset_vector(entry: u32) -> [u32; 8] {
+    [
+        0x0000_12b7, // lui   t0, 0x1         t0 = MROM_BASE
+        0x0202_8593, // addi  a1, t0, 0x20    a1 = POINTER_TO_DTB
+        0xf140_2573, // csrr  a0, mhartid
+        0x0182_a283, // lw    t0, 0x18(t0)    t0 = entry
+        0x0002_8067, // jr    t0
+        0,
+        entry,
+        0,
+    ]
 }
 
 /// The read-only memory (ROM).
@@ -119,14 +39,33 @@ pub struct Rom {
 }
 
 impl Rom {
-    /// Create a new `rom` object.
+    /// Create a new `rom` object without a device tree, whose reset vector jumps to the start of
+    /// RAM.
     pub fn new() -> Self {
-        let mut dtb = dtb();
+        Self::layout(Vec::new())
+    }
+
+    /// Create a new `rom` object holding the device tree blob `dtb` at [`POINTER_TO_DTB`], such
+    /// as [`crate::device_tree::Fdt::to_dtb`] writes. Fails when the blob does not fit.
+    pub fn with_dtb(dtb: Vec<u8>) -> Result<Self> {
+        let room = MROM_SIZE - (POINTER_TO_DTB as u64 - MROM_BASE);
+        if dtb.len() as u64 > room {
+            bail!(
+                "the device tree blob of {} bytes does not fit the ROM, which has room for {room} bytes",
+                dtb.len()
+            );
+        }
+        Ok(Self::layout(dtb))
+    }
+
+    /// The reset vector followed by `dtb`.
+    fn layout(mut dtb: Vec<u8>) -> Self {
         info!("The size of the device tree blob (DTB): {}", dtb.len());
 
-        // TODO: set a reset vector correctly.
-        // 0x20 is the size of a reset vector.
-        let mut rom = vec![0; 32];
+        let mut rom: Vec<u8> = reset_vector(DRAM_BASE as u32)
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
         rom.append(&mut dtb);
 
         info!("The size of the ROM: {}", rom.len());
@@ -141,11 +80,41 @@ impl Rom {
         VirtualDevice::new(Box::new(Self::new()), MROM_BASE, MROM_SIZE)
     }
 
+    pub fn new_device_with_dtb(dtb: Vec<u8>) -> Result<VirtualDevice> {
+        Ok(VirtualDevice::new(
+            Box::new(Self::with_dtb(dtb)?),
+            MROM_BASE,
+            MROM_SIZE,
+        ))
+    }
+
     pub fn new_with_data(data: Vec<u8>) -> Rom {
         info!("Initializing the ROM with the data of size: {}", data.len());
         Rom { data }
     }
 
+    /// Make the reset vector jump to `entry`, like firmware, a kernel or the entry point of an ELF
+    /// program.
+    pub fn with_entry(mut self, entry: u32) -> Self {
+        self.set_entry(entry);
+        self
+    }
+
+    /// The address the reset vector jumps to.
+    pub fn entry(&self) -> u32 {
+        self.data
+            .get(ENTRY_OFFSET..ENTRY_OFFSET + 4)
+            .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn set_entry(&mut self, entry: u32) {
+        let end = ENTRY_OFFSET + 4;
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[ENTRY_OFFSET..end].copy_from_slice(&entry.to_le_bytes());
+    }
+
     /// Load `size`-bit data from the memory.
     pub fn read(&self, addr: u64, size: Sizes) -> Result<u32> {
         match size {
@@ -194,6 +163,20 @@ impl Device for Rom {
     fn store(&mut self, _addr: u64, _size: Sizes, _value: MemorySize) -> Result<()> {
 		bail!(Exception::StoreAccessFault);
     }
+
+    fn host_memory(&self) -> Option<&[u8]> {
+        Some(&self.data)
+    }
+
+    fn preload(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let start = addr as usize;
+        let end = start + data.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(data);
+        Ok(())
+    }
 }
 
 impl Default for Rom {
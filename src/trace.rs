@@ -0,0 +1,210 @@
+//! Tracing of the accesses the harts make to the bus, for seeing which device registers a driver
+//! touches. A watch on a [`crate::bus::Bus`] pairs a [`WatchFilter`] with an [`MmioObserver`]
+//! that gets every access the filter lets through, as an [`MmioAccess`].
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+
+use log::debug;
+
+use crate::bus::DeviceHandle;
+use crate::memory::{dram::Sizes, virtual_memory::MemorySize};
+
+/// Identifies a watch on a bus, see [`crate::bus::Bus::watch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchId(pub(crate) usize);
+
+/// Which way an access went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// One access of a hart to a device on the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmioAccess {
+    pub kind: AccessKind,
+    /// The physical address.
+    pub address: u64,
+    pub size: Sizes,
+    /// The value read or written.
+    pub value: MemorySize,
+    /// The hart that made the access.
+    pub hart: u32,
+    /// The address of the instruction that made the access.
+    pub pc: u32,
+    pub device: DeviceHandle,
+    /// The name of the device, see [`crate::bus::Device::name`].
+    pub device_name: &'static str,
+    /// The address relative to the base of the device, the register offset.
+    pub offset: u64,
+}
+
+/// The compact text format, one access per line, like
+/// `hart0 0x80000010 W.w 0x00101010 GoldfishRtc+0x10 0x00000001`.
+impl fmt::Display for MmioAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Read => 'R',
+            AccessKind::Write => 'W',
+        };
+        let (size, width) = match self.size {
+            Sizes::Byte => ('b', 4),
+            Sizes::HalfWord => ('h', 6),
+            Sizes::Word => ('w', 10),
+        };
+        write!(
+            f,
+            "hart{} {:#010x} {kind}.{size} {:#010x} {}+{:#x} {:#0width$x}",
+            self.hart, self.pc, self.address, self.device_name, self.offset, self.value
+        )
+    }
+}
+
+/// Which accesses a watch sees. Every condition that is set has to hold; the default filter lets
+/// everything through.
+#[derive(Debug, Clone, Default)]
+pub struct WatchFilter {
+    range: Option<Range<u64>>,
+    device: Option<DeviceHandle>,
+    kind: Option<AccessKind>,
+    size: Option<Sizes>,
+    value: Option<MemorySize>,
+    hart: Option<u32>,
+    pc: Option<Range<u32>>,
+}
+
+impl WatchFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accesses to physical addresses in `range`.
+    pub fn with_range(mut self, range: Range<u64>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// Only accesses to the device `handle` refers to.
+    pub fn with_device(mut self, handle: DeviceHandle) -> Self {
+        self.device = Some(handle);
+        self
+    }
+
+    /// Only reads or only writes.
+    pub fn with_kind(mut self, kind: AccessKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn with_size(mut self, size: Sizes) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Only accesses that read or write `value`.
+    pub fn with_value(mut self, value: MemorySize) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn with_hart(mut self, hart: u32) -> Self {
+        self.hart = Some(hart);
+        self
+    }
+
+    /// Only accesses made by instructions in `pc`.
+    pub fn with_pc(mut self, pc: Range<u32>) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// Whether the filter may let an access to `address` through.
+    pub fn covers(&self, address: u64) -> bool {
+        self.range
+            .as_ref()
+            .is_none_or(|range| range.contains(&address))
+    }
+
+    pub fn matches(&self, access: &MmioAccess) -> bool {
+        self.covers(access.address)
+            && self.device.is_none_or(|device| device == access.device)
+            && self.kind.is_none_or(|kind| kind == access.kind)
+            && self.size.is_none_or(|size| size == access.size)
+            && self.value.is_none_or(|value| value == access.value)
+            && self.hart.is_none_or(|hart| hart == access.hart)
+            && self.pc.as_ref().is_none_or(|pc| pc.contains(&access.pc))
+    }
+}
+
+/// Gets the accesses a watch lets through. Closures taking an [`MmioAccess`] are observers.
+pub trait MmioObserver {
+    fn access(&mut self, access: &MmioAccess);
+}
+
+impl<F: FnMut(&MmioAccess)> MmioObserver for F {
+    fn access(&mut self, access: &MmioAccess) {
+        self(access)
+    }
+}
+
+/// Keeps the accesses as structured records. Clones share the records, so keep one to read them
+/// while the bus has the other.
+#[derive(Debug, Clone, Default)]
+pub struct TraceBuffer {
+    records: Rc<RefCell<Vec<MmioAccess>>>,
+}
+
+impl TraceBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The accesses so far.
+    pub fn records(&self) -> Vec<MmioAccess> {
+        self.records.borrow().clone()
+    }
+
+    /// The accesses so far, leaving the buffer empty.
+    pub fn take(&self) -> Vec<MmioAccess> {
+        self.records.take()
+    }
+}
+
+impl MmioObserver for TraceBuffer {
+    fn access(&mut self, access: &MmioAccess) {
+        self.records.borrow_mut().push(access.clone());
+    }
+}
+
+/// Writes the accesses in the compact text format, a line each.
+pub struct TextTrace<W: Write> {
+    output: W,
+}
+
+impl<W: Write> TextTrace<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: Write> MmioObserver for TextTrace<W> {
+    fn access(&mut self, access: &MmioAccess) {
+        if let Err(e) = writeln!(self.output, "{access}") {
+            debug!(target: "mmio", "Failed to write the trace: {e}");
+        }
+    }
+}
+
+/// Logs the accesses in the compact text format at debug level, with the `mmio` target.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogTrace;
+
+impl MmioObserver for LogTrace {
+    fn access(&mut self, access: &MmioAccess) {
+        debug!(target: "mmio", "{access}");
+    }
+}
@@ -5,8 +5,8 @@ use thiserror::Error;
 use log::info;
 
 use crate::{
-    cpu::Cpu,
-    csr::{MCAUSE, MEPC, MSTATUS, MTVAL},
+    cpu::{Cpu, Privilege},
+    csr::{MCAUSE, MEPC, MSTATUS, MTVAL, MTVEC},
 };
 
 /// All the exception kinds.
@@ -128,24 +128,6 @@ impl Exception {
         }
     }
 
-    fn epc(&self, pc: u32) -> u32 {
-        // 3.2.1 Environment Call and Breakpoint
-        // "ECALL and EBREAK cause the receiving privilege mode’s epc register to be set to the
-        // address of the ECALL or EBREAK instruction itself, not the address of the following
-        // instruction."
-        match self {
-            Exception::Breakpoint
-            | Exception::EnvironmentCallFromUMode
-            | Exception::EnvironmentCallFromSMode
-            | Exception::EnvironmentCallFromMMode
-            // TODO: why page fault needs this?
-            | Exception::InstructionPageFault { .. }
-            | Exception::LoadPageFault { .. }
-            | Exception::StorePageFault { .. } => pc.wrapping_sub(4),
-            _ => pc,
-        }
-    }
-
     fn trap_value(&self, pc: u32) -> u32 {
         // 3.1.17 Machine Trap Value Register (mtval)
         // 4.1.9 Supervisor Trap Value Register (stval)
@@ -188,7 +170,15 @@ impl Exception {
         // 4.1.7 Supervisor Exception Program Counter (sepc)
         // "When a trap is taken, mepc (sepc) is written with the virtual address of the
         // instruction that encountered the exception."
-        let epc = self.epc(cpu.get_pc());
+        //
+        // 3.2.1 Environment Call and Breakpoint
+        // "ECALL and EBREAK cause the receiving privilege mode’s epc register to be set to the
+        // address of the ECALL or EBREAK instruction itself, not the address of the following
+        // instruction."
+        //
+        // Every exception is raised after the instruction was fetched, which already moved the
+        // pc past it by the length of the instruction, so the address is the one fetch recorded.
+        let epc = cpu.get_instruction_pc();
 
         // 3.1.17 Machine Trap Value Register (mtval)
         // 4.1.9 Supervisor Trap Value Register (stval)
@@ -198,7 +188,7 @@ impl Exception {
         // may be written with the first XLEN or ILEN bits of the faulting instruction as described
         // below. For other traps, mtval (stval) is set to zero, but a future standard may redefine
         // mtval's (stval's) setting for other traps."
-        let trap_value = self.trap_value(cpu.get_pc());
+        let trap_value = self.trap_value(epc);
 
         // 3.1.6 Machine Trap Delegation Register (medeleg)
         // 4.1.8 Supervisor Trap Delegation Register (sedeleg)
@@ -210,8 +200,28 @@ impl Exception {
         cpu.write_csr(MCAUSE, cause);
         cpu.write_csr(MEPC, epc & !1);
         cpu.write_csr(MTVAL, trap_value);
+
+        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
+        // "When a trap is taken from privilege mode y into privilege mode x, xPIE is set to the
+        // value of xIE; xIE is set to 0; and xPP is set to y."
+        let mpp = match cpu.get_privilege() {
+            Privilege::User => 0,
+            Privilege::Supervisor => 1,
+            Privilege::Machine => 3,
+        };
         let mstatus = cpu.read_csr(MSTATUS);
-        cpu.write_csr(MSTATUS, mstatus.clear_bit(3));
+        cpu.write_csr(
+            MSTATUS,
+            mstatus
+                .set_bits(mstatus.get_bit(3), 1, 7)
+                .clear_bit(3)
+                .set_bits(mpp, 2, 11),
+        );
+        cpu.set_privilege(Privilege::Machine);
+
+        // Exceptions always go to the base address of mtvec, even in vectored mode.
+        let mtvec = cpu.read_csr(MTVEC);
+        cpu.set_pc(mtvec & !3);
 
         Trap::Contained
     }
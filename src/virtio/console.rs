@@ -0,0 +1,479 @@
+//! The virtio console device (section 5.3 of the virtio 1.1 specification) with the multiport
+//! feature. Every port is connected to its own host endpoint, so a guest agent can talk to the
+//! host on one port while the kernel console runs on another.
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use super::queue::Virtqueue;
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+use crate::bus::BusView;
+
+/// The virtio device type of a console.
+const CONSOLE_DEVICE_ID: u32 = 3;
+
+/// Device has support for multiple ports; max_nr_ports is valid and control virtqueues will be
+/// used.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// Queue indices of the control virtqueues.
+const CONTROL_RECEIVE: u16 = 2;
+const CONTROL_TRANSMIT: u16 = 3;
+
+// Control message events.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Where the data of a console port goes to and comes from on the host.
+pub enum PortEndpoint {
+    /// The standard input and output of the host process.
+    Stdio,
+    /// A Unix socket the port listens on. One client can be connected at a time.
+    UnixSocket(PathBuf),
+    /// Guest output is appended to a file. The port never receives input.
+    File(PathBuf),
+    /// An in-process channel, see [`PortEndpoint::channel`].
+    Channel {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+    },
+}
+
+impl PortEndpoint {
+    /// Create a channel endpoint and the host side of it.
+    pub fn channel() -> (Self, PortChannel) {
+        let (guest_tx, host_rx) = mpsc::channel();
+        let (host_tx, guest_rx) = mpsc::channel();
+        (
+            Self::Channel {
+                tx: guest_tx,
+                rx: guest_rx,
+            },
+            PortChannel {
+                tx: host_tx,
+                rx: host_rx,
+            },
+        )
+    }
+}
+
+/// The host side of a [`PortEndpoint::Channel`].
+pub struct PortChannel {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl PortChannel {
+    /// Queue `data` to be received by the guest.
+    pub fn send(&self, data: &[u8]) -> Result<()> {
+        self.tx
+            .send(data.to_vec())
+            .context("the console device was dropped")
+    }
+
+    /// Everything the guest wrote to the port since the last call.
+    pub fn try_recv(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Ok(chunk) = self.rx.try_recv() {
+            data.extend(chunk);
+        }
+        data
+    }
+
+    /// Block until the guest writes to the port.
+    pub fn recv(&self) -> Result<Vec<u8>> {
+        self.rx.recv().context("the console device was dropped")
+    }
+}
+
+/// An opened [`PortEndpoint`].
+enum Backend {
+    Stdio {
+        input: Receiver<Vec<u8>>,
+    },
+    Socket {
+        listener: UnixListener,
+        stream: Option<UnixStream>,
+    },
+    File(File),
+    Channel {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+    },
+}
+
+impl Backend {
+    fn open(endpoint: PortEndpoint) -> Result<Self> {
+        Ok(match endpoint {
+            PortEndpoint::Stdio => {
+                let (tx, input) = mpsc::channel();
+                // stdin has no portable non-blocking mode, so read it from a thread
+                std::thread::spawn(move || {
+                    let mut stdin = std::io::stdin();
+                    let mut buffer = [0; 256];
+                    while let Ok(len @ 1..) = stdin.read(&mut buffer) {
+                        if tx.send(buffer[..len].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                });
+                Self::Stdio { input }
+            }
+            PortEndpoint::UnixSocket(path) => {
+                // a socket left behind by a previous run would make bind fail
+                let _ = std::fs::remove_file(&path);
+                let listener = UnixListener::bind(&path)
+                    .with_context(|| format!("failed to bind {}", path.display()))?;
+                listener.set_nonblocking(true)?;
+                Self::Socket {
+                    listener,
+                    stream: None,
+                }
+            }
+            PortEndpoint::File(path) => Self::File(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("failed to open {}", path.display()))?,
+            ),
+            PortEndpoint::Channel { tx, rx } => Self::Channel { tx, rx },
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let result = match self {
+            Self::Stdio { .. } => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(data).and_then(|_| stdout.flush())
+            }
+            Self::Socket { stream, .. } => match stream {
+                Some(client) => {
+                    let result = client.write_all(data);
+                    if result.is_err() {
+                        *stream = None;
+                    }
+                    result
+                }
+                // nobody is listening, drop the data like a disconnected serial line
+                None => Ok(()),
+            },
+            Self::File(file) => file.write_all(data),
+            Self::Channel { tx, .. } => {
+                // the host side may have been dropped, which is fine
+                let _ = tx.send(data.to_vec());
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            warn!("Failed to write console output: {e}");
+        }
+    }
+
+    /// Everything the host sent since the last call. Never blocks.
+    fn read(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self {
+            Self::Stdio { input: rx } | Self::Channel { rx, .. } => {
+                while let Ok(chunk) = rx.try_recv() {
+                    data.extend(chunk);
+                }
+            }
+            Self::Socket { listener, stream } => {
+                if stream.is_none() {
+                    if let Ok((client, _)) = listener.accept() {
+                        if client.set_nonblocking(true).is_ok() {
+                            info!("Console client connected");
+                            *stream = Some(client);
+                        }
+                    }
+                }
+                if let Some(client) = stream {
+                    let mut buffer = [0; 256];
+                    loop {
+                        match client.read(&mut buffer) {
+                            Ok(0) => {
+                                info!("Console client disconnected");
+                                *stream = None;
+                                break;
+                            }
+                            Ok(len) => data.extend_from_slice(&buffer[..len]),
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(_) => {
+                                *stream = None;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            Self::File(_) => {}
+        }
+        data
+    }
+}
+
+struct Port {
+    name: String,
+    backend: Backend,
+    /// Host input that was not delivered to the guest yet.
+    input: VecDeque<u8>,
+    /// The guest opened the port.
+    guest_open: bool,
+}
+
+/// A virtio console with any number of ports. Port 0 is the console port.
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    multiport: bool,
+    /// Control messages waiting for a buffer in the control receive queue.
+    control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    /// Create a console whose port 0 is connected to `endpoint`.
+    pub fn new(endpoint: PortEndpoint) -> Result<Self> {
+        let mut console = Self {
+            ports: Vec::new(),
+            multiport: false,
+            control: VecDeque::new(),
+        };
+        console.add_port("console", endpoint)?;
+        Ok(console)
+    }
+
+    /// Add a port called `name` and return its id. Ports must be added before the console is
+    /// wrapped in a transport.
+    pub fn add_port(&mut self, name: &str, endpoint: PortEndpoint) -> Result<u32> {
+        let id = self.ports.len() as u32;
+        self.ports.push(Port {
+            name: name.to_string(),
+            backend: Backend::open(endpoint)?,
+            input: VecDeque::new(),
+            guest_open: false,
+        });
+        info!("Added console port {id} ({name})");
+        Ok(id)
+    }
+
+    /// Whether the guest has port `id` open.
+    pub fn is_open(&self, id: u32) -> bool {
+        self.ports
+            .get(id as usize)
+            .is_some_and(|port| port.guest_open)
+    }
+
+    fn receive_queue(port: u32) -> u16 {
+        match port {
+            0 => 0,
+            _ => 2 * (port as u16 + 1),
+        }
+    }
+
+    /// The port a data queue belongs to and whether it is the transmit queue of the port.
+    fn port_of_queue(queue: u16) -> Option<(u32, bool)> {
+        match queue {
+            0 | 1 => Some((0, queue == 1)),
+            CONTROL_RECEIVE | CONTROL_TRANSMIT => None,
+            _ => Some((queue as u32 / 2 - 1, queue % 2 == 1)),
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut message = Vec::with_capacity(8 + extra.len());
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    fn handle_control(&mut self, message: &[u8]) {
+        if message.len() < 8 {
+            warn!("Short console control message: {message:?}");
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() as u32 {
+                    self.send_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                let Some(port) = self.ports.get(id as usize) else {
+                    warn!("Driver reported unknown console port {id} ready");
+                    return;
+                };
+                let name = port.name.clone();
+                if id == 0 {
+                    self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                // the host side of every endpoint is always connected
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    info!(
+                        "Guest {} console port {id}",
+                        if value == 1 { "opened" } else { "closed" }
+                    );
+                    port.guest_open = value == 1;
+                }
+            }
+            _ => info!("Ignoring console control event {event} for port {id}"),
+        }
+    }
+
+    /// Move pending control messages into the control receive queue.
+    fn flush_control(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        let mut used = false;
+        let queue = &mut queues[CONTROL_RECEIVE as usize];
+        while let Some(message) = self.control.front() {
+            let Some(chain) = queue.pop(memory)? else {
+                break;
+            };
+            let len = chain.write(memory, message)?;
+            queue.push(memory, chain.head, len as u32)?;
+            self.control.pop_front();
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Move pending host input of `port` into its receive queue.
+    fn deliver_input(
+        &mut self,
+        port: u32,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool> {
+        let mut used = false;
+        let queue = &mut queues[Self::receive_queue(port) as usize];
+        let input = &mut self.ports[port as usize].input;
+        while !input.is_empty() {
+            let Some(chain) = queue.pop(memory)? else {
+                break;
+            };
+            let data: Vec<u8> = input.iter().take(chain.writable_len()).copied().collect();
+            let len = chain.write(memory, &data)?;
+            input.drain(..len);
+            queue.push(memory, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// The number of ports the driver can use.
+    fn active_ports(&self) -> u32 {
+        if self.multiport {
+            self.ports.len() as u32
+        } else {
+            1
+        }
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn device_id(&self) -> u32 {
+        CONSOLE_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn queue_count(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // struct virtio_console_config { le16 cols; le16 rows; le32 max_nr_ports; le32 emerg_wr; }
+        match offset {
+            4..=7 => (self.ports.len() as u32).to_le_bytes()[offset as usize - 4],
+            _ => 0,
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+        for port in &mut self.ports {
+            port.guest_open = false;
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool> {
+        let mut used = false;
+        match Self::port_of_queue(queue) {
+            None if !self.multiport => {}
+            None => {
+                if queue == CONTROL_TRANSMIT {
+                    let control = &mut queues[CONTROL_TRANSMIT as usize];
+                    while let Some(chain) = control.pop(memory)? {
+                        let message = chain.read(memory)?;
+                        control.push(memory, chain.head, 0)?;
+                        self.handle_control(&message);
+                        used = true;
+                    }
+                }
+                used |= self.flush_control(queues, memory)?;
+            }
+            Some((port, _)) if port >= self.active_ports() => {}
+            Some((port, true)) => {
+                let transmit = &mut queues[queue as usize];
+                while let Some(chain) = transmit.pop(memory)? {
+                    let data = chain.read(memory)?;
+                    self.ports[port as usize].backend.write(&data);
+                    transmit.push(memory, chain.head, 0)?;
+                    used = true;
+                }
+            }
+            Some((port, false)) => used |= self.deliver_input(port, queues, memory)?,
+        }
+        Ok(used)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        let mut used = false;
+        for port in 0..self.active_ports() {
+            let data = self.ports[port as usize].backend.read();
+            self.ports[port as usize].input.extend(data);
+            used |= self.deliver_input(port, queues, memory)?;
+        }
+        if self.multiport {
+            used |= self.flush_control(queues, memory)?;
+        }
+        Ok(used)
+    }
+}
@@ -0,0 +1,157 @@
+//! Linux input event codes and the mapping of characters and key names to them, assuming a US
+//! keyboard layout.
+
+// Event types.
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+pub const KEY_LEFTSHIFT: u16 = 42;
+
+/// The highest key code a keyboard reports.
+pub const KEY_MAX_KEYBOARD: u16 = 127;
+
+/// Named keys. Letters, digits and punctuation are looked up by [`key_for_char`] instead.
+const NAMED_KEYS: &[(&str, u16)] = &[
+    ("esc", 1),
+    ("backspace", 14),
+    ("tab", 15),
+    ("enter", 28),
+    ("ctrl", 29),
+    ("leftctrl", 29),
+    ("shift", KEY_LEFTSHIFT),
+    ("leftshift", KEY_LEFTSHIFT),
+    ("rightshift", 54),
+    ("alt", 56),
+    ("leftalt", 56),
+    ("space", 57),
+    ("capslock", 58),
+    ("f1", 59),
+    ("f2", 60),
+    ("f3", 61),
+    ("f4", 62),
+    ("f5", 63),
+    ("f6", 64),
+    ("f7", 65),
+    ("f8", 66),
+    ("f9", 67),
+    ("f10", 68),
+    ("f11", 87),
+    ("f12", 88),
+    ("rightctrl", 97),
+    ("rightalt", 100),
+    ("home", 102),
+    ("up", 103),
+    ("pageup", 104),
+    ("left", 105),
+    ("right", 106),
+    ("end", 107),
+    ("down", 108),
+    ("pagedown", 109),
+    ("insert", 110),
+    ("delete", 111),
+    ("meta", 125),
+    ("leftmeta", 125),
+];
+
+/// The keys of the letter rows, from `q` to `m`.
+const LETTERS: &[(char, u16)] = &[
+    ('q', 16),
+    ('w', 17),
+    ('e', 18),
+    ('r', 19),
+    ('t', 20),
+    ('y', 21),
+    ('u', 22),
+    ('i', 23),
+    ('o', 24),
+    ('p', 25),
+    ('a', 30),
+    ('s', 31),
+    ('d', 32),
+    ('f', 33),
+    ('g', 34),
+    ('h', 35),
+    ('j', 36),
+    ('k', 37),
+    ('l', 38),
+    ('z', 44),
+    ('x', 45),
+    ('c', 46),
+    ('v', 47),
+    ('b', 48),
+    ('n', 49),
+    ('m', 50),
+];
+
+/// Characters produced without and with shift.
+const SYMBOLS: &[(char, char, u16)] = &[
+    ('1', '!', 2),
+    ('2', '@', 3),
+    ('3', '#', 4),
+    ('4', '$', 5),
+    ('5', '%', 6),
+    ('6', '^', 7),
+    ('7', '&', 8),
+    ('8', '*', 9),
+    ('9', '(', 10),
+    ('0', ')', 11),
+    ('-', '_', 12),
+    ('=', '+', 13),
+    ('[', '{', 26),
+    (']', '}', 27),
+    (';', ':', 39),
+    ('\'', '"', 40),
+    ('`', '~', 41),
+    ('\\', '|', 43),
+    (',', '<', 51),
+    ('.', '>', 52),
+    ('/', '?', 53),
+];
+
+/// The key typing `c` and whether shift has to be held for it.
+pub fn key_for_char(c: char) -> Option<(u16, bool)> {
+    match c {
+        '\n' => return Some((28, false)),
+        '\t' => return Some((15, false)),
+        ' ' => return Some((57, false)),
+        '\x08' => return Some((14, false)),
+        '\x1b' => return Some((1, false)),
+        _ => {}
+    }
+    let lower = c.to_ascii_lowercase();
+    if let Some((_, code)) = LETTERS.iter().find(|(letter, _)| *letter == lower) {
+        return Some((*code, c.is_ascii_uppercase()));
+    }
+    SYMBOLS.iter().find_map(|(plain, shifted, code)| {
+        if *plain == c {
+            Some((*code, false))
+        } else if *shifted == c {
+            Some((*code, true))
+        } else {
+            None
+        }
+    })
+}
+
+/// The key called `name`, either a named key such as `enter` or a single character key.
+pub fn key_by_name(name: &str) -> Option<u16> {
+    let lower = name.to_ascii_lowercase();
+    if let Some((_, code)) = NAMED_KEYS.iter().find(|(key, _)| *key == lower) {
+        return Some(*code);
+    }
+    let mut chars = lower.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => key_for_char(c).map(|(code, _)| code),
+        _ => None,
+    }
+}
@@ -0,0 +1,415 @@
+//! The virtio input device (section 5.8 of the virtio 1.2 specification). A keyboard or an
+//! absolute pointer (tablet) whose events come from the host, either through an [`InputHandle`]
+//! or from a [`Script`] played back in emulated time.
+pub mod keys;
+pub mod script;
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use super::queue::Virtqueue;
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+use crate::bus::BusView;
+use crate::events::Timer;
+use keys::{ABS_X, ABS_Y, EV_ABS, EV_KEY, EV_SYN, KEY_LEFTSHIFT, KEY_MAX_KEYBOARD, SYN_REPORT};
+pub use script::{Action, Button, Script, ScriptEntry};
+
+/// The virtio device type of an input device.
+const INPUT_DEVICE_ID: u32 = 18;
+
+const EVENT_QUEUE: u16 = 0;
+const STATUS_QUEUE: u16 = 1;
+
+// Values of the select field of the configuration.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+/// The offset of the union in `struct virtio_input_config`.
+const CONFIG_UNION: u64 = 8;
+/// The size of `struct virtio_input_event`.
+const EVENT_SIZE: usize = 8;
+/// Events waiting for buffers are dropped past this.
+const MAX_PENDING: usize = 4096;
+
+/// A `struct virtio_input_event`, which mirrors the Linux `struct input_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl InputEvent {
+    fn new(kind: u16, code: u16, value: u32) -> Self {
+        Self { kind, code, value }
+    }
+
+    fn to_bytes(self) -> [u8; EVENT_SIZE] {
+        let mut bytes = [0; EVENT_SIZE];
+        bytes[0..2].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.code.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+}
+
+/// The kind of an input device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    Keyboard,
+    /// An absolute pointer reporting positions in `0..width` and `0..height`, usually the size of
+    /// the framebuffer.
+    Tablet {
+        width: u32,
+        height: u32,
+    },
+}
+
+/// Injects actions into a [`VirtioInput`] from the host, possibly from another thread.
+#[derive(Clone)]
+pub struct InputHandle {
+    tx: Sender<Action>,
+}
+
+impl InputHandle {
+    /// Perform `action` when the transport next polls the device.
+    pub fn send(&self, action: Action) -> Result<()> {
+        self.tx.send(action).context("the input device was dropped")
+    }
+
+    pub fn type_text(&self, text: &str) -> Result<()> {
+        self.send(Action::Type(text.to_string()))
+    }
+
+    pub fn move_to(&self, x: u32, y: u32) -> Result<()> {
+        self.send(Action::Move { x, y })
+    }
+
+    pub fn click(&self, x: u32, y: u32, button: Button) -> Result<()> {
+        self.send(Action::Click { x, y, button })
+    }
+}
+
+/// A virtio keyboard or tablet.
+pub struct VirtioInput {
+    kind: InputKind,
+    name: String,
+    select: u8,
+    subsel: u8,
+    /// Actions sent through an [`InputHandle`].
+    rx: Receiver<Action>,
+    tx: Sender<Action>,
+    script: Script,
+    /// The next entry of the script to perform.
+    next_entry: usize,
+    /// Fires when the next entry of the script is due.
+    timer: Timer,
+    /// Events waiting for buffers in the event queue.
+    pending: VecDeque<InputEvent>,
+}
+
+impl VirtioInput {
+    pub fn new(kind: InputKind) -> Self {
+        let name = match kind {
+            InputKind::Keyboard => "riscv-vm keyboard",
+            InputKind::Tablet { .. } => "riscv-vm tablet",
+        };
+        let (tx, rx) = mpsc::channel();
+        Self {
+            kind,
+            name: name.to_string(),
+            select: 0,
+            subsel: 0,
+            rx,
+            tx,
+            script: Script::default(),
+            next_entry: 0,
+            timer: Timer::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn keyboard() -> Self {
+        Self::new(InputKind::Keyboard)
+    }
+
+    pub fn tablet(width: u32, height: u32) -> Self {
+        Self::new(InputKind::Tablet { width, height })
+    }
+
+    pub fn kind(&self) -> InputKind {
+        self.kind
+    }
+
+    /// A handle injecting actions into the device.
+    pub fn handle(&self) -> InputHandle {
+        InputHandle {
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Play `script` back. Entries in the past are performed as soon as the bus runs its events,
+    /// actions from an [`InputHandle`] when the transport polls the device. A keyboard only
+    /// performs the keyboard actions and a tablet only the pointer actions, so the same script
+    /// can be given to both.
+    pub fn set_script(&mut self, script: Script) {
+        self.script = script;
+        self.next_entry = 0;
+        self.schedule();
+    }
+
+    /// Set the timer to the next entry of the script.
+    fn schedule(&self) {
+        if let Some(entry) = self.script.entries().get(self.next_entry) {
+            self.timer.wake_at(entry.time_ns);
+        }
+    }
+
+    /// Whether every entry of the script was performed.
+    pub fn script_done(&self) -> bool {
+        self.next_entry == self.script.entries().len()
+    }
+
+    /// Queue the events for `action`, if this kind of device performs it.
+    pub fn perform(&mut self, action: &Action) {
+        let events = match (self.kind, action) {
+            (InputKind::Keyboard, Action::Type(text)) => {
+                let mut events = Vec::new();
+                for c in text.chars() {
+                    let Some((key, shift)) = keys::key_for_char(c) else {
+                        warn!("Cannot type {c:?}");
+                        continue;
+                    };
+                    if shift {
+                        events.extend(Self::key(KEY_LEFTSHIFT, true));
+                    }
+                    events.extend(Self::key(key, true));
+                    events.extend(Self::key(key, false));
+                    if shift {
+                        events.extend(Self::key(KEY_LEFTSHIFT, false));
+                    }
+                }
+                events
+            }
+            (InputKind::Keyboard, Action::Key(keys)) => {
+                let mut events = Vec::new();
+                for key in keys {
+                    events.extend(Self::key(*key, true));
+                }
+                for key in keys.iter().rev() {
+                    events.extend(Self::key(*key, false));
+                }
+                events
+            }
+            (InputKind::Keyboard, Action::Press(key)) => Self::key(*key, true).to_vec(),
+            (InputKind::Keyboard, Action::Release(key)) => Self::key(*key, false).to_vec(),
+            (InputKind::Tablet { .. }, Action::Move { x, y }) => self.move_to(*x, *y).to_vec(),
+            (InputKind::Tablet { .. }, Action::Click { x, y, button }) => {
+                let mut events = self.move_to(*x, *y).to_vec();
+                events.extend(Self::key(button.code(), true));
+                events.extend(Self::key(button.code(), false));
+                events
+            }
+            _ => return,
+        };
+        if self.pending.len() + events.len() > MAX_PENDING {
+            warn!("Dropping input events, the guest does not read them");
+            return;
+        }
+        self.pending.extend(events);
+    }
+
+    /// The events of pressing or releasing `key`.
+    fn key(key: u16, pressed: bool) -> [InputEvent; 2] {
+        [
+            InputEvent::new(EV_KEY, key, pressed as u32),
+            InputEvent::new(EV_SYN, SYN_REPORT, 0),
+        ]
+    }
+
+    fn move_to(&self, x: u32, y: u32) -> [InputEvent; 3] {
+        let (width, height) = match self.kind {
+            InputKind::Tablet { width, height } => (width, height),
+            InputKind::Keyboard => (1, 1),
+        };
+        [
+            InputEvent::new(EV_ABS, ABS_X, x.min(width.saturating_sub(1))),
+            InputEvent::new(EV_ABS, ABS_Y, y.min(height.saturating_sub(1))),
+            InputEvent::new(EV_SYN, SYN_REPORT, 0),
+        ]
+    }
+
+    /// The key codes the device reports.
+    fn key_codes(&self) -> Vec<u16> {
+        match self.kind {
+            InputKind::Keyboard => (1..=KEY_MAX_KEYBOARD).collect(),
+            InputKind::Tablet { .. } => vec![
+                Button::Left.code(),
+                Button::Right.code(),
+                Button::Middle.code(),
+            ],
+        }
+    }
+
+    /// The contents of the configuration union for the current selection.
+    fn config(&self) -> Vec<u8> {
+        match (self.select, self.subsel) {
+            (VIRTIO_INPUT_CFG_ID_NAME, 0) => self.name.as_bytes().to_vec(),
+            (VIRTIO_INPUT_CFG_ID_SERIAL, 0) => b"0".to_vec(),
+            (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => {
+                // BUS_VIRTUAL, with vendor, product and version left at 0 like QEMU does
+                let mut ids = vec![0; 8];
+                ids[0..2].copy_from_slice(&0x06u16.to_le_bytes());
+                ids
+            }
+            (VIRTIO_INPUT_CFG_EV_BITS, kind) => {
+                let codes = match kind as u16 {
+                    EV_KEY => self.key_codes(),
+                    EV_ABS if matches!(self.kind, InputKind::Tablet { .. }) => vec![ABS_X, ABS_Y],
+                    _ => Vec::new(),
+                };
+                let Some(max) = codes.iter().max() else {
+                    return Vec::new();
+                };
+                let mut bitmap = vec![0; *max as usize / 8 + 1];
+                for code in codes {
+                    bitmap[code as usize / 8] |= 1 << (code % 8);
+                }
+                bitmap
+            }
+            (VIRTIO_INPUT_CFG_ABS_INFO, axis @ 0..=1) => {
+                let InputKind::Tablet { width, height } = self.kind else {
+                    return Vec::new();
+                };
+                let max = if axis as u16 == ABS_X { width } else { height };
+                // min, max, fuzz, flat, res
+                [0, max.saturating_sub(1), 0, 0, 0]
+                    .iter()
+                    .flat_map(|value: &u32| value.to_le_bytes())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Move pending events into the event queue.
+    fn deliver(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        let mut used = false;
+        let queue = &mut queues[EVENT_QUEUE as usize];
+        while let Some(event) = self.pending.front() {
+            let Some(chain) = queue.pop(memory)? else {
+                break;
+            };
+            let len = chain.write(memory, &event.to_bytes())?;
+            queue.push(memory, chain.head, len as u32)?;
+            self.pending.pop_front();
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioInput {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn device_id(&self) -> u32 {
+        INPUT_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // struct virtio_input_config { u8 select; u8 subsel; u8 size; u8 reserved[5]; union u; }
+        match offset {
+            0 => self.select,
+            1 => self.subsel,
+            2 => self.config().len().min(128) as u8,
+            CONFIG_UNION.. => self
+                .config()
+                .get((offset - CONFIG_UNION) as usize)
+                .copied()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, value: u8) {
+        match offset {
+            0 => self.select = value,
+            1 => self.subsel = value,
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.select = 0;
+        self.subsel = 0;
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.timer = timer;
+        self.schedule();
+    }
+
+    fn event(&mut self) {
+        while let Ok(action) = self.rx.try_recv() {
+            self.perform(&action);
+        }
+        while let Some(entry) = self.script.entries().get(self.next_entry) {
+            if entry.time_ns > self.timer.now() {
+                break;
+            }
+            let action = entry.action.clone();
+            info!("Input script at {} ns: {action:?}", entry.time_ns);
+            self.perform(&action);
+            self.next_entry += 1;
+        }
+        self.schedule();
+    }
+
+    fn process_queue(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool> {
+        match queue {
+            EVENT_QUEUE => self.deliver(queues, memory),
+            STATUS_QUEUE => {
+                // LED updates from the driver, there are no LEDs to show them on
+                let status = &mut queues[STATUS_QUEUE as usize];
+                let mut used = false;
+                while let Some(chain) = status.pop(memory)? {
+                    status.push(memory, chain.head, 0)?;
+                    used = true;
+                }
+                Ok(used)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        if self.pending.is_empty() {
+            return Ok(false);
+        }
+        self.deliver(queues, memory)
+    }
+}
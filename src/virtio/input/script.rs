@@ -0,0 +1,347 @@
+//! Timelines of input actions. A script is a list of entries of the form `at <time> <action>`,
+//! separated by commas or new lines, for example:
+//!
+//! ```text
+//! at 2s type 'ls\n', at 3s click 100,200
+//! at 3500ms key ctrl+c   # comments run to the end of the line
+//! ```
+//!
+//! Times are emulated time since the machine was created, with one of the units `s`, `ms`, `us`
+//! or `ns`. The actions are:
+//!
+//! - `type '<text>'` types the text, quoted with `'` or `"`, with `\n`, `\t`, `\\` and quote
+//!   escapes.
+//! - `key <key>[+<key>...]` presses the keys in order and releases them in reverse, e.g.
+//!   `key enter` or `key ctrl+alt+delete`.
+//! - `press <key>` and `release <key>` press or release a single key.
+//! - `move <x>,<y>` moves the pointer.
+//! - `click <x>,<y> [left|right|middle]` moves the pointer and clicks a button, left by default.
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use super::keys::{self, key_by_name, key_for_char};
+
+/// A pointer button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+}
+
+impl Button {
+    /// The key code of the button.
+    pub fn code(&self) -> u16 {
+        match self {
+            Button::Left => keys::BTN_LEFT,
+            Button::Right => keys::BTN_RIGHT,
+            Button::Middle => keys::BTN_MIDDLE,
+        }
+    }
+}
+
+/// Something the user does with the keyboard or the pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Type text.
+    Type(String),
+    /// Press the keys in order and release them in reverse.
+    Key(Vec<u16>),
+    Press(u16),
+    Release(u16),
+    /// Move the pointer to an absolute position.
+    Move {
+        x: u32,
+        y: u32,
+    },
+    /// Move the pointer and click a button.
+    Click {
+        x: u32,
+        y: u32,
+        button: Button,
+    },
+}
+
+impl Action {
+    /// Whether the action is performed by a pointer rather than a keyboard.
+    pub fn is_pointer(&self) -> bool {
+        matches!(self, Action::Move { .. } | Action::Click { .. })
+    }
+}
+
+/// An action and when it happens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptEntry {
+    /// Nanoseconds of emulated time since the machine was created.
+    pub time_ns: u64,
+    pub action: Action,
+}
+
+/// A timeline of input actions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    entries: Vec<ScriptEntry>,
+}
+
+impl Script {
+    /// Parse a script, see the module documentation for the syntax.
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let mut entries = Vec::new();
+        while let Some(token) = parser.next() {
+            match &token.kind {
+                TokenKind::Separator => continue,
+                TokenKind::Word(word) if word == "at" => {}
+                kind => bail!("line {}: expected `at`, found {kind}", token.line),
+            }
+            let time_ns = parser.time()?;
+            let action = parser.action()?;
+            entries.push(ScriptEntry { time_ns, action });
+            parser.end_of_entry()?;
+        }
+        // keep the order of entries with the same time
+        entries.sort_by_key(|entry| entry.time_ns);
+        Ok(Self { entries })
+    }
+
+    /// Read and parse the script in `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&source).with_context(|| format!("invalid input script {}", path.display()))
+    }
+
+    /// The entries ordered by time.
+    pub fn entries(&self) -> &[ScriptEntry] {
+        &self.entries
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Text(String),
+    /// A comma or a new line.
+    Separator,
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TokenKind::Word(word) => write!(f, "`{word}`"),
+            TokenKind::Text(text) => write!(f, "{text:?}"),
+            TokenKind::Separator => write!(f, "a separator"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | ',' => {
+                tokens.push(Token {
+                    kind: TokenKind::Separator,
+                    line,
+                });
+                if c == '\n' {
+                    line += 1;
+                }
+            }
+            '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '\'' | '"' => {
+                let start = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => bail!("line {start}: unterminated text"),
+                        Some(end) if end == c => break,
+                        Some('\\') => text.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('b') => '\x08',
+                            Some('e') => '\x1b',
+                            Some(escaped @ ('\\' | '\'' | '"')) => escaped,
+                            other => bail!("line {line}: unknown escape sequence \\{other:?}"),
+                        }),
+                        Some(other) => {
+                            if other == '\n' {
+                                line += 1;
+                            }
+                            text.push(other);
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Text(text),
+                    line: start,
+                });
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, ',' | '#' | '\'' | '"'))
+                {
+                    word.push(c);
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Word(word),
+                    line,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// The line of the last token, for errors at the end of the script.
+    fn last_line(&self) -> usize {
+        self.tokens.last().map_or(1, |token| token.line)
+    }
+
+    fn word(&mut self, what: &str) -> Result<(String, usize)> {
+        match self.next() {
+            Some(Token {
+                kind: TokenKind::Word(word),
+                line,
+            }) => Ok((word, line)),
+            Some(token) => bail!("line {}: expected {what}, found {}", token.line, token.kind),
+            None => bail!("line {}: expected {what}", self.last_line()),
+        }
+    }
+
+    fn time(&mut self) -> Result<u64> {
+        let (word, line) = self.word("a time")?;
+        let split = word
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(word.len());
+        let (number, unit) = word.split_at(split);
+        let scale = match unit {
+            "s" => 1e9,
+            "ms" => 1e6,
+            "us" => 1e3,
+            "ns" => 1.0,
+            _ => bail!("line {line}: `{word}` is not a time, use a unit of s, ms, us or ns"),
+        };
+        let number: f64 = number
+            .parse()
+            .with_context(|| format!("line {line}: `{word}` is not a time"))?;
+        Ok((number * scale).round() as u64)
+    }
+
+    fn key(name: &str, line: usize) -> Result<u16> {
+        key_by_name(name).with_context(|| format!("line {line}: unknown key `{name}`"))
+    }
+
+    /// Parse `<x>,<y>`, which the tokenizer split at the comma.
+    fn position(&mut self) -> Result<(u32, u32)> {
+        let (x, line) = self.word("an x coordinate")?;
+        if !matches!(
+            self.next().map(|token| token.kind),
+            Some(TokenKind::Separator)
+        ) {
+            bail!("line {line}: expected `,` after the x coordinate");
+        }
+        let (y, _) = self.word("a y coordinate")?;
+        let parse = |value: &str| {
+            value
+                .parse::<u32>()
+                .with_context(|| format!("line {line}: `{value}` is not a coordinate"))
+        };
+        Ok((parse(&x)?, parse(&y)?))
+    }
+
+    fn action(&mut self) -> Result<Action> {
+        let (word, line) = self.word("an action")?;
+        Ok(match word.as_str() {
+            "type" => {
+                let text = match self.next() {
+                    Some(Token {
+                        kind: TokenKind::Text(text),
+                        ..
+                    }) => text,
+                    _ => bail!("line {line}: expected quoted text after `type`"),
+                };
+                if let Some(c) = text.chars().find(|c| key_for_char(*c).is_none()) {
+                    bail!("line {line}: {c:?} cannot be typed");
+                }
+                Action::Type(text)
+            }
+            "key" => {
+                let (keys, line) = self.word("a key")?;
+                Action::Key(
+                    keys.split('+')
+                        .map(|key| Self::key(key, line))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            "press" | "release" => {
+                let (key, line) = self.word("a key")?;
+                let key = Self::key(&key, line)?;
+                if word == "press" {
+                    Action::Press(key)
+                } else {
+                    Action::Release(key)
+                }
+            }
+            "move" => {
+                let (x, y) = self.position()?;
+                Action::Move { x, y }
+            }
+            "click" => {
+                let (x, y) = self.position()?;
+                let button = match self.tokens.get(self.position).map(|token| &token.kind) {
+                    Some(TokenKind::Word(button)) if button != "at" => {
+                        let button = match button.as_str() {
+                            "left" => Button::Left,
+                            "right" => Button::Right,
+                            "middle" => Button::Middle,
+                            _ => bail!("line {line}: unknown button `{button}`"),
+                        };
+                        self.position += 1;
+                        button
+                    }
+                    _ => Button::Left,
+                };
+                Action::Click { x, y, button }
+            }
+            _ => bail!("line {line}: unknown action `{word}`"),
+        })
+    }
+
+    fn end_of_entry(&mut self) -> Result<()> {
+        match self.next() {
+            None
+            | Some(Token {
+                kind: TokenKind::Separator,
+                ..
+            }) => Ok(()),
+            Some(token) => bail!("line {}: unexpected {}", token.line, token.kind),
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! The virtio module contains the virtio-mmio transport (version 2) and the virtio devices that
+//! sit behind it. A device only implements [`VirtioDevice`]; register decoding, feature
+//! negotiation and queue setup are handled by [`VirtioMmio`].
+pub mod console;
+pub mod input;
+pub mod net;
+pub mod p9;
+pub mod queue;
+pub mod rng;
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::bus::{BusView, Device, IrqLine, IrqTarget, VirtualDevice};
+use crate::device_tree::NodeContext;
+use crate::events::Timer;
+use crate::memory::dram::Sizes;
+use crate::memory::virtual_memory::MemorySize;
+use queue::Virtqueue;
+
+/// The address of the first virtio-mmio slot on the virt board.
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+/// The size of a single virtio-mmio slot.
+pub const VIRTIO_SIZE: u64 = 0x1000;
+
+/// How often a transport polls its device for host input once the driver is ready, in
+/// nanoseconds of emulated time.
+pub const POLL_INTERVAL_NS: u64 = 100_000;
+
+/// The device complies with the virtio 1.x specification.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+/// The non-legacy virtio-mmio interface.
+const VERSION: u32 = 2;
+/// "QEMU" in little endian, which is what most guests expect to see.
+const VENDOR_ID: u32 = 0x554d_4551;
+/// The largest queue a driver may configure.
+const QUEUE_SIZE_MAX: u16 = 256;
+
+// virtio-mmio register offsets.
+const MAGIC: u64 = 0x000;
+const VERSION_REG: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+// Device status bits.
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+// Interrupt status bits.
+/// The device used a buffer in at least one of the queues.
+const INTERRUPT_USED_BUFFER: u32 = 1;
+/// The configuration of the device has changed.
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// A device that can be exposed to the guest through the virtio-mmio transport.
+pub trait VirtioDevice {
+    fn as_any(&self) -> &dyn std::any::Any;
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
+
+    /// The virtio device type (console = 3, entropy = 4, ...).
+    fn device_id(&self) -> u32;
+    /// The feature bits offered to the driver.
+    fn features(&self) -> u64;
+    /// The number of virtqueues the device uses.
+    fn queue_count(&self) -> usize;
+
+    /// Read a byte from the device specific configuration space.
+    fn read_config(&self, offset: u64) -> u8;
+    /// Write a byte to the device specific configuration space.
+    fn write_config(&mut self, _offset: u64, _value: u8) {}
+
+    /// The driver finished initialization and accepted `features`.
+    fn activate(&mut self, _features: u64) {}
+    /// The driver reset the device.
+    fn reset(&mut self) {}
+    /// Hand the device the timer of its transport. The device sets its deadlines with
+    /// [`Timer::wake_at`], which leaves earlier deadlines of the transport alone.
+    fn connect_timer(&mut self, _timer: Timer) {}
+    /// Called whenever the timer of the transport fires, also before the driver is ready.
+    fn event(&mut self) {}
+
+    /// The driver made new buffers available in `queue`. Returns `true` when buffers were
+    /// returned to the driver and it should be interrupted.
+    fn process_queue(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool>;
+
+    /// Called every [`POLL_INTERVAL_NS`] and after the driver notified a queue, once the driver
+    /// is ready, so the device can forward host input. Returns `true` when buffers were returned
+    /// to the driver.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _memory: &mut BusView) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The virtio-mmio transport wrapping a single [`VirtioDevice`].
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Virtqueue>,
+
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    interrupt_status: u32,
+
+    /// Queues the driver notified since the last call to `service`.
+    notified: Vec<u16>,
+    irq: IrqLine,
+    timer: Timer,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.queue_count())
+            .map(|_| Virtqueue::new(QUEUE_SIZE_MAX))
+            .collect();
+        Self {
+            device,
+            queues,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+            notified: Vec::new(),
+            irq: IrqLine::new(),
+            timer: Timer::new(),
+        }
+    }
+
+    /// Wrap `device` in a transport mapped at `base`. Like on the virt board, the transport in
+    /// slot `n` from [`VIRTIO_BASE`] on interrupts on PLIC source `n + 1`.
+    pub fn new_device(device: Box<dyn VirtioDevice>, base: u64) -> VirtualDevice {
+        let slot = base.saturating_sub(VIRTIO_BASE) / VIRTIO_SIZE;
+        VirtualDevice::new(Box::new(Self::new(device)), base, VIRTIO_SIZE)
+            .with_irqs([IrqTarget::Plic(slot as u32 + 1)])
+    }
+
+    /// The device behind the transport.
+    pub fn device<T: VirtioDevice + 'static>(&self) -> Option<&T> {
+        self.device.as_any().downcast_ref::<T>()
+    }
+
+    /// The device behind the transport.
+    pub fn device_mut<T: VirtioDevice + 'static>(&mut self) -> Option<&mut T> {
+        self.device.as_any_mut().downcast_mut::<T>()
+    }
+
+    /// Whether the transport is asserting its interrupt.
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn driver_ok(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_DEVICE_NEEDS_RESET == 0
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        info!("Resetting virtio device {}", self.device.device_id());
+        self.status = 0;
+        self.driver_features = 0;
+        self.interrupt_status = 0;
+        self.queue_sel = 0;
+        self.notified.clear();
+        for queue in &mut self.queues {
+            *queue = Virtqueue::new(QUEUE_SIZE_MAX);
+        }
+        self.device.reset();
+    }
+
+    /// Process the queues the driver notified and poll the device for host input.
+    pub fn service(&mut self, memory: &mut BusView) {
+        if !self.driver_ok() {
+            return;
+        }
+
+        let mut used = false;
+        let mut result = Ok(());
+        for queue in std::mem::take(&mut self.notified) {
+            match self.device.process_queue(queue, &mut self.queues, memory) {
+                Ok(returned) => used |= returned,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            match self.device.poll(&mut self.queues, memory) {
+                Ok(returned) => used |= returned,
+                Err(e) => result = Err(e),
+            }
+        }
+
+        if used {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        if let Err(e) = result {
+            error!("virtio device {} failed: {e:#}", self.device.device_id());
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+        self.irq.set(self.interrupt_pending());
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            MAGIC => MAGIC_VALUE,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device.features() as u32,
+                1 => (self.device.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |q| q.max_size() as u32),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => {
+                warn!("Read from unknown virtio register {offset:#x}");
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                self.driver_features &= !(0xffff_ffff << shift);
+                self.driver_features |= (value as u64) << shift;
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.set_size(value as u16);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value == 1;
+                }
+            }
+            QUEUE_NOTIFY => {
+                if (value as usize) < self.queues.len() && !self.notified.contains(&(value as u16))
+                {
+                    self.notified.push(value as u16);
+                }
+                self.timer.wake_at(self.timer.now());
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => {
+                if value == 0 {
+                    self.reset();
+                    return;
+                }
+                let activated =
+                    value & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0;
+                self.status = value;
+                if activated {
+                    info!(
+                        "virtio device {} activated with features {:#x}",
+                        self.device.device_id(),
+                        self.driver_features
+                    );
+                    self.device.activate(self.driver_features);
+                    self.timer.wake_at(self.timer.now());
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                let Some(queue) = self.selected_queue() else {
+                    return;
+                };
+                let (address, high) = match offset {
+                    QUEUE_DESC_LOW => (&mut queue.desc, false),
+                    QUEUE_DESC_HIGH => (&mut queue.desc, true),
+                    QUEUE_DRIVER_LOW => (&mut queue.driver, false),
+                    QUEUE_DRIVER_HIGH => (&mut queue.driver, true),
+                    QUEUE_DEVICE_LOW => (&mut queue.device, false),
+                    _ => (&mut queue.device, true),
+                };
+                *address = if high {
+                    (*address & 0xffff_ffff) | ((value as u64) << 32)
+                } else {
+                    (*address & !0xffff_ffff) | value as u64
+                };
+            }
+            _ => warn!("Write to unknown virtio register {offset:#x}: {value:#x}"),
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, addr: u64, size: Sizes) -> Result<MemorySize> {
+        if addr >= CONFIG {
+            let offset = addr - CONFIG;
+            let len = match size {
+                Sizes::Byte => 1,
+                Sizes::HalfWord => 2,
+                Sizes::Word => 4,
+            };
+            let mut value = 0;
+            for i in 0..len {
+                value |= (self.device.read_config(offset + i) as u32) << (8 * i);
+            }
+            return Ok(value);
+        }
+        Ok(self.read_register(addr))
+    }
+
+    fn store(&mut self, addr: u64, size: Sizes, value: MemorySize) -> Result<()> {
+        if addr >= CONFIG {
+            let offset = addr - CONFIG;
+            let len = match size {
+                Sizes::Byte => 1,
+                Sizes::HalfWord => 2,
+                Sizes::Word => 4,
+            };
+            for i in 0..len {
+                self.device
+                    .write_config(offset + i, (value >> (8 * i)) as u8);
+            }
+            return Ok(());
+        }
+        self.write_register(addr, value);
+        self.irq.set(self.interrupt_pending());
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        VirtioMmio::reset(self);
+        self.irq.lower();
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.device.connect_timer(timer.clone());
+        self.timer = timer;
+    }
+
+    fn event(&mut self, memory: &mut BusView) {
+        self.device.event();
+        self.service(memory);
+        if self.driver_ok() {
+            self.timer.wake_at(self.timer.now() + POLL_INTERVAL_NS);
+        }
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        if index == 0 {
+            self.irq = line;
+        }
+    }
+
+    fn dts_node(&self, node: &NodeContext) -> Option<String> {
+        Some(format!(
+            r#"
+	virtio_mmio@{:x} {{
+{}		compatible = "virtio,mmio";
+	}};
+"#,
+            node.base(),
+            node.properties(),
+        ))
+    }
+}
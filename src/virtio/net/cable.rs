@@ -0,0 +1,158 @@
+//! A virtual cable over a Unix socket. It connects the network devices of two machines, either in
+//! the same process or in different ones. Every frame is sent as a little endian `u32` length
+//! followed by the frame itself.
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+use super::NetBackend;
+
+/// Frames larger than this can not be ethernet frames and mean the stream is corrupted.
+const MAX_FRAME: usize = 65536;
+
+/// One end of a cable.
+pub struct CableBackend {
+    listener: Option<UnixListener>,
+    stream: Option<UnixStream>,
+    /// Bytes received that do not form a complete frame yet.
+    input: Vec<u8>,
+    /// Bytes the socket did not accept yet.
+    output: Vec<u8>,
+}
+
+impl CableBackend {
+    fn with_stream(listener: Option<UnixListener>, stream: Option<UnixStream>) -> Result<Self> {
+        if let Some(stream) = &stream {
+            stream.set_nonblocking(true)?;
+        }
+        Ok(Self {
+            listener,
+            stream,
+            input: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    /// Create both ends of a cable, for two machines in the same process.
+    pub fn pair() -> Result<(Self, Self)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((
+            Self::with_stream(None, Some(a))?,
+            Self::with_stream(None, Some(b))?,
+        ))
+    }
+
+    /// Wait for the other end at `path`. The peer may connect at any time and reconnect after
+    /// it went away.
+    pub fn listen(path: &Path) -> Result<Self> {
+        // a socket left behind by a previous run would make bind fail
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to bind {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        Self::with_stream(Some(listener), None)
+    }
+
+    /// Plug into the end listening at `path`.
+    pub fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("failed to connect to {}", path.display()))?;
+        Self::with_stream(None, Some(stream))
+    }
+
+    fn disconnect(&mut self) {
+        info!("Network cable unplugged");
+        self.stream = None;
+        self.input.clear();
+        self.output.clear();
+    }
+
+    /// Accept a peer if the cable is listening and nobody is connected.
+    fn accept(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+        if let Some(listener) = &self.listener {
+            if let Ok((stream, _)) = listener.accept() {
+                stream.set_nonblocking(true)?;
+                info!("Network cable plugged in");
+                self.stream = Some(stream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write as much of the pending output as the socket accepts.
+    fn flush(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            self.output.clear();
+            return;
+        };
+        while !self.output.is_empty() {
+            match stream.write(&self.output) {
+                Ok(0) => return self.disconnect(),
+                Ok(len) => {
+                    self.output.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return self.disconnect(),
+            }
+        }
+    }
+}
+
+impl NetBackend for CableBackend {
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        self.accept()?;
+        if self.stream.is_none() {
+            // frames sent over an unplugged cable are lost
+            return Ok(());
+        }
+        self.output
+            .extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        self.output.extend_from_slice(frame);
+        self.flush();
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+        self.accept()?;
+        self.flush();
+
+        if let Some(stream) = &mut self.stream {
+            let mut buffer = [0; 4096];
+            loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) => {
+                        self.disconnect();
+                        break;
+                    }
+                    Ok(len) => self.input.extend_from_slice(&buffer[..len]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        self.disconnect();
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.input.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(self.input[..4].try_into().unwrap()) as usize;
+        if len > MAX_FRAME {
+            self.disconnect();
+            bail!("corrupted frame of {len} bytes on the network cable");
+        }
+        if self.input.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.input[4..4 + len].to_vec();
+        self.input.drain(..4 + len);
+        Ok(Some(frame))
+    }
+}
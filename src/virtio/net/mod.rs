@@ -0,0 +1,210 @@
+//! The virtio network device (section 5.1 of the virtio 1.1 specification). Frames are exchanged
+//! with a [`NetBackend`], so guests can be networked without touching the host network.
+pub mod cable;
+pub mod channel;
+pub mod pcap;
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use log::warn;
+
+use super::queue::{DescriptorChain, Virtqueue};
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+use crate::bus::BusView;
+
+pub use cable::CableBackend;
+pub use channel::ChannelBackend;
+pub use pcap::PcapBackend;
+
+/// The virtio device type of a network card.
+const NET_DEVICE_ID: u32 = 1;
+
+/// Device has given MAC address.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// Driver can merge receive buffers.
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+/// Configuration status field is available.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+/// The link is up.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The size of `struct virtio_net_hdr`, which includes `num_buffers` for virtio 1.x devices.
+const HEADER_SIZE: usize = 12;
+/// The offset of `num_buffers` in the header.
+const NUM_BUFFERS: usize = 10;
+/// The backend is not read while this many frames wait for receive buffers, so further frames
+/// stay queued in the backend until the guest catches up.
+const MAX_PENDING: usize = 256;
+
+/// Where the frames of a virtio network device go to and come from.
+pub trait NetBackend {
+    /// Send a frame the guest transmitted.
+    fn send(&mut self, frame: &[u8]) -> Result<()>;
+    /// The next frame for the guest, if there is one. Must not block.
+    fn receive(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+/// A virtio network card.
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    mergeable_buffers: bool,
+    /// Frames waiting for receive buffers.
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            mac,
+            backend,
+            mergeable_buffers: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn backend_mut(&mut self) -> &mut dyn NetBackend {
+        self.backend.as_mut()
+    }
+
+    fn transmit(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        let mut used = false;
+        let queue = &mut queues[TRANSMIT_QUEUE as usize];
+        while let Some(chain) = queue.pop(memory)? {
+            let packet = chain.read(memory)?;
+            queue.push(memory, chain.head, 0)?;
+            used = true;
+
+            // checksum and segmentation offloads are not offered, so the header carries nothing
+            match packet.get(HEADER_SIZE..) {
+                Some(frame) => self.backend.send(frame)?,
+                None => warn!("Dropping a transmitted packet without a header"),
+            }
+        }
+        Ok(used)
+    }
+
+    /// Take enough receive buffers for `len` bytes. Returns `None`, leaving the queue untouched,
+    /// when the driver did not make enough buffers available.
+    fn take_buffers(
+        &self,
+        queue: &mut Virtqueue,
+        memory: &BusView,
+        len: usize,
+    ) -> Result<Option<Vec<DescriptorChain>>> {
+        let mut chains = Vec::new();
+        let mut capacity = 0;
+        while capacity < len {
+            let Some(chain) = queue.pop(memory)? else {
+                queue.undo_pop(chains.len() as u16);
+                return Ok(None);
+            };
+            capacity += chain.writable_len();
+            chains.push(chain);
+            if !self.mergeable_buffers {
+                break;
+            }
+        }
+        Ok(Some(chains))
+    }
+
+    fn deliver(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        let mut used = false;
+        let queue = &mut queues[RECEIVE_QUEUE as usize];
+        while let Some(frame) = self.pending.front() {
+            let mut packet = vec![0; HEADER_SIZE];
+            packet.extend_from_slice(frame);
+
+            let Some(chains) = self.take_buffers(queue, memory, packet.len())? else {
+                break;
+            };
+            packet[NUM_BUFFERS..HEADER_SIZE].copy_from_slice(&(chains.len() as u16).to_le_bytes());
+
+            let mut offset = 0;
+            for chain in &chains {
+                let len = chain.write(memory, &packet[offset..])?;
+                queue.push(memory, chain.head, len as u32)?;
+                offset += len;
+            }
+            if offset < packet.len() {
+                warn!(
+                    "Receive buffer too small, truncated a frame of {} bytes",
+                    packet.len()
+                );
+            }
+
+            self.pending.pop_front();
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn device_id(&self) -> u32 {
+        NET_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC | VIRTIO_NET_F_MRG_RXBUF | VIRTIO_NET_F_STATUS
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // struct virtio_net_config { u8 mac[6]; le16 status; ... }
+        match offset {
+            0..=5 => self.mac[offset as usize],
+            6 | 7 => VIRTIO_NET_S_LINK_UP.to_le_bytes()[offset as usize - 6],
+            _ => 0,
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.mergeable_buffers = features & VIRTIO_NET_F_MRG_RXBUF != 0;
+    }
+
+    fn reset(&mut self) {
+        self.mergeable_buffers = false;
+        self.pending.clear();
+    }
+
+    fn process_queue(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool> {
+        match queue {
+            TRANSMIT_QUEUE => self.transmit(queues, memory),
+            _ => self.deliver(queues, memory),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &mut BusView) -> Result<bool> {
+        while self.pending.len() < MAX_PENDING {
+            let Some(frame) = self.backend.receive()? else {
+                break;
+            };
+            self.pending.push_back(frame);
+        }
+        self.deliver(queues, memory)
+    }
+}
@@ -0,0 +1,146 @@
+//! A backend that records transmitted frames to a pcap file and replays received frames from
+//! another one, for offline analysis and replay.
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use super::NetBackend;
+
+/// The magic number of a pcap file with microsecond timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// The magic number of a pcap file with nanosecond timestamps.
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// LINKTYPE_ETHERNET
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 65535;
+/// No capture holds records longer than this, whatever snapshot length its header declares.
+const MAX_SNAPLEN: u32 = 262_144;
+
+/// Records what the guest transmits and replays captured frames to it.
+pub struct PcapBackend {
+    output: Option<BufWriter<File>>,
+    input: Option<PcapReader>,
+}
+
+struct PcapReader {
+    file: BufReader<File>,
+    big_endian: bool,
+    /// The longest record the file may contain.
+    snaplen: u32,
+}
+
+impl PcapReader {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+        );
+        let mut header = [0; 24];
+        file.read_exact(&mut header)
+            .with_context(|| format!("{} is not a pcap file", path.display()))?;
+
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let big_endian = match magic {
+            PCAP_MAGIC | PCAP_MAGIC_NANOS => false,
+            _ if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NANOS => true,
+            _ => bail!("{} is not a pcap file", path.display()),
+        };
+
+        let mut reader = Self {
+            file,
+            big_endian,
+            snaplen: 0,
+        };
+        reader.snaplen = reader.u32(&header[16..20]).min(MAX_SNAPLEN);
+        let link_type = reader.u32(&header[20..24]);
+        if link_type != LINKTYPE_ETHERNET {
+            bail!("{} does not contain ethernet frames", path.display());
+        }
+        Ok(reader)
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut header = [0; 16];
+        match self.file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = self.u32(&header[8..12]);
+        if len > self.snaplen {
+            bail!(
+                "pcap record of {len} bytes is longer than the snapshot length {}",
+                self.snaplen
+            );
+        }
+        let mut frame = vec![0; len as usize];
+        self.file
+            .read_exact(&mut frame)
+            .context("truncated pcap record")?;
+        Ok(Some(frame))
+    }
+}
+
+impl PcapBackend {
+    /// Write transmitted frames to `output` and replay the frames of `input` to the guest. Either
+    /// side can be left out.
+    pub fn new(output: Option<&Path>, input: Option<&Path>) -> Result<Self> {
+        let output = match output {
+            Some(path) => {
+                let mut file = BufWriter::new(
+                    File::create(path)
+                        .with_context(|| format!("failed to create {}", path.display()))?,
+                );
+                file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+                file.write_all(&2u16.to_le_bytes())?; // major version
+                file.write_all(&4u16.to_le_bytes())?; // minor version
+                file.write_all(&0i32.to_le_bytes())?; // GMT offset
+                file.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+                file.write_all(&SNAPLEN.to_le_bytes())?;
+                file.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+                Some(file)
+            }
+            None => None,
+        };
+        let input = input.map(PcapReader::open).transpose()?;
+        Ok(Self { output, input })
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) -> Result<()> {
+        let Some(output) = &mut self.output else {
+            return Ok(());
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = frame.len().min(SNAPLEN as usize);
+
+        output.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        output.write_all(&time.subsec_micros().to_le_bytes())?;
+        output.write_all(&(captured as u32).to_le_bytes())?;
+        output.write_all(&(frame.len() as u32).to_le_bytes())?;
+        output.write_all(&frame[..captured])?;
+        output.flush()?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+        match &mut self.input {
+            Some(input) => input.next(),
+            None => Ok(None),
+        }
+    }
+}
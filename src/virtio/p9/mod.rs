@@ -0,0 +1,118 @@
+//! The virtio 9P transport (section 5.11 of the virtio 1.1 specification) sharing a host
+//! directory with the guest, which mounts it with
+//! `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`.
+mod server;
+mod wire;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use super::queue::Virtqueue;
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+use crate::bus::BusView;
+use server::Server;
+
+/// The virtio device type of a 9P transport.
+const P9_DEVICE_ID: u32 = 9;
+/// The mount tag is available in the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+/// The longest mount tag the configuration space can describe.
+const MAX_TAG_LEN: usize = 64;
+
+/// A virtio 9P device exporting a host directory.
+pub struct Virtio9p {
+    tag: String,
+    root: PathBuf,
+    read_only: bool,
+    server: Server,
+}
+
+impl Virtio9p {
+    /// Export `root` under the mount tag `tag`. A `read_only` export refuses every request
+    /// that would modify the host.
+    pub fn new(tag: &str, root: &Path, read_only: bool) -> Result<Self> {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            bail!("mount tag {tag:?} must be between 1 and {MAX_TAG_LEN} bytes long");
+        }
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("failed to share {}", root.display()))?;
+        if !root.is_dir() {
+            bail!("{} is not a directory", root.display());
+        }
+        Ok(Self {
+            tag: tag.to_string(),
+            server: Server::new(root.clone(), read_only),
+            root,
+            read_only,
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn device_id(&self) -> u32 {
+        P9_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1 | VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+        let tag = self.tag.as_bytes();
+        match offset {
+            0 | 1 => (tag.len() as u16).to_le_bytes()[offset as usize],
+            _ => tag.get(offset as usize - 2).copied().unwrap_or(0),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.server.reset();
+    }
+
+    fn process_queue(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool> {
+        let mut used = false;
+        let requests = &mut queues[queue as usize];
+        while let Some(chain) = requests.pop(memory)? {
+            let request = chain.read(memory)?;
+            let reply = self.server.handle(&request);
+            let len = chain.write(memory, &reply)?;
+            if len < reply.len() {
+                bail!("9P reply of {} bytes does not fit the buffer", reply.len());
+            }
+            requests.push(memory, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
@@ -0,0 +1,744 @@
+//! A 9P2000.L file server exporting a host directory. The client can never reach files outside
+//! of the exported root: names are single path components, `..` stops at the root and every
+//! path is resolved on the host before use so symbolic links can't point outside either.
+use std::collections::HashMap;
+use std::fs::{self, File, FileTimes, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use super::wire::{Qid, Reader, Writer};
+
+// Linux error numbers, which is what 9P2000.L replies with.
+pub const ENOENT: u32 = 2;
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const EACCES: u32 = 13;
+pub const EBUSY: u32 = 16;
+pub const EEXIST: u32 = 17;
+pub const EISDIR: u32 = 21;
+pub const EINVAL: u32 = 22;
+pub const EROFS: u32 = 30;
+pub const ELOOP: u32 = 40;
+pub const EPROTO: u32 = 71;
+pub const EOPNOTSUPP: u32 = 95;
+
+/// An error reported to the client with Rlerror.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u32);
+
+impl From<io::Error> for Errno {
+    fn from(e: io::Error) -> Self {
+        // the host is Linux too, so its error numbers can be passed through
+        match e.raw_os_error() {
+            Some(code) => Errno(code as u32),
+            None => Errno(match e.kind() {
+                io::ErrorKind::NotFound => ENOENT,
+                io::ErrorKind::PermissionDenied => EACCES,
+                io::ErrorKind::AlreadyExists => EEXIST,
+                io::ErrorKind::InvalidInput => EINVAL,
+                _ => EIO,
+            }),
+        }
+    }
+}
+
+type P9Result<T> = Result<T, Errno>;
+
+// Message types.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION: &str = "9P2000.L";
+/// The largest message the server accepts.
+pub const MAX_MSIZE: u32 = 128 * 1024;
+/// The smallest message size a client may ask for, which leaves room for the headers of every
+/// reply.
+pub const MIN_MSIZE: u32 = 4096;
+/// size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+
+// Qid types.
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+// Linux open flags used by Tlopen and Tlcreate.
+const O_ACCMODE: u32 = 3;
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+// Tsetattr valid bits.
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Tgetattr fills in every basic field (P9_GETATTR_BASIC).
+const GETATTR_BASIC: u64 = 0x7ff;
+/// Tunlinkat removes a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+/// The `f_type` reported by Tstatfs.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+/// Tgetlock reports that nothing holds a lock.
+const F_UNLCK: u8 = 2;
+
+/// A file the client holds a reference to.
+struct Fid {
+    /// The path relative to the exported root.
+    path: PathBuf,
+    file: Option<File>,
+    /// The directory listing taken when the client started reading the directory.
+    entries: Option<Vec<(Qid, u8, String)>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            entries: None,
+        }
+    }
+}
+
+pub struct Server {
+    root: PathBuf,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: PathBuf, read_only: bool) -> Self {
+        Self {
+            root,
+            read_only,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.msize = MAX_MSIZE;
+        self.fids.clear();
+    }
+
+    /// Handle a single request and return the reply.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+        let header = (|| Ok::<_, Errno>((reader.u32()?, reader.u8()?, reader.u16()?)))();
+        let Ok((_size, kind, tag)) = header else {
+            warn!("Dropping a truncated 9P request");
+            return Vec::new();
+        };
+
+        let mut reply = Writer::default();
+        let (kind, body) = match self.dispatch(kind, &mut reader, &mut reply) {
+            Ok(()) => (kind + 1, reply),
+            Err(Errno(code)) => {
+                let mut error = Writer::default();
+                error.u32(code);
+                (RLERROR, error)
+            }
+        };
+
+        let mut message = Writer::default();
+        message
+            .u32((HEADER_SIZE + body.len()) as u32)
+            .u8(kind)
+            .u16(tag)
+            .bytes(&body.into_inner());
+        message.into_inner()
+    }
+
+    fn dispatch(&mut self, kind: u8, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        match kind {
+            TVERSION => self.version(request, reply),
+            TATTACH => self.attach(request, reply),
+            TWALK => self.walk(request, reply),
+            TLOPEN => self.lopen(request, reply),
+            TLCREATE => self.lcreate(request, reply),
+            TREAD => self.read(request, reply),
+            TWRITE => self.write(request, reply),
+            TCLUNK => {
+                self.fids.remove(&request.u32()?).ok_or(Errno(EBADF))?;
+                Ok(())
+            }
+            TREMOVE => self.remove(request),
+            TGETATTR => self.getattr(request, reply),
+            TSETATTR => self.setattr(request),
+            TREADDIR => self.readdir(request, reply),
+            TSTATFS => self.statfs(request, reply),
+            TMKDIR => self.mkdir(request, reply),
+            TSYMLINK => self.symlink(request, reply),
+            TLINK => self.link(request),
+            TREADLINK => self.readlink(request, reply),
+            TRENAME => self.rename(request),
+            TRENAMEAT => self.renameat(request),
+            TUNLINKAT => self.unlinkat(request),
+            TFSYNC => {
+                if let Some(file) = &self.fid(request.u32()?)?.file {
+                    file.sync_all()?;
+                }
+                Ok(())
+            }
+            TLOCK => {
+                // locks are advisory and the guest is the only client, grant everything
+                self.fid(request.u32()?)?;
+                reply.u8(0);
+                Ok(())
+            }
+            TGETLOCK => {
+                self.fid(request.u32()?)?;
+                let _kind = request.u8()?;
+                let start = request.u64()?;
+                let length = request.u64()?;
+                let proc_id = request.u32()?;
+                let client_id = request.string()?;
+                reply
+                    .u8(F_UNLCK)
+                    .u64(start)
+                    .u64(length)
+                    .u32(proc_id)
+                    .string(&client_id);
+                Ok(())
+            }
+            TFLUSH => Ok(()), // requests are answered in order, nothing is ever in flight
+            TXATTRWALK => Err(Errno(EOPNOTSUPP)),
+            _ => {
+                warn!("Unsupported 9P request {kind}");
+                Err(Errno(EOPNOTSUPP))
+            }
+        }
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<&Fid> {
+        self.fids.get(&fid).ok_or(Errno(EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> P9Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(Errno(EBADF))
+    }
+
+    fn check_writable(&self) -> P9Result<()> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        Ok(())
+    }
+
+    /// The host path of `path`, which is relative to the root. The directory containing it must
+    /// resolve to somewhere inside the root; the last component itself is not followed.
+    fn host_path(&self, path: &Path) -> P9Result<PathBuf> {
+        let full = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            let parent = self.root.join(parent).canonicalize()?;
+            if !parent.starts_with(&self.root) {
+                warn!("9P client tried to escape the shared directory through {path:?}");
+                return Err(Errno(EACCES));
+            }
+        }
+        Ok(full)
+    }
+
+    /// Like [`Server::host_path`], but also follows the last component.
+    fn resolved_path(&self, path: &Path) -> P9Result<PathBuf> {
+        let full = self.root.join(path).canonicalize()?;
+        if !full.starts_with(&self.root) {
+            warn!("9P client tried to escape the shared directory through {path:?}");
+            return Err(Errno(EACCES));
+        }
+        Ok(full)
+    }
+
+    /// A name the client wants to create, remove or walk to within a directory.
+    fn child(&self, dir: &Path, name: &str) -> P9Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(Errno(EINVAL));
+        }
+        Ok(dir.join(name))
+    }
+
+    fn qid(metadata: &fs::Metadata) -> Qid {
+        let kind = if metadata.is_dir() {
+            QTDIR
+        } else if metadata.file_type().is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        Qid {
+            kind,
+            version: metadata.mtime() as u32 ^ metadata.mtime_nsec() as u32,
+            path: metadata.ino(),
+        }
+    }
+
+    fn qid_of(&self, path: &Path) -> P9Result<Qid> {
+        Ok(Self::qid(&fs::symlink_metadata(self.host_path(path)?)?))
+    }
+
+    fn version(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let msize = request.u32()?;
+        let version = request.string()?;
+        if msize < MIN_MSIZE {
+            warn!("9P client asked for a message size of {msize} bytes");
+            return Err(Errno(EINVAL));
+        }
+
+        self.reset();
+        self.msize = msize.min(MAX_MSIZE);
+        let version = if version.starts_with(VERSION) {
+            VERSION
+        } else {
+            warn!("9P client asked for unsupported version {version}");
+            "unknown"
+        };
+        reply.u32(self.msize).string(version);
+        Ok(())
+    }
+
+    fn attach(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let _afid = request.u32()?;
+        let _uname = request.string()?;
+        let _aname = request.string()?;
+
+        let qid = self.qid_of(Path::new(""))?;
+        self.fids.insert(fid, Fid::new(PathBuf::new()));
+        reply.qid(qid);
+        Ok(())
+    }
+
+    fn walk(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let newfid = request.u32()?;
+        let count = request.u16()?;
+        let names = (0..count)
+            .map(|_| request.string())
+            .collect::<P9Result<Vec<_>>>()?;
+
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Vec::new();
+        for name in &names {
+            let next = if name == ".." {
+                // the parent of the root is the root itself
+                path.parent().map(Path::to_path_buf).unwrap_or_default()
+            } else {
+                self.child(&path, name)?
+            };
+            match self.qid_of(&next) {
+                Ok(qid) => qids.push(qid),
+                // a walk failing on its first element is an error, a later one is not
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+            path = next;
+        }
+
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, Fid::new(path));
+        }
+        reply.u16(qids.len() as u16);
+        for qid in qids {
+            reply.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn open_options(&self, flags: u32) -> P9Result<OpenOptions> {
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        if flags & O_ACCMODE != O_RDONLY || flags & (O_TRUNC | O_CREAT) != 0 {
+            self.check_writable()?;
+        }
+        options
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+        Ok(options)
+    }
+
+    fn lopen(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let flags = request.u32()?;
+
+        let path = self.fid(fid)?.path.clone();
+        let host = self.resolved_path(&path)?;
+        let metadata = fs::metadata(&host)?;
+        let file = if metadata.is_dir() {
+            if flags & O_ACCMODE != O_RDONLY {
+                return Err(Errno(EISDIR));
+            }
+            None
+        } else {
+            Some(self.open_options(flags & !O_CREAT)?.open(&host)?)
+        };
+
+        let entry = self.fid_mut(fid)?;
+        entry.file = file;
+        entry.entries = None;
+        reply.qid(Self::qid(&metadata)).u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let name = request.string()?;
+        let flags = request.u32()?;
+        let mode = request.u32()?;
+        let _gid = request.u32()?;
+        self.check_writable()?;
+
+        let path = self.child(&self.fid(fid)?.path, &name)?;
+        let host = self.host_path(&path)?;
+        // opening follows a link at the name, which may point out of the shared directory
+        if fs::symlink_metadata(&host).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            warn!("9P client tried to create {path:?} through a symbolic link");
+            return Err(Errno(ELOOP));
+        }
+        let mut options = self.open_options(flags)?;
+        if flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+        let file = options.mode(mode & 0o7777).open(&host)?;
+        let qid = Self::qid(&file.metadata()?);
+
+        // the fid now stands for the new file
+        let entry = self.fid_mut(fid)?;
+        entry.path = path;
+        entry.file = Some(file);
+        entry.entries = None;
+        reply.qid(qid).u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let offset = request.u64()?;
+        // size[4] Rread tag[2] count[4]
+        let count = request.u32()?.min(self.msize - 11);
+
+        let file = self.fid(fid)?.file.as_ref().ok_or(Errno(EBADF))?;
+        let mut data = vec![0; count as usize];
+        let mut len = 0;
+        while len < data.len() {
+            match file.read_at(&mut data[len..], offset + len as u64)? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        reply.u32(len as u32).bytes(&data[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let offset = request.u64()?;
+        let count = request.u32()?;
+        let data = request.bytes(count as usize)?;
+        self.check_writable()?;
+
+        let file = self.fid(fid)?.file.as_ref().ok_or(Errno(EBADF))?;
+        file.write_all_at(data, offset)?;
+        reply.u32(count);
+        Ok(())
+    }
+
+    fn remove(&mut self, request: &mut Reader) -> P9Result<()> {
+        // the fid is clunked even when the removal fails
+        let fid = self.fids.remove(&request.u32()?).ok_or(Errno(EBADF))?;
+        self.check_writable()?;
+        if fid.path.as_os_str().is_empty() {
+            return Err(Errno(EBUSY));
+        }
+        let host = self.host_path(&fid.path)?;
+        if fs::symlink_metadata(&host)?.is_dir() {
+            fs::remove_dir(&host)?;
+        } else {
+            fs::remove_file(&host)?;
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let _mask = request.u64()?;
+
+        let metadata = fs::symlink_metadata(self.host_path(&self.fid(fid)?.path)?)?;
+        reply
+            .u64(GETATTR_BASIC)
+            .qid(Self::qid(&metadata))
+            .u32(metadata.mode())
+            .u32(metadata.uid())
+            .u32(metadata.gid())
+            .u64(metadata.nlink())
+            .u64(metadata.rdev())
+            .u64(metadata.size())
+            .u64(metadata.blksize())
+            .u64(metadata.blocks())
+            .u64(metadata.atime() as u64)
+            .u64(metadata.atime_nsec() as u64)
+            .u64(metadata.mtime() as u64)
+            .u64(metadata.mtime_nsec() as u64)
+            .u64(metadata.ctime() as u64)
+            .u64(metadata.ctime_nsec() as u64)
+            // btime, gen and data_version are not part of the basic set
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0);
+        Ok(())
+    }
+
+    fn setattr(&mut self, request: &mut Reader) -> P9Result<()> {
+        let fid = request.u32()?;
+        let valid = request.u32()?;
+        let mode = request.u32()?;
+        let _uid = request.u32()?;
+        let _gid = request.u32()?;
+        let size = request.u64()?;
+        let atime = (request.u64()?, request.u64()?);
+        let mtime = (request.u64()?, request.u64()?);
+        self.check_writable()?;
+
+        let time = |set: u32, (secs, nsecs): (u64, u64)| {
+            if valid & set == 0 {
+                return Ok(SystemTime::now());
+            }
+            if nsecs >= 1_000_000_000 {
+                return Err(Errno(EINVAL));
+            }
+            UNIX_EPOCH
+                .checked_add(Duration::new(secs, nsecs as u32))
+                .ok_or(Errno(EINVAL))
+        };
+        let atime = time(SETATTR_ATIME_SET, atime)?;
+        let mtime = time(SETATTR_MTIME_SET, mtime)?;
+
+        // ownership is left alone, the files stay owned by whoever runs the emulator
+        let host = self.resolved_path(&self.fid(fid)?.path)?;
+        if valid & SETATTR_MODE != 0 {
+            fs::set_permissions(&host, Permissions::from_mode(mode & 0o7777))?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            OpenOptions::new().write(true).open(&host)?.set_len(size)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let mut times = FileTimes::new();
+            if valid & SETATTR_ATIME != 0 {
+                times = times.set_accessed(atime);
+            }
+            if valid & SETATTR_MTIME != 0 {
+                times = times.set_modified(mtime);
+            }
+            File::open(&host)?.set_times(times)?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let offset = request.u64()?;
+        let count = request.u32()?.min(self.msize - 11) as usize;
+
+        if offset == 0 || self.fid(fid)?.entries.is_none() {
+            let path = self.fid(fid)?.path.clone();
+            let host = self.resolved_path(&path)?;
+            let mut entries = vec![
+                (self.qid_of(&path)?, QTDIR, ".".to_string()),
+                (
+                    self.qid_of(path.parent().unwrap_or(Path::new("")))?,
+                    QTDIR,
+                    "..".to_string(),
+                ),
+            ];
+            let mut names = fs::read_dir(&host)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            names.sort();
+            for name in names {
+                // names that are not valid UTF-8 can't be sent to the client
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                let Ok(metadata) = fs::symlink_metadata(host.join(name)) else {
+                    continue;
+                };
+                let qid = Self::qid(&metadata);
+                entries.push((qid, qid.kind, name.to_string()));
+            }
+            self.fid_mut(fid)?.entries = Some(entries);
+        }
+
+        // entries are addressed by their index plus one, zero being the start of the directory
+        let entries = self.fid(fid)?.entries.as_ref().unwrap();
+        let mut data = Writer::default();
+        for (index, (qid, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let len = Qid::SIZE + 8 + 1 + 2 + name.len();
+            if data.len() + len > count {
+                break;
+            }
+            // the dirent type is the file type in the upper bits of the mode
+            let dirent_type = match *kind {
+                QTDIR => 4,
+                QTSYMLINK => 10,
+                _ => 8,
+            };
+            data.qid(*qid)
+                .u64(index as u64 + 1)
+                .u8(dirent_type)
+                .string(name);
+        }
+        let data = data.into_inner();
+        reply.u32(data.len() as u32).bytes(&data);
+        Ok(())
+    }
+
+    fn statfs(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        self.fid(request.u32()?)?;
+        // the host file system can't be queried without libc, so report a large and empty disk
+        reply
+            .u32(V9FS_MAGIC)
+            .u32(4096)
+            .u64(1 << 24)
+            .u64(1 << 24)
+            .u64(1 << 24)
+            .u64(1 << 20)
+            .u64(1 << 20)
+            .u64(0)
+            .u32(255);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let name = request.string()?;
+        let mode = request.u32()?;
+        let _gid = request.u32()?;
+        self.check_writable()?;
+
+        let path = self.child(&self.fid(fid)?.path, &name)?;
+        let host = self.host_path(&path)?;
+        fs::create_dir(&host)?;
+        fs::set_permissions(&host, Permissions::from_mode(mode & 0o7777))?;
+        reply.qid(self.qid_of(&path)?);
+        Ok(())
+    }
+
+    fn symlink(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let name = request.string()?;
+        let target = request.string()?;
+        let _gid = request.u32()?;
+        self.check_writable()?;
+
+        // the target is stored as is, resolving it on the host is always checked against the root
+        let path = self.child(&self.fid(fid)?.path, &name)?;
+        std::os::unix::fs::symlink(target, self.host_path(&path)?)?;
+        reply.qid(self.qid_of(&path)?);
+        Ok(())
+    }
+
+    fn link(&mut self, request: &mut Reader) -> P9Result<()> {
+        let dir = request.u32()?;
+        let fid = request.u32()?;
+        let name = request.string()?;
+        self.check_writable()?;
+
+        let target = self.host_path(&self.fid(fid)?.path)?;
+        let path = self.child(&self.fid(dir)?.path, &name)?;
+        fs::hard_link(target, self.host_path(&path)?)?;
+        Ok(())
+    }
+
+    fn readlink(&mut self, request: &mut Reader, reply: &mut Writer) -> P9Result<()> {
+        let fid = request.u32()?;
+        let target = fs::read_link(self.host_path(&self.fid(fid)?.path)?)?;
+        reply.string(target.to_str().ok_or(Errno(EINVAL))?);
+        Ok(())
+    }
+
+    fn rename(&mut self, request: &mut Reader) -> P9Result<()> {
+        let fid = request.u32()?;
+        let dir = request.u32()?;
+        let name = request.string()?;
+        self.check_writable()?;
+
+        let from = self.fid(fid)?.path.clone();
+        let to = self.child(&self.fid(dir)?.path, &name)?;
+        fs::rename(self.host_path(&from)?, self.host_path(&to)?)?;
+        self.fid_mut(fid)?.path = to;
+        Ok(())
+    }
+
+    fn renameat(&mut self, request: &mut Reader) -> P9Result<()> {
+        let old_dir = request.u32()?;
+        let old_name = request.string()?;
+        let new_dir = request.u32()?;
+        let new_name = request.string()?;
+        self.check_writable()?;
+
+        let from = self.child(&self.fid(old_dir)?.path, &old_name)?;
+        let to = self.child(&self.fid(new_dir)?.path, &new_name)?;
+        fs::rename(self.host_path(&from)?, self.host_path(&to)?)?;
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, request: &mut Reader) -> P9Result<()> {
+        let dir = request.u32()?;
+        let name = request.string()?;
+        let flags = request.u32()?;
+        self.check_writable()?;
+
+        let path = self.child(&self.fid(dir)?.path, &name)?;
+        let host = self.host_path(&path)?;
+        if flags & AT_REMOVEDIR != 0 {
+            Ok(fs::remove_dir(&host)?)
+        } else {
+            if fs::symlink_metadata(&host)?.is_dir() {
+                return Err(Errno(EISDIR));
+            }
+            Ok(fs::remove_file(&host)?)
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Split virtqueues as described in section 2.7 of the virtio 1.1 specification.
+use anyhow::{bail, Result};
+
+use crate::bus::BusView;
+use crate::memory::dram::Sizes;
+
+/// This marks a buffer as continuing via the next field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// This marks a buffer as device write-only (otherwise device read-only).
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The size of an entry in the descriptor table.
+const DESC_SIZE: u64 = 16;
+/// The most bytes the buffers of one chain may add up to. Devices copy whole chains into host
+/// memory, so the lengths the guest puts in the descriptors must not be trusted blindly.
+pub const MAX_CHAIN_LEN: u64 = 1 << 20;
+
+/// A single buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    /// The device may write to the buffer (otherwise it may only read it).
+    pub writable: bool,
+}
+
+/// The buffers the driver made available through one entry of the available ring.
+#[derive(Debug)]
+pub struct DescriptorChain {
+    /// The index of the first descriptor, which identifies the chain in the used ring.
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Concatenate all the device-readable buffers of the chain.
+    pub fn read(&self, memory: &BusView) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for desc in self.descriptors.iter().filter(|desc| !desc.writable) {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            memory.read_bytes(desc.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Fill the device-writable buffers of the chain with `data`. Returns the number of bytes
+    /// written, which is less than `data.len()` when the buffers are too small.
+    pub fn write(&self, memory: &mut BusView, data: &[u8]) -> Result<usize> {
+        let mut written = 0;
+        for desc in self.descriptors.iter().filter(|desc| desc.writable) {
+            if written == data.len() {
+                break;
+            }
+            let len = (desc.len as usize).min(data.len() - written);
+            memory.write_bytes(desc.addr, &data[written..written + len])?;
+            written += len;
+        }
+        Ok(written)
+    }
+
+    /// The total size of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.descriptors
+            .iter()
+            .filter(|desc| desc.writable)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+}
+
+/// A split virtqueue configured by the driver.
+pub struct Virtqueue {
+    max_size: u16,
+    size: u16,
+    pub(crate) ready: bool,
+
+    /// Guest physical address of the descriptor table.
+    pub(crate) desc: u64,
+    /// Guest physical address of the available ring.
+    pub(crate) driver: u64,
+    /// Guest physical address of the used ring.
+    pub(crate) device: u64,
+
+    /// The next entry of the available ring the device will look at.
+    last_avail: u16,
+    /// The next entry of the used ring the device will fill.
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_avail: 0,
+            used_idx: 0,
+        }
+    }
+
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    pub(crate) fn set_size(&mut self, size: u16) {
+        self.size = size.min(self.max_size);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready && self.size != 0
+    }
+
+    fn read16(memory: &BusView, addr: u64) -> Result<u16> {
+        Ok(memory.read(addr, Sizes::HalfWord)? as u16)
+    }
+
+    fn read32(memory: &BusView, addr: u64) -> Result<u32> {
+        memory.read(addr, Sizes::Word)
+    }
+
+    /// Whether the driver made buffers available that the device did not consume yet.
+    pub fn has_available(&self, memory: &BusView) -> Result<bool> {
+        if !self.is_ready() {
+            return Ok(false);
+        }
+        Ok(Self::read16(memory, self.driver + 2)? != self.last_avail)
+    }
+
+    /// Take the next available descriptor chain, if there is one.
+    pub fn pop(&mut self, memory: &BusView) -> Result<Option<DescriptorChain>> {
+        if !self.has_available(memory)? {
+            return Ok(None);
+        }
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = Self::read16(memory, self.driver + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descriptors = Vec::new();
+        let mut total = 0;
+        let mut index = head;
+        loop {
+            if index >= self.size || descriptors.len() >= self.size as usize {
+                bail!("malformed descriptor chain starting at {head}");
+            }
+            let entry = self.desc + index as u64 * DESC_SIZE;
+            let addr = Self::read32(memory, entry)? as u64
+                | (Self::read32(memory, entry + 4)? as u64) << 32;
+            let len = Self::read32(memory, entry + 8)?;
+            let flags = Self::read16(memory, entry + 12)?;
+            let next = Self::read16(memory, entry + 14)?;
+
+            total += len as u64;
+            if total > MAX_CHAIN_LEN {
+                bail!("descriptor chain starting at {head} is longer than {MAX_CHAIN_LEN} bytes");
+            }
+            descriptors.push(Descriptor {
+                addr,
+                len,
+                writable: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// Give back the last `count` chains taken with [`Virtqueue::pop`] without using them.
+    pub fn undo_pop(&mut self, count: u16) {
+        self.last_avail = self.last_avail.wrapping_sub(count);
+    }
+
+    /// Return the chain starting at `head` to the driver, reporting `len` bytes written to it.
+    pub fn push(&mut self, memory: &mut BusView, head: u16, len: u32) -> Result<()> {
+        let slot = (self.used_idx % self.size) as u64;
+        let entry = self.device + 4 + slot * 8;
+        memory.write(entry, head as u32, Sizes::Word)?;
+        memory.write(entry + 4, len, Sizes::Word)?;
+
+        self.used_idx = self.used_idx.wrapping_add(1);
+        memory.write(self.device + 2, self.used_idx as u32, Sizes::HalfWord)
+    }
+}
@@ -0,0 +1,163 @@
+//! The virtio entropy device (section 5.4 of the virtio 1.1 specification). Entropy comes either
+//! from the host or from a seeded generator, which makes runs reproducible bit for bit.
+use std::fs::File;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+
+use super::queue::Virtqueue;
+use super::{VirtioDevice, VIRTIO_F_VERSION_1};
+use crate::bus::BusView;
+
+/// The virtio device type of an entropy source.
+const ENTROPY_DEVICE_ID: u32 = 4;
+/// The most bytes handed out for a single request.
+const MAX_REQUEST: usize = 4096;
+
+/// Where the entropy device gets its bytes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    /// The random number generator of the host operating system.
+    Host,
+    /// A deterministic generator started from the given seed.
+    Seeded(u64),
+}
+
+/// A small deterministic generator (xoshiro256**), seeded through splitmix64.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: [u64; 4],
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        let mut seed = seed;
+        let mut splitmix = || {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        Self {
+            state: [splitmix(), splitmix(), splitmix(), splitmix()],
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+
+    pub fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+enum Generator {
+    Host(File),
+    Seeded(SeededRng),
+}
+
+/// A virtio entropy device.
+pub struct VirtioRng {
+    source: EntropySource,
+    generator: Generator,
+}
+
+impl VirtioRng {
+    pub fn new(source: EntropySource) -> Result<Self> {
+        Ok(Self {
+            source,
+            generator: Self::generator(source)?,
+        })
+    }
+
+    pub fn source(&self) -> EntropySource {
+        self.source
+    }
+
+    fn generator(source: EntropySource) -> Result<Generator> {
+        Ok(match source {
+            EntropySource::Host => Generator::Host(
+                File::open("/dev/urandom").context("failed to open the host entropy source")?,
+            ),
+            EntropySource::Seeded(seed) => Generator::Seeded(SeededRng::new(seed)),
+        })
+    }
+
+    fn fill_bytes(&mut self, buffer: &mut [u8]) -> Result<()> {
+        match &mut self.generator {
+            Generator::Host(file) => file
+                .read_exact(buffer)
+                .context("failed to read host entropy"),
+            Generator::Seeded(rng) => {
+                rng.fill_bytes(buffer);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn device_id(&self) -> u32 {
+        ENTROPY_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: u64) -> u8 {
+        // the entropy device has no configuration
+        0
+    }
+
+    fn reset(&mut self) {
+        // restart the seeded stream so a reset guest sees the same bytes again
+        if let EntropySource::Seeded(seed) = self.source {
+            self.generator = Generator::Seeded(SeededRng::new(seed));
+        }
+    }
+
+    fn process_queue(
+        &mut self,
+        queue: u16,
+        queues: &mut [Virtqueue],
+        memory: &mut BusView,
+    ) -> Result<bool> {
+        let mut used = false;
+        let requests = &mut queues[queue as usize];
+        while let Some(chain) = requests.pop(memory)? {
+            let mut entropy = vec![0; chain.writable_len().min(MAX_REQUEST)];
+            self.fill_bytes(&mut entropy)?;
+            let len = chain.write(memory, &entropy)?;
+            requests.push(memory, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
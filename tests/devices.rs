@@ -0,0 +1,1359 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use riscv_vm::{
+    bus::{Bus, Device, DeviceRequest, IrqLine, IrqTarget, VirtualDevice},
+    cpu::{AccessType, Cpu, ExitStatus, Privilege, Riscv32Cpu},
+    csr::{MCAUSE, MEIP_BIT, MEPC, MIE, MIP, MSIP_BIT, MSTATUS, MTVEC, SEIP_BIT},
+    device_tree::{self, Chosen, Fdt},
+    devices::{
+        framebuffer::{Framebuffer, ImageFormat, PixelFormat, FRAMEBUFFER_BASE},
+        gpio::{Gpio, PinChange, PinDriver, GPIO_BASE, GPIO_IRQ_BASE, GPIO_PINS},
+        pdma::{Pdma, PDMA_BASE},
+        pflash::{CommandSet, PFlash, PFlashConfig, PFLASH_BASE},
+        plic::{Plic, PLIC_BASE},
+        rtc::{GoldfishRtc, TimeSource, RTC_BASE, RTC_SIZE},
+        spi::{SdCard, SifiveSpi, SpiFlash, SpiSlave, SPI_BASE},
+        test_finisher::{TestFinisher, TEST_FINISHER_BASE},
+        watchdog::{Watchdog, WATCHDOG_BASE},
+        NS_PER_STEP,
+    },
+    events::Timer,
+    memory::{
+        dram::{Dram, Sizes, DRAM_BASE, DRAM_SIZE},
+        virtual_memory::MemorySize,
+    },
+    rom::{Rom, MROM_BASE, MROM_SIZE, POINTER_TO_DTB},
+    trace::{AccessKind, MmioAccess, TraceBuffer, WatchFilter},
+};
+
+#[test]
+fn test_finisher_requests() {
+    let mut bus = Bus::new();
+    bus.add_device(TestFinisher::new_device()).unwrap();
+    assert_eq!(bus.take_request(), None);
+
+    bus.write(TEST_FINISHER_BASE, 0x5555, Sizes::Word).unwrap();
+    assert_eq!(
+        bus.take_request(),
+        Some(DeviceRequest::Exit(ExitStatus::Exit { code: 0 }))
+    );
+    assert_eq!(bus.take_request(), None);
+
+    bus.write(TEST_FINISHER_BASE, 42 << 16 | 0x3333, Sizes::Word)
+        .unwrap();
+    assert_eq!(
+        bus.take_request(),
+        Some(DeviceRequest::Exit(ExitStatus::Exit { code: 42 }))
+    );
+
+    bus.write(TEST_FINISHER_BASE, 0x7777, Sizes::Word).unwrap();
+    assert_eq!(bus.take_request(), Some(DeviceRequest::Reset));
+
+    // anything else is ignored
+    bus.write(TEST_FINISHER_BASE, 0x1234, Sizes::Word).unwrap();
+    assert_eq!(bus.take_request(), None);
+}
+
+#[test]
+fn stepping_handles_device_requests() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(TestFinisher::new_device()).unwrap();
+    cpu.add_device(Rom::new_device()).unwrap();
+    // lui t0, 0x100; lui t1, 0x7; addi t1, t1, 0x777; sw t1, 0(t0); j .
+    let program = [
+        0x0010_02b7,
+        0x0000_7337,
+        0x7773_0313,
+        0x0062_a023,
+        0x0000_006f,
+    ];
+    for (i, inst) in program.into_iter().enumerate() {
+        cpu.write(
+            DRAM_BASE as u32 + i as u32 * 4,
+            inst,
+            Sizes::Word,
+            AccessType::Writable,
+        )
+        .unwrap();
+    }
+    cpu.set_pc(DRAM_BASE as u32);
+
+    for _ in 0..4 {
+        assert_eq!(cpu.step().unwrap(), None);
+    }
+    // the reset requested by the store puts the hart back at the reset vector
+    assert_eq!(cpu.step().unwrap(), None);
+    assert_eq!(cpu.get_pc(), MROM_BASE as u32 + 4);
+
+    cpu.write(
+        TEST_FINISHER_BASE as u32,
+        0x5555,
+        Sizes::Word,
+        AccessType::Writable,
+    )
+    .unwrap();
+    // nothing is executed once the machine stops
+    assert_eq!(cpu.step().unwrap(), Some(ExitStatus::Exit { code: 0 }));
+    assert_eq!(cpu.get_pc(), MROM_BASE as u32 + 4);
+}
+
+#[test]
+fn emulated_rtc_is_deterministic() {
+    let mut bus = Bus::new();
+    bus.add_device(GoldfishRtc::new_device(TimeSource::Emulated(1_700_000_000)))
+        .unwrap();
+    let read_time = |bus: &Bus| {
+        let low = bus.read(RTC_BASE, Sizes::Word).unwrap() as u64;
+        let high = bus.read(RTC_BASE + 4, Sizes::Word).unwrap() as u64;
+        high << 32 | low
+    };
+    assert_eq!(read_time(&bus), 1_700_000_000 * 1_000_000_000);
+
+    bus.run_until(bus.now() + NS_PER_STEP);
+    assert_eq!(read_time(&bus), 1_700_000_000 * 1_000_000_000 + NS_PER_STEP);
+
+    // an alarm three steps from now
+    let alarm = read_time(&bus) + 3 * NS_PER_STEP;
+    bus.write(RTC_BASE + 0x10, 1, Sizes::Word).unwrap();
+    bus.write(RTC_BASE + 0x0c, (alarm >> 32) as u32, Sizes::Word)
+        .unwrap();
+    bus.write(RTC_BASE + 0x08, alarm as u32, Sizes::Word)
+        .unwrap();
+    let pending = |bus: &Bus| bus.get_device::<GoldfishRtc>().unwrap().interrupt_pending();
+    for _ in 0..2 {
+        bus.run_until(bus.now() + NS_PER_STEP);
+        assert!(!pending(&bus));
+    }
+    bus.run_until(bus.now() + NS_PER_STEP);
+    assert!(pending(&bus));
+    assert_eq!(bus.read(RTC_BASE + 0x18, Sizes::Word).unwrap(), 0);
+
+    bus.write(RTC_BASE + 0x1c, 1, Sizes::Word).unwrap();
+    assert!(!pending(&bus));
+}
+
+#[test]
+fn framebuffer_snapshots() {
+    let mut bus = Bus::new();
+    bus.add_device(Framebuffer::new_device(
+        FRAMEBUFFER_BASE,
+        2,
+        2,
+        PixelFormat::X8R8G8B8,
+    ))
+    .unwrap();
+    bus.write(FRAMEBUFFER_BASE, 0x00ff_0000, Sizes::Word)
+        .unwrap();
+    bus.write(FRAMEBUFFER_BASE + 4, 0x0000_ff00, Sizes::Word)
+        .unwrap();
+    bus.write(FRAMEBUFFER_BASE + 8, 0x0000_00ff, Sizes::Word)
+        .unwrap();
+    bus.write(FRAMEBUFFER_BASE + 12, 0x80, Sizes::Byte).unwrap();
+    assert_eq!(bus.read(FRAMEBUFFER_BASE + 2, Sizes::Byte).unwrap(), 0xff);
+    assert!(bus.write(FRAMEBUFFER_BASE + 16, 0, Sizes::Word).is_err());
+
+    assert!(device_tree::generate(&bus, &[]).contains(
+        "framebuffer@28000000 {\n\t\t\tcompatible = \"simple-framebuffer\";\n\t\t\treg = <0x0 0x28000000 0x0 0x10>;"
+    ));
+    assert!(device_tree::generate(&bus, &[]).contains("format = \"x8r8g8b8\""));
+
+    let framebuffer = bus.get_device::<Framebuffer>().unwrap();
+
+    let mut ppm = Vec::new();
+    framebuffer.write_image(&mut ppm, ImageFormat::Ppm).unwrap();
+    let mut expected = b"P6\n2 2\n255\n".to_vec();
+    expected.extend_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 128]);
+    assert_eq!(ppm, expected);
+
+    let mut png = Vec::new();
+    framebuffer.write_image(&mut png, ImageFormat::Png).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x02"));
+    assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+
+    // one frame now and one per millisecond of emulated time after that
+    let directory = std::env::temp_dir().join("riscv-vm-frames");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    bus.get_device_mut::<Framebuffer>()
+        .unwrap()
+        .dump_frames(&directory, 1, ImageFormat::Ppm);
+    bus.run_until(2_000_000);
+    assert!(directory.join("frame-00002.ppm").exists());
+    assert!(!directory.join("frame-00003.ppm").exists());
+    assert_eq!(
+        std::fs::read(directory.join("frame-00000.ppm")).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn intel_flash_persists_programmed_data() {
+    let path = std::env::temp_dir().join(format!("riscv-vm-pflash-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = PFlashConfig {
+        size: 0x10000,
+        sector_size: 0x1000,
+        ..PFlashConfig::default()
+    };
+    let mut bus = Bus::new();
+    bus.add_device(PFlash::open_device(&path, config, PFLASH_BASE).unwrap())
+        .unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0x10000);
+
+    // query mode
+    bus.write(PFLASH_BASE + 0x55 * 4, 0x98, Sizes::Word)
+        .unwrap();
+    let query: Vec<u8> = (0x10..0x13)
+        .map(|i| bus.read(PFLASH_BASE + i * 4, Sizes::Word).unwrap() as u8)
+        .collect();
+    assert_eq!(query, b"QRY");
+    assert_eq!(bus.read(PFLASH_BASE + 0x27 * 4, Sizes::Word).unwrap(), 16);
+    bus.write(PFLASH_BASE, 0xff, Sizes::Word).unwrap();
+
+    // word program, then poll the status register
+    bus.write(PFLASH_BASE + 0x1000, 0x40, Sizes::Word).unwrap();
+    bus.write(PFLASH_BASE + 0x1000, 0x1234_5678, Sizes::Word)
+        .unwrap();
+    assert_eq!(bus.read(PFLASH_BASE, Sizes::Word).unwrap(), 0x80);
+    bus.write(PFLASH_BASE, 0xff, Sizes::Word).unwrap();
+    assert_eq!(
+        bus.read(PFLASH_BASE + 0x1000, Sizes::Word).unwrap(),
+        0x1234_5678
+    );
+
+    // programming can't set bits
+    bus.write(PFLASH_BASE + 0x1000, 0x10, Sizes::Word).unwrap();
+    bus.write(PFLASH_BASE + 0x1000, 0xffff_ff0f, Sizes::Word)
+        .unwrap();
+    bus.write(PFLASH_BASE, 0xff, Sizes::Word).unwrap();
+    assert_eq!(
+        bus.read(PFLASH_BASE + 0x1000, Sizes::Word).unwrap(),
+        0x1234_5608
+    );
+    drop(bus);
+
+    // the data survives, and an erase brings the sector back to all ones
+    let mut bus = Bus::new();
+    bus.add_device(PFlash::open_device(&path, config, PFLASH_BASE).unwrap())
+        .unwrap();
+    assert_eq!(
+        bus.read(PFLASH_BASE + 0x1000, Sizes::Word).unwrap(),
+        0x1234_5608
+    );
+    bus.write(PFLASH_BASE + 0x1004, 0x20, Sizes::Word).unwrap();
+    bus.write(PFLASH_BASE + 0x1004, 0xd0, Sizes::Word).unwrap();
+    assert_eq!(bus.read(PFLASH_BASE, Sizes::Word).unwrap(), 0x80);
+    bus.write(PFLASH_BASE, 0xff, Sizes::Word).unwrap();
+    assert_eq!(
+        bus.read(PFLASH_BASE + 0x1000, Sizes::Word).unwrap(),
+        0xffff_ffff
+    );
+    assert!(std::fs::read(&path)
+        .unwrap()
+        .iter()
+        .all(|byte| *byte == 0xff));
+
+    // a bad erase confirmation is a command sequence error
+    bus.write(PFLASH_BASE, 0x20, Sizes::Word).unwrap();
+    bus.write(PFLASH_BASE, 0xff, Sizes::Word).unwrap();
+    assert_eq!(bus.read(PFLASH_BASE, Sizes::Word).unwrap(), 0xb0);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn amd_flash_command_sequences() {
+    let config = PFlashConfig {
+        command_set: CommandSet::Amd,
+        size: 0x10000,
+        sector_size: 0x1000,
+        bank_width: 2,
+        execute_in_place: false,
+    };
+    let mut bus = Bus::new();
+    bus.add_device(PFlash::new_device(config, PFLASH_BASE).unwrap())
+        .unwrap();
+    let unlock = |bus: &mut Bus| {
+        bus.write(PFLASH_BASE + 0x555 * 2, 0xaa, Sizes::HalfWord)
+            .unwrap();
+        bus.write(PFLASH_BASE + 0x2aa * 2, 0x55, Sizes::HalfWord)
+            .unwrap();
+    };
+
+    unlock(&mut bus);
+    bus.write(PFLASH_BASE + 0x555 * 2, 0xa0, Sizes::HalfWord)
+        .unwrap();
+    bus.write(PFLASH_BASE + 0x2002, 0xbeef, Sizes::HalfWord)
+        .unwrap();
+    // data polling reads the programmed value right away
+    assert_eq!(
+        bus.read(PFLASH_BASE + 0x2002, Sizes::HalfWord).unwrap(),
+        0xbeef
+    );
+    // data that looks like a reset command is programmed too
+    for (address, value) in [(0x2004, 0xabf0), (0x2006, 0x00ff)] {
+        unlock(&mut bus);
+        bus.write(PFLASH_BASE + 0x555 * 2, 0xa0, Sizes::HalfWord)
+            .unwrap();
+        bus.write(PFLASH_BASE + address, value, Sizes::HalfWord)
+            .unwrap();
+        assert_eq!(
+            bus.read(PFLASH_BASE + address, Sizes::HalfWord).unwrap(),
+            value
+        );
+    }
+
+    unlock(&mut bus);
+    bus.write(PFLASH_BASE + 0x555 * 2, 0x90, Sizes::HalfWord)
+        .unwrap();
+    assert_eq!(bus.read(PFLASH_BASE, Sizes::HalfWord).unwrap(), 0x01);
+    bus.write(PFLASH_BASE, 0xf0, Sizes::HalfWord).unwrap();
+
+    // sector erase
+    unlock(&mut bus);
+    bus.write(PFLASH_BASE + 0x555 * 2, 0x80, Sizes::HalfWord)
+        .unwrap();
+    unlock(&mut bus);
+    bus.write(PFLASH_BASE + 0x2000, 0x30, Sizes::HalfWord)
+        .unwrap();
+    assert_eq!(
+        bus.read(PFLASH_BASE + 0x2002, Sizes::HalfWord).unwrap(),
+        0xffff
+    );
+    assert!(!bus.is_executable(PFLASH_BASE));
+
+    // jumping into the flash faults on the fetch
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(PFlash::new_device(config, PFLASH_BASE).unwrap())
+        .unwrap();
+    // lui t0, 0x20000; jr t0
+    for (i, inst) in [0x2000_02b7, 0x0002_8067].into_iter().enumerate() {
+        let address = DRAM_BASE as u32 + i as u32 * 4;
+        cpu.write(address, inst, Sizes::Word, AccessType::Writable)
+            .unwrap();
+    }
+    cpu.write_csr(MTVEC, DRAM_BASE as u32 + 0x100);
+    cpu.set_pc(DRAM_BASE as u32);
+    for _ in 0..3 {
+        cpu.step().unwrap();
+    }
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32 + 0x100);
+    assert_eq!(cpu.read_csr(MCAUSE), 1);
+    assert_eq!(cpu.read_csr(MEPC), PFLASH_BASE as u32);
+}
+
+#[test]
+fn gpio_pins_and_edges() {
+    let mut bus = Bus::new();
+    bus.add_device(Gpio::new_device(GPIO_PINS)).unwrap();
+    fn gpio(bus: &mut Bus) -> &mut Gpio {
+        bus.get_device_mut::<Gpio>().unwrap()
+    }
+
+    // pin 0 is an output, pin 1 an input with a rising edge interrupt
+    bus.write(GPIO_BASE + 0x08, 0b01, Sizes::Word).unwrap();
+    bus.write(GPIO_BASE + 0x04, 0b10, Sizes::Word).unwrap();
+    bus.write(GPIO_BASE + 0x18, 0b10, Sizes::Word).unwrap();
+    bus.write(GPIO_BASE + 0x0c, 0b01, Sizes::Word).unwrap();
+    assert_eq!(gpio(&mut bus).output(0), Some(true));
+    assert_eq!(gpio(&mut bus).output(1), None);
+
+    gpio(&mut bus).set_input(1, false);
+    assert_eq!(gpio(&mut bus).interrupts(), 0);
+    bus.run_until(bus.now() + NS_PER_STEP);
+    gpio(&mut bus).set_input(1, true);
+    assert_eq!(gpio(&mut bus).interrupts(), 0b10);
+    assert_eq!(bus.read(GPIO_BASE, Sizes::Word).unwrap(), 0b10);
+
+    // the pending bit is cleared by writing it
+    bus.write(GPIO_BASE + 0x1c, 0b10, Sizes::Word).unwrap();
+    assert_eq!(gpio(&mut bus).interrupts(), 0);
+
+    // the host drives the output pin in vain
+    gpio(&mut bus).set_input(0, false);
+    assert_eq!(
+        gpio(&mut bus).take_changes(),
+        [
+            PinChange {
+                time_ns: 0,
+                pin: 0,
+                level: true,
+                driver: PinDriver::Guest
+            },
+            PinChange {
+                time_ns: NS_PER_STEP,
+                pin: 1,
+                level: true,
+                driver: PinDriver::Host
+            },
+        ]
+    );
+}
+
+#[test]
+fn gpio_edge_interrupts_the_hart_through_the_plic() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Plic::new_device()).unwrap();
+    cpu.add_device(Gpio::new_device(GPIO_PINS)).unwrap();
+    let source = GPIO_IRQ_BASE + 2;
+    let write = |cpu: &mut Riscv32Cpu, address: u64, value: u32| {
+        cpu.get_interface()
+            .write_raw(address, value, Sizes::Word)
+            .unwrap()
+    };
+    write(&mut cpu, PLIC_BASE + 4 * source as u64, 1);
+    write(&mut cpu, PLIC_BASE + 0x2000, 1 << source);
+    write(&mut cpu, GPIO_BASE + 0x04, 1 << 2);
+    write(&mut cpu, GPIO_BASE + 0x20, 1 << 2);
+
+    let mem = cpu.get_interface();
+    mem.write_csr(MTVEC, DRAM_BASE as u32 + 0x100);
+    mem.write_csr(MIE, 1 << 11);
+    mem.write_csr(MSTATUS, 1 << 3);
+    mem.set_privilege(Privilege::User);
+    cpu.set_pc(DRAM_BASE as u32 + 0x40);
+
+    // a falling edge on pin 2
+    cpu.get_device_mut::<Gpio>().unwrap().set_input(2, true);
+    cpu.get_device_mut::<Gpio>().unwrap().set_input(2, false);
+    cpu.step().unwrap();
+
+    let mem = cpu.get_interface();
+    assert_eq!(mem.read_csr(MCAUSE), 1 << 31 | 11);
+    assert_eq!(mem.read_csr(MEPC), DRAM_BASE as u32 + 0x40);
+    assert_eq!(mem.get_privilege(), Privilege::Machine);
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32 + 0x100);
+
+    // claim and complete, the line is still raised so the source is pending again
+    let claim = PLIC_BASE + 0x20_0004;
+    let mem = cpu.get_interface();
+    assert_eq!(mem.read_raw(claim, Sizes::Word).unwrap(), source);
+    assert_eq!(mem.read_raw(claim, Sizes::Word).unwrap(), 0);
+    assert!(!cpu.get_device::<Plic>().unwrap().is_pending(source));
+    write(&mut cpu, claim, source);
+    assert!(cpu.get_device::<Plic>().unwrap().is_pending(source));
+}
+
+/// Exchange `bytes` with the device selected on the SPI controller, returning what it sent back.
+fn spi_exchange(bus: &mut Bus, bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .map(|byte| {
+            bus.write(SPI_BASE + 0x48, *byte as u32, Sizes::Word)
+                .unwrap();
+            let rx = bus.read(SPI_BASE + 0x4c, Sizes::Word).unwrap();
+            assert_eq!(rx >> 31, 0, "the receive FIFO is empty");
+            rx as u8
+        })
+        .collect()
+}
+
+/// Send an SD command and return the R1 response and the bytes following it.
+fn sd_command(bus: &mut Bus, index: u8, arg: u32, extra: usize) -> (u8, Vec<u8>) {
+    let arg = arg.to_be_bytes();
+    spi_exchange(bus, &[0x40 | index, arg[0], arg[1], arg[2], arg[3], 0x95]);
+    let response = spi_exchange(bus, &vec![0xff; 8 + extra]);
+    let start = response.iter().position(|byte| *byte != 0xff).unwrap();
+    (
+        response[start],
+        response[start + 1..start + 1 + extra].to_vec(),
+    )
+}
+
+#[test]
+fn sd_card_in_spi_mode() {
+    let mut image = vec![0; 4 * 512];
+    image[512..1024].fill(0xa5);
+    let card: Box<dyn SpiSlave> = Box::new(SdCard::new(image));
+    let mut bus = Bus::new();
+    bus.add_device(SifiveSpi::new_device(vec![Some(card)], SPI_BASE))
+        .unwrap();
+
+    // power up clocks with the card deselected, then hold the chip select
+    bus.write(SPI_BASE + 0x18, 3, Sizes::Word).unwrap();
+    spi_exchange(&mut bus, &[0xff; 10]);
+    bus.write(SPI_BASE + 0x18, 2, Sizes::Word).unwrap();
+
+    assert_eq!(sd_command(&mut bus, 0, 0, 0).0, 0x01);
+    assert_eq!(
+        sd_command(&mut bus, 8, 0x1aa, 4),
+        (0x01, vec![0, 0, 0x01, 0xaa])
+    );
+    // reads are illegal until the card is initialized
+    assert_eq!(sd_command(&mut bus, 17, 0, 0).0, 0x05);
+    assert_eq!(sd_command(&mut bus, 55, 0, 0).0, 0x01);
+    assert_eq!(sd_command(&mut bus, 41, 1 << 30, 0).0, 0x00);
+    let (r1, ocr) = sd_command(&mut bus, 58, 0, 4);
+    assert_eq!(r1, 0);
+    assert_ne!(ocr[0] & 0x40, 0, "the card is high capacity");
+
+    // read block 1: the response, the start token, the data and the CRC
+    spi_exchange(&mut bus, &[0x40 | 17, 0, 0, 0, 1, 0xff]);
+    let data = spi_exchange(&mut bus, &[0xff; 530]);
+    let r1 = data.iter().position(|byte| *byte != 0xff).unwrap();
+    assert_eq!(data[r1], 0);
+    let start = r1
+        + 1
+        + data[r1 + 1..]
+            .iter()
+            .position(|byte| *byte == 0xfe)
+            .unwrap();
+    assert!(data[start + 1..start + 513]
+        .iter()
+        .all(|byte| *byte == 0xa5));
+
+    // write block 2
+    assert_eq!(sd_command(&mut bus, 24, 2, 0).0, 0);
+    spi_exchange(&mut bus, &[0xff, 0xfe]);
+    spi_exchange(&mut bus, &[0x5a; 512]);
+    let response = spi_exchange(&mut bus, &[0xff; 4]);
+    let token = response.iter().find(|byte| **byte != 0xff).unwrap();
+    assert_eq!(token & 0x1f, 0x05);
+
+    // out of range blocks are rejected
+    assert_eq!(sd_command(&mut bus, 17, 4, 0).0, 0x20);
+
+    let spi = bus.get_device_mut::<SifiveSpi>().unwrap();
+    let card = spi.slave_mut::<SdCard>(0).unwrap();
+    assert_eq!(card.read_block(2).unwrap(), [0x5a; 512]);
+    assert_eq!(card.read_block(3).unwrap(), [0; 512]);
+}
+
+#[test]
+fn spi_flash_commands() {
+    let flash: Box<dyn SpiSlave> = Box::new(SpiFlash::new(1 << 20).unwrap());
+    let mut bus = Bus::new();
+    bus.add_device(SifiveSpi::new_device(vec![None, Some(flash)], SPI_BASE))
+        .unwrap();
+    bus.write(SPI_BASE + 0x10, 1, Sizes::Word).unwrap();
+
+    // every command ends by going back to automatic chip select
+    let command = |bus: &mut Bus, bytes: &[u8]| {
+        bus.write(SPI_BASE + 0x18, 2, Sizes::Word).unwrap();
+        let response = spi_exchange(bus, bytes);
+        bus.write(SPI_BASE + 0x18, 0, Sizes::Word).unwrap();
+        response
+    };
+
+    assert_eq!(command(&mut bus, &[0x9f, 0, 0, 0])[1..], [0xef, 0x40, 0x14]);
+
+    // programming needs the write enable latch
+    command(&mut bus, &[0x02, 0x00, 0x01, 0x00, 0x12]);
+    assert_eq!(command(&mut bus, &[0x03, 0x00, 0x01, 0x00, 0])[4], 0xff);
+
+    command(&mut bus, &[0x06]);
+    assert_eq!(command(&mut bus, &[0x05, 0])[1], 0x02);
+    command(&mut bus, &[0x02, 0x00, 0x01, 0xff, 0x12, 0x34]);
+    assert_eq!(command(&mut bus, &[0x05, 0])[1], 0x00);
+    // the second byte wrapped around to the start of the page
+    assert_eq!(
+        command(&mut bus, &[0x0b, 0x00, 0x01, 0x00, 0, 0x00, 0x00])[5..],
+        [0x34, 0xff]
+    );
+    assert_eq!(command(&mut bus, &[0x03, 0x00, 0x01, 0xff, 0])[4], 0x12);
+
+    command(&mut bus, &[0x06]);
+    command(&mut bus, &[0x20, 0x00, 0x01, 0x80]);
+    let spi = bus.get_device::<SifiveSpi>().unwrap();
+    let flash = spi.slave::<SpiFlash>(1).unwrap();
+    assert!(flash.data().iter().all(|byte| *byte == 0xff));
+}
+
+#[test]
+fn watchdog_interrupts_then_resets() {
+    let mut bus = Bus::new();
+    bus.add_device(Watchdog::new_device()).unwrap();
+    let tick = |bus: &mut Bus, steps: u64| bus.run_until(bus.now() + steps * NS_PER_STEP);
+
+    // locked registers ignore writes
+    bus.write(WATCHDOG_BASE + 0xc00, 0, Sizes::Word).unwrap();
+    bus.write(WATCHDOG_BASE, 10, Sizes::Word).unwrap();
+    assert_eq!(bus.read(WATCHDOG_BASE, Sizes::Word).unwrap(), u32::MAX);
+    bus.write(WATCHDOG_BASE + 0xc00, 0x1acc_e551, Sizes::Word)
+        .unwrap();
+    bus.write(WATCHDOG_BASE, 10, Sizes::Word).unwrap();
+    bus.write(WATCHDOG_BASE + 0x8, 0b11, Sizes::Word).unwrap();
+
+    tick(&mut bus, 9);
+    assert_eq!(bus.read(WATCHDOG_BASE + 0x4, Sizes::Word).unwrap(), 1);
+    assert_eq!(bus.read(WATCHDOG_BASE + 0x10, Sizes::Word).unwrap(), 0);
+    tick(&mut bus, 1);
+    assert_eq!(bus.read(WATCHDOG_BASE + 0x14, Sizes::Word).unwrap(), 1);
+    assert!(bus.get_device::<Watchdog>().unwrap().interrupt_pending());
+
+    // kicking clears the interrupt and restarts the count
+    bus.write(WATCHDOG_BASE + 0xc, 0, Sizes::Word).unwrap();
+    assert_eq!(bus.read(WATCHDOG_BASE + 0x10, Sizes::Word).unwrap(), 0);
+    tick(&mut bus, 19);
+    assert_eq!(bus.take_request(), None);
+    tick(&mut bus, 1);
+    assert_eq!(
+        bus.take_request(),
+        Some(DeviceRequest::Exit(ExitStatus::WatchdogReset))
+    );
+    assert!(!ExitStatus::WatchdogReset.success());
+
+    // an armed watchdog starts over after a reset
+    let mut bus = Bus::new();
+    bus.add_device(Watchdog::armed_device(1000 * NS_PER_STEP))
+        .unwrap();
+    bus.get_devices_mut()[0].reset();
+    let watchdog = bus.get_device::<Watchdog>().unwrap();
+    assert!(watchdog.running());
+    assert_eq!(watchdog.remaining_ns(), 1000 * NS_PER_STEP);
+    tick(&mut bus, 1999);
+    assert_eq!(bus.take_request(), None);
+    tick(&mut bus, 1);
+    assert_eq!(
+        bus.take_request(),
+        Some(DeviceRequest::Exit(ExitStatus::WatchdogReset))
+    );
+}
+
+#[test]
+fn pdma_copies_memory_as_a_bus_master() {
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    bus.add_device(Pdma::new_device()).unwrap();
+    let source = DRAM_BASE + 0x1000;
+    let destination = DRAM_BASE + 0x2000;
+    for i in 0..100 {
+        bus.write(source + i, i as u32, Sizes::Byte).unwrap();
+    }
+
+    // channel 1: claim, describe the transfer and run it with both interrupts enabled
+    let channel = PDMA_BASE + 0x1000;
+    bus.write(channel, 1, Sizes::Word).unwrap();
+    bus.write(channel + 0x8, 100, Sizes::Word).unwrap();
+    bus.write(channel + 0x10, destination as u32, Sizes::Word)
+        .unwrap();
+    bus.write(channel + 0x18, source as u32, Sizes::Word)
+        .unwrap();
+    bus.write(channel, 1 | 1 << 1 | 1 << 14 | 1 << 15, Sizes::Word)
+        .unwrap();
+
+    // the transfer takes two steps
+    bus.run_events();
+    assert_eq!(bus.read(channel + 0x108, Sizes::Word).unwrap(), 36);
+    assert!(!bus.get_device::<Pdma>().unwrap().done_pending(1));
+    bus.run_until(bus.now() + NS_PER_STEP);
+    let control = bus.read(channel, Sizes::Word).unwrap();
+    assert_eq!(control & (1 << 1 | 1 << 30), 1 << 30);
+    assert!(bus.get_device::<Pdma>().unwrap().done_pending(1));
+    for i in 0..100 {
+        assert_eq!(bus.read(destination + i, Sizes::Byte).unwrap(), i as u32);
+    }
+    assert_eq!(bus.read(destination + 100, Sizes::Byte).unwrap(), 0);
+
+    // clearing done lowers the interrupt
+    bus.write(channel, control & !(1 << 30), Sizes::Word)
+        .unwrap();
+    assert!(!bus.get_device::<Pdma>().unwrap().done_pending(1));
+
+    // copying from nowhere is reported as an error
+    bus.write(channel + 0x18, 0x4000_0000, Sizes::Word).unwrap();
+    bus.write(channel, 1 | 1 << 1 | 1 << 15, Sizes::Word)
+        .unwrap();
+    bus.run_events();
+    assert_ne!(bus.read(channel, Sizes::Word).unwrap() & 1 << 31, 0);
+    let pdma = bus.get_device::<Pdma>().unwrap();
+    assert!(pdma.error_pending(1));
+    assert!(pdma.error(1).unwrap().contains("0x40000000"));
+}
+
+/// A device whose register drives its two interrupt outputs, a bit each.
+#[derive(Default)]
+struct IrqSource {
+    lines: Vec<IrqLine>,
+}
+
+impl Device for IrqSource {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, _addr: u64, _size: Sizes) -> anyhow::Result<MemorySize> {
+        Ok(0)
+    }
+
+    fn store(&mut self, _addr: u64, _size: Sizes, value: MemorySize) -> anyhow::Result<()> {
+        for (index, line) in self.lines.iter().enumerate() {
+            line.set(value & (1 << index) != 0);
+        }
+        Ok(())
+    }
+
+    fn connect_irq(&mut self, index: usize, line: IrqLine) {
+        // added again after being removed from a bus
+        self.lines.truncate(index);
+        self.lines.push(line);
+    }
+}
+
+#[test]
+fn irq_lines_follow_the_machine_wiring() {
+    const SOURCE_BASE: u64 = 0x4000_0000;
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Plic::new_device()).unwrap();
+    let source = cpu
+        .add_device(
+            VirtualDevice::new(Box::<IrqSource>::default(), SOURCE_BASE, 0x1000)
+                .with_irqs([IrqTarget::Plic(5), IrqTarget::Mip(MSIP_BIT)]),
+        )
+        .unwrap();
+
+    let mem = cpu.get_interface();
+    mem.write_raw(SOURCE_BASE, 0b11, Sizes::Word).unwrap();
+    mem.write_csr(MTVEC, DRAM_BASE as u32 + 0x100);
+    mem.write_csr(MIE, MSIP_BIT);
+    mem.write_csr(MSTATUS, 1 << 3);
+    cpu.set_pc(DRAM_BASE as u32 + 0x40);
+    cpu.step().unwrap();
+
+    // the line wired straight to the hart interrupts it
+    let mem = cpu.get_interface();
+    assert_eq!(mem.read_csr(MCAUSE), 1 << 31 | 3);
+    assert_ne!(mem.read_csr(MIP) & MSIP_BIT, 0);
+    // the other one is pending at the PLIC, which has no context enabled for it
+    assert!(cpu.get_device::<Plic>().unwrap().is_pending(5));
+
+    // once the device is removed its lines are low, whatever it does with them
+    let mut device = cpu.remove_device(source).unwrap();
+    device.store(0, Sizes::Word, 0).unwrap();
+    device.store(0, Sizes::Word, 0b11).unwrap();
+    // nops
+    let mem = cpu.get_interface();
+    mem.write_raw(DRAM_BASE + 0x100, 0x13, Sizes::Word).unwrap();
+    mem.write_raw(DRAM_BASE + 0x104, 0x13, Sizes::Word).unwrap();
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_interface().read_csr(MIP) & MSIP_BIT, 0);
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32 + 0x104);
+
+    // after that software owns the bit again
+    cpu.get_interface().write_csr(MIP, MSIP_BIT);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_interface().read_csr(MIP) & MSIP_BIT, MSIP_BIT);
+
+    // a PLIC source is raised while any of its lines is
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Plic::new_device()).unwrap();
+    for base in [SOURCE_BASE, SOURCE_BASE + 0x1000] {
+        cpu.add_device(
+            VirtualDevice::new(Box::<IrqSource>::default(), base, 0x1000)
+                .with_irqs([IrqTarget::Plic(5)]),
+        )
+        .unwrap();
+    }
+    let mem = cpu.get_interface();
+    mem.write_raw(SOURCE_BASE, 1, Sizes::Word).unwrap();
+    mem.write_raw(DRAM_BASE, 0x13, Sizes::Word).unwrap();
+    cpu.set_pc(DRAM_BASE as u32);
+    cpu.step().unwrap();
+    assert!(cpu.get_device::<Plic>().unwrap().is_pending(5));
+
+    // the bus follows the level of every line
+    let mut bus = Bus::new();
+    bus.add_device(Plic::new_device()).unwrap();
+    bus.add_device(
+        VirtualDevice::new(Box::<IrqSource>::default(), SOURCE_BASE, 0x1000)
+            .with_irqs([IrqTarget::Plic(5), IrqTarget::Mip(MSIP_BIT)]),
+    )
+    .unwrap();
+    bus.write(SOURCE_BASE, 0b01, Sizes::Word).unwrap();
+    assert_eq!(
+        bus.irq_levels().collect::<Vec<_>>(),
+        [
+            (IrqTarget::Mip(MEIP_BIT), false),
+            (IrqTarget::Mip(SEIP_BIT), false),
+            (IrqTarget::Plic(5), true),
+            (IrqTarget::Mip(MSIP_BIT), false),
+        ]
+    );
+}
+
+/// A device that schedules an event at the time written to it, 0 cancelling it, and logs when
+/// its events run.
+struct Alarm {
+    id: u32,
+    timer: Timer,
+    log: Rc<RefCell<Vec<(u32, u64)>>>,
+}
+
+impl Device for Alarm {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn load(&self, _addr: u64, _size: Sizes) -> anyhow::Result<MemorySize> {
+        Ok(0)
+    }
+
+    fn store(&mut self, _addr: u64, _size: Sizes, value: MemorySize) -> anyhow::Result<()> {
+        match value {
+            0 => self.timer.cancel(),
+            time => self.timer.schedule_at(time as u64),
+        }
+        Ok(())
+    }
+
+    fn connect_timer(&mut self, timer: Timer) {
+        self.timer = timer;
+    }
+
+    fn event(&mut self, _memory: &mut riscv_vm::bus::BusView) {
+        self.log.borrow_mut().push((self.id, self.timer.now()));
+    }
+}
+
+#[test]
+fn events_run_at_their_deadlines() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut bus = Bus::new();
+    for id in 0..2 {
+        let alarm = Alarm {
+            id,
+            timer: Timer::new(),
+            log: log.clone(),
+        };
+        bus.add_device(VirtualDevice::new(
+            Box::new(alarm),
+            0x1000 * id as u64,
+            0x1000,
+        ))
+        .unwrap();
+    }
+    assert_eq!(bus.next_event(), None);
+
+    // rescheduling replaces the deadline
+    bus.write(0x0, 300, Sizes::Word).unwrap();
+    bus.write(0x0, 150, Sizes::Word).unwrap();
+    bus.write(0x1000, 120, Sizes::Word).unwrap();
+    assert_eq!(bus.next_event(), Some(120));
+    bus.run_until(140);
+    assert_eq!(*log.borrow(), [(1, 120)]);
+    assert_eq!(bus.now(), 140);
+    bus.run_until(1000);
+    assert_eq!(*log.borrow(), [(1, 120), (0, 150)]);
+    assert_eq!(bus.next_event(), None);
+
+    // a cancelled event never runs, one in the past runs right away
+    bus.write(0x0, 2000, Sizes::Word).unwrap();
+    bus.write(0x0, 0, Sizes::Word).unwrap();
+    bus.write(0x1000, 10, Sizes::Word).unwrap();
+    assert_eq!(bus.next_event(), Some(1000));
+    bus.run_events();
+    bus.run_until(3000);
+    assert_eq!(*log.borrow(), [(1, 120), (0, 150), (1, 1000)]);
+}
+
+#[test]
+fn bus_rejects_overlapping_mappings() {
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    bus.add_device(TestFinisher::new_device()).unwrap();
+    bus.add_device(GoldfishRtc::new_device(TimeSource::Fixed(0)))
+        .unwrap();
+
+    let error = bus
+        .add_device(VirtualDevice::new(
+            Box::new(Watchdog::new()),
+            RTC_BASE + 0x800,
+            0x1000,
+        ))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Watchdog at 0x101800-0x1027ff overlaps GoldfishRtc at 0x101000-0x101fff"
+    );
+    let error = bus
+        .add_device(VirtualDevice::new(
+            Box::new(Dram::new()),
+            DRAM_BASE - 0x1000,
+            0x2000,
+        ))
+        .unwrap_err();
+    assert!(error.to_string().contains("overlaps Dram at 0x80000000-"));
+    assert!(bus
+        .add_device(VirtualDevice::new(Box::new(Dram::new()), 0x2000_0000, 0))
+        .is_err());
+    assert!(bus
+        .add_device(VirtualDevice::new(Box::new(Dram::new()), u64::MAX, 2))
+        .is_err());
+
+    // devices are listed by address, whatever order they were added in
+    let map: Vec<String> = bus.memory_map().iter().map(ToString::to_string).collect();
+    assert_eq!(
+        map,
+        [
+            "0x00100000-0x00100fff TestFinisher",
+            "0x00101000-0x00101fff GoldfishRtc",
+            "0x80000000-0x87ffffff Dram",
+        ]
+    );
+
+    // accesses alternating between devices and into the holes between them
+    bus.write(DRAM_BASE + 8, 0x1234, Sizes::Word).unwrap();
+    assert_eq!(bus.read(RTC_BASE + 0x10, Sizes::Word).unwrap(), 0);
+    assert_eq!(bus.read(DRAM_BASE + 8, Sizes::Word).unwrap(), 0x1234);
+    assert!(bus.read(RTC_BASE + 0x1000, Sizes::Word).is_err());
+    assert!(bus.read(0, Sizes::Word).is_err());
+    assert!(bus.read(DRAM_BASE + DRAM_SIZE, Sizes::Word).is_err());
+}
+
+#[test]
+fn device_handles_remap_disable_and_remove() {
+    const SOURCE_BASE: u64 = 0x4000_0000;
+    let mut bus = Bus::new();
+    let first = bus
+        .add_device(GoldfishRtc::new_device(TimeSource::Fixed(1)))
+        .unwrap();
+    let second = bus
+        .add_device(VirtualDevice::new(
+            Box::new(GoldfishRtc::new(TimeSource::Fixed(2))),
+            RTC_BASE + RTC_SIZE,
+            RTC_SIZE,
+        ))
+        .unwrap();
+    let source = bus
+        .add_device(
+            VirtualDevice::new(Box::<IrqSource>::default(), SOURCE_BASE, 0x1000)
+                .with_irqs([IrqTarget::Plic(5)]),
+        )
+        .unwrap();
+
+    // typed lookups tell devices of the same type apart
+    let time_source = |bus: &Bus, handle| bus.device::<GoldfishRtc>(handle).unwrap().source();
+    assert_eq!(time_source(&bus, first), TimeSource::Fixed(1));
+    assert_eq!(time_source(&bus, second), TimeSource::Fixed(2));
+    assert!(bus.device::<Watchdog>(first).is_none());
+
+    // remapping moves the device, unless the new range is taken
+    bus.remap(second, 0x5000_0000).unwrap();
+    assert!(bus.read(RTC_BASE + RTC_SIZE, Sizes::Word).is_err());
+    assert_eq!(bus.read(0x5000_0000, Sizes::Word).unwrap(), 2_000_000_000);
+    let error = bus.remap(second, RTC_BASE + 0x800).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "GoldfishRtc at 0x101800-0x1027ff overlaps GoldfishRtc at 0x101000-0x101fff"
+    );
+    assert_eq!(bus.read(0x5000_0000, Sizes::Word).unwrap(), 2_000_000_000);
+
+    // a disabled device is not decoded and leaves its range to others
+    bus.disable(first).unwrap();
+    assert!(!bus.is_enabled(first));
+    assert!(bus.read(RTC_BASE, Sizes::Word).is_err());
+    assert_eq!(time_source(&bus, first), TimeSource::Fixed(1));
+    let watchdog = bus
+        .add_device(VirtualDevice::new(
+            Box::new(Watchdog::new()),
+            RTC_BASE,
+            RTC_SIZE,
+        ))
+        .unwrap();
+    assert!(bus.enable(first).is_err());
+    bus.remove(watchdog).unwrap();
+    bus.enable(first).unwrap();
+    assert_eq!(bus.read(RTC_BASE, Sizes::Word).unwrap(), 1_000_000_000);
+
+    // the lines of disabled and removed devices read as low
+    bus.write(SOURCE_BASE, 1, Sizes::Word).unwrap();
+    let level = |bus: &Bus| {
+        bus.irq_levels()
+            .filter(|(target, _)| *target == IrqTarget::Plic(5))
+            .any(|(_, raised)| raised)
+    };
+    assert!(level(&bus));
+    bus.disable(source).unwrap();
+    assert!(!level(&bus));
+    bus.enable(source).unwrap();
+    assert!(level(&bus));
+
+    // a removed device is disconnected, raising its line again changes nothing
+    let mut device = bus.remove(source).unwrap();
+    assert!(!level(&bus));
+    device.store(0, Sizes::Word, 0).unwrap();
+    device.store(0, Sizes::Word, 1).unwrap();
+    assert!(!level(&bus));
+    let lines = |bus: &Bus| {
+        bus.irq_levels()
+            .filter(|(target, _)| *target == IrqTarget::Plic(5))
+            .count()
+    };
+    assert_eq!(lines(&bus), 0);
+
+    // it can be added again, with a new handle
+    assert!(bus.device::<IrqSource>(source).is_none());
+    assert!(bus.remove(source).is_err());
+    assert!(bus.remap(source, 0).is_err());
+    let source_again = bus.add_device(device).unwrap();
+    assert_ne!(source_again, source);
+    bus.write(SOURCE_BASE, 1, Sizes::Word).unwrap();
+    assert!(level(&bus));
+    assert_eq!(lines(&bus), 1);
+
+    // the connections of removed devices don't pile up
+    let device = bus.remove(source_again).unwrap();
+    bus.add_device(device).unwrap();
+    assert_eq!(lines(&bus), 1);
+}
+
+#[test]
+fn ram_and_rom_are_accessed_in_place() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(VirtualDevice::new(
+        Box::new(Rom::new_with_data(vec![0x13, 0x05, 0x80, 0xfa])),
+        MROM_BASE,
+        4,
+    ))
+    .unwrap();
+    cpu.add_device(TestFinisher::new_device()).unwrap();
+
+    // stores land in the memory of the device and loads sign-extend like the device does
+    let mem = cpu.get_interface();
+    mem.write_raw(DRAM_BASE + 0x10, 0x8180_7f01, Sizes::Word)
+        .unwrap();
+    mem.write_raw(DRAM_BASE + 0x14, 0xfffe, Sizes::HalfWord)
+        .unwrap();
+    mem.write_raw(DRAM_BASE + 0x16, 0x1ff, Sizes::Byte).unwrap();
+    assert_eq!(
+        mem.read_raw(DRAM_BASE + 0x12, Sizes::Byte).unwrap(),
+        0xffff_ff80
+    );
+    assert_eq!(
+        mem.read_raw(DRAM_BASE + 0x14, Sizes::HalfWord).unwrap(),
+        0xffff_fffe
+    );
+    for size in [Sizes::Byte, Sizes::HalfWord, Sizes::Word] {
+        for offset in 0x10..0x18 {
+            let value = cpu
+                .get_interface()
+                .read_raw(DRAM_BASE + offset, size)
+                .unwrap();
+            let dram = cpu.get_device::<Dram>().unwrap();
+            assert_eq!(value, dram.read(offset, size).unwrap());
+        }
+    }
+    assert_eq!(
+        cpu.get_device::<Dram>().unwrap().host_memory().unwrap()[0x10..0x18],
+        [0x01, 0x7f, 0x80, 0x81, 0xfe, 0xff, 0xff, 0x00]
+    );
+
+    // accesses running off the end of the memory fault instead of being cut short
+    let mem = cpu.get_interface();
+    let end = DRAM_BASE + DRAM_SIZE;
+    assert!(mem.read_raw(end - 2, Sizes::Word).is_err());
+    assert!(mem.write_raw(end - 1, 0, Sizes::HalfWord).is_err());
+    mem.write_raw(end - 4, 0x1234_5678, Sizes::Word).unwrap();
+    assert_eq!(mem.read_raw(end - 4, Sizes::Word).unwrap(), 0x1234_5678);
+
+    // the ROM is read in place but stays read-only
+    assert_eq!(mem.read_raw(MROM_BASE, Sizes::Word).unwrap(), 0xfa80_0513);
+    assert_eq!(
+        mem.read_raw(MROM_BASE + 3, Sizes::Byte).unwrap(),
+        0xffff_fffa
+    );
+    assert!(mem.write_raw(MROM_BASE, 0, Sizes::Word).is_err());
+
+    // other devices still see every access
+    mem.write_raw(TEST_FINISHER_BASE, 0x5555, Sizes::Word)
+        .unwrap();
+    assert_eq!(cpu.run().unwrap(), ExitStatus::Exit { code: 0 });
+}
+
+#[test]
+fn watches_see_the_accesses_they_filter() {
+    let mut cpu = Riscv32Cpu::new();
+    let dram = cpu.add_device(Dram::new_device()).unwrap();
+    let rtc = cpu
+        .add_device(GoldfishRtc::new_device(TimeSource::Fixed(0)))
+        .unwrap();
+    let program = [
+        0x0010_1537, // lui a0, 0x101
+        0x0005_2583, // lw a1, 0(a0)
+        0x0010_0613, // li a2, 1
+        0x00c5_2823, // sw a2, 16(a0)
+        0x8000_16b7, // lui a3, 0x80001
+        0x00c6_a023, // sw a2, 0(a3)
+    ];
+    for (index, instruction) in program.into_iter().enumerate() {
+        cpu.get_interface()
+            .write_raw(DRAM_BASE + 4 * index as u64, instruction, Sizes::Word)
+            .unwrap();
+    }
+
+    let buffer = TraceBuffer::new();
+    cpu.watch(
+        WatchFilter::new().with_range(RTC_BASE..RTC_BASE + RTC_SIZE),
+        buffer.clone(),
+    );
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let log = lines.clone();
+    cpu.watch(
+        WatchFilter::new()
+            .with_device(dram)
+            .with_kind(AccessKind::Write),
+        move |access: &MmioAccess| log.borrow_mut().push(access.to_string()),
+    );
+    let ones = Rc::new(Cell::new(0));
+    let count = ones.clone();
+    let counter = cpu.watch(
+        WatchFilter::new()
+            .with_value(1)
+            .with_size(Sizes::Word)
+            .with_hart(0)
+            .with_pc(DRAM_BASE as u32..DRAM_BASE as u32 + 0x10),
+        move |_: &MmioAccess| count.set(count.get() + 1),
+    );
+
+    cpu.set_pc(DRAM_BASE as u32);
+    for _ in 0..program.len() {
+        cpu.step().unwrap();
+    }
+
+    // structured records of the RTC registers the program touched
+    let records = buffer.take();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].kind, AccessKind::Read);
+    assert_eq!(records[0].pc, DRAM_BASE as u32 + 4);
+    assert_eq!(records[0].offset, 0);
+    assert_eq!(
+        records[1],
+        MmioAccess {
+            kind: AccessKind::Write,
+            address: RTC_BASE + 0x10,
+            size: Sizes::Word,
+            value: 1,
+            hart: 0,
+            pc: DRAM_BASE as u32 + 0xc,
+            device: rtc,
+            device_name: "GoldfishRtc",
+            offset: 0x10,
+        }
+    );
+    // RAM is watched too, without the instruction fetches that are reads
+    assert_eq!(
+        *lines.borrow(),
+        ["hart0 0x80000014 W.w 0x80001000 Dram+0x1000 0x00000001"]
+    );
+    // only the store to the RTC is in the range of instructions watched
+    assert_eq!(ones.get(), 1);
+    assert!(cpu.unwatch(counter));
+    assert!(!cpu.unwatch(counter));
+}
+
+#[test]
+fn device_tree_describes_the_machine() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Plic::new_device()).unwrap();
+    let rtc = cpu
+        .add_device(GoldfishRtc::new_device(TimeSource::Emulated(0)))
+        .unwrap();
+    cpu.add_device(TestFinisher::new_device()).unwrap();
+    cpu.add_device(Watchdog::new_device()).unwrap();
+    // one pin on the PLIC and one straight to the hart
+    cpu.add_device(Gpio::new_device(2).with_irqs([IrqTarget::Plic(5), IrqTarget::Mip(MSIP_BIT)]))
+        .unwrap();
+
+    // phandles: 1 and 2 for the hart and its interrupt controller, then the devices by address
+    let dts = cpu.device_tree();
+    assert!(dts.contains("riscv,isa = \"rv32ima\";"));
+    assert!(dts.contains("mmu-type = \"riscv,sv32\";"));
+    assert!(dts.contains("timebase-frequency = <0x989680>;"));
+    assert!(dts.contains(
+        "memory@80000000 {\n\t\tdevice_type = \"memory\";\n\t\treg = <0x0 0x80000000 0x0 0x8000000>;"
+    ));
+    assert!(dts.contains(
+        "\t\trtc@101000 {\n\t\t\treg = <0x0 0x101000 0x0 0x1000>;\n\t\t\tinterrupts = <0xb>;\n\t\t\tinterrupt-parent = <0x6>;\n\t\t\tcompatible = \"google,goldfish-rtc\";\n\t\t};\n"
+    ));
+    assert!(dts.contains("interrupt-controller@c000000 {\n\t\t\tphandle = <0x6>;"));
+    assert!(dts.contains("interrupts-extended = <0x2 0xb 0x2 0x9>;"));
+    assert!(dts.contains("interrupts-extended = <0x6 0x5 0x2 0x3>;"));
+    assert!(dts.contains("test@100000 {\n\t\t\tphandle = <0x3>;"));
+    assert!(dts.contains("regmap = <0x3>;"));
+    assert!(dts.contains("clocks = <0x5 0x5>;"));
+    for absent in ["uart", "clint", "rv64", "sv39", "virtio_mmio"] {
+        assert!(!dts.contains(absent), "{absent}");
+    }
+
+    // devices that are not mapped are not described
+    cpu.disable_device(rtc).unwrap();
+    assert!(!cpu.device_tree().contains("rtc@"));
+
+    // the ROM carries the tree but is not in it
+    cpu.add_rom(&Chosen::new()).unwrap();
+    assert!(cpu.get_device::<Rom>().is_some());
+    assert!(!cpu.device_tree().contains("@1000 {"));
+}
+
+/// A device tree blob like `dtc` writes for
+/// `/memreserve/ 0x80000000 0x1000; / { model = "test"; chosen { bootargs = "quiet"; }; };`.
+fn user_dtb() -> Vec<u8> {
+    let words =
+        |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|word| word.to_be_bytes()).collect() };
+    let structure = [
+        words(&[1, 0, 3, 5, 0]),
+        b"test\0\0\0\0".to_vec(),
+        words(&[1]),
+        b"chosen\0\0".to_vec(),
+        words(&[3, 6, 6]),
+        b"quiet\0\0\0".to_vec(),
+        words(&[2, 2, 9]),
+    ]
+    .concat();
+    let strings = b"model\0bootargs\0";
+
+    let total = 40 + 32 + structure.len() + strings.len();
+    let mut dtb = Vec::new();
+    let header = [
+        0xd00d_feed,
+        total as u32,
+        72,
+        72 + structure.len() as u32,
+        40,
+        17,
+        16,
+        0,
+        strings.len() as u32,
+        structure.len() as u32,
+    ];
+    for word in header {
+        dtb.extend_from_slice(&word.to_be_bytes());
+    }
+    for value in [0x8000_0000u64, 0x1000, 0, 0] {
+        dtb.extend_from_slice(&value.to_be_bytes());
+    }
+    dtb.extend_from_slice(&structure);
+    dtb.extend_from_slice(strings);
+    dtb
+}
+
+#[test]
+fn user_device_trees_get_the_chosen_node() {
+    let dtb = user_dtb();
+    let fdt = Fdt::parse(&dtb).unwrap();
+    assert_eq!(fdt.to_dtb(), dtb);
+    assert_eq!(fdt.property("/", "model"), Some(&b"test\0"[..]));
+    assert_eq!(fdt.property("/chosen", "bootargs"), Some(&b"quiet\0"[..]));
+
+    let chosen = Chosen::new()
+        .with_bootargs("console=hvc0")
+        .with_stdout_path("/soc/virtio_mmio@10001000")
+        .with_initrd(0x8400_0000..0x8410_0000)
+        .with_rng_seed([1, 2, 3, 4]);
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_rom_with_device_tree(fdt, &chosen).unwrap();
+
+    // the blob in the ROM is where a1 points at boot
+    let mem = cpu.get_interface();
+    let rom: Vec<u8> = (0..0x200)
+        .map(|offset| {
+            mem.read_raw(POINTER_TO_DTB as u64 + offset, Sizes::Byte)
+                .unwrap() as u8
+        })
+        .collect();
+    let fdt = Fdt::parse(&rom).unwrap();
+    assert_eq!(fdt.property("/", "model"), Some(&b"test\0"[..]));
+    let property = |name| fdt.property("/chosen", name).unwrap();
+    assert_eq!(property("bootargs"), b"console=hvc0\0");
+    assert_eq!(property("stdout-path"), b"/soc/virtio_mmio@10001000\0");
+    assert_eq!(property("linux,initrd-start"), 0x8400_0000u64.to_be_bytes());
+    assert_eq!(property("linux,initrd-end"), 0x8410_0000u64.to_be_bytes());
+    assert_eq!(property("rng-seed"), [1, 2, 3, 4]);
+
+    // trees without a chosen node get one
+    let mut fdt = Fdt::parse(&dtb).unwrap();
+    fdt.set_string("/soc/uart@10000000", "status", "okay");
+    assert_eq!(fdt.property("/soc/uart", "status"), Some(&b"okay\0"[..]));
+
+    let chosen = Chosen::new().with_rng_seed(vec![0; MROM_SIZE as usize]);
+    let error = Riscv32Cpu::new()
+        .add_rom_with_device_tree(fdt, &chosen)
+        .unwrap_err()
+        .to_string();
+    assert!(error.ends_with("does not fit the ROM, which has room for 61408 bytes"));
+
+    assert_eq!(
+        Fdt::parse(b"\x7fELF").unwrap_err().to_string(),
+        "not a device tree blob"
+    );
+    assert_eq!(
+        Fdt::parse(&dtb[..dtb.len() - 4]).unwrap_err().to_string(),
+        "the device tree blob says it has 159 bytes but has 155"
+    );
+}
+
+#[test]
+fn device_tree_source_compiles_or_fails() {
+    // the tree of the machine survives being compiled, read and written again
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Plic::new_device()).unwrap();
+    let fdt = Fdt::from_dts(&cpu.device_tree()).unwrap();
+    assert_eq!(
+        fdt.property("/", "model"),
+        Some(&b"riscv-virtio,qemu\0"[..])
+    );
+    let dtb = fdt.to_dtb();
+    assert_eq!(Fdt::parse(&dtb).unwrap(), fdt);
+    assert_eq!(Fdt::parse(&dtb).unwrap().to_dtb(), dtb);
+
+    // source that is cut short is an error instead of a panic
+    let malformed = "/dts-v1/;\n\n/ {\n\tmodel = \"cut short\";\n";
+    assert!(device_tree::compile(malformed).is_err());
+    let error = Fdt::from_dts(malformed).unwrap_err().to_string();
+    assert!(error.starts_with("the device tree source did not compile"));
+}
+
+#[test]
+fn powered_on_harts_boot_through_the_reset_vector() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(TestFinisher::new_device()).unwrap();
+    cpu.add_rom(&Chosen::new()).unwrap();
+    assert_eq!(cpu.get_pc(), MROM_BASE as u32);
+    assert_eq!(cpu.get_device::<Rom>().unwrap().entry(), DRAM_BASE as u32);
+
+    // the program at the entry fails with a0 + a1, the hart id and the tree, as its exit code
+    let program = [
+        0x0010_02b7, // lui   t0, 0x100
+        0x00a5_8333, // add   t1, a1, a0
+        0x0103_1313, // slli  t1, t1, 16
+        0x0000_33b7, // lui   t2, 0x3
+        0x3333_8393, // addi  t2, t2, 0x333
+        0x0073_0333, // add   t1, t1, t2
+        0x0062_a023, // sw    t1, 0(t0)
+        0x0000_006f, // j     .
+    ];
+    for (i, inst) in program.into_iter().enumerate() {
+        let address = DRAM_BASE as u32 + i as u32 * 4;
+        cpu.write(address, inst, Sizes::Word, AccessType::Writable)
+            .unwrap();
+    }
+    // clobber the arguments to see the reset vector set them
+    *cpu.get_register_mut(10).unwrap() = 0xdead;
+    *cpu.get_register_mut(11).unwrap() = 0xdead;
+    assert_eq!(
+        cpu.run().unwrap(),
+        ExitStatus::Exit {
+            code: POINTER_TO_DTB
+        }
+    );
+
+    // a reset boots through the ROM again, which keeps its entry
+    cpu.reset();
+    assert_eq!(cpu.get_pc(), MROM_BASE as u32);
+    assert_eq!(cpu.get_device::<Rom>().unwrap().entry(), DRAM_BASE as u32);
+}
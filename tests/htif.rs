@@ -0,0 +1,81 @@
+use riscv_vm::{
+    cpu::{AccessType, Riscv32Cpu},
+    htif::Htif,
+    memory::dram::{Dram, Sizes, DRAM_BASE},
+};
+
+fn store64(cpu: &mut Riscv32Cpu, address: u64, value: u64) {
+    let address = address as u32;
+    cpu.write(address, value as u32, Sizes::Word, AccessType::Writable)
+        .unwrap();
+    cpu.write(
+        address + 4,
+        (value >> 32) as u32,
+        Sizes::Word,
+        AccessType::Writable,
+    )
+    .unwrap();
+}
+
+fn load64(cpu: &mut Riscv32Cpu, address: u64) -> u64 {
+    let address = address as u32;
+    let low = cpu
+        .read(address, Sizes::Word, AccessType::Readable)
+        .unwrap() as u64;
+    let high = cpu
+        .read(address + 4, Sizes::Word, AccessType::Readable)
+        .unwrap() as u64;
+    high << 32 | low
+}
+
+#[test]
+fn symbols_of_riscv_tests() {
+    let elf = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/rvtests/rv32ui_p_add"
+    ));
+    let htif = Htif::from_elf(elf).unwrap();
+    assert_eq!(htif.tohost(), 0x8000_1000);
+    assert_eq!(htif.fromhost(), 0x8000_1040);
+
+    assert!(Htif::from_elf(b"not an elf file").is_err());
+}
+
+#[test]
+fn console_and_syscall_proxy() {
+    let tohost = DRAM_BASE + 0x1000;
+    let fromhost = DRAM_BASE + 0x1040;
+    let magic_mem = DRAM_BASE + 0x2000;
+    let buffer = DRAM_BASE + 0x3000;
+
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    let mut htif = Htif::new(tohost, fromhost);
+    htif.set_echo(false);
+    cpu.set_htif(htif);
+
+    // putchar on the console device
+    store64(&mut cpu, tohost, 1 << 56 | 1 << 48 | b'h' as u64);
+    assert_eq!(load64(&mut cpu, tohost), 0);
+    assert_eq!(load64(&mut cpu, fromhost), 1 << 56 | 1 << 48);
+    store64(&mut cpu, fromhost, 0);
+
+    // write(1, buffer, 5) through the syscall proxy
+    for (i, byte) in b"ello\n".iter().enumerate() {
+        cpu.write(
+            buffer as u32 + i as u32,
+            *byte as u32,
+            Sizes::Byte,
+            AccessType::Writable,
+        )
+        .unwrap();
+    }
+    for (i, arg) in [64, 1, buffer, 5].iter().enumerate() {
+        store64(&mut cpu, magic_mem + i as u64 * 8, *arg);
+    }
+    store64(&mut cpu, tohost, magic_mem);
+    assert_eq!(load64(&mut cpu, magic_mem), 5);
+    assert_eq!(load64(&mut cpu, fromhost), 1);
+
+    assert_eq!(cpu.htif_mut().unwrap().take_output(), b"hello\n");
+}
@@ -0,0 +1,359 @@
+use riscv_vm::{
+    cpu::Riscv32Cpu,
+    devices::pflash::{PFlash, PFlashConfig, PFLASH_BASE},
+    loader::{
+        elf::{Class, Elf},
+        ihex, srec,
+    },
+    memory::dram::{Dram, Sizes, DRAM_BASE, DRAM_SIZE},
+    rom::{Rom, MROM_BASE},
+};
+
+const EF_RISCV_RVC: u32 = 0x1;
+const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+
+/// A RISC-V ELF32 executable without sections whose `(address, contents, size in memory)`
+/// segments follow the headers.
+fn elf32(entry: u32, flags: u32, segments: &[(u32, &[u8], u32)]) -> Vec<u8> {
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+    for half in [2u16, 243] {
+        elf.extend_from_slice(&half.to_le_bytes());
+    }
+    for word in [1, entry, 52, 0, flags] {
+        elf.extend_from_slice(&word.to_le_bytes());
+    }
+    for half in [52u16, 32, segments.len() as u16, 40, 0, 0] {
+        elf.extend_from_slice(&half.to_le_bytes());
+    }
+
+    let mut offset = 52 + 32 * segments.len() as u32;
+    for (address, data, size) in segments {
+        let header = [
+            1,
+            offset,
+            *address,
+            *address,
+            data.len() as u32,
+            *size,
+            7,
+            4,
+        ];
+        for word in header {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+        offset += data.len() as u32;
+    }
+    for (_, data, _) in segments {
+        elf.extend_from_slice(data);
+    }
+    elf
+}
+
+fn read(cpu: &Riscv32Cpu, address: u64, len: usize) -> Vec<u8> {
+    let dram = cpu.get_device::<Dram>().unwrap();
+    (address - DRAM_BASE..)
+        .take(len)
+        .map(|offset| dram.read(offset, Sizes::Byte).unwrap() as u8)
+        .collect()
+}
+
+#[test]
+fn riscv_tests_load_like_their_flat_binaries() {
+    let elf = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/rvtests/rv32ui_p_add"
+    ));
+    let flat = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/rvtests/rv32ui_p_add.bin"
+    ));
+
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.set_pc(0);
+    let symbols = cpu.load_elf(elf).unwrap();
+
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32);
+    assert_eq!(read(&cpu, DRAM_BASE, flat.len()), flat);
+    assert_eq!(symbols["tohost"], 0x8000_1000);
+    assert_eq!(symbols["fromhost"], 0x8000_1040);
+
+    let parsed = Elf::parse(elf).unwrap();
+    assert_eq!(parsed.class(), Class::Elf32);
+    assert_eq!(parsed.entry().unwrap(), DRAM_BASE);
+    assert_eq!(parsed.segments().unwrap().len(), 2);
+}
+
+#[test]
+fn loaded_programs_run_from_their_entry() {
+    let text = [
+        0x02a0_0513u32, // li a0, 42
+        0x0000_006f,    // j .
+    ]
+    .map(u32::to_le_bytes)
+    .concat();
+    let elf = elf32(
+        DRAM_BASE as u32,
+        0,
+        &[(DRAM_BASE as u32, &text, text.len() as u32)],
+    );
+
+    // without a ROM the hart starts at the entry itself
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.load_elf(&elf).unwrap();
+    cpu.step().unwrap();
+    assert_eq!(*cpu.get_register(10).unwrap(), 42);
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32 + 4);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32 + 4);
+
+    // with one the reset vector jumps there
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Rom::new_device()).unwrap();
+    cpu.load_elf(&elf).unwrap();
+    for _ in 0..6 {
+        cpu.step().unwrap();
+    }
+    assert_eq!(*cpu.get_register(10).unwrap(), 42);
+    assert_eq!(cpu.get_pc(), DRAM_BASE as u32 + 4);
+}
+
+#[test]
+fn bss_is_zeroed_and_segments_need_memory() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Rom::new_device()).unwrap();
+    cpu.get_device_mut::<Dram>()
+        .unwrap()
+        .initialize(&[0xaa; 0x3000]);
+
+    let text = [0x13, 0, 0, 0, 0x6f, 0, 0, 0];
+    let data = [1, 2, 3];
+    let elf = elf32(
+        DRAM_BASE as u32 + 4,
+        0,
+        &[
+            (DRAM_BASE as u32, &text, text.len() as u32),
+            // .data followed by a .bss spanning more than a page
+            (DRAM_BASE as u32 + 0x800, &data, 0x1800),
+        ],
+    );
+    cpu.load_elf(&elf).unwrap();
+    // the hart boots through the reset vector of the ROM
+    assert_eq!(cpu.get_pc(), MROM_BASE as u32);
+    assert_eq!(
+        cpu.get_device::<Rom>().unwrap().entry(),
+        DRAM_BASE as u32 + 4
+    );
+    assert_eq!(
+        read(&cpu, DRAM_BASE, 9),
+        [0x13, 0, 0, 0, 0x6f, 0, 0, 0, 0xaa]
+    );
+    assert_eq!(read(&cpu, DRAM_BASE + 0x800, 4), [1, 2, 3, 0]);
+    let bss = read(&cpu, DRAM_BASE + 0x803, 0x17fd);
+    assert!(bss.iter().all(|&byte| byte == 0));
+    assert_eq!(read(&cpu, DRAM_BASE + 0x2000, 1), [0xaa]);
+
+    // segments outside of RAM
+    let end = (DRAM_BASE + DRAM_SIZE) as u32;
+    let error = cpu
+        .load_elf(&elf32(end - 4, 0, &[(end - 4, &text, 8)]))
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "no memory for the segment at 0x87fffffc-0x88000004"
+    );
+    assert!(cpu
+        .load_elf(&elf32(0, 0, &[(MROM_BASE as u32, &text, 8)]))
+        .is_err());
+    assert!(cpu
+        .load_elf(&elf32(0, 0, &[(DRAM_BASE as u32, &text, 4)]))
+        .is_err());
+}
+
+#[test]
+fn programs_must_match_the_hart() {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    let load = |cpu: &mut Riscv32Cpu, elf: &[u8]| cpu.load_elf(elf).unwrap_err().to_string();
+
+    let mut elf = elf32(DRAM_BASE as u32, 0, &[]);
+    elf[18] = 62;
+    assert_eq!(
+        load(&mut cpu, &elf),
+        "the ELF file is for machine 62, not RISC-V"
+    );
+
+    let mut elf = elf32(DRAM_BASE as u32, 0, &[]);
+    elf[16] = 3;
+    assert_eq!(load(&mut cpu, &elf), "the ELF file is not an executable");
+
+    let mut elf = elf32(DRAM_BASE as u32, 0, &[]);
+    elf[4] = 2;
+    elf.resize(64, 0);
+    assert_eq!(
+        load(&mut cpu, &elf),
+        "the ELF file is Elf64 but the hart needs Elf32"
+    );
+
+    // the hart has neither the C nor the D extension
+    let elf = elf32(DRAM_BASE as u32, EF_RISCV_RVC, &[]);
+    assert_eq!(
+        load(&mut cpu, &elf),
+        "the program uses compressed instructions but the hart has no C extension"
+    );
+    let elf = elf32(DRAM_BASE as u32, EF_RISCV_FLOAT_ABI_DOUBLE, &[]);
+    assert_eq!(
+        load(&mut cpu, &elf),
+        "the program uses the double float ABI but the hart has no D extension"
+    );
+
+    assert!(cpu.load_elf(b"\x7fELF").is_err());
+}
+
+#[test]
+fn intel_hex_images() {
+    let hex = "\
+:0200000480007A
+:08000000130580FA6F000000F7
+:03000800010203EF
+:020000040000FA
+:02100000AABB89
+:040000058000000473
+:00000001FF
+";
+    let image = ihex::parse(hex).unwrap();
+    assert_eq!(
+        image.chunks,
+        [
+            (
+                DRAM_BASE,
+                vec![0x13, 0x05, 0x80, 0xfa, 0x6f, 0, 0, 0, 1, 2, 3]
+            ),
+            (MROM_BASE, vec![0xaa, 0xbb]),
+        ]
+    );
+    assert_eq!(image.start, Some(DRAM_BASE + 4));
+
+    // ROM is filled too, even though the guest cannot write it
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Rom::new_device()).unwrap();
+    cpu.load_image(&image).unwrap();
+    assert_eq!(cpu.get_pc(), MROM_BASE as u32);
+    assert_eq!(
+        cpu.get_device::<Rom>().unwrap().entry(),
+        DRAM_BASE as u32 + 4
+    );
+    assert_eq!(read(&cpu, DRAM_BASE + 8, 3), [1, 2, 3]);
+    let mem = cpu.get_interface();
+    assert_eq!(
+        mem.read_raw(MROM_BASE, Sizes::HalfWord).unwrap() as u16,
+        0xbbaa
+    );
+    assert!(mem.write_raw(MROM_BASE, 0, Sizes::Byte).is_err());
+
+    // segment addresses
+    let image = ihex::parse(":020000021000EC\n:0400000310000020C9\n:00000001FF").unwrap();
+    assert_eq!(image.start, Some(0x1_0020));
+
+    let error = |hex: &str| format!("{:#}", ihex::parse(hex).unwrap_err());
+    assert_eq!(
+        error(":0200000480007A\n:08000000130580FA6F000000F8\n"),
+        "line 2: checksum is 0xf8, expected 0xf7"
+    );
+    assert_eq!(
+        error(":0200000480007A\n\n:03000800010G03EF\n"),
+        "line 3: invalid hex digit 'G' at column 13"
+    );
+    assert_eq!(
+        error(":03000800010203\n"),
+        "line 1: record says it has 3 data bytes but has 2"
+    );
+    assert_eq!(error("0200000480007A"), "line 1: records start with ':'");
+    assert_eq!(error(":00000007F9\n"), "line 1: unknown record type 0x07");
+    assert_eq!(
+        error(":0100000400FB\n"),
+        "line 1: record of type 0x04 has 1 data bytes instead of 2"
+    );
+    assert_eq!(
+        error(":00000001FF\n:00000001FF\n"),
+        "line 2: record after the end of file record"
+    );
+    assert_eq!(error(":03000800010203EF\n"), "no end of file record");
+}
+
+#[test]
+fn s_record_images() {
+    let srec = "\
+S008000068656C6C6FE3
+S30980000000130580FAE4
+S309800000046F00000003
+S30920000000DEADBEEF9E
+S10410005596
+S5030004F8
+S705800000007A
+";
+    let image = srec::parse(srec).unwrap();
+    assert_eq!(
+        image.chunks,
+        [
+            (DRAM_BASE, vec![0x13, 0x05, 0x80, 0xfa, 0x6f, 0, 0, 0]),
+            (PFLASH_BASE, vec![0xde, 0xad, 0xbe, 0xef]),
+            (MROM_BASE, vec![0x55]),
+        ]
+    );
+    assert_eq!(image.start, Some(DRAM_BASE));
+
+    // the flash is programmed without going through its command set
+    let config = PFlashConfig {
+        size: 0x10000,
+        sector_size: 0x1000,
+        ..PFlashConfig::default()
+    };
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.add_device(Rom::new_device()).unwrap();
+    cpu.add_device(PFlash::new_device(config, PFLASH_BASE).unwrap())
+        .unwrap();
+    cpu.set_pc(0);
+    cpu.load_image(&image).unwrap();
+    assert_eq!(cpu.get_device::<Rom>().unwrap().entry(), DRAM_BASE as u32);
+    let flash = cpu.get_device::<PFlash>().unwrap();
+    assert_eq!(flash.data()[..5], [0xde, 0xad, 0xbe, 0xef, 0xff]);
+
+    // nothing is mapped there
+    let image = srec::parse("S30940000000DEADBEEF7E\nS705800000007A").unwrap();
+    assert_eq!(
+        cpu.load_image(&image).unwrap_err().to_string(),
+        "no device at 0x40000000"
+    );
+
+    let error = |srec: &str| format!("{:#}", srec::parse(srec).unwrap_err());
+    assert_eq!(
+        error("S10410005596\nS70580000000 7A"),
+        "line 2: invalid hex digit ' ' at column 13"
+    );
+    assert_eq!(
+        error("S10410005597\nS9030000FC"),
+        "line 1: checksum is 0x97, expected 0x96"
+    );
+    assert_eq!(
+        error("S10410005596\nS5030002FA\nS9030000FC"),
+        "line 2: 2 data records counted, the file has 1"
+    );
+    assert_eq!(error("S4030000FC"), "line 1: unknown record type S4");
+    assert_eq!(error("S1051000559\n"), "line 1: odd number of hex digits");
+    assert_eq!(
+        error("S10510005596\n"),
+        "line 1: record says it has 5 bytes but has 4"
+    );
+    assert_eq!(
+        error("S9030000FC\nS10410005596"),
+        "line 2: record after the termination record"
+    );
+    assert_eq!(error("S10410005596\n"), "no termination record");
+}
@@ -1,8 +1,7 @@
 use riscv_vm::{
-    bus::VirtualDevice,
-    cpu::{Cpu, Privilege, Riscv32Cpu},
-    memory::dram::{Dram, DRAM_BASE, DRAM_SIZE},
-    trap::Trap,
+    cpu::{Cpu, ExitStatus, Privilege, Riscv32Cpu},
+    htif::Htif,
+    memory::dram::{Dram, DRAM_BASE},
 };
 
 macro_rules! add_test {
@@ -11,41 +10,21 @@ macro_rules! add_test {
         fn $name() -> ::std::io::Result<()> {
             riscv_vm::init_logging(riscv_vm::log::LevelFilter::Debug);
 
-            let data = include_bytes!(concat!(
-                concat!(
-                    concat!(env!("CARGO_MANIFEST_DIR"), "/tests/rvtests/"),
-                    stringify!($name)
-                ),
-                ".bin"
-            ));
+            let path = concat!(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/rvtests/"),
+                stringify!($name)
+            );
             let mut cpu = Riscv32Cpu::new();
-            let dram = {
-                let mut tmp = Box::new(Dram::new());
-                tmp.initialize(data);
-                tmp
-            };
-            cpu.add_device(VirtualDevice::new(dram, DRAM_BASE, DRAM_SIZE));
-            cpu.register_syscall(93, |cpu| {
-                let exit_code = *cpu.get_register(10 /* register ( a0 ) */).unwrap();
-                let gp = *cpu.get_register(3 /* register ( gp ) */).unwrap();
-                cpu.set_privilege(Privilege::Machine);
+            cpu.add_device(Dram::new_device()).unwrap();
 
-                if exit_code != 0 || gp != 1 {
-                    panic!(
-                        "exit code: {0:} [{0:#X}], gp: {1:} [{1:#X}], pc: {2:} [{2:#X}]",
-                        exit_code,
-                        gp,
-                        cpu.get_pc()
-                    );
-                }
+            let elf = std::fs::read(path)?;
+            cpu.load_elf(&elf).unwrap();
+            // the tests report their result through tohost
+            cpu.set_htif(Htif::from_elf(&elf).unwrap());
 
-                Trap::Fatal
-            });
-
-            let _ = cpu.run();
-
-            let a0 = cpu.get_register(10).unwrap();
-            assert_eq!(*a0, 0);
+            let status = cpu.run().unwrap();
+            // a failing test reports the number of the failed case
+            assert!(status.success(), "{status:?}, pc: {:#X}", cpu.get_pc());
 
             let mode = cpu.get_privilege();
             assert_eq!(mode, Privilege::Machine);
@@ -55,6 +34,71 @@ macro_rules! add_test {
     };
 }
 
+/// An executable in the shape of riscv-tests: it stores `code << 1 | 1` to its `tohost` symbol,
+/// like the tests report passing with 0 or the number of the case that failed.
+fn tohost_elf(code: u32) -> Vec<u8> {
+    const CODE: u32 = 84;
+    const SYMTAB: u32 = CODE + 20;
+    const STRTAB: u32 = SYMTAB + 48;
+    const SECTIONS: u32 = STRTAB + 20;
+    let tohost = DRAM_BASE as u32 + 0x1000;
+
+    fn words(elf: &mut Vec<u8>, words: &[u32]) {
+        for word in words {
+            elf.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    let mut elf = b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0".to_vec();
+    // executable for RISC-V with one segment and three sections
+    elf.extend_from_slice(&[2, 0, 243, 0]);
+    words(&mut elf, &[1, DRAM_BASE as u32, 52, SECTIONS, 0]);
+    for half in [52u16, 32, 1, 40, 3, 0] {
+        elf.extend_from_slice(&half.to_le_bytes());
+    }
+    words(
+        &mut elf,
+        &[1, CODE, DRAM_BASE as u32, DRAM_BASE as u32, 20, 20, 7, 4],
+    );
+
+    words(
+        &mut elf,
+        &[
+            0x8000_12b7,                   // lui   t0, 0x80001
+            (code << 1 | 1) << 20 | 0x313, // li    t1, code << 1 | 1
+            0x0062_a023,                   // sw    t1, 0(t0)
+            0x0002_a223,                   // sw    zero, 4(t0)
+            0x0000_006f,                   // j     .
+        ],
+    );
+    // global absolute symbols for tohost and fromhost, and their names
+    words(&mut elf, &[0, 0, 0, 0]);
+    words(&mut elf, &[1, tohost, 8, 0xfff1_0011]);
+    words(&mut elf, &[8, tohost + 0x40, 8, 0xfff1_0011]);
+    elf.extend_from_slice(b"\0tohost\0fromhost\0\0\0\0");
+
+    // the null section, the symbol table and its string table
+    words(&mut elf, &[0; 10]);
+    words(&mut elf, &[0, 2, 0, 0, SYMTAB, 48, 2, 1, 4, 16]);
+    words(&mut elf, &[0, 3, 0, 0, STRTAB, 20, 0, 0, 1, 0]);
+    elf
+}
+
+#[test]
+fn programs_report_through_tohost() {
+    for (code, success) in [(0, true), (3, false)] {
+        let mut cpu = Riscv32Cpu::new();
+        cpu.add_device(Dram::new_device()).unwrap();
+        let elf = tohost_elf(code);
+        cpu.load_elf(&elf).unwrap();
+        cpu.set_htif(Htif::from_elf(&elf).unwrap());
+
+        let status = cpu.run().unwrap();
+        assert_eq!(status, ExitStatus::Exit { code });
+        assert_eq!(status.success(), success);
+    }
+}
+
 /*
 add_test!(rv32mi_p_breakpoint);
 add_test!(rv32mi_p_csr);
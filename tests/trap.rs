@@ -0,0 +1,106 @@
+use riscv_vm::{
+    cpu::{AccessType, Cpu, Privilege, Riscv32Cpu},
+    csr::{MCAUSE, MEPC, MSTATUS, MTVAL, MTVEC},
+    memory::dram::{Dram, Sizes, DRAM_BASE},
+};
+
+const ECALL: u32 = 0x0000_0073;
+const C_EBREAK: u32 = 0x9002;
+
+const MSTATUS_MIE: u32 = 1 << 3;
+const MSTATUS_MPIE: u32 = 1 << 7;
+const MSTATUS_MPP: u32 = 3 << 11;
+
+/// A hart with RAM whose first instruction is an `ecall`.
+fn ecall_cpu() -> Riscv32Cpu {
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.write(DRAM_BASE as u32, ECALL, Sizes::Word, AccessType::Writable)
+        .unwrap();
+    cpu.set_pc(DRAM_BASE as u32);
+    cpu
+}
+
+#[test]
+fn exceptions_trap_into_machine_mode_through_mtvec() {
+    let handler = DRAM_BASE as u32 + 0x100;
+
+    let mut cpu = ecall_cpu();
+    let pc = cpu.get_pc();
+    // vectored mode only applies to interrupts
+    cpu.write_csr(MTVEC, handler | 1);
+    cpu.write_csr(MSTATUS, MSTATUS_MIE);
+    cpu.set_privilege(Privilege::User);
+
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_privilege(), Privilege::Machine);
+    assert_eq!(cpu.get_pc(), handler);
+    assert_eq!(cpu.read_csr(MCAUSE), 8);
+    assert_eq!(cpu.read_csr(MEPC), pc);
+
+    // MIE is stacked into MPIE and MPP remembers user mode
+    let mstatus = cpu.read_csr(MSTATUS);
+    assert_eq!(mstatus & MSTATUS_MIE, 0);
+    assert_eq!(mstatus & MSTATUS_MPIE, MSTATUS_MPIE);
+    assert_eq!(mstatus & MSTATUS_MPP, 0);
+}
+
+#[test]
+fn traps_from_machine_mode_stack_machine_mode() {
+    let mut cpu = ecall_cpu();
+    cpu.write_csr(MTVEC, DRAM_BASE as u32 + 0x100);
+
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_privilege(), Privilege::Machine);
+    assert_eq!(cpu.read_csr(MCAUSE), 11);
+
+    let mstatus = cpu.read_csr(MSTATUS);
+    assert_eq!(mstatus & (MSTATUS_MIE | MSTATUS_MPIE), 0);
+    assert_eq!(mstatus & MSTATUS_MPP, MSTATUS_MPP);
+}
+
+#[test]
+fn unknown_ecalls_without_a_trap_handler_are_fatal() {
+    let mut cpu = ecall_cpu();
+    assert_eq!(cpu.read_csr(MTVEC), 0);
+    assert!(cpu.step().is_err());
+}
+
+#[test]
+fn compressed_instructions_trap_at_their_own_address() {
+    let handler = DRAM_BASE as u32 + 0x100;
+    let ebreak = DRAM_BASE as u32 + 0x12;
+
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    cpu.write(ebreak, C_EBREAK, Sizes::HalfWord, AccessType::Writable)
+        .unwrap();
+    cpu.write_csr(MTVEC, handler);
+    cpu.set_pc(ebreak);
+
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_pc(), handler);
+    assert_eq!(cpu.read_csr(MCAUSE), 3);
+    assert_eq!(cpu.read_csr(MEPC), ebreak);
+    assert_eq!(cpu.read_csr(MTVAL), ebreak);
+}
+
+#[test]
+fn fetch_faults_trap_or_stop_the_machine() {
+    // a hart without a ROM powers on where nothing is mapped
+    let mut cpu = Riscv32Cpu::new();
+    cpu.add_device(Dram::new_device()).unwrap();
+    assert!(cpu.step().is_err());
+    assert!(cpu.run().is_err());
+
+    // with a trap handler the fault is taken at the address fetched from
+    let handler = DRAM_BASE as u32 + 0x100;
+    let nowhere = 0x4000_0000;
+    cpu.write_csr(MTVEC, handler);
+    cpu.set_pc(nowhere);
+    cpu.step().unwrap();
+    assert_eq!(cpu.get_pc(), handler);
+    assert_eq!(cpu.read_csr(MCAUSE), 1);
+    assert_eq!(cpu.read_csr(MEPC), nowhere);
+    assert_eq!(cpu.read_csr(MTVAL), nowhere);
+}
@@ -0,0 +1,857 @@
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+use riscv_vm::{
+    bus::Bus,
+    cpu::{AccessType, Riscv32Cpu},
+    devices::NS_PER_STEP,
+    memory::dram::{Dram, Sizes, DRAM_BASE},
+    virtio::{
+        console::{PortEndpoint, VirtioConsole},
+        input::{Action, Script, VirtioInput},
+        net::{CableBackend, ChannelBackend, NetBackend, PcapBackend, VirtioNet},
+        p9::Virtio9p,
+        queue::MAX_CHAIN_LEN,
+        rng::{EntropySource, SeededRng, VirtioRng},
+        VirtioMmio, POLL_INTERVAL_NS, VIRTIO_BASE, VIRTIO_F_VERSION_1,
+    },
+};
+
+const DESC: u64 = DRAM_BASE;
+const AVAIL: u64 = DRAM_BASE + 0x1000;
+const USED: u64 = DRAM_BASE + 0x2000;
+const BUFFER: u64 = DRAM_BASE + 0x3000;
+
+fn write_register(bus: &mut Bus, offset: u64, value: u32) {
+    bus.write(VIRTIO_BASE + offset, value, Sizes::Word).unwrap();
+}
+
+/// Drive the virtio-mmio initialization sequence, setting up `queue` only.
+fn initialize(bus: &mut Bus, queue: u32) {
+    write_register(bus, 0x070, 0x1 | 0x2); // ACKNOWLEDGE | DRIVER
+    write_register(bus, 0x024, 1); // DriverFeaturesSel
+    write_register(bus, 0x020, 1); // VIRTIO_F_VERSION_1
+    write_register(bus, 0x070, 0x1 | 0x2 | 0x8); // FEATURES_OK
+    write_register(bus, 0x030, queue); // QueueSel
+    write_register(bus, 0x038, 8); // QueueNum
+    write_register(bus, 0x080, DESC as u32);
+    write_register(bus, 0x090, AVAIL as u32);
+    write_register(bus, 0x0a0, USED as u32);
+    write_register(bus, 0x044, 1); // QueueReady
+    write_register(bus, 0x070, 0x1 | 0x2 | 0x8 | 0x4); // DRIVER_OK
+}
+
+/// Guest physical memory, reached through a bare bus or through a hart.
+trait Memory {
+    fn load(&mut self, address: u64, size: Sizes) -> u32;
+    fn store(&mut self, address: u64, value: u32, size: Sizes);
+}
+
+impl Memory for Bus {
+    fn load(&mut self, address: u64, size: Sizes) -> u32 {
+        self.read(address, size).unwrap()
+    }
+
+    fn store(&mut self, address: u64, value: u32, size: Sizes) {
+        self.write(address, value, size).unwrap();
+    }
+}
+
+impl Memory for Riscv32Cpu {
+    fn load(&mut self, address: u64, size: Sizes) -> u32 {
+        self.read(address as u32, size, AccessType::Readable)
+            .unwrap()
+    }
+
+    fn store(&mut self, address: u64, value: u32, size: Sizes) {
+        self.write(address as u32, value, size, AccessType::Writable)
+            .unwrap();
+    }
+}
+
+/// Negotiate `features` and set up `queues`. Every queue gets its own rings and buffers, see
+/// [`Ring`].
+fn initialize_queues(memory: &mut impl Memory, features: u64, queues: &[u16]) -> Vec<Ring> {
+    let mut write_register =
+        |offset: u64, value: u32| memory.store(VIRTIO_BASE + offset, value, Sizes::Word);
+    write_register(0x070, 0x1 | 0x2); // ACKNOWLEDGE | DRIVER
+    for sel in 0..2 {
+        write_register(0x024, sel);
+        write_register(0x020, (features >> (32 * sel)) as u32);
+    }
+    write_register(0x070, 0x1 | 0x2 | 0x8); // FEATURES_OK
+    let rings: Vec<Ring> = queues.iter().map(|&queue| Ring::new(queue)).collect();
+    for ring in &rings {
+        write_register(0x030, ring.queue as u32);
+        write_register(0x038, 8);
+        write_register(0x080, ring.base as u32);
+        write_register(0x090, ring.base as u32 + 0x100);
+        write_register(0x0a0, ring.base as u32 + 0x200);
+        write_register(0x044, 1);
+    }
+    write_register(0x070, 0x1 | 0x2 | 0x8 | 0x4); // DRIVER_OK
+    rings
+}
+
+/// The driver side of a queue of eight entries, each with a 256 byte buffer of its own.
+struct Ring {
+    queue: u16,
+    base: u64,
+    avail: u16,
+    used: u16,
+}
+
+impl Ring {
+    fn new(queue: u16) -> Self {
+        Self {
+            queue,
+            base: DRAM_BASE + 0x10000 * (queue as u64 + 1),
+            avail: 0,
+            used: 0,
+        }
+    }
+
+    fn buffer(&self, slot: u16) -> u64 {
+        self.base + 0x1000 + slot as u64 * 0x100
+    }
+
+    /// Make a buffer available and notify the device. The buffer holds `data` for the device to
+    /// read, or is device-writable when `data` is `None`.
+    fn give(&mut self, memory: &mut impl Memory, data: Option<&[u8]>) {
+        let slot = self.avail % 8;
+        let desc = self.base + slot as u64 * 16;
+        let len = match data {
+            Some(data) => {
+                for (i, byte) in data.iter().enumerate() {
+                    memory.store(self.buffer(slot) + i as u64, *byte as u32, Sizes::Byte);
+                }
+                data.len() as u32
+            }
+            None => 0x100,
+        };
+        memory.store(desc, self.buffer(slot) as u32, Sizes::Word);
+        memory.store(desc + 8, len, Sizes::Word);
+        let flags = if data.is_some() { 0 } else { 2 };
+        memory.store(desc + 12, flags, Sizes::HalfWord);
+        let entry = self.base + 0x104 + slot as u64 * 2;
+        memory.store(entry, slot as u32, Sizes::HalfWord);
+        self.avail = self.avail.wrapping_add(1);
+        memory.store(self.base + 0x102, self.avail as u32, Sizes::HalfWord);
+        memory.store(VIRTIO_BASE + 0x050, self.queue as u32, Sizes::Word);
+    }
+
+    /// The contents of the buffers the device used since the last call.
+    fn take(&mut self, memory: &mut impl Memory) -> Vec<Vec<u8>> {
+        let used = memory.load(self.base + 0x202, Sizes::HalfWord) as u16;
+        let mut buffers = Vec::new();
+        while self.used != used {
+            let entry = self.base + 0x204 + (self.used % 8) as u64 * 8;
+            let slot = memory.load(entry, Sizes::Word) as u16;
+            let len = memory.load(entry + 4, Sizes::Word) as u64;
+            buffers.push(
+                (0..len)
+                    .map(|i| memory.load(self.buffer(slot) + i, Sizes::Byte) as u8)
+                    .collect(),
+            );
+            self.used = self.used.wrapping_add(1);
+        }
+        buffers
+    }
+}
+
+/// A console control message.
+fn control(id: u32, event: u16, value: u16, extra: &[u8]) -> Vec<u8> {
+    let mut message = id.to_le_bytes().to_vec();
+    message.extend(event.to_le_bytes());
+    message.extend(value.to_le_bytes());
+    message.extend(extra);
+    message
+}
+
+#[test]
+fn console_ports_are_announced_over_the_control_queues() {
+    let (endpoint, _console) = PortEndpoint::channel();
+    let mut console = VirtioConsole::new(endpoint).unwrap();
+    let (endpoint, _agent) = PortEndpoint::channel();
+    assert_eq!(console.add_port("agent", endpoint).unwrap(), 1);
+
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(console), VIRTIO_BASE))
+        .unwrap();
+    // max_nr_ports
+    assert_eq!(bus.read(VIRTIO_BASE + 0x104, Sizes::Word).unwrap(), 2);
+
+    let mut rings = initialize_queues(&mut bus, VIRTIO_F_VERSION_1 | 1 << 1, &[2, 3]);
+    let [receive, transmit] = &mut rings[..] else {
+        unreachable!()
+    };
+    for _ in 0..8 {
+        receive.give(&mut bus, None);
+    }
+
+    // DEVICE_READY adds every port
+    transmit.give(&mut bus, Some(&control(0, 0, 1, &[])));
+    bus.run_events();
+    assert_eq!(transmit.take(&mut bus).len(), 1);
+    assert_eq!(
+        receive.take(&mut bus),
+        [control(0, 1, 0, &[]), control(1, 1, 0, &[])]
+    );
+
+    // PORT_READY is answered with the name of the port and the host side opening it, and the
+    // console port is marked as such
+    transmit.give(&mut bus, Some(&control(1, 3, 1, &[])));
+    bus.run_events();
+    assert_eq!(
+        receive.take(&mut bus),
+        [control(1, 7, 1, b"agent"), control(1, 6, 1, &[])]
+    );
+    transmit.give(&mut bus, Some(&control(0, 3, 1, &[])));
+    bus.run_events();
+    assert_eq!(
+        receive.take(&mut bus),
+        [
+            control(0, 4, 1, &[]),
+            control(0, 7, 1, b"console"),
+            control(0, 6, 1, &[])
+        ]
+    );
+
+    // the guest opens and closes ports with PORT_OPEN
+    let is_open = |bus: &Bus, id| {
+        let mmio = bus.get_device::<VirtioMmio>().unwrap();
+        mmio.device::<VirtioConsole>().unwrap().is_open(id)
+    };
+    assert!(!is_open(&bus, 1));
+    transmit.give(&mut bus, Some(&control(1, 6, 1, &[])));
+    bus.run_events();
+    assert!(is_open(&bus, 1));
+    assert!(!is_open(&bus, 0));
+    transmit.give(&mut bus, Some(&control(1, 6, 0, &[])));
+    bus.run_events();
+    assert!(!is_open(&bus, 1));
+}
+
+/// A console whose port 0 goes to `console` and port 1 to `endpoint`, with the data queues of
+/// both ports set up.
+fn console_bus(console: PortEndpoint, endpoint: PortEndpoint) -> (Bus, Vec<Ring>) {
+    let mut device = VirtioConsole::new(console).unwrap();
+    device.add_port("port", endpoint).unwrap();
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(device), VIRTIO_BASE))
+        .unwrap();
+    let rings = initialize_queues(&mut bus, VIRTIO_F_VERSION_1 | 1 << 1, &[0, 1, 4, 5]);
+    (bus, rings)
+}
+
+#[test]
+fn console_ports_move_data_through_their_endpoints() {
+    let dir = std::env::temp_dir().join(format!("riscv-vm-console-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // a channel carries data both ways
+    let (endpoint, host) = PortEndpoint::channel();
+    let (mut bus, mut rings) = console_bus(PortEndpoint::Stdio, endpoint);
+    rings[3].give(&mut bus, Some(b"to the host"));
+    rings[2].give(&mut bus, None);
+    bus.run_events();
+    assert_eq!(host.try_recv(), b"to the host");
+    host.send(b"to the guest").unwrap();
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    assert_eq!(rings[2].take(&mut bus), [b"to the guest"]);
+
+    // stdout takes the output of the console port
+    rings[1].give(&mut bus, Some(b"console output\n"));
+    bus.run_events();
+    assert_eq!(rings[1].take(&mut bus), [b""]);
+
+    // a file collects the output, and never has input
+    let path = dir.join("log");
+    std::fs::write(&path, "old ").unwrap();
+    let (endpoint, _host) = PortEndpoint::channel();
+    let (mut bus, mut rings) = console_bus(endpoint, PortEndpoint::File(path.clone()));
+    rings[3].give(&mut bus, Some(b"new"));
+    rings[2].give(&mut bus, None);
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    assert_eq!(std::fs::read(&path).unwrap(), b"old new");
+    assert!(rings[2].take(&mut bus).is_empty());
+
+    // a socket talks to one client at a time, data sent without a client is dropped
+    let path = dir.join("socket");
+    let (endpoint, _host) = PortEndpoint::channel();
+    let (mut bus, mut rings) = console_bus(endpoint, PortEndpoint::UnixSocket(path.clone()));
+    rings[3].give(&mut bus, Some(b"nobody listens"));
+    bus.run_events();
+    assert_eq!(rings[3].take(&mut bus).len(), 1);
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(b"from the client").unwrap();
+    rings[2].give(&mut bus, None);
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    assert_eq!(rings[2].take(&mut bus), [b"from the client"]);
+    rings[3].give(&mut bus, Some(b"to the client"));
+    bus.run_events();
+    let mut reply = [0; 13];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"to the client");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn descriptor_chains_longer_than_the_cap_are_rejected() {
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let rng = VirtioRng::new(EntropySource::Seeded(0)).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(rng), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 0);
+
+    bus.write(DESC, BUFFER as u32, Sizes::Word).unwrap();
+    bus.write(DESC + 8, MAX_CHAIN_LEN as u32 + 1, Sizes::Word)
+        .unwrap();
+    bus.write(DESC + 12, 2, Sizes::HalfWord).unwrap();
+    bus.write(AVAIL + 4, 0, Sizes::HalfWord).unwrap();
+    bus.write(AVAIL + 2, 1, Sizes::HalfWord).unwrap();
+    write_register(&mut bus, 0x050, 0);
+    bus.run_events();
+
+    // nothing is used and the device asks for a reset
+    assert_eq!(bus.read(USED + 2, Sizes::HalfWord).unwrap(), 0);
+    assert_ne!(
+        bus.read(VIRTIO_BASE + 0x070, Sizes::Word).unwrap() & 0x40,
+        0
+    );
+}
+
+fn request_entropy(seed: u64, len: u32) -> Vec<u8> {
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let rng = VirtioRng::new(EntropySource::Seeded(seed)).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(rng), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 0);
+
+    // a single device-writable descriptor
+    bus.write(DESC, BUFFER as u32, Sizes::Word).unwrap();
+    bus.write(DESC + 8, len, Sizes::Word).unwrap();
+    bus.write(DESC + 12, 2, Sizes::HalfWord).unwrap();
+    // make it available and notify the device
+    bus.write(AVAIL + 4, 0, Sizes::HalfWord).unwrap();
+    bus.write(AVAIL + 2, 1, Sizes::HalfWord).unwrap();
+    write_register(&mut bus, 0x050, 0);
+
+    bus.run_events();
+
+    assert_eq!(bus.read(USED + 2, Sizes::HalfWord).unwrap() as u16, 1);
+    assert_eq!(bus.read(USED + 8, Sizes::Word).unwrap(), len);
+    assert_eq!(bus.read(VIRTIO_BASE + 0x060, Sizes::Word).unwrap(), 1);
+
+    (0..len as u64)
+        .map(|i| bus.read(BUFFER + i, Sizes::Byte).unwrap() as u8)
+        .collect()
+}
+
+#[test]
+fn seeded_entropy_is_reproducible() {
+    let first = request_entropy(42, 64);
+    assert_eq!(first, request_entropy(42, 64));
+    assert_ne!(first, request_entropy(43, 64));
+
+    let mut expected = vec![0; 64];
+    SeededRng::new(42).fill_bytes(&mut expected);
+    assert_eq!(first, expected);
+}
+
+#[test]
+fn transmitted_frames_reach_the_backend() {
+    let (backend, mut peer) = ChannelBackend::pair();
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let net = VirtioNet::new([0x52, 0x54, 0, 0x12, 0x34, 0x56], Box::new(backend));
+    bus.add_device(VirtioMmio::new_device(Box::new(net), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 1);
+
+    // the mac address is in the configuration space
+    assert_eq!(bus.read(VIRTIO_BASE + 0x100, Sizes::Byte).unwrap(), 0x52);
+    assert_eq!(bus.read(VIRTIO_BASE + 0x105, Sizes::Byte).unwrap(), 0x56);
+
+    // a zeroed header followed by the frame, in a single device-readable descriptor
+    let frame = b"not quite an ethernet frame";
+    for (i, byte) in frame.iter().enumerate() {
+        bus.write(BUFFER + 12 + i as u64, *byte as u32, Sizes::Byte)
+            .unwrap();
+    }
+    bus.write(DESC, BUFFER as u32, Sizes::Word).unwrap();
+    bus.write(DESC + 8, 12 + frame.len() as u32, Sizes::Word)
+        .unwrap();
+    bus.write(AVAIL + 4, 0, Sizes::HalfWord).unwrap();
+    bus.write(AVAIL + 2, 1, Sizes::HalfWord).unwrap();
+    write_register(&mut bus, 0x050, 1);
+
+    bus.run_events();
+
+    assert_eq!(bus.read(USED + 2, Sizes::HalfWord).unwrap() as u16, 1);
+    assert_eq!(peer.receive().unwrap().as_deref(), Some(&frame[..]));
+    assert_eq!(peer.receive().unwrap(), None);
+}
+
+/// A bus with a network card on `backend` whose receive and transmit queues are set up.
+fn net_bus(backend: Box<dyn NetBackend>, features: u64) -> (Bus, Vec<Ring>) {
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let net = VirtioNet::new([0x52, 0x54, 0, 0x12, 0x34, 0x56], backend);
+    bus.add_device(VirtioMmio::new_device(Box::new(net), VIRTIO_BASE))
+        .unwrap();
+    let rings = initialize_queues(&mut bus, features, &[0, 1]);
+    (bus, rings)
+}
+
+/// A received packet: the header, with `num_buffers` only, and the frame.
+fn received(num_buffers: u16, frame: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; 10];
+    packet.extend(num_buffers.to_le_bytes());
+    packet.extend(frame);
+    packet
+}
+
+#[test]
+fn received_frames_reach_the_guest_without_losses() {
+    let (backend, mut peer) = ChannelBackend::pair();
+    let (mut bus, mut rings) = net_bus(Box::new(backend), VIRTIO_F_VERSION_1);
+    let receive = &mut rings[0];
+
+    receive.give(&mut bus, None);
+    peer.send(b"a frame").unwrap();
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    assert_eq!(receive.take(&mut bus), [received(1, b"a frame")]);
+
+    // frames the guest has no buffers for stay with the backend until it catches up
+    let frames: Vec<Vec<u8>> = (0..300u32).map(|i| i.to_le_bytes().to_vec()).collect();
+    for frame in &frames {
+        peer.send(frame).unwrap();
+    }
+    bus.run_until(bus.now() + 10 * POLL_INTERVAL_NS);
+    let mut packets = Vec::new();
+    for _ in 0..frames.len().div_ceil(8) {
+        for _ in 0..8 {
+            receive.give(&mut bus, None);
+        }
+        bus.run_until(bus.now() + POLL_INTERVAL_NS);
+        packets.extend(receive.take(&mut bus));
+    }
+    let expected: Vec<Vec<u8>> = frames.iter().map(|frame| received(1, frame)).collect();
+    assert_eq!(packets, expected);
+}
+
+#[test]
+fn mergeable_receive_buffers_hold_large_frames() {
+    let frame: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+    let (backend, mut peer) = ChannelBackend::pair();
+    let features = VIRTIO_F_VERSION_1 | 1 << 15; // VIRTIO_NET_F_MRG_RXBUF
+    let (mut bus, mut rings) = net_bus(Box::new(backend), features);
+    for _ in 0..3 {
+        rings[0].give(&mut bus, None);
+    }
+    peer.send(&frame).unwrap();
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    let buffers = rings[0].take(&mut bus);
+    assert_eq!(
+        buffers.iter().map(Vec::len).collect::<Vec<_>>(),
+        [256, 256, 100]
+    );
+    assert_eq!(buffers.concat(), received(3, &frame));
+
+    // without the feature a frame gets a single buffer and is truncated to it
+    let (backend, mut peer) = ChannelBackend::pair();
+    let (mut bus, mut rings) = net_bus(Box::new(backend), VIRTIO_F_VERSION_1);
+    rings[0].give(&mut bus, None);
+    peer.send(&frame).unwrap();
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    assert_eq!(
+        rings[0].take(&mut bus),
+        [received(1, &frame)[..256].to_vec()]
+    );
+}
+
+#[test]
+fn pcap_files_record_and_replay_frames() {
+    let dir = std::env::temp_dir().join(format!("riscv-vm-pcap-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("capture.pcap");
+
+    let mut recorder = PcapBackend::new(Some(&path), None).unwrap();
+    assert_eq!(recorder.receive().unwrap(), None);
+    recorder.send(b"first").unwrap();
+    recorder.send(b"second").unwrap();
+    drop(recorder);
+
+    let capture = std::fs::read(&path).unwrap();
+    assert_eq!(capture[..4], 0xa1b2_c3d4u32.to_le_bytes());
+    assert_eq!(capture.len(), 24 + 16 + 5 + 16 + 6);
+
+    let mut player = PcapBackend::new(None, Some(&path)).unwrap();
+    assert_eq!(player.receive().unwrap().as_deref(), Some(&b"first"[..]));
+    assert_eq!(player.receive().unwrap().as_deref(), Some(&b"second"[..]));
+    assert_eq!(player.receive().unwrap(), None);
+
+    // records longer than the snapshot length of the file are refused
+    let mut corrupted = capture.clone();
+    corrupted[16..20].copy_from_slice(&5u32.to_le_bytes());
+    std::fs::write(&path, &corrupted).unwrap();
+    let mut player = PcapBackend::new(None, Some(&path)).unwrap();
+    assert_eq!(player.receive().unwrap().as_deref(), Some(&b"first"[..]));
+    assert_eq!(
+        player.receive().unwrap_err().to_string(),
+        "pcap record of 6 bytes is longer than the snapshot length 5"
+    );
+
+    std::fs::write(&path, b"not a capture at all, but long enough").unwrap();
+    assert!(PcapBackend::new(None, Some(&path)).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn cable_connects_two_machines() {
+    let (a, b) = CableBackend::pair().unwrap();
+    let mut machines: Vec<(Riscv32Cpu, Vec<Ring>)> = [a, b]
+        .into_iter()
+        .enumerate()
+        .map(|(i, end)| {
+            let mut cpu = Riscv32Cpu::new();
+            cpu.add_device(Dram::new_device()).unwrap();
+            let net = VirtioNet::new([0x52, 0x54, 0, 0, 0, i as u8], Box::new(end));
+            cpu.add_device(VirtioMmio::new_device(Box::new(net), VIRTIO_BASE))
+                .unwrap();
+            // spin while the device does the work
+            cpu.write(
+                DRAM_BASE as u32,
+                0x0000_006f,
+                Sizes::Word,
+                AccessType::Writable,
+            )
+            .unwrap();
+            cpu.set_pc(DRAM_BASE as u32);
+            let rings = initialize_queues(&mut cpu, VIRTIO_F_VERSION_1, &[0, 1]);
+            (cpu, rings)
+        })
+        .collect();
+
+    let (cpu, rings) = &mut machines[1];
+    rings[0].give(cpu, None);
+    let (cpu, rings) = &mut machines[0];
+    let mut packet = vec![0; 12];
+    packet.extend(b"over the cable");
+    rings[1].give(cpu, Some(&packet));
+
+    let steps = POLL_INTERVAL_NS / NS_PER_STEP + 1;
+    for (cpu, _) in &mut machines {
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+    }
+    let (cpu, rings) = &mut machines[0];
+    assert_eq!(rings[1].take(cpu).len(), 1);
+    let (cpu, rings) = &mut machines[1];
+    assert_eq!(rings[0].take(cpu), [received(1, b"over the cable")]);
+}
+
+/// Send a 9P request through the first queue and return the reply. Each request uses the next
+/// entry of the available ring.
+fn transact(bus: &mut Bus, index: u16, kind: u8, body: &[u8]) -> (u8, Vec<u8>) {
+    let request = BUFFER;
+    let reply = BUFFER + 0x1000;
+    let mut message = ((7 + body.len()) as u32).to_le_bytes().to_vec();
+    message.push(kind);
+    message.extend_from_slice(&index.to_le_bytes());
+    message.extend_from_slice(body);
+    for (i, byte) in message.iter().enumerate() {
+        bus.write(request + i as u64, *byte as u32, Sizes::Byte)
+            .unwrap();
+    }
+
+    // a device-readable request chained to a device-writable reply buffer
+    bus.write(DESC, request as u32, Sizes::Word).unwrap();
+    bus.write(DESC + 8, message.len() as u32, Sizes::Word)
+        .unwrap();
+    bus.write(DESC + 12, 1, Sizes::HalfWord).unwrap();
+    bus.write(DESC + 14, 1, Sizes::HalfWord).unwrap();
+    bus.write(DESC + 16, reply as u32, Sizes::Word).unwrap();
+    bus.write(DESC + 24, 0x1000, Sizes::Word).unwrap();
+    bus.write(DESC + 28, 2, Sizes::HalfWord).unwrap();
+    bus.write(AVAIL + 4 + index as u64 % 8 * 2, 0, Sizes::HalfWord)
+        .unwrap();
+    bus.write(AVAIL + 2, index as u32 + 1, Sizes::HalfWord)
+        .unwrap();
+    write_register(bus, 0x050, 0);
+    bus.run_events();
+
+    let len = bus.read(reply, Sizes::Word).unwrap() as u64;
+    let kind = bus.read(reply + 4, Sizes::Byte).unwrap() as u8;
+    let body = (7..len)
+        .map(|i| bus.read(reply + i, Sizes::Byte).unwrap() as u8)
+        .collect();
+    (kind, body)
+}
+
+fn string(value: &str) -> Vec<u8> {
+    let mut bytes = (value.len() as u16).to_le_bytes().to_vec();
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
+#[test]
+fn shared_directory_is_sandboxed() {
+    let root = std::env::temp_dir().join(format!("riscv-vm-9p-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("share")).unwrap();
+    std::fs::write(root.join("share/hello"), "hello from the host").unwrap();
+    std::fs::write(root.join("secret"), "outside").unwrap();
+    std::os::unix::fs::symlink("../secret", root.join("share/escape")).ok();
+
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let p9 = Virtio9p::new("share", &root.join("share"), true).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(p9), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 0);
+
+    // Tversion, Tattach fid 0
+    let mut version = 8192u32.to_le_bytes().to_vec();
+    version.extend(string("9P2000.L"));
+    assert_eq!(transact(&mut bus, 0, 100, &version).0, 101);
+    let mut attach = [0u32.to_le_bytes(), u32::MAX.to_le_bytes()].concat();
+    attach.extend(string("root"));
+    attach.extend(string(""));
+    attach.extend(0u32.to_le_bytes());
+    assert_eq!(transact(&mut bus, 1, 104, &attach).0, 105);
+
+    let walk = |newfid: u32, names: &[&str]| {
+        let mut walk = [0u32.to_le_bytes(), newfid.to_le_bytes()].concat();
+        walk.extend((names.len() as u16).to_le_bytes());
+        for name in names {
+            walk.extend(string(name));
+        }
+        walk
+    };
+    let open = |fid: u32, flags: u32| [fid.to_le_bytes(), flags.to_le_bytes()].concat();
+
+    // walking above the root stays at the root, so the walk stops before "secret"
+    let (kind, body) = transact(&mut bus, 2, 110, &walk(1, &["..", "..", "secret"]));
+    assert_eq!((kind, &body[..2]), (111, &2u16.to_le_bytes()[..]));
+    // a symbolic link pointing outside can't be opened
+    assert_eq!(transact(&mut bus, 3, 110, &walk(2, &["escape"])).0, 111);
+    let (kind, body) = transact(&mut bus, 4, 12, &open(2, 0));
+    assert_eq!((kind, body), (7, 13u32.to_le_bytes().to_vec()));
+
+    // files inside can be read, but not written
+    assert_eq!(transact(&mut bus, 5, 110, &walk(3, &["hello"])).0, 111);
+    let (kind, body) = transact(&mut bus, 6, 12, &open(3, 2));
+    assert_eq!((kind, body), (7, 30u32.to_le_bytes().to_vec()));
+    assert_eq!(transact(&mut bus, 7, 12, &open(3, 0)).0, 13);
+    let mut read = 3u32.to_le_bytes().to_vec();
+    read.extend(0u64.to_le_bytes());
+    read.extend(100u32.to_le_bytes());
+    let (kind, body) = transact(&mut bus, 8, 116, &read);
+    assert_eq!(kind, 117);
+    assert_eq!(&body[4..], b"hello from the host");
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn shared_directory_does_not_create_through_links() {
+    let root = std::env::temp_dir().join(format!("riscv-vm-9p-links-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("share")).unwrap();
+    let outside = root.join("outside");
+
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let p9 = Virtio9p::new("share", &root.join("share"), false).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(p9), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 0);
+
+    // Tversion, Tattach fid 0
+    let mut version = 8192u32.to_le_bytes().to_vec();
+    version.extend(string("9P2000.L"));
+    assert_eq!(transact(&mut bus, 0, 100, &version).0, 101);
+    let mut attach = [0u32.to_le_bytes(), u32::MAX.to_le_bytes()].concat();
+    attach.extend(string("root"));
+    attach.extend(string(""));
+    attach.extend(0u32.to_le_bytes());
+    assert_eq!(transact(&mut bus, 1, 104, &attach).0, 105);
+
+    // Tsymlink of a name in the root to a file outside of it
+    let mut symlink = 0u32.to_le_bytes().to_vec();
+    symlink.extend(string("link"));
+    symlink.extend(string(outside.to_str().unwrap()));
+    symlink.extend(0u32.to_le_bytes());
+    assert_eq!(transact(&mut bus, 2, 16, &symlink).0, 17);
+
+    // Tlcreate of the same name, write only and without O_EXCL, is refused with ELOOP
+    let mut lcreate = 0u32.to_le_bytes().to_vec();
+    lcreate.extend(string("link"));
+    lcreate.extend([0x41u32, 0o644, 0].map(u32::to_le_bytes).concat());
+    let (kind, body) = transact(&mut bus, 3, 14, &lcreate);
+    assert_eq!((kind, body), (7, 40u32.to_le_bytes().to_vec()));
+    assert!(!outside.exists());
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn shared_directory_rejects_malformed_requests() {
+    let root = std::env::temp_dir().join(format!("riscv-vm-9p-times-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("file"), "").unwrap();
+
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let p9 = Virtio9p::new("share", &root, false).unwrap();
+    bus.add_device(VirtioMmio::new_device(Box::new(p9), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 0);
+
+    // a message size too small for the reply headers is refused with EINVAL
+    let mut version = 16u32.to_le_bytes().to_vec();
+    version.extend(string("9P2000.L"));
+    let (kind, body) = transact(&mut bus, 0, 100, &version);
+    assert_eq!((kind, body), (7, 22u32.to_le_bytes().to_vec()));
+
+    let mut version = 8192u32.to_le_bytes().to_vec();
+    version.extend(string("9P2000.L"));
+    assert_eq!(transact(&mut bus, 1, 100, &version).0, 101);
+    let mut attach = [0u32.to_le_bytes(), u32::MAX.to_le_bytes()].concat();
+    attach.extend(string("root"));
+    attach.extend(string(""));
+    attach.extend(0u32.to_le_bytes());
+    assert_eq!(transact(&mut bus, 2, 104, &attach).0, 105);
+    let mut walk = [0u32.to_le_bytes(), 1u32.to_le_bytes()].concat();
+    walk.extend(1u16.to_le_bytes());
+    walk.extend(string("file"));
+    assert_eq!(transact(&mut bus, 3, 110, &walk).0, 111);
+
+    // Tsetattr of the access time (ATIME | ATIME_SET)
+    let setattr = |secs: u64, nsecs: u64| {
+        let mut setattr = [1u32, 0x90, 0, 0, 0].map(u32::to_le_bytes).concat();
+        setattr.extend([0, secs, nsecs, 0, 0].map(u64::to_le_bytes).concat());
+        setattr
+    };
+    let einval = (7, 22u32.to_le_bytes().to_vec());
+    assert_eq!(transact(&mut bus, 4, 26, &setattr(u64::MAX, 0)), einval);
+    assert_eq!(
+        transact(&mut bus, 5, 26, &setattr(0, 1_000_000_000)),
+        einval
+    );
+    assert_eq!(
+        transact(&mut bus, 6, 26, &setattr(1_000_000, 5)),
+        (27, vec![])
+    );
+
+    let accessed = std::fs::metadata(root.join("file"))
+        .unwrap()
+        .accessed()
+        .unwrap();
+    let expected = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000, 5);
+    assert_eq!(accessed, expected);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn scripted_keyboard_input() {
+    let script = Script::parse("at 2s type 'ls\\n', at 3s click 100,200\nat 1ms key A").unwrap();
+    assert_eq!(script.entries()[0].time_ns, 1_000_000);
+    assert!(script.entries()[2].action.is_pointer());
+    assert!(Script::parse("at 1s type 'é'").is_err());
+    assert!(Script::parse("at 1s press nokey").is_err());
+    assert!(Script::parse("at 1 move 1,2").is_err());
+
+    let mut bus = Bus::new();
+    bus.add_device(Dram::new_device()).unwrap();
+    let mut keyboard = VirtioInput::keyboard();
+    keyboard.set_script(Script::parse("at 1ms type 'A'").unwrap());
+    let handle = keyboard.handle();
+    bus.add_device(VirtioMmio::new_device(Box::new(keyboard), VIRTIO_BASE))
+        .unwrap();
+    initialize(&mut bus, 0);
+
+    // the name of the device
+    bus.write(VIRTIO_BASE + 0x100, 1, Sizes::Byte).unwrap();
+    let size = bus.read(VIRTIO_BASE + 0x102, Sizes::Byte).unwrap() as u64;
+    let name: Vec<u8> = (0..size)
+        .map(|i| bus.read(VIRTIO_BASE + 0x108 + i, Sizes::Byte).unwrap() as u8)
+        .collect();
+    assert_eq!(name, b"riscv-vm keyboard");
+
+    // eight device-writable buffers for one event each
+    for i in 0..8 {
+        bus.write(DESC + i * 16, (BUFFER + i * 8) as u32, Sizes::Word)
+            .unwrap();
+        bus.write(DESC + i * 16 + 8, 8, Sizes::Word).unwrap();
+        bus.write(DESC + i * 16 + 12, 2, Sizes::HalfWord).unwrap();
+        bus.write(AVAIL + 4 + i * 2, i as u32, Sizes::HalfWord)
+            .unwrap();
+    }
+    bus.write(AVAIL + 2, 8, Sizes::HalfWord).unwrap();
+    write_register(&mut bus, 0x050, 0);
+
+    bus.run_until(1_000_000 - NS_PER_STEP);
+    assert_eq!(bus.read(USED + 2, Sizes::HalfWord).unwrap(), 0);
+    bus.run_until(1_000_000);
+
+    // shift, a and their reports
+    assert_eq!(bus.read(USED + 2, Sizes::HalfWord).unwrap(), 8);
+    let event = |bus: &Bus, i: u64| {
+        (
+            bus.read(BUFFER + i * 8, Sizes::HalfWord).unwrap() as u16,
+            bus.read(BUFFER + i * 8 + 2, Sizes::HalfWord).unwrap() as u16,
+            bus.read(BUFFER + i * 8 + 4, Sizes::Word).unwrap(),
+        )
+    };
+    let expected = [
+        (1, 42, 1),
+        (0, 0, 0),
+        (1, 30, 1),
+        (0, 0, 0),
+        (1, 30, 0),
+        (0, 0, 0),
+        (1, 42, 0),
+        (0, 0, 0),
+    ];
+    for (i, expected) in expected.iter().enumerate() {
+        assert_eq!(event(&bus, i as u64), *expected);
+    }
+
+    // hand the first four buffers back to the device
+    for i in 0..4 {
+        bus.write(AVAIL + 4 + (8 + i) * 2, i as u32, Sizes::HalfWord)
+            .unwrap();
+    }
+    bus.write(AVAIL + 2, 12, Sizes::HalfWord).unwrap();
+    write_register(&mut bus, 0x050, 0);
+
+    // pointer actions are ignored by a keyboard, host actions are performed on the next poll
+    handle.send(Action::Move { x: 1, y: 1 }).unwrap();
+    handle.type_text("x").unwrap();
+    bus.run_until(bus.now() + POLL_INTERVAL_NS);
+    let mmio = bus.get_device::<VirtioMmio>().unwrap();
+    assert!(mmio.device::<VirtioInput>().unwrap().script_done());
+
+    // x goes down and up, nothing comes from the pointer
+    assert_eq!(bus.read(USED + 2, Sizes::HalfWord).unwrap(), 12);
+    let expected = [(1, 45, 1), (0, 0, 0), (1, 45, 0), (0, 0, 0)];
+    for (i, expected) in expected.iter().enumerate() {
+        assert_eq!(event(&bus, i as u64), *expected);
+    }
+}